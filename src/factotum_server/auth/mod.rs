@@ -0,0 +1,336 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use iron::prelude::*;
+use iron::middleware::{BeforeMiddleware, Handler};
+use iron::mime::*;
+use iron::status;
+use iron::status::Status;
+use iron::typemap::Key;
+use rustc_serialize::hex::FromHex;
+use serde_json;
+
+use factotum_server::responder::ResponseMessage;
+use factotum_server::responder::v2::{API_V2_PREFIX, ApiError, ErrorResponse};
+
+#[cfg(test)]
+mod tests;
+
+const BEARER_SCHEME: &'static str = "Bearer";
+const SHA256_PREFIX: &'static str = "sha256:";
+
+// Consul health checks and orchestrator probes can't present a token
+const EXEMPT_PATHS: &'static [&'static str] = &[::HEALTH_CHECK_PATH, ::READINESS_CHECK_PATH];
+
+/// What a caller may do, each role including everything below it: viewers read
+/// server and job state, submitters can also submit jobs and admins can also
+/// change server settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Submitter,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Role, String> {
+        match role {
+            "viewer" => Ok(Role::Viewer),
+            "submitter" => Ok(Role::Submitter),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role '{}', must be one of (viewer|submitter|admin)", role)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Submitter => write!(f, "submitter"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Identity of the authenticated caller, available to handlers through the
+/// request extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Caller {
+    pub identity: String,
+    pub role: Role,
+}
+
+impl Key for Caller {
+    type Value = Caller;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    InsufficientRole(Role),
+}
+
+impl AuthError {
+    fn status(&self) -> Status {
+        match *self {
+            AuthError::MissingToken => status::Unauthorized,
+            AuthError::InvalidToken => status::Unauthorized,
+            AuthError::InsufficientRole(_) => status::Forbidden,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match *self {
+            AuthError::MissingToken => "UNAUTHENTICATED",
+            AuthError::InvalidToken => "INVALID_TOKEN",
+            AuthError::InsufficientRole(_) => "FORBIDDEN",
+        }
+    }
+
+    // the challenge sent back with a 401, see RFC 6750 section 3
+    fn challenge(&self) -> Option<String> {
+        match *self {
+            AuthError::MissingToken => Some(BEARER_SCHEME.to_string()),
+            AuthError::InvalidToken => Some(format!("{} error=\"invalid_token\"", BEARER_SCHEME)),
+            AuthError::InsufficientRole(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::MissingToken => write!(f, "Authorization: Bearer token required"),
+            AuthError::InvalidToken => write!(f, "Bearer token not recognised"),
+            AuthError::InsufficientRole(ref required) => write!(f, "Requires the [{}] role or above", required),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TokenEntry {
+    identity: String,
+    digest: Vec<u8>,
+    role: Role,
+}
+
+/// Tokens allowed to call the API, one `<identity> <token> [role]` line each. The
+/// token can be stored as `sha256:<hex digest>` so the file holds no secrets, and
+/// a token without a role can only view, so nobody gets more access than was
+/// written down. Blank lines and lines starting with `#` are skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenStore {
+    tokens: Vec<TokenEntry>,
+}
+
+impl TokenStore {
+    pub fn from_file(path: &str) -> Result<TokenStore, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Could not read token file '{}': {}", path, e))?;
+        TokenStore::parse(&contents).map_err(|e| format!("Invalid token file '{}': {}", path, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<TokenStore, String> {
+        let mut tokens = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 || fields.len() > 3 {
+                return Err(format!("line {}: expected '<identity> <token> [role]'", number + 1))
+            }
+            let digest = if fields[1].starts_with(SHA256_PREFIX) {
+                let digest = fields[1][SHA256_PREFIX.len()..].from_hex().map_err(|e| format!("line {}: {}", number + 1, e))?;
+                if digest.len() != 32 {
+                    return Err(format!("line {}: SHA-256 digest must be 64 hex characters", number + 1))
+                }
+                digest
+            } else {
+                hash_token(fields[1])
+            };
+            let role = match fields.get(2) {
+                Some(role) => Role::from_str(role).map_err(|e| format!("line {}: {}", number + 1, e))?,
+                None => {
+                    warn!("No role given for [{}] in token file, defaulting to [{}]", fields[0], Role::Viewer);
+                    Role::Viewer
+                },
+            };
+            tokens.push(TokenEntry { identity: fields[0].to_string(), digest: digest, role: role });
+        }
+        if tokens.is_empty() {
+            return Err("no tokens found".to_string())
+        }
+        Ok(TokenStore { tokens: tokens })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Caller> {
+        let digest = hash_token(token);
+        // compare against every entry so timing doesn't reveal which one matched
+        let mut caller = None;
+        for entry in self.tokens.iter() {
+            if fixed_time_eq(&entry.digest, &digest) && caller.is_none() {
+                caller = Some(Caller { identity: entry.identity.clone(), role: entry.role });
+            }
+        }
+        caller
+    }
+}
+
+pub fn hash_token(token: &str) -> Vec<u8> {
+    let mut digest = Sha256::new();
+    digest.input_str(token);
+    let mut output = vec![0; digest.output_bytes()];
+    digest.result(&mut output);
+    output
+}
+
+pub fn get_bearer_token(header: Option<&str>) -> Result<&str, AuthError> {
+    let header = header.ok_or(AuthError::MissingToken)?.trim();
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case(BEARER_SCHEME) && !token.trim().is_empty() => Ok(token.trim()),
+        _ => Err(AuthError::MissingToken),
+    }
+}
+
+pub fn is_exempt(path: &str) -> bool {
+    EXEMPT_PATHS.iter().any(|exempt| *exempt == path)
+}
+
+pub fn check_authorization(store: &TokenStore, header: Option<&str>) -> Result<Caller, AuthError> {
+    let token = get_bearer_token(header)?;
+    store.authenticate(token).ok_or(AuthError::InvalidToken)
+}
+
+/// Without a caller the server runs with authentication disabled, as the
+/// `Authenticator` rejects every non-exempt request it can't identify.
+pub fn check_role(caller: Option<&Caller>, required: Role) -> Result<(), AuthError> {
+    match caller {
+        Some(caller) if caller.role < required => Err(AuthError::InsufficientRole(required)),
+        _ => Ok(()),
+    }
+}
+
+pub struct Authenticator {
+    store: TokenStore,
+}
+
+impl Authenticator {
+    pub fn new(store: TokenStore) -> Authenticator {
+        Authenticator {
+            store: store,
+        }
+    }
+}
+
+impl BeforeMiddleware for Authenticator {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let path = get_request_path(request);
+        if is_exempt(&path) {
+            return Ok(())
+        }
+        let header = request.headers.get_raw("Authorization")
+                                    .and_then(|values| values.first())
+                                    .and_then(|value| ::std::str::from_utf8(value).ok())
+                                    .map(|value| value.to_string());
+        match check_authorization(&self.store, header.as_ref().map(|value| value.as_str())) {
+            Ok(caller) => {
+                debug!("Authenticated [{}] for [{}]", caller.identity, path);
+                request.extensions.insert::<Caller>(caller);
+                Ok(())
+            },
+            Err(e) => {
+                warn!("Rejected request for [{}] from [{}] - {}", path, request.remote_addr, e);
+                let response = create_error_response(&path, &e);
+                Err(IronError { error: Box::new(e), response: response })
+            },
+        }
+    }
+}
+
+/// Route handler that only runs for callers holding at least `role`.
+pub struct Authorized<H: Handler> {
+    role: Role,
+    handler: H,
+}
+
+pub fn require<H: Handler>(role: Role, handler: H) -> Authorized<H> {
+    Authorized {
+        role: role,
+        handler: handler,
+    }
+}
+
+impl<H: Handler> Handler for Authorized<H> {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let checked = check_role(request.extensions.get::<Caller>(), self.role);
+        match checked {
+            Ok(_) => self.handler.handle(request),
+            Err(e) => {
+                let path = get_request_path(request);
+                if let Some(caller) = request.extensions.get::<Caller>() {
+                    warn!("Denied [{}] with role [{}] access to [{}] - {}", caller.identity, caller.role, path, e);
+                }
+                let response = create_error_response(&path, &e);
+                Err(IronError { error: Box::new(e), response: response })
+            },
+        }
+    }
+}
+
+pub fn get_request_path(request: &Request) -> String {
+    format!("/{}", request.url.path().join("/"))
+}
+
+// v2 routes promise structured errors, everything else gets the v1 message body
+pub fn create_error_body(path: &str, code: &str, message: &str) -> String {
+    if path.starts_with(API_V2_PREFIX) {
+        let body = ErrorResponse { error: ApiError::new(code, message) };
+        serde_json::to_string(&body).expect("JSON compact encode error")
+    } else {
+        let body = ResponseMessage { message: message.to_string() };
+        serde_json::to_string(&body).expect("JSON compact encode error")
+    }
+}
+
+fn create_error_response(path: &str, error: &AuthError) -> Response {
+    let content_type = ::JSON_CONTENT_TYPE.parse::<Mime>().expect(&format!("Unable to parse Mime type for '{}'", ::JSON_CONTENT_TYPE));
+    let mut response = Response::with((content_type, error.status(), create_error_body(path, error.code(), &error.to_string())));
+    if let Some(challenge) = error.challenge() {
+        response.headers.set_raw("WWW-Authenticate", vec![challenge.into_bytes()]);
+    }
+    response
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use rustc_serialize::hex::ToHex;
+
+fn new_token_store() -> TokenStore {
+    let contents = format!("# ops tokens\n\nalice s3cr3t admin\nscheduler {}{} submitter\nanalyst an4lyst viewer\n", SHA256_PREFIX, hash_token("sch3dul3r").to_hex());
+    TokenStore::parse(&contents).unwrap()
+}
+
+#[test]
+fn token_store_parses_plain_and_hashed_tokens() {
+    let store = new_token_store();
+
+    assert_eq!(3, store.len());
+    assert_eq!(Some(Caller { identity: "alice".to_string(), role: Role::Admin }), store.authenticate("s3cr3t"));
+    assert_eq!(Some(Caller { identity: "scheduler".to_string(), role: Role::Submitter }), store.authenticate("sch3dul3r"));
+    assert_eq!(Some(Caller { identity: "analyst".to_string(), role: Role::Viewer }), store.authenticate("an4lyst"));
+    assert_eq!(None, store.authenticate("guess"));
+}
+
+#[test]
+fn token_store_rejects_bad_lines() {
+    assert_eq!(Err("line 1: expected '<identity> <token> [role]'".to_string()), TokenStore::parse("alice"));
+    assert_eq!(Err("line 1: unknown role 'root', must be one of (viewer|submitter|admin)".to_string()), TokenStore::parse("alice s3cr3t root"));
+    assert_eq!(Err("line 2: SHA-256 digest must be 64 hex characters".to_string()), TokenStore::parse("alice s3cr3t\nbob sha256:abcd"));
+    assert!(TokenStore::parse("bob sha256:not-hex").is_err());
+    assert_eq!(Err("no tokens found".to_string()), TokenStore::parse("# nothing here\n"));
+}
+
+#[test]
+fn token_store_from_missing_file() {
+    assert!(TokenStore::from_file("/does/not/exist").is_err());
+}
+
+#[test]
+fn get_bearer_token_parses_header() {
+    assert_eq!(Ok("abc"), get_bearer_token(Some("Bearer abc")));
+    assert_eq!(Ok("abc"), get_bearer_token(Some("bearer  abc ")));
+    assert_eq!(Err(AuthError::MissingToken), get_bearer_token(None));
+    assert_eq!(Err(AuthError::MissingToken), get_bearer_token(Some("Basic YWxpY2U6cHc=")));
+    assert_eq!(Err(AuthError::MissingToken), get_bearer_token(Some("Bearer ")));
+}
+
+#[test]
+fn token_store_role_defaults_to_viewer() {
+    let store = TokenStore::parse("legacy l3gacy").unwrap();
+
+    assert_eq!(Some(Caller { identity: "legacy".to_string(), role: Role::Viewer }), store.authenticate("l3gacy"));
+}
+
+#[test]
+fn check_authorization_status_codes() {
+    let store = new_token_store();
+
+    assert_eq!(Ok(Caller { identity: "alice".to_string(), role: Role::Admin }), check_authorization(&store, Some("Bearer s3cr3t")));
+    assert_eq!(status::Unauthorized, check_authorization(&store, None).unwrap_err().status());
+    assert_eq!(status::Unauthorized, check_authorization(&store, Some("Bearer guess")).unwrap_err().status());
+}
+
+#[test]
+fn unauthorized_responses_carry_bearer_challenge() {
+    let get_challenge = |error: AuthError| {
+        let response = create_error_response("/submit", &error);
+        response.headers.get_raw("WWW-Authenticate").map(|values| String::from_utf8(values[0].clone()).unwrap())
+    };
+
+    assert_eq!(Some("Bearer".to_string()), get_challenge(AuthError::MissingToken));
+    assert_eq!(Some(r#"Bearer error="invalid_token""#.to_string()), get_challenge(AuthError::InvalidToken));
+    assert_eq!(None, get_challenge(AuthError::InsufficientRole(Role::Admin)));
+}
+
+#[test]
+fn health_check_is_exempt() {
+    assert!(is_exempt("/healthz"));
+    assert!(is_exempt("/readyz"));
+    assert_eq!(false, is_exempt("/submit"));
+}
+
+#[test]
+fn create_error_body_matches_api_version() {
+    assert_eq!(r#"{"message":"Bearer token not recognised"}"#, create_error_body("/submit", "INVALID_TOKEN", "Bearer token not recognised"));
+    assert_eq!(r#"{"error":{"code":"INVALID_TOKEN","message":"Bearer token not recognised"}}"#, create_error_body("/api/v2/jobs", "INVALID_TOKEN", "Bearer token not recognised"));
+}
+
+#[test]
+fn roles_include_lower_roles() {
+    let viewer = Caller { identity: "analyst".to_string(), role: Role::Viewer };
+    let submitter = Caller { identity: "scheduler".to_string(), role: Role::Submitter };
+    let admin = Caller { identity: "alice".to_string(), role: Role::Admin };
+
+    assert_eq!(Ok(()), check_role(Some(&viewer), Role::Viewer));
+    assert_eq!(Err(AuthError::InsufficientRole(Role::Submitter)), check_role(Some(&viewer), Role::Submitter));
+    assert_eq!(Ok(()), check_role(Some(&submitter), Role::Submitter));
+    assert_eq!(Err(AuthError::InsufficientRole(Role::Admin)), check_role(Some(&submitter), Role::Admin));
+    assert_eq!(Ok(()), check_role(Some(&admin), Role::Admin));
+}
+
+#[test]
+fn check_role_allows_all_when_auth_disabled() {
+    assert_eq!(Ok(()), check_role(None, Role::Admin));
+}
+
+#[test]
+fn insufficient_role_is_forbidden() {
+    let error = AuthError::InsufficientRole(Role::Admin);
+
+    assert_eq!(status::Forbidden, error.status());
+    assert_eq!("Requires the [admin] role or above", error.to_string());
+}
@@ -0,0 +1,238 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use base64::decode;
+use chrono::{DateTime, UTC};
+use serde_json;
+use serde_json::{Map, Value};
+
+use factotum_server::persistence::{Persistence, PersistenceError};
+use factotum_server::server::{JobRequest, ValidationError};
+
+#[cfg(test)]
+mod tests;
+
+// definitions are keyed by name under this, beside the job entries in the namespace
+const CATALOG_PREFIX: &'static str = "catalog/";
+
+// the tag a definition's pool is passed to factotum as
+pub const POOL_TAG: &'static str = "pool";
+
+/// A named job registered by a platform owner, so callers can run it without
+/// knowing where its factfile lives. A run may only override the factfile args
+/// and env keys the definition lists as overridable.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub factfile_path: String,
+    #[serde(default)]
+    pub factfile: Option<Value>,
+    #[serde(default)]
+    pub factfile_args: Vec<String>,
+    #[serde(default)]
+    pub env: Option<Value>,
+    #[serde(default)]
+    pub tags: Option<Value>,
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub pool: Option<String>,
+    // flags, e.g. `--start`, a run may add to the factfile args
+    #[serde(default)]
+    pub overridable_args: Vec<String>,
+    // env keys a run may set
+    #[serde(default)]
+    pub overridable_env: Vec<String>,
+    // set by the server when the definition is registered, never from the request body
+    #[serde(default)]
+    pub registered_by: Option<String>,
+    #[serde(default)]
+    pub registered_at: Option<DateTime<UTC>>,
+}
+
+impl JobDefinition {
+    pub fn validate(definition: JobDefinition) -> Result<JobDefinition, ValidationError> {
+        if definition.name.is_empty() || !definition.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(ValidationError::no_output(format!("Invalid value for 'name':'{}', must be letters, digits, '-', '_' or '.'", definition.name)))
+        }
+        match definition.factfile {
+            None if definition.factfile_path.is_empty() => {
+                return Err(ValidationError::no_output("One of 'factfile' and 'factfilePath' must be given".to_string()))
+            },
+            Some(_) if !definition.factfile_path.is_empty() => {
+                return Err(ValidationError::no_output("Only one of 'factfile' and 'factfilePath' can be given".to_string()))
+            },
+            Some(ref factfile) if !factfile.is_object() => {
+                return Err(ValidationError::no_output("Invalid value for 'factfile', must be a JSON object".to_string()))
+            },
+            _ => {},
+        }
+        if definition.timeout_seconds == Some(0) {
+            return Err(ValidationError::no_output("Invalid value for 'timeoutSeconds', must be greater than 0".to_string()))
+        }
+        if let Some(ref pool) = definition.pool {
+            if pool.trim().is_empty() || pool.contains(',') {
+                return Err(ValidationError::no_output(format!("Invalid value for 'pool':'{}', must be non-empty with no commas", pool)))
+            }
+        }
+        if let Some(flag) = definition.overridable_args.iter().find(|flag| !flag.starts_with('-') || flag.contains('=')) {
+            return Err(ValidationError::no_output(format!("Invalid value for 'overridableArgs':'{}', must be a flag such as '--start'", flag)))
+        }
+        // the defaults are checked the same way they will be when the job is run
+        definition.to_job_request(RunRequest::default(), None).take_option_args().map_err(ValidationError::no_output)?;
+        Ok(definition)
+    }
+
+    /// Checks a run only overrides what the definition lets it. Option values in
+    /// the args follow their flag, so the args can't start with one.
+    pub fn check_overrides(&self, overrides: &RunRequest) -> Result<(), String> {
+        let not_overridable = |field: &str, value: &str| {
+            Err(format!("Invalid override for '{}':'{}', catalog job '{}' doesn't list it in '{}'", field, value, self.name, if field == "env" { "overridableEnv" } else { "overridableArgs" }))
+        };
+        for (i, arg) in overrides.factfile_args.iter().enumerate() {
+            if arg.starts_with('-') {
+                let flag = arg.split('=').next().unwrap_or_default();
+                if !self.overridable_args.iter().any(|allowed| allowed == flag) {
+                    return not_overridable("factfileArgs", flag)
+                }
+            } else if i == 0 {
+                return Err(format!("Invalid override for 'factfileArgs':'{}', must start with a flag", arg))
+            }
+        }
+        if overrides.start.is_some() && !self.overridable_args.iter().any(|allowed| allowed == "--start") {
+            return not_overridable("start", "--start")
+        }
+        // a value that isn't an object fails validation once merged
+        if let Some(Value::Object(ref env)) = overrides.env {
+            if let Some(key) = env.keys().find(|key| !self.overridable_env.contains(key)) {
+                return not_overridable("env", key)
+            }
+        }
+        Ok(())
+    }
+
+    /// The job request running this definition submits. Overrides add to the
+    /// default args, and replace default env and tag values key by key; the pool
+    /// is always the definition's own.
+    pub fn to_job_request(&self, overrides: RunRequest, submitted_by: Option<String>) -> JobRequest {
+        let mut job_request = JobRequest::new("", &self.name, &self.factfile_path, self.factfile_args.clone());
+        job_request.factfile_args.extend(overrides.factfile_args);
+        job_request.factfile = self.factfile.clone();
+        job_request.env = merge_objects(self.env.clone(), overrides.env);
+        job_request.tags = merge_objects(self.tags.clone(), overrides.tags);
+        if let Some(ref pool) = self.pool {
+            let mut pool_tag = Map::new();
+            pool_tag.insert(POOL_TAG.to_string(), Value::String(pool.clone()));
+            job_request.tags = match job_request.tags.take() {
+                Some(Value::Object(mut tags)) => {
+                    tags.extend(pool_tag);
+                    Some(Value::Object(tags))
+                },
+                None => Some(Value::Object(pool_tag)),
+                // left for validation to reject
+                invalid => invalid,
+            };
+        }
+        job_request.timeout_seconds = self.timeout_seconds;
+        job_request.start = overrides.start;
+        job_request.dry_run = overrides.dry_run;
+        job_request.callback_url = overrides.callback_url;
+        job_request.submitted_by = submitted_by;
+        job_request
+    }
+}
+
+/// What a caller can change when running a catalog job, as far as the definition allows.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRequest {
+    #[serde(default)]
+    pub factfile_args: Vec<String>,
+    #[serde(default)]
+    pub env: Option<Value>,
+    #[serde(default)]
+    pub tags: Option<Value>,
+    #[serde(default)]
+    pub start: Option<Value>,
+    #[serde(default)]
+    pub dry_run: Option<Value>,
+    #[serde(default)]
+    pub callback_url: Option<String>,
+}
+
+// a value that isn't an object replaces the default outright, and fails validation there
+fn merge_objects(defaults: Option<Value>, overrides: Option<Value>) -> Option<Value> {
+    match (defaults, overrides) {
+        (Some(Value::Object(mut merged)), Some(Value::Object(overrides))) => {
+            merged.extend(overrides);
+            Some(Value::Object(merged))
+        },
+        (defaults, None) => defaults,
+        (_, overrides) => overrides,
+    }
+}
+
+// Catalog storage
+
+fn get_definition_key<T: Persistence>(persistence: &T, name: &str) -> String {
+    persistence.prepend_namespace(&format!("{}{}", CATALOG_PREFIX, name))
+}
+
+fn decode_definition(base64_str: &str) -> Result<JobDefinition, String> {
+    decode(base64_str).map_err(|e| e.to_string())
+        .and_then(|bytes| String::from_utf8(bytes).map_err(|e| e.to_string()))
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+}
+
+/// Adds the definition, replacing any with the same name.
+pub fn save_definition<T: Persistence>(persistence: &T, definition: &JobDefinition) -> Result<(), PersistenceError> {
+    let definition_json = serde_json::to_string(definition).expect("JSON compact encode error");
+    persistence.set_key(&get_definition_key(persistence, &definition.name), &definition_json)
+}
+
+pub fn get_definition<T: Persistence>(persistence: &T, name: &str) -> Result<JobDefinition, PersistenceError> {
+    match persistence.get_key_with_index(&get_definition_key(persistence, name))? {
+        Some((base64_str, _)) => decode_definition(&base64_str).map_err(|e| PersistenceError::Corrupt(format!("Could not decode catalog job '{}' - {}", name, e))),
+        None => Err(PersistenceError::NotFound(format!("No job named '{}' found in the catalog", name))),
+    }
+}
+
+/// Every stored definition by name; one that can't be decoded is skipped rather
+/// than hiding the rest.
+pub fn get_definitions<T: Persistence>(persistence: &T) -> Result<Vec<JobDefinition>, PersistenceError> {
+    let keys = persistence.get_keys_with_index(&persistence.prepend_namespace(CATALOG_PREFIX))?;
+    let mut definitions: Vec<JobDefinition> = keys.into_iter()
+        .filter_map(|(key, base64_str, _)| {
+            match decode_definition(&base64_str) {
+                Ok(definition) => Some(definition),
+                Err(e) => {
+                    warn!("Skipping catalog job '{}' - {}", key, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    definitions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(definitions)
+}
+
+pub fn delete_definition<T: Persistence>(persistence: &T, name: &str) -> Result<(), PersistenceError> {
+    let key = get_definition_key(persistence, name);
+    match persistence.get_key_with_index(&key)? {
+        Some((_, index)) => persistence.delete_key_if_index(&key, index),
+        None => Err(PersistenceError::NotFound(format!("No job named '{}' found in the catalog", name))),
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use base64::encode;
+use factotum_server::persistence::tests::ConsulKeyValueMock;
+
+fn definition(name: &str) -> JobDefinition {
+    JobDefinition {
+        name: name.to_string(),
+        factfile_path: "/tmp/somewhere".to_string(),
+        factfile: None,
+        factfile_args: vec!["--no-colour".to_string()],
+        env: Some(json!({ "bucket": "s3://acme", "region": "eu-west-1" })),
+        tags: Some(json!({ "team": "data" })),
+        timeout_seconds: Some(3600),
+        pool: None,
+        overridable_args: vec!["--start".to_string(), "--tag".to_string()],
+        overridable_env: vec!["bucket".to_string()],
+        registered_by: None,
+        registered_at: None,
+    }
+}
+
+fn validation_error(definition: JobDefinition) -> String {
+    JobDefinition::validate(definition).unwrap_err().error
+}
+
+#[test]
+fn validate_definition() {
+    assert!(JobDefinition::validate(definition("acme-main.v2_1")).is_ok());
+
+    assert_eq!("Invalid value for 'name':'acme/main', must be letters, digits, '-', '_' or '.'", validation_error(definition("acme/main")));
+    assert_eq!("Invalid value for 'timeoutSeconds', must be greater than 0", validation_error(JobDefinition { timeout_seconds: Some(0), ..definition("acme-main") }));
+    assert_eq!("Invalid value for 'pool':'a,b', must be non-empty with no commas", validation_error(JobDefinition { pool: Some("a,b".to_string()), ..definition("acme-main") }));
+    assert_eq!("Invalid value for 'env', must be a JSON object", validation_error(JobDefinition { env: Some(json!("bucket")), ..definition("acme-main") }));
+    assert_eq!("Invalid value for 'overridableArgs':'start', must be a flag such as '--start'", validation_error(JobDefinition { overridable_args: vec!["start".to_string()], ..definition("acme-main") }));
+}
+
+#[test]
+fn validate_definition_factfile() {
+    let no_factfile = JobDefinition { factfile_path: String::new(), ..definition("acme-main") };
+    assert_eq!("One of 'factfile' and 'factfilePath' must be given", validation_error(no_factfile.clone()));
+    assert_eq!("Invalid value for 'factfile', must be a JSON object", validation_error(JobDefinition { factfile: Some(json!([])), ..no_factfile.clone() }));
+    assert!(JobDefinition::validate(JobDefinition { factfile: Some(json!({})), ..no_factfile }).is_ok());
+    assert_eq!("Only one of 'factfile' and 'factfilePath' can be given", validation_error(JobDefinition { factfile: Some(json!({})), ..definition("acme-main") }));
+}
+
+#[test]
+fn check_overrides_allows_listed() {
+    let overrides = RunRequest {
+        factfile_args: vec!["--start".to_string(), "step-2".to_string(), "--tag=run,backfill".to_string()],
+        env: Some(json!({ "bucket": "s3://acme-test" })),
+        start: Some(json!("step-2")),
+        ..RunRequest::default()
+    };
+    assert_eq!(Ok(()), definition("acme-main").check_overrides(&overrides));
+    assert_eq!(Ok(()), definition("acme-main").check_overrides(&RunRequest::default()));
+}
+
+#[test]
+fn check_overrides_fail_not_listed() {
+    let check = |overrides: RunRequest| definition("acme-main").check_overrides(&overrides).unwrap_err();
+    let locked = JobDefinition { overridable_args: vec![], ..definition("acme-main") };
+
+    assert_eq!("Invalid override for 'factfileArgs':'--env', catalog job 'acme-main' doesn't list it in 'overridableArgs'", check(RunRequest { factfile_args: vec!["--env={}".to_string()], ..RunRequest::default() }));
+    assert_eq!("Invalid override for 'factfileArgs':'step-2', must start with a flag", check(RunRequest { factfile_args: vec!["step-2".to_string()], ..RunRequest::default() }));
+    assert_eq!("Invalid override for 'env':'region', catalog job 'acme-main' doesn't list it in 'overridableEnv'", check(RunRequest { env: Some(json!({ "bucket": "s3://acme-test", "region": "us-east-1" })), ..RunRequest::default() }));
+    assert_eq!("Invalid override for 'start':'--start', catalog job 'acme-main' doesn't list it in 'overridableArgs'", locked.check_overrides(&RunRequest { start: Some(json!("step-2")), ..RunRequest::default() }).unwrap_err());
+}
+
+#[test]
+fn to_job_request_uses_defaults() {
+    let job_request = definition("acme-main").to_job_request(RunRequest::default(), Some("alice".to_string()));
+
+    assert_eq!("acme-main", job_request.job_name);
+    assert_eq!("/tmp/somewhere", job_request.factfile_path);
+    assert_eq!(vec!["--no-colour".to_string()], job_request.factfile_args);
+    assert_eq!(Some(json!({ "bucket": "s3://acme", "region": "eu-west-1" })), job_request.env);
+    assert_eq!(Some("alice".to_string()), job_request.submitted_by);
+    assert_eq!(Some(3600), job_request.timeout_seconds);
+}
+
+#[test]
+fn to_job_request_applies_overrides() {
+    let overrides = RunRequest {
+        factfile_args: vec!["--start".to_string(), "step-2".to_string()],
+        env: Some(json!({ "bucket": "s3://acme-test" })),
+        tags: Some(json!({ "run": "backfill", "pool": "mine" })),
+        dry_run: Some(json!(true)),
+        ..RunRequest::default()
+    };
+    let job_request = JobDefinition { pool: Some("batch".to_string()), ..definition("acme-main") }.to_job_request(overrides, None);
+
+    assert_eq!(vec!["--no-colour", "--start", "step-2"], job_request.factfile_args);
+    assert_eq!(Some(json!({ "bucket": "s3://acme-test", "region": "eu-west-1" })), job_request.env);
+    assert_eq!(Some(json!({ "team": "data", "run": "backfill", "pool": "batch" })), job_request.tags);
+    assert_eq!(Some(json!(true)), job_request.dry_run);
+}
+
+#[test]
+fn to_job_request_keeps_invalid_overrides_for_validation() {
+    let overrides = RunRequest { tags: Some(json!("backfill")), ..RunRequest::default() };
+    let mut job_request = JobDefinition { pool: Some("batch".to_string()), ..definition("acme-main") }.to_job_request(overrides, None);
+
+    assert_eq!(Some(json!("backfill")), job_request.tags);
+    assert!(job_request.take_option_args().is_err());
+}
+
+#[test]
+fn definitions_round_trip_through_storage() {
+    let persistence = ConsulKeyValueMock::new();
+    save_definition(&persistence, &definition("zeta")).unwrap();
+    save_definition(&persistence, &definition("alpha")).unwrap();
+    let replaced = JobDefinition { factfile_args: vec![], ..definition("alpha") };
+    save_definition(&persistence, &replaced).unwrap();
+    persistence.ref_map.borrow_mut().insert("com.test/namespace/catalog/corrupt".to_string(), encode(b"{\"name\":"));
+
+    assert_eq!(Ok(replaced.clone()), get_definition(&persistence, "alpha"));
+    assert_eq!(vec!["alpha", "zeta"], get_definitions(&persistence).unwrap().iter().map(|definition| definition.name.as_str()).collect::<Vec<_>>());
+    assert_eq!(Ok(()), delete_definition(&persistence, "alpha"));
+    assert_eq!(Err(PersistenceError::NotFound("No job named 'alpha' found in the catalog".to_string())), get_definition(&persistence, "alpha"));
+    assert_eq!(Err(PersistenceError::NotFound("No job named 'alpha' found in the catalog".to_string())), delete_definition(&persistence, "alpha"));
+}
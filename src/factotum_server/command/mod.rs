@@ -13,7 +13,15 @@
 //
 
 use std::collections::HashMap;
-use std::process::Command;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
 
 macro_rules! commands {
     ($( $key: expr => $val: expr ),*) => {{
@@ -28,7 +36,24 @@ mod tests;
 
 pub trait Execution {
     fn get_command(&self, command: &str) -> Result<String, String>;
-    fn execute(&self, cmd_path: String, cmd_args: Vec<String>) -> Result<String, String>;
+    fn run(&self, cmd_path: String, cmd_args: Vec<String>, timeout: Option<Duration>) -> Result<CommandOutput, String>;
+}
+
+// how often a command with a timeout is checked on
+const TIMEOUT_POLL_MILLIS: u64 = 100;
+
+/// Everything a finished command left behind, whether or not it succeeded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandOutput {
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -52,24 +77,91 @@ impl Execution for CommandStore {
         }
     }
 
-    fn execute(&self, cmd_path: String, cmd_args: Vec<String>) -> Result<String, String> {
+    /// Runs the command to completion, or until the timeout when one is given. A
+    /// command that times out is killed along with everything it started, and
+    /// finishes with no exit code.
+    fn run(&self, cmd_path: String, cmd_args: Vec<String>, timeout: Option<Duration>) -> Result<CommandOutput, String> {
         let command_str = format!("{} {}", cmd_path, cmd_args.join(" "));
+        let failed_command_msg = get_failed_command_msg(&cmd_path, &cmd_args);
         debug!("Executing: [{}]", command_str);
-        let failed_command_msg = format!("Failed to execute command: [{}]", command_str);
-        match Command::new(cmd_path)
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => {
+                return match Command::new(cmd_path)
                     .args(&cmd_args)
                     .output()
                     {
-                        Ok(output) => {
-                            if output.status.success() {
-                                let stdout = String::from_utf8_lossy(&output.stdout);
-                                Ok(stdout.into_owned())
-                            } else {
-                                let stderr = String::from_utf8_lossy(&output.stderr);
-                                Err(format!("{} - {}", failed_command_msg, stderr.into_owned()))
-                            }
-                        }
+                        Ok(output) => Ok(CommandOutput {
+                            exit_code: output.status.code(),
+                            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                        }),
                         Err(e) => Err(format!("{} - {}", failed_command_msg, e))
                     }
+            }
+        };
+        // in a group of its own, so the tasks it starts are killed with it
+        let mut child = Command::new(cmd_path)
+            .args(&cmd_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .map_err(|e| format!("{} - {}", failed_command_msg, e))?;
+        let stdout = read_to_end(child.stdout.take());
+        let stderr = read_to_end(child.stderr.take());
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = false;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if Instant::now() >= deadline => {
+                    warn!("Killing [{}], it ran for longer than {}s", command_str, timeout.as_secs());
+                    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL); }
+                    let _ = child.wait();
+                    timed_out = true;
+                    break None
+                },
+                Ok(None) => thread::sleep(Duration::from_millis(TIMEOUT_POLL_MILLIS)),
+                Err(e) => return Err(format!("Failed to wait for command: [{}] - {}", command_str, e)),
+            }
+        };
+        let mut output = CommandOutput {
+            exit_code: status.and_then(|status| status.code()),
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        };
+        if timed_out {
+            output.stderr.push_str(&format!("Killed after running for longer than {}s\n", timeout.as_secs()));
+        }
+        Ok(output)
+    }
+}
+
+/// How a command that couldn't be run, or failed, is reported.
+pub fn get_failed_command_msg(cmd_path: &str, cmd_args: &[String]) -> String {
+    format!("Failed to execute command: [{} {}]", cmd_path, cmd_args.join(" "))
+}
+
+fn read_to_end<R: 'static + Read + Send>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+/// Checks the path is still a file with an execute bit set, e.g. that the
+/// Factotum binary hasn't been removed or replaced since start up.
+pub fn check_executable(path: &str) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("Cannot read '{}' - {}", path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("'{}' is not a file", path))
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!("'{}' is not executable", path))
     }
+    Ok(())
 }
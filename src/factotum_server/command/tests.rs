@@ -34,16 +34,64 @@ fn command_store_get_command_error() {
 }
 
 #[test]
-fn command_store_execute_fail() {
-    let command_store = commands!["dummy".to_string() => "/tmp/fake_command".to_string()];
-    let output = command_store.execute("/tmp/fake_command".to_string(), vec!["--random_arg".to_string()]).unwrap_err();
-    assert_eq!(output, "Failed to execute command: [/tmp/fake_command --random_arg] - No such file or directory (os error 2)");
+fn command_store_run_captures_exit_code_and_output() {
+    let command_store = CommandStore::new(HashMap::new());
+    let output = command_store.run("sh".to_string(), vec!["-c".to_string(), "echo out; echo err >&2; exit 3".to_string()], None).unwrap();
+    assert_eq!(Some(3), output.exit_code);
+    assert_eq!("out\n", output.stdout);
+    assert_eq!("err\n", output.stderr);
+    assert_eq!(false, output.success());
+}
+
+#[test]
+fn command_store_run_within_timeout() {
+    let command_store = CommandStore::new(HashMap::new());
+    let output = command_store.run("sh".to_string(), vec!["-c".to_string(), "echo out".to_string()], Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(Some(0), output.exit_code);
+    assert_eq!("out\n", output.stdout);
+}
+
+#[test]
+fn command_store_run_kills_on_timeout() {
+    let command_store = CommandStore::new(HashMap::new());
+    let started = Instant::now();
+    let output = command_store.run("sh".to_string(), vec!["-c".to_string(), "echo out; sleep 30 & wait".to_string()], Some(Duration::from_millis(300))).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(None, output.exit_code);
+    assert_eq!("out\n", output.stdout);
+    assert_eq!("Killed after running for longer than 0s\n", output.stderr);
+    assert_eq!(false, output.success());
+}
+
+#[test]
+fn command_store_run_fail() {
+    let command_store = CommandStore::new(HashMap::new());
+    let output = command_store.run("/tmp/fake_command".to_string(), vec![], None).unwrap_err();
+    assert_eq!(output, "Failed to execute command: [/tmp/fake_command ] - No such file or directory (os error 2)");
+}
+
+#[test]
+fn check_executable_success() {
+    assert_eq!(Ok(()), check_executable("/bin/sh"));
+}
+
+#[test]
+fn check_executable_fail() {
+    let not_executable = ::std::env::temp_dir().join(format!("factotum-not-executable-{}", ::std::process::id()));
+    fs::File::create(&not_executable).unwrap();
+    let not_executable_path = not_executable.to_str().unwrap();
+
+    assert_eq!(Err(format!("'{}' is not executable", not_executable_path)), check_executable(not_executable_path));
+    assert_eq!(Err("'/tmp' is not a file".to_string()), check_executable("/tmp"));
+    assert!(check_executable("/tmp/fake_command").unwrap_err().starts_with("Cannot read '/tmp/fake_command'"));
+    fs::remove_file(&not_executable).unwrap();
 }
 
 #[test]
 #[ignore]
-fn command_store_execute_illegal_option() {
+fn command_store_run_illegal_option() {
     let command_store = commands!["dummy".to_string() => "/tmp/fake_command".to_string()];
-    let output = command_store.execute("pwd".to_string(), vec!["--random_arg".to_string()]).unwrap_err();
-    assert_eq!(output, "Failed to execute command: [pwd --random_arg] - pwd: unrecognized option \'--random_arg\'\nTry \'pwd --help\' for more information.\n");
+    let output = command_store.run("pwd".to_string(), vec!["--random_arg".to_string()], None).unwrap();
+    assert_eq!(false, output.success());
+    assert_eq!(output.stderr, "pwd: unrecognized option \'--random_arg\'\nTry \'pwd --help\' for more information.\n");
 }
@@ -0,0 +1,389 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::cmp;
+use std::process;
+use std::time::Duration;
+use curl::easy::{Easy, List};
+use libc;
+use serde_json;
+
+#[cfg(test)]
+mod tests;
+
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+// Consul caps blocking queries at 10 minutes and adds up to wait/16 of jitter
+pub const BLOCKING_WAIT_MAX_SECS: u64 = 600;
+const BLOCKING_JITTER_DIVISOR: u64 = 16;
+
+const TOKEN_HEADER: &'static str = "X-Consul-Token";
+
+const HEALTH_CHECK_INTERVAL: &'static str = "10s";
+const HEALTH_CHECK_TIMEOUT: &'static str = "5s";
+const HEALTH_CHECK_LOCAL_IP: &'static str = "127.0.0.1";
+
+/// ACL token and TLS settings applied to every request made to the Consul agent.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsulSecurity {
+    pub token: Option<String>,
+    pub https: bool,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl ConsulSecurity {
+    pub fn new(token: Option<String>, https: bool, ca_cert: Option<String>, client_cert: Option<String>, client_key: Option<String>) -> ConsulSecurity {
+        ConsulSecurity {
+            token: token,
+            https: https,
+            ca_cert: ca_cert,
+            client_cert: client_cert,
+            client_key: client_key,
+        }
+    }
+
+    pub fn scheme(&self) -> &str {
+        if self.https { "https" } else { "http" }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct KvPair {
+    pub key: String,
+    pub value: Option<String>,
+    pub modify_index: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceRegistration {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: String,
+    pub tags: Vec<String>,
+    pub address: String,
+    pub port: u32,
+    pub check: HealthCheck,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HealthCheck {
+    #[serde(rename = "HTTP", skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    #[serde(rename = "TCP", skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<String>,
+    #[serde(rename = "TLSSkipVerify", skip_serializing_if = "Option::is_none")]
+    pub tls_skip_verify: Option<bool>,
+    pub interval: String,
+    pub timeout: String,
+}
+
+/// How Consul checks the server. Under mutual TLS the agent has no client
+/// certificate to present, so it can only check that the port accepts connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HealthCheckKind {
+    Http,
+    Https,
+    Tcp,
+}
+
+/// The host's name, used to tell apart servers that all register the same
+/// address (e.g. everything bound to 0.0.0.0 checks itself on 127.0.0.1).
+pub fn get_node_name() -> String {
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    let length = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    match String::from_utf8(buffer[..length].to_vec()) {
+        Ok(ref hostname) if result == 0 && !hostname.is_empty() => hostname.clone(),
+        _ => format!("pid{}", process::id()),
+    }
+}
+
+impl ServiceRegistration {
+    /// An empty address lets Consul fall back to the agent's node address,
+    /// which is what we want when the server is bound to all interfaces.
+    pub fn new(name: &str, node: &str, tags: Vec<String>, ip: &str, port: u32, check_kind: HealthCheckKind) -> ServiceRegistration {
+        let address = if ip == ::IP_DEFAULT { String::new() } else { ip.to_owned() };
+        let check_ip = if address.is_empty() { HEALTH_CHECK_LOCAL_IP } else { &address };
+        let (http, tcp, tls_skip_verify) = match check_kind {
+            HealthCheckKind::Http => (Some(format!("http://{}:{}{}", check_ip, port, ::HEALTH_CHECK_PATH)), None, None),
+            // the check only proves liveness, so don't make the agent trust our CA
+            HealthCheckKind::Https => (Some(format!("https://{}:{}{}", check_ip, port, ::HEALTH_CHECK_PATH)), None, Some(true)),
+            HealthCheckKind::Tcp => (None, Some(format!("{}:{}", check_ip, port)), None),
+        };
+        ServiceRegistration {
+            id: format!("{}-{}-{}-{}", name, node, check_ip, port),
+            name: name.to_owned(),
+            tags: tags,
+            address: address.clone(),
+            port: port,
+            check: HealthCheck {
+                http: http,
+                tcp: tcp,
+                tls_skip_verify: tls_skip_verify,
+                interval: HEALTH_CHECK_INTERVAL.to_string(),
+                timeout: HEALTH_CHECK_TIMEOUT.to_string(),
+            },
+        }
+    }
+}
+
+/// Session used to hold KV locks. With the "release" behavior any lock held by
+/// the session is released (but the key kept) once the session is invalidated.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionRequest {
+    pub name: String,
+    #[serde(rename = "TTL")]
+    pub ttl: String,
+    pub lock_delay: String,
+    pub behavior: String,
+}
+
+impl SessionRequest {
+    pub fn new(name: &str, ttl_secs: u64, lock_delay_secs: u64) -> SessionRequest {
+        SessionRequest {
+            name: name.to_owned(),
+            ttl: format!("{}s", ttl_secs),
+            lock_delay: format!("{}s", lock_delay_secs),
+            behavior: "release".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionResponse {
+    #[serde(rename = "ID")]
+    pub id: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ConsulResponse {
+    pub code: u32,
+    pub body: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsulClient {
+    address: String,
+    security: ConsulSecurity,
+}
+
+impl ConsulClient {
+    pub fn new(host: &str, port: u32, security: &ConsulSecurity) -> ConsulClient {
+        ConsulClient {
+            address: format!("{}://{}:{}", security.scheme(), host, port),
+            security: security.clone(),
+        }
+    }
+
+    pub fn kv_get(&self, key: &str) -> Result<Option<KvPair>, String> {
+        let response = self.send("GET", &kv_path(key, None), None)?;
+        match response.code {
+            200 => decode_kv_pairs(&response.body).map(|pairs| pairs.into_iter().next()),
+            404 => Ok(None),
+            code => Err(format!("Consul: Error getting key '{}' - HTTP {}: {}", key, code, response.body)),
+        }
+    }
+
+    /// Blocking query: returns once the key's ModifyIndex moves past `index`, or
+    /// with the unchanged entry when `wait` runs out.
+    pub fn kv_get_blocking(&self, key: &str, index: u64, wait: Duration) -> Result<Option<KvPair>, String> {
+        let wait_secs = cmp::max(1, cmp::min(wait.as_secs(), BLOCKING_WAIT_MAX_SECS));
+        let query = format!("index={}&wait={}s", index, wait_secs);
+        let timeout = Duration::from_secs(wait_secs + wait_secs / BLOCKING_JITTER_DIVISOR + REQUEST_TIMEOUT_SECS);
+        let response = self.send_with_timeout("GET", &kv_path(key, Some(&query)), None, timeout)?;
+        match response.code {
+            200 => decode_kv_pairs(&response.body).map(|pairs| pairs.into_iter().next()),
+            404 => Ok(None),
+            code => Err(format!("Consul: Error watching key '{}' - HTTP {}: {}", key, code, response.body)),
+        }
+    }
+
+    pub fn kv_put(&self, key: &str, value: &str) -> Result<(), String> {
+        let response = self.send("PUT", &kv_path(key, None), Some(value))?;
+        match response.code {
+            200 => Ok(()),
+            code => Err(format!("Consul: Error setting key '{}' - HTTP {}: {}", key, code, response.body)),
+        }
+    }
+
+    /// Check-and-set write: only succeeds if the key's ModifyIndex still matches
+    /// `index` (an index of 0 means the key must not exist yet).
+    pub fn kv_put_cas(&self, key: &str, value: &str, index: u64) -> Result<bool, String> {
+        let query = format!("cas={}", index);
+        let response = self.send("PUT", &kv_path(key, Some(&query)), Some(value))?;
+        match response.code {
+            200 => Ok(response.body.trim() == "true"),
+            code => Err(format!("Consul: Error setting key '{}' with cas={} - HTTP {}: {}", key, index, code, response.body)),
+        }
+    }
+
+    pub fn kv_list(&self, prefix: &str) -> Result<Vec<KvPair>, String> {
+        let response = self.send("GET", &kv_path(prefix, Some("recurse")), None)?;
+        match response.code {
+            200 => decode_kv_pairs(&response.body),
+            404 => Ok(vec![]),
+            code => Err(format!("Consul: Error listing prefix '{}' - HTTP {}: {}", prefix, code, response.body)),
+        }
+    }
+
+    /// Lists the keys under the prefix without their values.
+    pub fn kv_keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let response = self.send("GET", &kv_path(prefix, Some("keys")), None)?;
+        match response.code {
+            200 => decode_kv_keys(&response.body),
+            404 => Ok(vec![]),
+            code => Err(format!("Consul: Error listing keys under '{}' - HTTP {}: {}", prefix, code, response.body)),
+        }
+    }
+
+    /// Check-and-set delete: only removes the key if its ModifyIndex still matches `index`.
+    pub fn kv_delete_cas(&self, key: &str, index: u64) -> Result<bool, String> {
+        let query = format!("cas={}", index);
+        let response = self.send("DELETE", &kv_path(key, Some(&query)), None)?;
+        match response.code {
+            200 => Ok(response.body.trim() == "true"),
+            code => Err(format!("Consul: Error deleting key '{}' with cas={} - HTTP {}: {}", key, index, code, response.body)),
+        }
+    }
+
+    pub fn agent_service_register(&self, service: &ServiceRegistration) -> Result<(), String> {
+        let service_json = serde_json::to_string(service).expect("JSON compact encode error");
+        let response = self.send("PUT", "/v1/agent/service/register", Some(&service_json))?;
+        match response.code {
+            200 => Ok(()),
+            code => Err(format!("Consul: Error registering service '{}' - HTTP {}: {}", service.id, code, response.body)),
+        }
+    }
+
+    pub fn agent_service_deregister(&self, service_id: &str) -> Result<(), String> {
+        let response = self.send("PUT", &format!("/v1/agent/service/deregister/{}", service_id), Some(""))?;
+        match response.code {
+            200 => Ok(()),
+            code => Err(format!("Consul: Error deregistering service '{}' - HTTP {}: {}", service_id, code, response.body)),
+        }
+    }
+
+    pub fn session_create(&self, session: &SessionRequest) -> Result<String, String> {
+        let session_json = serde_json::to_string(session).expect("JSON compact encode error");
+        let response = self.send("PUT", "/v1/session/create", Some(&session_json))?;
+        match response.code {
+            200 => serde_json::from_str::<SessionResponse>(&response.body)
+                       .map(|created| created.id)
+                       .map_err(|e| format!("Consul: Could not decode session response: {}", e)),
+            code => Err(format!("Consul: Error creating session '{}' - HTTP {}: {}", session.name, code, response.body)),
+        }
+    }
+
+    /// Returns false if the session no longer exists, e.g. because its TTL expired.
+    pub fn session_renew(&self, session_id: &str) -> Result<bool, String> {
+        let response = self.send("PUT", &format!("/v1/session/renew/{}", session_id), Some(""))?;
+        match response.code {
+            200 => Ok(true),
+            404 => Ok(false),
+            code => Err(format!("Consul: Error renewing session '{}' - HTTP {}: {}", session_id, code, response.body)),
+        }
+    }
+
+    pub fn session_destroy(&self, session_id: &str) -> Result<(), String> {
+        let response = self.send("PUT", &format!("/v1/session/destroy/{}", session_id), Some(""))?;
+        match response.code {
+            200 => Ok(()),
+            code => Err(format!("Consul: Error destroying session '{}' - HTTP {}: {}", session_id, code, response.body)),
+        }
+    }
+
+    /// Tries to take the lock on `key` for the session. Succeeds if the lock is free
+    /// or already held by the same session.
+    pub fn kv_acquire(&self, key: &str, value: &str, session_id: &str) -> Result<bool, String> {
+        let query = format!("acquire={}", session_id);
+        let response = self.send("PUT", &kv_path(key, Some(&query)), Some(value))?;
+        match response.code {
+            200 => Ok(response.body.trim() == "true"),
+            code => Err(format!("Consul: Error acquiring lock '{}' - HTTP {}: {}", key, code, response.body)),
+        }
+    }
+
+    fn send(&self, method: &str, path: &str, body: Option<&str>) -> Result<ConsulResponse, String> {
+        self.send_with_timeout(method, path, body, Duration::from_secs(REQUEST_TIMEOUT_SECS))
+    }
+
+    fn send_with_timeout(&self, method: &str, path: &str, body: Option<&str>, timeout: Duration) -> Result<ConsulResponse, String> {
+        let url = format!("{}{}", self.address, path);
+        let mut easy = Easy::new();
+        let mut buffer = Vec::new();
+        configure(&mut easy, &url, method, body, timeout, &self.security).map_err(|e| format!("Consul: Error building request [{} {}] - {}", method, url, e))?;
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|data| {
+                buffer.extend_from_slice(data);
+                Ok(data.len())
+            }).map_err(|e| e.to_string())?;
+            transfer.perform().map_err(|e| format!("Consul: Request failed [{} {}] - {}", method, url, e))?;
+        }
+        let code = easy.response_code().map_err(|e| e.to_string())?;
+        Ok(ConsulResponse {
+            code: code,
+            body: String::from_utf8_lossy(&buffer).into_owned(),
+        })
+    }
+}
+
+fn configure(easy: &mut Easy, url: &str, method: &str, body: Option<&str>, timeout: Duration, security: &ConsulSecurity) -> Result<(), ::curl::Error> {
+    easy.url(url)?;
+    easy.custom_request(method)?;
+    easy.connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+    easy.timeout(timeout)?;
+    if let Some(ref token) = security.token {
+        let mut headers = List::new();
+        headers.append(&format!("{}: {}", TOKEN_HEADER, token))?;
+        easy.http_headers(headers)?;
+    }
+    if let Some(ref ca_cert) = security.ca_cert {
+        easy.cainfo(ca_cert)?;
+    }
+    if let Some(ref client_cert) = security.client_cert {
+        easy.ssl_cert(client_cert)?;
+    }
+    if let Some(ref client_key) = security.client_key {
+        easy.ssl_key(client_key)?;
+    }
+    if let Some(data) = body {
+        easy.post_fields_copy(data.as_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn kv_path(key: &str, query: Option<&str>) -> String {
+    let trimmed = key.trim_start_matches('/');
+    match query {
+        Some(params) => format!("/v1/kv/{}?{}", trimmed, params),
+        None => format!("/v1/kv/{}", trimmed),
+    }
+}
+
+pub fn decode_kv_pairs(body: &str) -> Result<Vec<KvPair>, String> {
+    serde_json::from_str(body).map_err(|e| format!("Consul: Could not decode K/V response: {}", e))
+}
+
+pub fn decode_kv_keys(body: &str) -> Result<Vec<String>, String> {
+    serde_json::from_str(body).map_err(|e| format!("Consul: Could not decode K/V keys response: {}", e))
+}
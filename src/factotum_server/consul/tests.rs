@@ -0,0 +1,106 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+
+#[test]
+fn kv_path_without_query() {
+    assert_eq!("/v1/kv/com.test/namespace/dummy", kv_path("com.test/namespace/dummy", None));
+}
+
+#[test]
+fn kv_path_strips_leading_slash() {
+    assert_eq!("/v1/kv/com.test/dummy?cas=0", kv_path("/com.test/dummy", Some("cas=0")));
+}
+
+#[test]
+fn decode_kv_pairs_success() {
+    let body = r#"[{"LockIndex":0,"Key":"com.test/dummy","Flags":0,"Value":"dGVzdA==","CreateIndex":10,"ModifyIndex":12}]"#;
+    let expected = vec![KvPair { key: "com.test/dummy".to_string(), value: Some("dGVzdA==".to_string()), modify_index: 12 }];
+    assert_eq!(Ok(expected), decode_kv_pairs(body));
+}
+
+#[test]
+fn decode_kv_pairs_null_value() {
+    let body = r#"[{"LockIndex":0,"Key":"com.test/dummy","Flags":0,"Value":null,"CreateIndex":10,"ModifyIndex":10}]"#;
+    let pairs = decode_kv_pairs(body).unwrap();
+    assert_eq!(None, pairs[0].value);
+}
+
+#[test]
+fn decode_kv_pairs_fail() {
+    assert!(decode_kv_pairs("not json").is_err());
+}
+
+#[test]
+fn consul_security_scheme() {
+    let plain = ConsulSecurity::default();
+    let secure = ConsulSecurity::new(Some("secret".to_string()), true, None, None, None);
+    assert_eq!("http", plain.scheme());
+    assert_eq!("https", secure.scheme());
+}
+
+#[test]
+fn kv_get_fail_no_agent() {
+    let client = ConsulClient::new("127.0.0.1", 1, &ConsulSecurity::default());
+    assert!(client.kv_get("com.test/dummy").is_err());
+}
+
+#[test]
+fn service_registration_all_interfaces() {
+    let registration = ServiceRegistration::new("factotum-server", "node-a", vec!["prod".to_string()], "0.0.0.0", 3000, HealthCheckKind::Http);
+
+    assert_eq!("factotum-server-node-a-127.0.0.1-3000", registration.id);
+    assert_eq!("", registration.address);
+    assert_eq!(Some("http://127.0.0.1:3000/healthz".to_string()), registration.check.http);
+}
+
+#[test]
+fn service_registration_bound_ip() {
+    let registration = ServiceRegistration::new("factotum-server", "node-a", vec![], "10.0.0.5", 8080, HealthCheckKind::Http);
+
+    assert_eq!("factotum-server-node-a-10.0.0.5-8080", registration.id);
+    assert_eq!("10.0.0.5", registration.address);
+    assert_eq!(Some("http://10.0.0.5:8080/healthz".to_string()), registration.check.http);
+}
+
+#[test]
+fn service_registration_encode() {
+    let registration = ServiceRegistration::new("factotum-server", "node-a", vec!["prod".to_string()], "10.0.0.5", 8080, HealthCheckKind::Http);
+    let expected = r#"{"ID":"factotum-server-node-a-10.0.0.5-8080","Name":"factotum-server","Tags":["prod"],"Address":"10.0.0.5","Port":8080,"Check":{"HTTP":"http://10.0.0.5:8080/healthz","Interval":"10s","Timeout":"5s"}}"#;
+    assert_eq!(expected, serde_json::to_string(&registration).unwrap());
+}
+
+#[test]
+fn service_registration_encode_tls_checks() {
+    let https = ServiceRegistration::new("factotum-server", "node-a", vec![], "10.0.0.5", 8080, HealthCheckKind::Https);
+    let tcp = ServiceRegistration::new("factotum-server", "node-a", vec![], "10.0.0.5", 8080, HealthCheckKind::Tcp);
+
+    assert_eq!(r#"{"HTTP":"https://10.0.0.5:8080/healthz","TLSSkipVerify":true,"Interval":"10s","Timeout":"5s"}"#, serde_json::to_string(&https.check).unwrap());
+    assert_eq!(r#"{"TCP":"10.0.0.5:8080","Interval":"10s","Timeout":"5s"}"#, serde_json::to_string(&tcp.check).unwrap());
+}
+
+#[test]
+fn session_request_encode() {
+    let session = SessionRequest::new("factotum-server-node-a-10.0.0.5-8080", 15, 5);
+    let expected = r#"{"Name":"factotum-server-node-a-10.0.0.5-8080","TTL":"15s","LockDelay":"5s","Behavior":"release"}"#;
+    assert_eq!(expected, serde_json::to_string(&session).unwrap());
+}
+
+#[test]
+fn decode_kv_keys_success() {
+    let body = r#"["com.test/namespace-factfiles/abc","com.test/namespace-factfiles/def"]"#;
+    assert_eq!(Ok(vec!["com.test/namespace-factfiles/abc".to_string(), "com.test/namespace-factfiles/def".to_string()]), decode_kv_keys(body));
+    assert!(decode_kv_keys("not json").is_err());
+}
@@ -15,8 +15,12 @@
 #[cfg(test)]
 mod tests;
 
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
+use factotum_server::persistence::{JobResult, StoredJobEntry};
 use factotum_server::server::JobRequest;
 use factotum_server::responder::DispatcherStatus;
 
@@ -27,8 +31,8 @@ pub enum Dispatch {
     CheckQueue(Query<bool>),
     NewRequest(JobRequest),
     ProcessRequest,
-    RequestComplete(JobRequest),
-    RequestFailure(JobRequest),
+    RequestComplete(JobRequest, JobResult),
+    RequestFailure(JobRequest, JobResult),
     StopProcessing,
 }
 
@@ -37,18 +41,81 @@ pub struct Dispatcher {
     pub max_jobs: usize,
     pub max_workers: usize,
     pub requests_queue: VecDeque<JobRequest>,
+    pub cluster_mode: bool,
 }
 
 impl Dispatcher {
-    pub fn new(queue_size: usize, workers_size: usize) -> Dispatcher {
+    pub fn new(queue_size: usize, workers_size: usize, cluster_mode: bool) -> Dispatcher {
         Dispatcher {
             max_jobs: if queue_size > 0 { queue_size } else { ::MAX_JOBS_DEFAULT },
             max_workers: if workers_size > 0 { workers_size } else { ::MAX_WORKERS_DEFAULT },
             requests_queue: VecDeque::with_capacity(queue_size),
+            cluster_mode: cluster_mode,
         }
     }
 }
 
+/// What a server in cluster mode knows about the shared queue without asking
+/// Consul: the QUEUED entry count as of the last poll, plus the jobs this server
+/// has taken and is still running, each with the entry it last wrote for the job.
+#[derive(Clone, Debug, Default)]
+pub struct ClusterQueue {
+    queued: Arc<AtomicUsize>,
+    running: Arc<Mutex<BTreeMap<String, StoredJobEntry>>>,
+}
+
+impl ClusterQueue {
+    pub fn new() -> ClusterQueue {
+        ClusterQueue::default()
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn set_queued_count(&self, count: usize) {
+        self.queued.store(count, Ordering::SeqCst);
+    }
+
+    /// Accounts for a job this server submitted before the next poll sees it.
+    pub fn add_queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Starts tracking a job from the entry this server wrote when taking it.
+    pub fn start_job(&self, stored_entry: &StoredJobEntry) {
+        let queued = self.queued_count();
+        self.set_queued_count(cmp::max(queued, 1) - 1);
+        self.running.lock().unwrap().insert(stored_entry.entry.job_request.job_id.clone(), stored_entry.clone());
+    }
+
+    /// Stops tracking the job, handing `finish` the entry this server last wrote
+    /// for it. Returns `None` without calling `finish` if the job was lost to a peer.
+    /// Running jobs stay locked until `finish` returns, so no heartbeat can race it.
+    pub fn finish_job<F, R>(&self, job_id: &str, finish: F) -> Option<R> where F: FnOnce(StoredJobEntry) -> R {
+        let mut running = self.running.lock().unwrap();
+        running.remove(job_id).map(finish)
+    }
+
+    /// Hands `heartbeat` the entry this server last wrote for each running job,
+    /// keeping the entry it returns in its place. A job it returns `None` for is
+    /// lost to a peer and no longer tracked.
+    pub fn heartbeat_jobs<F>(&self, heartbeat: F) where F: Fn(&StoredJobEntry) -> Option<StoredJobEntry> {
+        let mut running = self.running.lock().unwrap();
+        let job_ids: Vec<String> = running.keys().cloned().collect();
+        for job_id in job_ids {
+            match heartbeat(&running[&job_id]) {
+                Some(stored_entry) => running.insert(job_id, stored_entry),
+                None => running.remove(&job_id),
+            };
+        }
+    }
+
+    pub fn is_running(&self, job_id: &str) -> bool {
+        self.running.lock().unwrap().contains_key(job_id)
+    }
+}
+
 #[derive(Debug)]
 pub struct Query<T> {
     pub name: String,
@@ -14,14 +14,25 @@
 
 use super::*;
 use std::sync::mpsc;
+use factotum_server::persistence::{JobEntry, JobState, JobOutcome};
+
+fn stored_entry(job_id: &str, index: u64) -> StoredJobEntry {
+    let request = JobRequest::new(job_id, "dummy", "/tmp/somewhere", vec![]);
+    StoredJobEntry {
+        key: format!("com.test/namespace/{}", job_id),
+        index: index,
+        entry: JobEntry::new(&JobState::WORKING, &request, "test_cluster", &JobOutcome::RUNNING),
+    }
+}
 
 #[test]
 fn create_new_dispatcher() {
-    let dispatcher = Dispatcher::new(10, 2);
+    let dispatcher = Dispatcher::new(10, 2, false);
 
     assert_eq!(dispatcher.max_jobs, 10);
     assert_eq!(dispatcher.max_workers, 2);
     assert!(dispatcher.requests_queue.is_empty());
+    assert_eq!(dispatcher.cluster_mode, false);
 }
 
 #[test]
@@ -57,3 +68,40 @@ fn create_new_queries_different_name_same_sender_is_not_equal() {
     let query_two: Query<String> = Query::new("dummy_query_two", tx.clone());
     assert!(query_one != query_two);
 }
+
+#[test]
+fn cluster_queue_tracks_taken_jobs() {
+    let cluster_queue = ClusterQueue::new();
+    let stored_entry = stored_entry("1", 5);
+    cluster_queue.set_queued_count(1);
+    cluster_queue.add_queued();
+
+    cluster_queue.start_job(&stored_entry);
+
+    assert_eq!(1, cluster_queue.queued_count());
+    assert!(cluster_queue.is_running("1"));
+
+    let finished = cluster_queue.finish_job("1", |finished_entry| finished_entry.index);
+
+    assert_eq!(Some(5), finished);
+    assert!(!cluster_queue.is_running("1"));
+    assert_eq!(None, cluster_queue.finish_job("1", |finished_entry| finished_entry.index));
+}
+
+#[test]
+fn cluster_queue_heartbeat_keeps_rewritten_entries_and_drops_lost_jobs() {
+    let cluster_queue = ClusterQueue::new();
+    cluster_queue.start_job(&stored_entry("kept", 5));
+    cluster_queue.start_job(&stored_entry("lost", 5));
+
+    cluster_queue.heartbeat_jobs(|running_entry| {
+        if running_entry.entry.job_request.job_id == "kept" {
+            Some(stored_entry("kept", running_entry.index + 1))
+        } else {
+            None
+        }
+    });
+
+    assert!(!cluster_queue.is_running("lost"));
+    assert_eq!(Some(6), cluster_queue.finish_job("kept", |finished_entry| finished_entry.index));
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::time::Duration;
+use chrono::{DateTime, UTC};
+use iron::response::WriteBody;
+use serde_json;
+
+use factotum_server::persistence::{JobOutcome, JobState};
+use factotum_server::server::JobRequest;
+
+#[cfg(test)]
+mod tests;
+
+pub const EVENT_STREAM_CONTENT_TYPE: &'static str = "text/event-stream";
+
+// every open stream holds one of the server's request threads
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+// a subscriber this far behind is cut off rather than holding up the dispatcher
+const SUBSCRIBER_BUFFER_SIZE: usize = 256;
+
+// keeps proxies from closing an idle stream, and notices clients that went away
+const HEARTBEAT_INTERVAL_SECS: u64 = 15;
+
+const RECONNECT_DELAY_MILLIS: u64 = 3000;
+
+lazy_static! {
+    /// Process-wide bus, streamed from `/events`.
+    pub static ref EVENTS: EventBus = EventBus::new(MAX_SUBSCRIBERS);
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobEvent {
+    pub job_id: String,
+    pub job_name: String,
+    pub tags: BTreeMap<String, String>,
+    pub state: JobState,
+    pub outcome: JobOutcome,
+    pub timestamp: DateTime<UTC>,
+    // may carry credentials, so it's only for the webhook sender and never streamed
+    #[serde(skip_serializing)]
+    pub callback_url: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerEvent {
+    pub state: String,
+    pub timestamp: DateTime<UTC>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Job(JobEvent),
+    Server(ServerEvent),
+}
+
+impl Event {
+    pub fn job(job_request: &JobRequest, state: &JobState, outcome: &JobOutcome) -> Event {
+        Event::Job(JobEvent {
+            job_id: job_request.job_id.clone(),
+            job_name: job_request.job_name.clone(),
+            tags: job_request.get_tags().into_iter().collect(),
+            state: state.clone(),
+            outcome: outcome.clone(),
+            timestamp: UTC::now(),
+            callback_url: job_request.callback_url.clone(),
+        })
+    }
+
+    pub fn server(state: &str) -> Event {
+        Event::Server(ServerEvent {
+            state: state.to_string(),
+            timestamp: UTC::now(),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Job(_) => "job",
+            Event::Server(_) => "server",
+        }
+    }
+
+    pub fn data(&self) -> String {
+        match *self {
+            Event::Job(ref event) => serde_json::to_string(event),
+            Event::Server(ref event) => serde_json::to_string(event),
+        }.expect("JSON compact encode error")
+    }
+}
+
+/// Which job events a subscriber wants. Server events always get through, as
+/// a drain affects every job.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventFilter {
+    pub job_name: Option<String>,
+    pub tags: HashMap<String, String>,
+}
+
+impl EventFilter {
+    pub fn new(job_name: Option<String>, tags: HashMap<String, String>) -> EventFilter {
+        EventFilter {
+            job_name: job_name,
+            tags: tags,
+        }
+    }
+
+    /// A tag filter with an empty value matches any job carrying the tag.
+    pub fn matches(&self, event: &Event) -> bool {
+        let job_event = match *event {
+            Event::Job(ref job_event) => job_event,
+            Event::Server(_) => return true,
+        };
+        if let Some(ref job_name) = self.job_name {
+            if *job_name != job_event.job_name {
+                return false
+            }
+        }
+        self.tags.iter().all(|(key, value)| match job_event.tags.get(key) {
+            Some(tag_value) => value.is_empty() || value == tag_value,
+            None => false,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    filter: EventFilter,
+    tx: SyncSender<(u64, Event)>,
+}
+
+#[derive(Debug)]
+struct Subscribers {
+    next_subscriber_id: u64,
+    next_event_id: u64,
+    list: Vec<Subscriber>,
+    sinks: Vec<Sender<(u64, Event)>>,
+}
+
+/// Fans job and server events out to `/events` subscribers. Publishing never
+/// blocks: a subscriber that falls too far behind is dropped, and its stream ends.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    max_subscribers: usize,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl EventBus {
+    pub fn new(max_subscribers: usize) -> EventBus {
+        EventBus {
+            max_subscribers: max_subscribers,
+            subscribers: Arc::new(Mutex::new(Subscribers { next_subscriber_id: 0, next_event_id: 1, list: vec![], sinks: vec![] })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Subscribers> {
+        match self.subscribers.lock() {
+            Ok(subscribers) => subscribers,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn subscribe(&self, filter: EventFilter) -> Result<Subscription, String> {
+        let mut subscribers = self.lock();
+        if subscribers.list.len() >= self.max_subscribers {
+            return Err(format!("Event stream limit of {} subscribers reached", self.max_subscribers))
+        }
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER_SIZE);
+        let id = subscribers.next_subscriber_id;
+        subscribers.next_subscriber_id += 1;
+        subscribers.list.push(Subscriber { id: id, filter: filter, tx: tx });
+        Ok(Subscription { id: id, rx: rx, bus: self.clone() })
+    }
+
+    /// Adds an internal consumer that gets every event, however far behind it is,
+    /// and doesn't count towards the subscriber limit.
+    pub fn add_sink(&self, tx: Sender<(u64, Event)>) {
+        self.lock().sinks.push(tx);
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.lock();
+        let event_id = subscribers.next_event_id;
+        subscribers.next_event_id += 1;
+        subscribers.sinks.retain(|sink| sink.send((event_id, event.clone())).is_ok());
+        subscribers.list.retain(|subscriber| {
+            if !subscriber.filter.matches(&event) {
+                return true
+            }
+            match subscriber.tx.try_send((event_id, event.clone())) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropping event subscriber [{}] - more than {} events behind", subscriber.id, SUBSCRIBER_BUFFER_SIZE);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.lock().list.len()
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.lock().list.retain(|subscriber| subscriber.id != id);
+    }
+}
+
+/// A place on the bus, given up when dropped so a closed stream frees its slot
+/// straight away.
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    rx: Receiver<(u64, Event)>,
+    bus: EventBus,
+}
+
+impl Subscription {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<(u64, Event), RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.bus.unsubscribe(self.id);
+    }
+}
+
+pub fn format_event(event_id: u64, event: &Event) -> String {
+    format!("id: {}\nevent: {}\ndata: {}\n\n", event_id, event.name(), event.data())
+}
+
+/// Response body that writes events as they are published, until the client
+/// goes away or the subscriber is dropped for falling behind.
+pub struct EventStream {
+    subscription: Subscription,
+}
+
+impl EventStream {
+    pub fn new(subscription: Subscription) -> EventStream {
+        EventStream {
+            subscription: subscription,
+        }
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+        write!(res, "retry: {}\n\n", RECONNECT_DELAY_MILLIS)?;
+        res.flush()?;
+        loop {
+            match self.subscription.recv_timeout(Duration::from_secs(HEARTBEAT_INTERVAL_SECS)) {
+                Ok((event_id, event)) => res.write_all(format_event(event_id, &event).as_bytes())?,
+                Err(RecvTimeoutError::Timeout) => res.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            res.flush()?;
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+
+fn tagged_request(job_name: &str, tags: &[&str]) -> JobRequest {
+    let mut factfile_args = vec![];
+    for tag in tags {
+        factfile_args.push("--tag".to_string());
+        factfile_args.push(tag.to_string());
+    }
+    JobRequest::new("dummy_id_1", job_name, "/tmp/somewhere", factfile_args)
+}
+
+fn tag_filter(tags: &[(&str, &str)]) -> HashMap<String, String> {
+    tags.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+#[test]
+fn event_filter_matches_job_name_and_tags() {
+    let event = Event::job(&tagged_request("com.acme-main", &["env,prod", "nightly"]), &JobState::QUEUED, &JobOutcome::WAITING);
+
+    assert!(EventFilter::default().matches(&event));
+    assert!(EventFilter::new(Some("com.acme-main".to_string()), HashMap::new()).matches(&event));
+    assert!(EventFilter::new(None, tag_filter(&[("env", "prod"), ("nightly", "")])).matches(&event));
+    assert!(EventFilter::new(None, tag_filter(&[("env", "")])).matches(&event));
+    assert!(!EventFilter::new(Some("com.acme-other".to_string()), HashMap::new()).matches(&event));
+    assert!(!EventFilter::new(None, tag_filter(&[("env", "staging")])).matches(&event));
+    assert!(!EventFilter::new(None, tag_filter(&[("team", "")])).matches(&event));
+}
+
+#[test]
+fn event_filter_always_matches_server_events() {
+    let filter = EventFilter::new(Some("com.acme-main".to_string()), tag_filter(&[("env", "prod")]));
+    assert!(filter.matches(&Event::server("drain")));
+}
+
+#[test]
+fn format_job_event() {
+    let mut event = Event::job(&tagged_request("com.acme-main", &["env,prod"]), &JobState::DONE, &JobOutcome::FAILED);
+    if let Event::Job(ref mut job_event) = event {
+        job_event.timestamp = "2017-05-01T12:00:00Z".parse().unwrap();
+    }
+
+    let expected = "id: 7\nevent: job\ndata: {\"jobId\":\"dummy_id_1\",\"jobName\":\"com.acme-main\",\"tags\":{\"env\":\"prod\"},\"state\":\"DONE\",\"outcome\":\"FAILED\",\"timestamp\":\"2017-05-01T12:00:00Z\"}\n\n";
+    assert_eq!(expected, format_event(7, &event));
+}
+
+#[test]
+fn publish_delivers_matching_events_in_order() {
+    let bus = EventBus::new(2);
+    let everything = bus.subscribe(EventFilter::default()).unwrap();
+    let other_job = bus.subscribe(EventFilter::new(Some("com.acme-other".to_string()), HashMap::new())).unwrap();
+
+    bus.publish(Event::job(&tagged_request("com.acme-main", &[]), &JobState::QUEUED, &JobOutcome::WAITING));
+    bus.publish(Event::server("drain"));
+
+    let timeout = Duration::from_millis(100);
+    assert_eq!(1, everything.recv_timeout(timeout).unwrap().0);
+    assert_eq!((2, "server"), everything.recv_timeout(timeout).map(|(id, event)| (id, event.name())).unwrap());
+    assert_eq!((2, "server"), other_job.recv_timeout(timeout).map(|(id, event)| (id, event.name())).unwrap());
+    assert!(other_job.recv_timeout(timeout).is_err());
+}
+
+#[test]
+fn publish_delivers_everything_to_sinks() {
+    let bus = EventBus::new(0);
+    let (tx, rx) = mpsc::channel();
+    bus.add_sink(tx);
+
+    for _ in 0..SUBSCRIBER_BUFFER_SIZE + 1 {
+        bus.publish(Event::server("run"));
+    }
+
+    assert_eq!(SUBSCRIBER_BUFFER_SIZE + 1, rx.try_iter().count());
+    assert!(bus.subscribe(EventFilter::default()).is_err());
+}
+
+#[test]
+fn subscribe_limit_and_unsubscribe_on_drop() {
+    let bus = EventBus::new(1);
+    let subscription = bus.subscribe(EventFilter::default()).unwrap();
+
+    assert_eq!(Err("Event stream limit of 1 subscribers reached".to_string()), bus.subscribe(EventFilter::default()).map(|_| ()));
+    drop(subscription);
+    assert_eq!(0, bus.subscriber_count());
+    assert!(bus.subscribe(EventFilter::default()).is_ok());
+}
+
+#[test]
+fn publish_drops_slow_subscribers() {
+    let bus = EventBus::new(1);
+    let subscription = bus.subscribe(EventFilter::default()).unwrap();
+
+    for _ in 0..SUBSCRIBER_BUFFER_SIZE + 1 {
+        bus.publish(Event::server("run"));
+    }
+
+    assert_eq!(0, bus.subscriber_count());
+    for _ in 0..SUBSCRIBER_BUFFER_SIZE {
+        assert!(subscription.recv_timeout(Duration::from_millis(100)).is_ok());
+    }
+    assert_eq!(Err(RecvTimeoutError::Disconnected), subscription.recv_timeout(Duration::from_millis(100)).map(|_| ()));
+}
+
+#[test]
+fn event_stream_ends_when_dropped_from_bus() {
+    let bus = EventBus::new(1);
+    let mut stream = EventStream::new(bus.subscribe(EventFilter::default()).unwrap());
+    bus.publish(Event::job(&tagged_request("com.acme-main", &[]), &JobState::WORKING, &JobOutcome::RUNNING));
+    for _ in 0..SUBSCRIBER_BUFFER_SIZE {
+        bus.publish(Event::server("run"));
+    }
+
+    let mut body: Vec<u8> = vec![];
+    stream.write_body(&mut body).unwrap();
+    let body = String::from_utf8(body).unwrap();
+
+    assert!(body.starts_with("retry: 3000\n\nid: 1\nevent: job\ndata: {\"jobId\":\"dummy_id_1\""));
+    assert_eq!(SUBSCRIBER_BUFFER_SIZE, body.matches("event: ").count());
+}
@@ -0,0 +1,292 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use base64::decode;
+use serde_json;
+use serde_json::Value;
+
+use factotum_server::persistence::{Persistence, PersistenceError, StoredJobEntry};
+use factotum_server::server;
+use factotum_server::server::JobRequest;
+
+#[cfg(test)]
+mod tests;
+
+const FACTFILE_EXTENSION: &'static str = "factfile";
+
+// appended to the namespace, so content sits beside the job entries rather than
+// under them, and listing the entries never fetches factfile bodies
+const CONTENT_SUFFIX: &'static str = "-factfiles/";
+
+// keeps temporary files from two submissions of the same content apart
+static NEXT_TEMP_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory of factfiles submitted inline, each named by the SHA256 of its
+/// content so the same factfile is only ever written once. Job entries keep
+/// just the hash, the content itself is kept beside the namespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactfileStore {
+    pub root: PathBuf,
+}
+
+impl FactfileStore {
+    pub fn new(wrapped_root: Option<String>) -> FactfileStore {
+        let root = PathBuf::from(wrapped_root.unwrap_or(::FACTFILE_STORE_DEFAULT.to_string()));
+        // factotum is handed the path, so it mustn't depend on where it runs from
+        let root = if root.is_relative() {
+            env::current_dir().map(|dir| dir.join(&root)).unwrap_or(root)
+        } else {
+            root
+        };
+        FactfileStore {
+            root: root,
+        }
+    }
+
+    pub fn get_path(&self, factfile: &Value) -> PathBuf {
+        self.get_hash_path(&server::get_factfile_hash(factfile))
+    }
+
+    fn get_hash_path(&self, hash: &str) -> PathBuf {
+        self.root.join(format!("{}.{}", hash, FACTFILE_EXTENSION))
+    }
+
+    /// Writes the factfile unless the store already has it, returning its path.
+    pub fn put(&self, factfile: &Value) -> Result<PathBuf, String> {
+        let path = self.get_path(factfile);
+        if path.exists() {
+            return Ok(path)
+        }
+        fs::create_dir_all(&self.root).map_err(|e| format!("Could not create factfile store '{}': {}", self.root.display(), e))?;
+        write_factfile(&path, factfile)?;
+        debug!("Stored factfile [{}]", path.display());
+        Ok(path)
+    }
+
+    /// Swaps a validated request's inline content for its hash and the path it
+    /// will have in the store, handing back the content for `save_content`.
+    /// Nothing is written; the store only gets a factfile when a job runs it.
+    pub fn take_inline_factfile(&self, mut job_request: JobRequest) -> (JobRequest, Option<Value>) {
+        let factfile = job_request.factfile.take();
+        if let Some(ref factfile) = factfile {
+            let hash = server::get_factfile_hash(factfile);
+            job_request.factfile_path = self.get_hash_path(&hash).to_string_lossy().into_owned();
+            job_request.factfile_hash = Some(hash);
+        }
+        (job_request, factfile)
+    }
+
+    /// Where to run a job's factfile from on this server. Inline content missing
+    /// from the store is fetched from Consul first, as the job may have
+    /// been submitted to another server or the file collected since.
+    pub fn get_run_path<T: Persistence>(&self, persistence: &T, job_request: &JobRequest) -> Result<String, String> {
+        let hash = match job_request.factfile_hash {
+            Some(ref hash) => hash,
+            None => return Ok(job_request.factfile_path.clone()),
+        };
+        let path = self.get_hash_path(hash);
+        if path.exists() {
+            return Ok(path.to_string_lossy().into_owned())
+        }
+        let factfile = get_content(persistence, hash).map_err(|e| e.to_string())?;
+        self.put(&factfile).map(|path| path.to_string_lossy().into_owned())
+    }
+
+    /// Removes stored factfiles no job entry refers to any more, returning how
+    /// many were removed. Files being written, which aren't named by a hash yet,
+    /// are left alone, as are those written within `min_age`: a job taken after
+    /// the entries were listed may be about to run one.
+    pub fn collect_garbage(&self, referenced: &HashSet<String>, min_age: Duration) -> Result<usize, String> {
+        let dir = match fs::read_dir(&self.root) {
+            Ok(dir) => dir,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("Could not read factfile store '{}': {}", self.root.display(), e)),
+        };
+        let mut removed = 0;
+        for path in dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let is_factfile = path.extension().map(|extension| extension == FACTFILE_EXTENSION).unwrap_or(false);
+            let hash = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+            if !is_factfile || referenced.contains(&hash) {
+                continue
+            }
+            let is_recent = fs::metadata(&path).and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().map(|age| age < min_age).unwrap_or(true))
+                .unwrap_or(true);
+            if is_recent {
+                continue
+            }
+            match fs::remove_file(&path) {
+                Ok(_) => {
+                    debug!("Removed unreferenced factfile [{}]", path.display());
+                    removed += 1;
+                },
+                Err(e) => warn!("Could not remove unreferenced factfile '{}': {}", path.display(), e),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// Inline content storage
+
+fn get_content_prefix<T: Persistence>(persistence: &T) -> String {
+    format!("{}{}", persistence.prepend_namespace("").trim_end_matches('/'), CONTENT_SUFFIX)
+}
+
+fn get_content_key<T: Persistence>(persistence: &T, hash: &str) -> String {
+    format!("{}{}", get_content_prefix(persistence), hash)
+}
+
+/// Keeps accepted inline content beside the namespace, so whichever server runs
+/// the job can put it in its own store.
+pub fn save_content<T: Persistence>(persistence: &T, factfile: &Value) -> Result<(), PersistenceError> {
+    let factfile_json = serde_json::to_string(factfile).expect("JSON compact encode error");
+    persistence.set_key(&get_content_key(persistence, &server::get_factfile_hash(factfile)), &factfile_json)
+}
+
+pub fn get_content<T: Persistence>(persistence: &T, hash: &str) -> Result<Value, PersistenceError> {
+    let base64_str = match persistence.get_key_with_index(&get_content_key(persistence, hash))? {
+        Some((base64_str, _)) => base64_str,
+        None => return Err(PersistenceError::NotFound(format!("No factfile found with hash '{}'", hash))),
+    };
+    let factfile: Value = decode(&base64_str).map_err(|e| e.to_string())
+        .and_then(|bytes| String::from_utf8(bytes).map_err(|e| e.to_string()))
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        .map_err(|e| PersistenceError::Corrupt(format!("Could not decode factfile with hash '{}' - {}", hash, e)))?;
+    if server::get_factfile_hash(&factfile) != hash {
+        return Err(PersistenceError::Corrupt(format!("Factfile stored for hash '{}' has different content", hash)))
+    }
+    Ok(factfile)
+}
+
+/// Removes the stored copies of inline content no job entry refers to any more,
+/// returning how many were removed. Content is saved just before the entry
+/// referring to it is claimed, so it's only removed once `unreferenced`, the keys
+/// found unreferenced last time, shows it was already garbage a call ago.
+pub fn delete_unreferenced_content<T: Persistence>(persistence: &T, referenced: &HashSet<String>, unreferenced: &mut HashSet<String>) -> Result<usize, PersistenceError> {
+    let prefix = get_content_prefix(persistence);
+    let previously_unreferenced = mem::replace(unreferenced, HashSet::new());
+    let mut removed = 0;
+    for key in persistence.get_keys(&prefix)? {
+        if referenced.contains(&key[prefix.len()..]) {
+            continue
+        }
+        if !previously_unreferenced.contains(&key) {
+            unreferenced.insert(key);
+            continue
+        }
+        // only the content being removed is fetched, for the index to delete it at
+        let index = match persistence.get_key_with_index(&key)? {
+            Some((_, index)) => index,
+            None => continue,
+        };
+        match persistence.delete_key_if_index(&key, index) {
+            Ok(_) => removed += 1,
+            Err(PersistenceError::Conflict(_)) => debug!("Factfile '{}' changed since it was read, skipping", key),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Hashes of the inline factfiles the given job entries still refer to.
+pub fn get_referenced_hashes(entries: &[StoredJobEntry]) -> HashSet<String> {
+    entries.iter()
+           .filter_map(|stored_entry| stored_entry.entry.job_request.factfile_hash.clone())
+           .collect()
+}
+
+/// An inline factfile written outside the store for a dry run, removed again
+/// once it's dropped.
+#[derive(Debug)]
+pub struct TempFactfile {
+    pub path: PathBuf,
+}
+
+impl Drop for TempFactfile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove temporary factfile '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Points a request with inline content at a temporary copy, so it can be dry run
+/// without adding to the store. Requests that give a `factfilePath` are left alone.
+pub fn write_temp_factfile(job_request: JobRequest) -> Result<(JobRequest, Option<TempFactfile>), String> {
+    let temp_factfile = match job_request.factfile {
+        None => return Ok((job_request, None)),
+        Some(_) if !job_request.factfile_path.is_empty() => {
+            return Err("Only one of 'factfile' and 'factfilePath' can be given".to_string())
+        },
+        Some(ref factfile) if !factfile.is_object() => {
+            return Err("Invalid value for 'factfile', must be a JSON object".to_string())
+        },
+        Some(ref factfile) => {
+            let path = get_temp_path(&env::temp_dir().join(format!("factotum-{}.{}", server::get_factfile_hash(factfile), FACTFILE_EXTENSION)));
+            write_factfile(&path, factfile)?;
+            TempFactfile { path: path }
+        },
+    };
+    let factfile_path = temp_factfile.path.to_string_lossy().into_owned();
+    Ok((JobRequest { factfile_path: factfile_path, ..job_request }, Some(temp_factfile)))
+}
+
+fn get_temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{}.{}.{}.tmp", FACTFILE_EXTENSION, process::id(), NEXT_TEMP_FILE_ID.fetch_add(1, Ordering::SeqCst)))
+}
+
+// written aside and renamed into place, so a half-written factfile is never run
+fn write_factfile(path: &Path, factfile: &Value) -> Result<(), String> {
+    let temp_path = get_temp_path(path);
+    let written = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(serde_json::to_string(factfile).expect("JSON compact encode error").as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(format!("Could not write factfile '{}': {}", path.display(), e))
+    }
+    Ok(())
+}
+
+/// Resolves the `--factfile-root` directories, which must exist.
+pub fn get_factfile_roots(paths: Vec<String>) -> Result<Vec<PathBuf>, String> {
+    paths.into_iter()
+         .map(|path| fs::canonicalize(&path).map_err(|e| format!("Invalid path for factfile root at: '{}' - {}", path, e)))
+         .collect()
+}
+
+/// Resolves a submitted `factfilePath`, following symlinks, and checks it ends up
+/// inside one of the roots.
+pub fn check_factfile_path(factfile_path: &str, roots: &[PathBuf]) -> Result<PathBuf, String> {
+    if Path::new(factfile_path).components().any(|component| component == Component::ParentDir) {
+        return Err(format!("Invalid value for 'factfilePath':'{}', must not contain '..'", factfile_path))
+    }
+    let canonical_path = fs::canonicalize(factfile_path).map_err(|_| format!("Value does not exist on host for 'factfilePath':'{}'", factfile_path))?;
+    if roots.iter().any(|root| canonical_path.starts_with(root)) {
+        Ok(canonical_path)
+    } else {
+        Err(format!("Invalid value for 'factfilePath':'{}', must be inside an allowed factfile root", factfile_path))
+    }
+}
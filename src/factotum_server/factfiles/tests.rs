@@ -0,0 +1,247 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::io::Read;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use factotum_server::persistence::tests::ConsulKeyValueMock;
+
+// the root isn't created, so tests also cover the store making it
+fn new_test_store(name: &str) -> FactfileStore {
+    let root = env::temp_dir().join(format!("factotum-factfiles-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&root);
+    FactfileStore::new(Some(root.to_string_lossy().into_owned()))
+}
+
+fn sample_factfile() -> Value {
+    serde_json::from_str(r#"{ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "echo", "tasks": [] } }"#).unwrap()
+}
+
+fn inline_request(factfile: Value) -> JobRequest {
+    let mut job_request = JobRequest::new("", "echo", "", vec![]);
+    job_request.factfile = Some(factfile);
+    job_request
+}
+
+#[test]
+fn relative_root_is_made_absolute() {
+    let store = FactfileStore::new(None);
+    assert!(store.root.is_absolute());
+    assert!(store.root.ends_with(::FACTFILE_STORE_DEFAULT));
+}
+
+#[test]
+fn put_writes_compact_content_named_by_hash() {
+    let store = new_test_store("put");
+    let path = store.put(&sample_factfile()).unwrap();
+
+    assert_eq!(store.root.join(format!("{}.factfile", server::get_factfile_hash(&sample_factfile()))), path);
+    let mut contents = String::new();
+    File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(serde_json::to_string(&sample_factfile()).unwrap(), contents);
+    assert_eq!(1, fs::read_dir(&store.root).unwrap().count());
+}
+
+#[test]
+fn put_deduplicates_identical_content() {
+    let store = new_test_store("dedupe");
+    let reformatted: Value = serde_json::from_str(&serde_json::to_string_pretty(&sample_factfile()).unwrap()).unwrap();
+
+    assert_eq!(store.put(&sample_factfile()).unwrap(), store.put(&reformatted).unwrap());
+    assert_eq!(1, fs::read_dir(&store.root).unwrap().count());
+}
+
+#[test]
+fn take_inline_factfile_keeps_hash() {
+    let store = new_test_store("inline");
+    let (job_request, factfile) = store.take_inline_factfile(inline_request(sample_factfile()));
+
+    assert_eq!(Some(sample_factfile()), factfile);
+    assert_eq!(None, job_request.factfile);
+    assert_eq!(Some(server::get_factfile_hash(&sample_factfile())), job_request.factfile_hash);
+    assert_eq!(store.get_path(&sample_factfile()).to_string_lossy(), job_request.factfile_path);
+    assert!(!store.root.exists());
+}
+
+#[test]
+fn take_inline_factfile_leaves_path_requests_alone() {
+    let store = new_test_store("path");
+    let job_request = JobRequest::new("", "echo", "/tmp/somewhere", vec![]);
+
+    assert_eq!((job_request.clone(), None), store.take_inline_factfile(job_request.clone()));
+    assert_eq!(Ok("/tmp/somewhere".to_string()), store.get_run_path(&ConsulKeyValueMock::new(), &job_request));
+    assert!(!store.root.exists());
+}
+
+#[test]
+fn get_run_path_fetches_missing_factfile() {
+    let store = new_test_store("running");
+    let persistence = ConsulKeyValueMock::new();
+    let (job_request, factfile) = store.take_inline_factfile(inline_request(sample_factfile()));
+    save_content(&persistence, &factfile.unwrap()).unwrap();
+
+    let run_path = store.get_run_path(&persistence, &job_request).unwrap();
+
+    assert_eq!(job_request.factfile_path, run_path);
+    assert!(Path::new(&run_path).exists());
+}
+
+#[test]
+fn get_run_path_fails_without_content() {
+    let store = new_test_store("missing");
+    let (job_request, _) = store.take_inline_factfile(inline_request(sample_factfile()));
+    let hash = server::get_factfile_hash(&sample_factfile());
+
+    assert_eq!(Err(format!("Persistence Error: not found - No factfile found with hash '{}'", hash)), store.get_run_path(&ConsulKeyValueMock::new(), &job_request));
+}
+
+#[test]
+fn get_content_checks_hash() {
+    let persistence = ConsulKeyValueMock::new();
+    save_content(&persistence, &sample_factfile()).unwrap();
+    let hash = server::get_factfile_hash(&sample_factfile());
+    assert_eq!(Ok(sample_factfile()), get_content(&persistence, &hash));
+    // listing the job entries never fetches content
+    assert!(persistence.get_keys_with_index(&persistence.prepend_namespace("")).unwrap().is_empty());
+
+    let other_key = format!("com.test/namespace-factfiles/{}", "0".repeat(64));
+    let stored = persistence.ref_map.borrow()[&format!("com.test/namespace-factfiles/{}", hash)].clone();
+    persistence.ref_map.borrow_mut().insert(other_key, stored);
+    assert!(get_content(&persistence, &"0".repeat(64)).is_err());
+}
+
+#[test]
+fn unreferenced_factfiles_are_collected() {
+    let store = new_test_store("garbage");
+    let persistence = ConsulKeyValueMock::new();
+    let other_factfile = json!({ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "other", "tasks": [] } });
+    let kept_path = store.put(&sample_factfile()).unwrap();
+    let removed_path = store.put(&other_factfile).unwrap();
+    save_content(&persistence, &sample_factfile()).unwrap();
+    save_content(&persistence, &other_factfile).unwrap();
+    let in_progress = store.root.join(".partial.factfile.tmp");
+    File::create(&in_progress).unwrap();
+    let referenced: HashSet<String> = vec![server::get_factfile_hash(&sample_factfile())].into_iter().collect();
+
+    let mut unreferenced = HashSet::new();
+
+    assert_eq!(Ok(0), store.collect_garbage(&referenced, Duration::from_secs(3600)));
+    assert_eq!(Ok(1), store.collect_garbage(&referenced, Duration::from_secs(0)));
+    assert_eq!(Ok(0), delete_unreferenced_content(&persistence, &referenced, &mut unreferenced));
+    assert_eq!(1, unreferenced.len());
+    assert_eq!(Ok(1), delete_unreferenced_content(&persistence, &referenced, &mut unreferenced));
+    assert!(unreferenced.is_empty());
+
+    assert!(kept_path.exists());
+    assert!(!removed_path.exists());
+    assert!(in_progress.exists());
+    assert_eq!(vec![format!("com.test/namespace-factfiles/{}", server::get_factfile_hash(&sample_factfile()))], persistence.ref_map.borrow().keys().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn collect_garbage_without_store() {
+    assert_eq!(Ok(0), new_test_store("no-store").collect_garbage(&HashSet::new(), Duration::from_secs(0)));
+}
+
+#[test]
+fn write_temp_factfile_removed_on_drop() {
+    let (job_request, temp_factfile) = write_temp_factfile(inline_request(sample_factfile())).unwrap();
+    let temp_factfile = temp_factfile.unwrap();
+
+    assert_eq!(temp_factfile.path.to_string_lossy(), job_request.factfile_path);
+    assert!(temp_factfile.path.starts_with(env::temp_dir()));
+    let mut contents = String::new();
+    File::open(&temp_factfile.path).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(serde_json::to_string(&sample_factfile()).unwrap(), contents);
+
+    let path = temp_factfile.path.clone();
+    drop(temp_factfile);
+    assert!(!path.exists());
+}
+
+#[test]
+fn write_temp_factfile_leaves_path_requests_alone() {
+    let job_request = JobRequest::new("", "echo", "/tmp/somewhere", vec![]);
+    let (unchanged, temp_factfile) = write_temp_factfile(job_request.clone()).unwrap();
+
+    assert_eq!(job_request, unchanged);
+    assert!(temp_factfile.is_none());
+}
+
+#[test]
+fn write_temp_factfile_invalid() {
+    let mut both = inline_request(sample_factfile());
+    both.factfile_path = "/tmp/somewhere".to_string();
+
+    assert_eq!("Only one of 'factfile' and 'factfilePath' can be given", write_temp_factfile(both).unwrap_err());
+    assert_eq!("Invalid value for 'factfile', must be a JSON object", write_temp_factfile(inline_request(Value::String("{}".to_string()))).unwrap_err());
+}
+
+// a root holding one factfile, with a sibling directory outside it
+fn new_test_roots(name: &str) -> (PathBuf, PathBuf) {
+    let base = env::temp_dir().join(format!("factotum-roots-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let root = base.join("allowed");
+    let outside = base.join("outside");
+    fs::create_dir_all(&root).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    File::create(root.join("echo.factfile")).unwrap();
+    File::create(outside.join("secret.factfile")).unwrap();
+    (fs::canonicalize(root).unwrap(), fs::canonicalize(outside).unwrap())
+}
+
+#[test]
+fn get_factfile_roots_must_exist() {
+    let (root, _) = new_test_roots("exist");
+    assert_eq!(Ok(vec![root.clone()]), get_factfile_roots(vec![root.to_string_lossy().into_owned()]));
+    assert!(get_factfile_roots(vec!["/does/not/exist".to_string()]).unwrap_err().starts_with("Invalid path for factfile root at: '/does/not/exist'"));
+}
+
+#[test]
+fn check_factfile_path_inside_root() {
+    let (root, _) = new_test_roots("inside");
+    let path = root.join("echo.factfile");
+    assert_eq!(Ok(path.clone()), check_factfile_path(&path.to_string_lossy(), &[root]));
+}
+
+#[test]
+fn check_factfile_path_outside_roots() {
+    let (root, outside) = new_test_roots("outside");
+    let path = outside.join("secret.factfile").to_string_lossy().into_owned();
+    assert_eq!(Err(format!("Invalid value for 'factfilePath':'{}', must be inside an allowed factfile root", path)), check_factfile_path(&path, &[root]));
+}
+
+#[test]
+fn check_factfile_path_resolves_symlinks() {
+    let (root, outside) = new_test_roots("symlink");
+    let link = root.join("link.factfile");
+    symlink(outside.join("secret.factfile"), &link).unwrap();
+    let path = link.to_string_lossy().into_owned();
+    assert_eq!(Err(format!("Invalid value for 'factfilePath':'{}', must be inside an allowed factfile root", path)), check_factfile_path(&path, &[root]));
+}
+
+#[test]
+fn check_factfile_path_rejects_parent_dir() {
+    let (root, _) = new_test_roots("parent");
+    let path = format!("{}/../outside/secret.factfile", root.display());
+    assert_eq!(Err(format!("Invalid value for 'factfilePath':'{}', must not contain '..'", path)), check_factfile_path(&path, &[root]));
+}
+
+#[test]
+fn check_factfile_path_missing() {
+    let (root, _) = new_test_roots("missing");
+    let path = root.join("missing.factfile").to_string_lossy().into_owned();
+    assert_eq!(Err(format!("Value does not exist on host for 'factfilePath':'{}'", path)), check_factfile_path(&path, &[root]));
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::collections::{BTreeMap, HashSet};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Duration, UTC};
+
+use factotum_server::factfiles;
+use factotum_server::factfiles::FactfileStore;
+use factotum_server::leader::Leadership;
+use factotum_server::persistence;
+use factotum_server::persistence::{Persistence, PersistenceError, JobState, StoredJobEntry};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+    pub max_age_hours: Option<i64>,
+    pub max_count: Option<usize>,
+    pub interval_secs: u64,
+}
+
+impl RetentionPolicy {
+    pub fn new(max_age_hours: Option<i64>, max_count: Option<usize>, wrapped_interval: Option<u64>) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_hours: max_age_hours,
+            max_count: max_count,
+            interval_secs: match wrapped_interval {
+                Some(interval) if interval > 0 => interval,
+                _ => ::RETENTION_INTERVAL_DEFAULT,
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_age_hours.is_some() || self.max_count.is_some()
+    }
+
+    /// A limit of zero or less would remove every finished entry on the first run.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.max_age_hours, self.max_count) {
+            (Some(max_age_hours), _) if max_age_hours <= 0 => Err(format!("Invalid value for '--retention-max-age':'{}', must be greater than 0", max_age_hours)),
+            (_, Some(0)) => Err("Invalid value for '--retention-max-count':'0', must be greater than 0".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiredEntry {
+    pub job_id: String,
+    pub job_name: String,
+    pub last_updated: Option<DateTime<UTC>>,
+    pub reason: String,
+    #[serde(skip_serializing)]
+    pub stored_entry: StoredJobEntry,
+}
+
+impl ExpiredEntry {
+    fn new(stored_entry: StoredJobEntry, reason: String) -> ExpiredEntry {
+        ExpiredEntry {
+            job_id: stored_entry.entry.job_request.job_id.clone(),
+            job_name: stored_entry.entry.job_request.job_name.clone(),
+            last_updated: stored_entry.entry.last_updated.clone(),
+            reason: reason,
+            stored_entry: stored_entry,
+        }
+    }
+}
+
+/// Runs on every node, but only the cluster leader collects garbage from
+/// Consul. Every node clears its own factfile store.
+pub fn spawn_janitor<T: 'static + Persistence + Send>(persistence: T, policy: RetentionPolicy, leadership: Leadership, factfile_store: FactfileStore) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut unreferenced = HashSet::new();
+        loop {
+            thread::sleep(StdDuration::from_secs(policy.interval_secs));
+            let is_leader = leadership.is_leader();
+            if is_leader {
+                match collect_garbage(&persistence, &policy) {
+                    Ok(removed) => info!("Janitor removed {} finished job entries", removed.len()),
+                    Err(e) => error!("Janitor failed to collect finished job entries - {}", e),
+                }
+            } else {
+                debug!("Janitor skipped job entries, this server is not the cluster leader");
+            }
+            if !is_leader {
+                // a new leader starts over, it can't know how long content has been unreferenced
+                unreferenced.clear();
+            }
+            match collect_factfiles(&persistence, &factfile_store, is_leader, &mut unreferenced, StdDuration::from_secs(policy.interval_secs)) {
+                Ok(removed) => info!("Janitor removed {} unreferenced factfiles", removed),
+                Err(e) => error!("Janitor failed to collect unreferenced factfiles - {}", e),
+            }
+        }
+    })
+}
+
+/// Lists the finished job entries the janitor would remove, without deleting anything.
+pub fn report_garbage<T: Persistence>(persistence: &T, policy: &RetentionPolicy) -> Result<Vec<ExpiredEntry>, PersistenceError> {
+    let entries = persistence::get_entries(persistence)?;
+    Ok(find_expired_entries(entries, policy, UTC::now()))
+}
+
+pub fn collect_garbage<T: Persistence>(persistence: &T, policy: &RetentionPolicy) -> Result<Vec<ExpiredEntry>, PersistenceError> {
+    let expired = report_garbage(persistence, policy)?;
+    let mut removed = vec![];
+    for expired_entry in expired {
+        match persistence::delete_entry(persistence, &expired_entry.stored_entry) {
+            Ok(_) => {
+                debug!("Removed job entry id='{}' - {}", expired_entry.job_id, expired_entry.reason);
+                removed.push(expired_entry);
+            },
+            Err(PersistenceError::Conflict(_)) => debug!("Job entry id='{}' changed since it was read, skipping", expired_entry.job_id),
+            Err(e) => error!("Could not remove job entry id='{}' - {}", expired_entry.job_id, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes inline factfiles no remaining job entry refers to, from this server's
+/// store and, on the leader, from Consul. Neither is removed the first time
+/// it's found unreferenced: store files must be older than `min_age`, and
+/// content in Consul must already be in `unreferenced` from the last pass.
+/// Returns how many were removed.
+pub fn collect_factfiles<T: Persistence>(persistence: &T, factfile_store: &FactfileStore, is_leader: bool, unreferenced: &mut HashSet<String>, min_age: StdDuration) -> Result<usize, String> {
+    let entries = persistence::get_entries(persistence).map_err(|e| e.to_string())?;
+    let referenced = factfiles::get_referenced_hashes(&entries);
+    let removed_content = if is_leader {
+        factfiles::delete_unreferenced_content(persistence, &referenced, unreferenced).map_err(|e| e.to_string())?
+    } else {
+        0
+    };
+    Ok(removed_content + factfile_store.collect_garbage(&referenced, min_age)?)
+}
+
+pub fn find_expired_entries(entries: Vec<StoredJobEntry>, policy: &RetentionPolicy, now: DateTime<UTC>) -> Vec<ExpiredEntry> {
+    let mut entries_by_name: BTreeMap<String, Vec<StoredJobEntry>> = BTreeMap::new();
+    for stored_entry in entries.into_iter().filter(|e| e.entry.state == JobState::DONE) {
+        entries_by_name.entry(stored_entry.entry.job_request.job_name.clone())
+                       .or_insert_with(Vec::new)
+                       .push(stored_entry);
+    }
+
+    let mut expired = vec![];
+    for (_, mut named_entries) in entries_by_name {
+        // newest first, entries without a timestamp are treated as the oldest
+        named_entries.sort_by(|a, b| b.entry.last_updated.cmp(&a.entry.last_updated));
+        for (position, stored_entry) in named_entries.into_iter().enumerate() {
+            if let Some(reason) = get_expiry_reason(&stored_entry, position, policy, now) {
+                expired.push(ExpiredEntry::new(stored_entry, reason));
+            }
+        }
+    }
+    expired
+}
+
+fn get_expiry_reason(stored_entry: &StoredJobEntry, position: usize, policy: &RetentionPolicy, now: DateTime<UTC>) -> Option<String> {
+    if let Some(max_count) = policy.max_count {
+        if position >= max_count {
+            return Some(format!("Exceeds max count of {} entries for jobName", max_count))
+        }
+    }
+    if let (Some(max_age_hours), Some(last_updated)) = (policy.max_age_hours, stored_entry.entry.last_updated) {
+        if now.signed_duration_since(last_updated) > Duration::hours(max_age_hours) {
+            return Some(format!("Older than max age of {} hours", max_age_hours))
+        }
+    }
+    None
+}
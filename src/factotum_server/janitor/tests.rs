@@ -0,0 +1,160 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use factotum_server::persistence::{JobEntry, JobOutcome};
+use factotum_server::server::JobRequest;
+
+fn stored_entry(job_id: &str, job_name: &str, state: JobState, hours_ago: Option<i64>) -> StoredJobEntry {
+    let request = JobRequest::new(job_id, job_name, "/fake/path", vec![]);
+    let mut entry = JobEntry::new(&state, &request, "test_janitor", &JobOutcome::SUCCEEDED);
+    entry.last_updated = hours_ago.map(|hours| UTC::now() - Duration::hours(hours));
+    StoredJobEntry {
+        key: format!("com.test/namespace/{}", job_id),
+        index: 1,
+        entry: entry,
+    }
+}
+
+fn expired_ids(expired: &Vec<ExpiredEntry>) -> Vec<String> {
+    expired.iter().map(|e| e.job_id.clone()).collect()
+}
+
+#[test]
+fn create_new_retention_policy_defaults() {
+    let policy = RetentionPolicy::new(None, None, None);
+
+    assert_eq!(::RETENTION_INTERVAL_DEFAULT, policy.interval_secs);
+    assert_eq!(false, policy.is_enabled());
+}
+
+#[test]
+fn create_new_retention_policy_enabled() {
+    let policy = RetentionPolicy::new(Some(24), None, Some(60));
+
+    assert_eq!(60, policy.interval_secs);
+    assert!(policy.is_enabled());
+}
+
+#[test]
+fn validate_retention_policy() {
+    assert_eq!(Ok(()), RetentionPolicy::new(Some(24), Some(5), None).validate());
+    assert_eq!(Ok(()), RetentionPolicy::new(None, None, None).validate());
+    assert_eq!(Err("Invalid value for '--retention-max-age':'0', must be greater than 0".to_string()), RetentionPolicy::new(Some(0), None, None).validate());
+    assert_eq!(Err("Invalid value for '--retention-max-age':'-1', must be greater than 0".to_string()), RetentionPolicy::new(Some(-1), None, None).validate());
+    assert_eq!(Err("Invalid value for '--retention-max-count':'0', must be greater than 0".to_string()), RetentionPolicy::new(None, Some(0), None).validate());
+}
+
+#[test]
+fn find_expired_entries_disabled_policy() {
+    let policy = RetentionPolicy::new(None, None, None);
+    let entries = vec![stored_entry("1", "dummy", JobState::DONE, Some(1000))];
+
+    let expired = find_expired_entries(entries, &policy, UTC::now());
+
+    assert!(expired.is_empty());
+}
+
+#[test]
+fn find_expired_entries_by_max_age() {
+    let policy = RetentionPolicy::new(Some(24), None, None);
+    let entries = vec![
+        stored_entry("old", "dummy", JobState::DONE, Some(48)),
+        stored_entry("new", "dummy", JobState::DONE, Some(1)),
+        stored_entry("unknown", "dummy", JobState::DONE, None),
+    ];
+
+    let expired = find_expired_entries(entries, &policy, UTC::now());
+
+    assert_eq!(vec!["old".to_string()], expired_ids(&expired));
+    assert_eq!("Older than max age of 24 hours", expired[0].reason);
+}
+
+#[test]
+fn find_expired_entries_by_max_count_per_job_name() {
+    let policy = RetentionPolicy::new(None, Some(1), None);
+    let entries = vec![
+        stored_entry("a_old", "a", JobState::DONE, Some(10)),
+        stored_entry("a_new", "a", JobState::DONE, Some(1)),
+        stored_entry("a_unknown", "a", JobState::DONE, None),
+        stored_entry("b_only", "b", JobState::DONE, Some(10)),
+    ];
+
+    let expired = find_expired_entries(entries, &policy, UTC::now());
+
+    assert_eq!(vec!["a_old".to_string(), "a_unknown".to_string()], expired_ids(&expired));
+}
+
+#[test]
+fn find_expired_entries_ignores_unfinished() {
+    let policy = RetentionPolicy::new(Some(1), Some(0), None);
+    let entries = vec![
+        stored_entry("queued", "dummy", JobState::QUEUED, Some(48)),
+        stored_entry("working", "dummy", JobState::WORKING, Some(48)),
+    ];
+
+    let expired = find_expired_entries(entries, &policy, UTC::now());
+
+    assert!(expired.is_empty());
+}
+
+#[test]
+fn collect_factfiles_keeps_referenced() {
+    use std::env;
+    use std::process;
+    use serde_json::Value;
+    use factotum_server::persistence::tests::ConsulKeyValueMock;
+
+    let store = FactfileStore::new(Some(env::temp_dir().join(format!("factotum-factfiles-janitor-{}", process::id())).to_string_lossy().into_owned()));
+    let persistence = ConsulKeyValueMock::new();
+    let kept: Value = json!({ "data": { "name": "kept" } });
+    let unreferenced: Value = json!({ "data": { "name": "unreferenced" } });
+    let (request, _) = store.take_inline_factfile(JobRequest { factfile: Some(kept.clone()), ..JobRequest::new("dummy_id_1", "kept", "", vec![]) });
+    persistence::claim_entry(&persistence, "dummy_id_1", &request).unwrap();
+    for factfile in vec![&kept, &unreferenced] {
+        store.put(factfile).unwrap();
+        factfiles::save_content(&persistence, factfile).unwrap();
+    }
+
+    let mut unreferenced_keys = HashSet::new();
+
+    assert_eq!(Ok(1), collect_factfiles(&persistence, &store, false, &mut unreferenced_keys, StdDuration::from_secs(0)));
+    assert!(store.get_path(&kept).exists());
+    assert!(!store.get_path(&unreferenced).exists());
+    assert_eq!(Ok(0), collect_factfiles(&persistence, &store, true, &mut unreferenced_keys, StdDuration::from_secs(0)));
+    assert_eq!(Ok(1), collect_factfiles(&persistence, &store, true, &mut unreferenced_keys, StdDuration::from_secs(0)));
+    assert!(factfiles::get_content(&persistence, &::factotum_server::server::get_factfile_hash(&kept)).is_ok());
+    assert!(factfiles::get_content(&persistence, &::factotum_server::server::get_factfile_hash(&unreferenced)).is_err());
+}
+
+#[test]
+fn collect_factfiles_keeps_content_saved_before_its_claim() {
+    use std::env;
+    use std::process;
+    use factotum_server::persistence::tests::ConsulKeyValueMock;
+
+    let store = FactfileStore::new(Some(env::temp_dir().join(format!("factotum-factfiles-janitor-claim-{}", process::id())).to_string_lossy().into_owned()));
+    let persistence = ConsulKeyValueMock::new();
+    let factfile = json!({ "data": { "name": "claimed" } });
+    let (request, _) = store.take_inline_factfile(JobRequest { factfile: Some(factfile.clone()), ..JobRequest::new("dummy_id_1", "claimed", "", vec![]) });
+    let mut unreferenced = HashSet::new();
+
+    // submission saves the content, the janitor passes, then the entry is claimed
+    factfiles::save_content(&persistence, &factfile).unwrap();
+    assert_eq!(Ok(0), collect_factfiles(&persistence, &store, true, &mut unreferenced, StdDuration::from_secs(0)));
+    persistence::claim_entry(&persistence, "dummy_id_1", &request).unwrap();
+    assert_eq!(Ok(0), collect_factfiles(&persistence, &store, true, &mut unreferenced, StdDuration::from_secs(0)));
+
+    assert!(store.get_run_path(&persistence, &request).is_ok());
+}
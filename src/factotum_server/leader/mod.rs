@@ -0,0 +1,152 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use factotum_server::consul::{ConsulClient, SessionRequest};
+
+#[cfg(test)]
+mod tests;
+
+// Consul won't accept a session TTL below 10s
+pub const SESSION_TTL_SECS: u64 = 15;
+const SESSION_LOCK_DELAY_SECS: u64 = 5;
+const ELECTION_INTERVAL_SECS: u64 = 5;
+
+// nested so it's never mistaken for a job entry stored directly under the namespace
+pub const LEADER_KEY: &'static str = "cluster/leader";
+
+pub trait LockService {
+    fn create_session(&self, name: &str) -> Result<String, String>;
+    fn renew_session(&self, session_id: &str) -> Result<bool, String>;
+    fn acquire_lock(&self, key: &str, value: &str, session_id: &str) -> Result<bool, String>;
+}
+
+impl LockService for ConsulClient {
+    fn create_session(&self, name: &str) -> Result<String, String> {
+        self.session_create(&SessionRequest::new(name, SESSION_TTL_SECS, SESSION_LOCK_DELAY_SECS))
+    }
+
+    fn renew_session(&self, session_id: &str) -> Result<bool, String> {
+        self.session_renew(session_id)
+    }
+
+    fn acquire_lock(&self, key: &str, value: &str, session_id: &str) -> Result<bool, String> {
+        self.kv_acquire(key, value, session_id)
+    }
+}
+
+/// Shared view of this node's leadership, cheap to clone into handlers and
+/// background threads that should only act on the leader.
+#[derive(Clone, Debug, Default)]
+pub struct Leadership {
+    is_leader: Arc<AtomicBool>,
+    session_id: Arc<Mutex<Option<String>>>,
+}
+
+impl Leadership {
+    pub fn new() -> Leadership {
+        Leadership::default()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    pub fn session_id(&self) -> Option<String> {
+        self.session_id.lock().ok().and_then(|session_id| session_id.clone())
+    }
+
+    fn update(&self, is_leader: bool, session_id: Option<String>) {
+        self.is_leader.store(is_leader, Ordering::SeqCst);
+        if let Ok(mut current) = self.session_id.lock() {
+            *current = session_id;
+        }
+    }
+}
+
+pub struct Elector<T: LockService> {
+    service: T,
+    key: String,
+    candidate: String,
+    session_id: Option<String>,
+    leadership: Leadership,
+}
+
+impl<T: LockService> Elector<T> {
+    pub fn new(service: T, key: &str, candidate: &str, leadership: Leadership) -> Elector<T> {
+        Elector {
+            service: service,
+            key: key.to_owned(),
+            candidate: candidate.to_owned(),
+            session_id: None,
+            leadership: leadership,
+        }
+    }
+
+    /// Runs one round of the election: keeps the session alive (creating a new one
+    /// if it expired) and tries to take the leader lock. Any error with Consul means
+    /// we can't prove we still hold the lock, so the node steps down.
+    pub fn step(&mut self) -> bool {
+        let was_leader = self.leadership.is_leader();
+        let is_leader = match self.try_acquire() {
+            Ok(acquired) => acquired,
+            Err(msg) => {
+                warn!("Leader election failed for [{}] - {}", self.candidate, msg);
+                false
+            }
+        };
+        if is_leader != was_leader {
+            if is_leader {
+                info!("[{}] is now the cluster leader", self.candidate);
+            } else {
+                info!("[{}] is no longer the cluster leader", self.candidate);
+            }
+        }
+        self.leadership.update(is_leader, self.session_id.clone());
+        is_leader
+    }
+
+    fn try_acquire(&mut self) -> Result<bool, String> {
+        if let Some(session_id) = self.session_id.clone() {
+            if !self.service.renew_session(&session_id)? {
+                debug!("Session [{}] expired, creating a new one", session_id);
+                self.session_id = None;
+            }
+        }
+        let session_id = match self.session_id.clone() {
+            Some(session_id) => session_id,
+            None => {
+                let session_id = self.service.create_session(&self.candidate)?;
+                self.session_id = Some(session_id.clone());
+                session_id
+            }
+        };
+        self.service.acquire_lock(&self.key, &self.candidate, &session_id)
+    }
+}
+
+pub fn spawn_leader_election<T: 'static + LockService + Send>(elector: Elector<T>) -> JoinHandle<()> {
+    let mut elector = elector;
+    thread::spawn(move || {
+        loop {
+            elector.step();
+            thread::sleep(Duration::from_secs(ELECTION_INTERVAL_SECS));
+        }
+    })
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::cell::RefCell;
+
+// Single lock shared by every candidate, sessions expire when `expire` is called
+#[derive(Debug, Default)]
+struct LockServiceMock {
+    holder: RefCell<Option<String>>,
+    live_sessions: RefCell<Vec<String>>,
+    created: RefCell<usize>,
+    unavailable: RefCell<bool>,
+}
+
+impl LockServiceMock {
+    fn expire(&self, session_id: &str) {
+        self.live_sessions.borrow_mut().retain(|id| id != session_id);
+        let mut holder = self.holder.borrow_mut();
+        if holder.as_ref().map(|id| id == session_id).unwrap_or(false) {
+            *holder = None;
+        }
+    }
+
+    fn check_available(&self) -> Result<(), String> {
+        if *self.unavailable.borrow() { Err("consul unavailable".to_string()) } else { Ok(()) }
+    }
+}
+
+impl<'a> LockService for &'a LockServiceMock {
+    fn create_session(&self, name: &str) -> Result<String, String> {
+        self.check_available()?;
+        *self.created.borrow_mut() += 1;
+        let session_id = format!("{}-{}", name, self.created.borrow());
+        self.live_sessions.borrow_mut().push(session_id.clone());
+        Ok(session_id)
+    }
+
+    fn renew_session(&self, session_id: &str) -> Result<bool, String> {
+        self.check_available()?;
+        Ok(self.live_sessions.borrow().iter().any(|id| id == session_id))
+    }
+
+    fn acquire_lock(&self, _: &str, _: &str, session_id: &str) -> Result<bool, String> {
+        self.check_available()?;
+        let mut holder = self.holder.borrow_mut();
+        match holder.clone() {
+            Some(ref current) => Ok(current == session_id),
+            None => {
+                *holder = Some(session_id.to_owned());
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[test]
+fn leadership_defaults_to_follower() {
+    let leadership = Leadership::new();
+
+    assert_eq!(false, leadership.is_leader());
+    assert_eq!(None, leadership.session_id());
+}
+
+#[test]
+fn single_candidate_becomes_leader() {
+    let service = LockServiceMock::default();
+    let leadership = Leadership::new();
+    let mut elector = Elector::new(&service, LEADER_KEY, "node_a", leadership.clone());
+
+    assert!(elector.step());
+    assert!(leadership.is_leader());
+    assert_eq!(Some("node_a-1".to_string()), leadership.session_id());
+
+    assert!(elector.step());
+    assert_eq!(1, *service.created.borrow());
+}
+
+#[test]
+fn only_one_candidate_leads() {
+    let service = LockServiceMock::default();
+    let leadership_a = Leadership::new();
+    let leadership_b = Leadership::new();
+    let mut elector_a = Elector::new(&service, LEADER_KEY, "node_a", leadership_a.clone());
+    let mut elector_b = Elector::new(&service, LEADER_KEY, "node_b", leadership_b.clone());
+
+    elector_a.step();
+    elector_b.step();
+
+    assert!(leadership_a.is_leader());
+    assert_eq!(false, leadership_b.is_leader());
+}
+
+#[test]
+fn follower_takes_over_when_leader_session_expires() {
+    let service = LockServiceMock::default();
+    let leadership_a = Leadership::new();
+    let leadership_b = Leadership::new();
+    let mut elector_a = Elector::new(&service, LEADER_KEY, "node_a", leadership_a.clone());
+    let mut elector_b = Elector::new(&service, LEADER_KEY, "node_b", leadership_b.clone());
+    elector_a.step();
+    elector_b.step();
+
+    service.expire(&leadership_a.session_id().unwrap());
+    elector_b.step();
+    elector_a.step();
+
+    assert!(leadership_b.is_leader());
+    assert_eq!(false, leadership_a.is_leader());
+    assert_eq!(Some("node_a-3".to_string()), leadership_a.session_id());
+}
+
+#[test]
+fn leader_steps_down_when_consul_unavailable() {
+    let service = LockServiceMock::default();
+    let leadership = Leadership::new();
+    let mut elector = Elector::new(&service, LEADER_KEY, "node_a", leadership.clone());
+    elector.step();
+
+    *service.unavailable.borrow_mut() = true;
+
+    assert_eq!(false, elector.step());
+    assert_eq!(false, leadership.is_leader());
+}
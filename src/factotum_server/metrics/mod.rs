@@ -0,0 +1,345 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use iron::prelude::*;
+use iron::middleware::{AfterMiddleware, BeforeMiddleware};
+use iron::typemap::Key;
+use router::{Params, Router};
+
+use factotum_server::persistence::{JobOutcome, PersistenceError};
+use factotum_server::responder::DispatcherStatus;
+
+#[cfg(test)]
+mod tests;
+
+pub const PROMETHEUS_CONTENT_TYPE: &'static str = "text/plain; version=0.0.4; charset=utf-8";
+
+// requests that never reached a route (unknown paths, failed authentication) share
+// one label, so scanners can't blow up the series count
+const UNMATCHED_ROUTE: &'static str = "unmatched";
+
+lazy_static! {
+    /// Process-wide registry, scraped from `/metrics`.
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+impl MetricKind {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram(_) => "histogram",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+}
+
+pub const JOBS_SUBMITTED: Metric = Metric {
+    name: "factotum_jobs_submitted_total",
+    help: "Job requests accepted by this server.",
+    kind: MetricKind::Counter,
+};
+
+pub const JOBS_FINISHED: Metric = Metric {
+    name: "factotum_jobs_finished_total",
+    help: "Jobs run by this server, by outcome.",
+    kind: MetricKind::Counter,
+};
+
+pub const JOB_DURATION: Metric = Metric {
+    name: "factotum_job_duration_seconds",
+    help: "Time taken to run a job, by outcome.",
+    kind: MetricKind::Histogram(&[1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 21600.0]),
+};
+
+pub const VALIDATION_FAILURES: Metric = Metric {
+    name: "factotum_job_validation_failures_total",
+    help: "Job requests rejected by validation, including failed dry runs.",
+    kind: MetricKind::Counter,
+};
+
+pub const PERSISTENCE_ERRORS: Metric = Metric {
+    name: "factotum_persistence_errors_total",
+    help: "Errors reading or writing job entries in Consul, by kind.",
+    kind: MetricKind::Counter,
+};
+
+pub const HTTP_REQUEST_DURATION: Metric = Metric {
+    name: "factotum_http_request_duration_seconds",
+    help: "Time taken to answer an HTTP request, by route.",
+    kind: MetricKind::Histogram(&[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+};
+
+pub const QUEUE_DEPTH: Metric = Metric {
+    name: "factotum_queue_depth",
+    help: "Jobs waiting for a worker.",
+    kind: MetricKind::Gauge,
+};
+
+pub const QUEUE_MAX_SIZE: Metric = Metric {
+    name: "factotum_queue_max_size",
+    help: "Jobs that can wait for a worker before submissions are turned away.",
+    kind: MetricKind::Gauge,
+};
+
+pub const WORKERS: Metric = Metric {
+    name: "factotum_workers",
+    help: "Workers in the pool, by state.",
+    kind: MetricKind::Gauge,
+};
+
+pub const PENDING_WRITES: Metric = Metric {
+    name: "factotum_persistence_pending_writes",
+    help: "Job entry updates waiting to be written to Consul.",
+    kind: MetricKind::Gauge,
+};
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug)]
+enum Series {
+    Value(f64),
+    Histogram { bucket_counts: Vec<u64>, sum: f64, count: u64 },
+}
+
+impl Series {
+    fn new(kind: &MetricKind) -> Series {
+        match *kind {
+            MetricKind::Histogram(buckets) => Series::Histogram { bucket_counts: vec![0; buckets.len()], sum: 0.0, count: 0 },
+            _ => Series::Value(0.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: MetricKind,
+    series: BTreeMap<Labels, Series>,
+}
+
+/// Counters, gauges and histograms rendered in the Prometheus text format.
+/// Families appear once they have a first sample.
+#[derive(Debug)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            families: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, metric: &Metric, labels: &[(&'static str, &str)]) {
+        self.update(metric, labels, |series| if let Series::Value(ref mut value) = *series { *value += 1.0 });
+    }
+
+    pub fn set(&self, metric: &Metric, labels: &[(&'static str, &str)], new_value: f64) {
+        self.update(metric, labels, |series| if let Series::Value(ref mut value) = *series { *value = new_value });
+    }
+
+    pub fn observe(&self, metric: &Metric, labels: &[(&'static str, &str)], sample: f64) {
+        let buckets = match metric.kind {
+            MetricKind::Histogram(buckets) => buckets,
+            _ => return,
+        };
+        self.update(metric, labels, |series| {
+            if let Series::Histogram { ref mut bucket_counts, ref mut sum, ref mut count } = *series {
+                for (bucket_count, upper_bound) in bucket_counts.iter_mut().zip(buckets.iter()) {
+                    if sample <= *upper_bound {
+                        *bucket_count += 1;
+                    }
+                }
+                *sum += sample;
+                *count += 1;
+            }
+        });
+    }
+
+    fn update<F: FnOnce(&mut Series)>(&self, metric: &Metric, labels: &[(&'static str, &str)], apply: F) {
+        // a panic elsewhere shouldn't stop metrics being recorded
+        let mut families = match self.families.lock() {
+            Ok(families) => families,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let family = families.entry(metric.name).or_insert_with(|| Family { help: metric.help, kind: metric.kind, series: BTreeMap::new() });
+        let labels = labels.iter().map(|&(name, value)| (name, value.to_string())).collect();
+        apply(family.series.entry(labels).or_insert_with(|| Series::new(&metric.kind)));
+    }
+
+    pub fn job_submitted(&self, job_name: &str) {
+        self.inc(&JOBS_SUBMITTED, &[("job_name", job_name)]);
+    }
+
+    pub fn job_finished(&self, job_name: &str, outcome: &JobOutcome, duration: Duration) {
+        let outcome = outcome.to_string().to_lowercase();
+        let labels = [("job_name", job_name), ("outcome", outcome.as_str())];
+        self.inc(&JOBS_FINISHED, &labels);
+        self.observe(&JOB_DURATION, &labels, as_seconds(duration));
+    }
+
+    // unlabelled, as a rejected request's job name is whatever the client sent
+    pub fn validation_failed(&self) {
+        self.inc(&VALIDATION_FAILURES, &[]);
+    }
+
+    pub fn persistence_error(&self, error: &PersistenceError) {
+        self.inc(&PERSISTENCE_ERRORS, &[("kind", get_persistence_error_kind(error))]);
+    }
+
+    pub fn http_request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        let status = status.to_string();
+        self.observe(&HTTP_REQUEST_DURATION, &[("method", method), ("route", route), ("status", status.as_str())], as_seconds(duration));
+    }
+
+    pub fn dispatcher_status(&self, status: &DispatcherStatus) {
+        self.set(&QUEUE_DEPTH, &[], status.jobs.in_queue as f64);
+        self.set(&QUEUE_MAX_SIZE, &[], status.jobs.max_queue_size as f64);
+        self.set(&WORKERS, &[("state", "active")], status.workers.active as f64);
+        self.set(&WORKERS, &[("state", "idle")], status.workers.idle as f64);
+        self.set(&PENDING_WRITES, &[], status.persistence.pending_writes as f64);
+    }
+
+    pub fn render(&self) -> String {
+        let families = match self.families.lock() {
+            Ok(families) => families,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut output = String::new();
+        for (name, family) in families.iter() {
+            writeln!(output, "# HELP {} {}", name, family.help).expect("String write error");
+            writeln!(output, "# TYPE {} {}", name, family.kind.type_name()).expect("String write error");
+            for (labels, series) in family.series.iter() {
+                match (series, &family.kind) {
+                    (&Series::Value(value), _) => {
+                        writeln!(output, "{}{} {}", name, format_labels(labels, None), value).expect("String write error");
+                    },
+                    (&Series::Histogram { ref bucket_counts, sum, count }, &MetricKind::Histogram(buckets)) => {
+                        for (bucket_count, upper_bound) in bucket_counts.iter().zip(buckets.iter()) {
+                            writeln!(output, "{}_bucket{} {}", name, format_labels(labels, Some(&upper_bound.to_string())), bucket_count).expect("String write error");
+                        }
+                        writeln!(output, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), count).expect("String write error");
+                        writeln!(output, "{}_sum{} {}", name, format_labels(labels, None), sum).expect("String write error");
+                        writeln!(output, "{}_count{} {}", name, format_labels(labels, None), count).expect("String write error");
+                    },
+                    _ => {},
+                }
+            }
+        }
+        output
+    }
+}
+
+pub fn get_persistence_error_kind(error: &PersistenceError) -> &'static str {
+    match *error {
+        PersistenceError::Unavailable(_) => "unavailable",
+        PersistenceError::NotFound(_) => "not_found",
+        PersistenceError::Corrupt(_) => "corrupt",
+        PersistenceError::Conflict(_) => "conflict",
+    }
+}
+
+fn format_labels(labels: &Labels, upper_bound: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter().map(|&(name, ref value)| format!("{}=\"{}\"", name, escape_label_value(value))).collect();
+    if let Some(upper_bound) = upper_bound {
+        pairs.push(format!("le=\"{}\"", upper_bound));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+fn as_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Turns a request path back into the route it matched, e.g. `/jobs/abc/wait`
+/// into `/jobs/:id/wait`, so latency is recorded per route rather than per job.
+pub fn get_route_label(path: &[&str], params: Option<&Params>) -> String {
+    let params = match params {
+        Some(params) => params,
+        None => return UNMATCHED_ROUTE.to_string(),
+    };
+    let segments: Vec<String> = path.iter()
+        .map(|segment| match params.iter().find(|&(_, value)| value == *segment) {
+            Some((name, _)) => format!(":{}", name),
+            None => segment.to_string(),
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+#[derive(Debug, Copy, Clone)]
+struct RequestStart;
+impl Key for RequestStart {
+    type Value = Instant;
+}
+
+/// Times every request, including those turned away by earlier middleware.
+pub struct RequestTimer;
+
+impl RequestTimer {
+    fn record(request: &mut Request, status: u16) {
+        let duration = match request.extensions.get::<RequestStart>() {
+            Some(start) => start.elapsed(),
+            None => return,
+        };
+        let route = get_route_label(&request.url.path(), request.extensions.get::<Router>());
+        METRICS.http_request(&request.method.to_string(), &route, status, duration);
+    }
+}
+
+impl BeforeMiddleware for RequestTimer {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        request.extensions.insert::<RequestStart>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestTimer {
+    fn after(&self, request: &mut Request, response: Response) -> IronResult<Response> {
+        RequestTimer::record(request, response.status.map(|status| status.to_u16()).unwrap_or(200));
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, err: IronError) -> IronResult<Response> {
+        RequestTimer::record(request, err.response.status.map(|status| status.to_u16()).unwrap_or(500));
+        Err(err)
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::time::Duration;
+use router::Params;
+use factotum_server::metrics::*;
+use factotum_server::persistence::{JobOutcome, PersistenceError};
+use factotum_server::responder::{DispatcherStatus, JobStatus, PersistenceStatus, WorkerStatus};
+
+#[test]
+fn render_empty_registry() {
+    let metrics = Metrics::new();
+    assert_eq!(metrics.render(), "");
+}
+
+#[test]
+fn render_counters_by_label() {
+    let metrics = Metrics::new();
+    metrics.job_submitted("com.acme-main");
+    metrics.job_submitted("com.acme-main");
+    metrics.job_submitted("com.acme-\"quoted\"");
+    metrics.validation_failed();
+
+    let expected = "# HELP factotum_job_validation_failures_total Job requests rejected by validation, including failed dry runs.\n\
+                    # TYPE factotum_job_validation_failures_total counter\n\
+                    factotum_job_validation_failures_total 1\n\
+                    # HELP factotum_jobs_submitted_total Job requests accepted by this server.\n\
+                    # TYPE factotum_jobs_submitted_total counter\n\
+                    factotum_jobs_submitted_total{job_name=\"com.acme-\\\"quoted\\\"\"} 1\n\
+                    factotum_jobs_submitted_total{job_name=\"com.acme-main\"} 2\n";
+    assert_eq!(metrics.render(), expected);
+}
+
+#[test]
+fn render_job_duration_histogram() {
+    let metrics = Metrics::new();
+    metrics.job_finished("com.acme-main", &JobOutcome::SUCCEEDED, Duration::from_millis(2500));
+    metrics.job_finished("com.acme-main", &JobOutcome::SUCCEEDED, Duration::from_secs(45));
+    metrics.job_finished("com.acme-main", &JobOutcome::FAILED, Duration::from_secs(100000));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("# TYPE factotum_job_duration_seconds histogram\n"));
+    assert!(rendered.contains("factotum_jobs_finished_total{job_name=\"com.acme-main\",outcome=\"succeeded\"} 2\n"));
+    assert!(rendered.contains("factotum_jobs_finished_total{job_name=\"com.acme-main\",outcome=\"failed\"} 1\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"succeeded\",le=\"1\"} 0\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"succeeded\",le=\"5\"} 1\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"succeeded\",le=\"60\"} 2\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"succeeded\",le=\"+Inf\"} 2\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_sum{job_name=\"com.acme-main\",outcome=\"succeeded\"} 47.5\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_count{job_name=\"com.acme-main\",outcome=\"succeeded\"} 2\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"failed\",le=\"21600\"} 0\n"));
+    assert!(rendered.contains("factotum_job_duration_seconds_bucket{job_name=\"com.acme-main\",outcome=\"failed\",le=\"+Inf\"} 1\n"));
+}
+
+#[test]
+fn render_dispatcher_status_gauges() {
+    let metrics = Metrics::new();
+    let status = DispatcherStatus {
+        workers: WorkerStatus { total: 4, idle: 3, active: 1 },
+        jobs: JobStatus { max_queue_size: 10, in_queue: 2 },
+        persistence: PersistenceStatus { pending_writes: 5 },
+    };
+    metrics.dispatcher_status(&status);
+    metrics.persistence_error(&PersistenceError::Unavailable("connection refused".to_string()));
+    metrics.persistence_error(&PersistenceError::Conflict("modified".to_string()));
+    metrics.persistence_error(&PersistenceError::Unavailable("connection refused".to_string()));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("# TYPE factotum_queue_depth gauge\nfactotum_queue_depth 2\n"));
+    assert!(rendered.contains("factotum_queue_max_size 10\n"));
+    assert!(rendered.contains("factotum_workers{state=\"active\"} 1\nfactotum_workers{state=\"idle\"} 3\n"));
+    assert!(rendered.contains("factotum_persistence_pending_writes 5\n"));
+    assert!(rendered.contains("factotum_persistence_errors_total{kind=\"conflict\"} 1\nfactotum_persistence_errors_total{kind=\"unavailable\"} 2\n"));
+}
+
+#[test]
+fn render_http_request_latency() {
+    let metrics = Metrics::new();
+    metrics.http_request("GET", "/jobs/:id/wait", 200, Duration::from_millis(20));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("factotum_http_request_duration_seconds_bucket{method=\"GET\",route=\"/jobs/:id/wait\",status=\"200\",le=\"0.01\"} 0\n"));
+    assert!(rendered.contains("factotum_http_request_duration_seconds_bucket{method=\"GET\",route=\"/jobs/:id/wait\",status=\"200\",le=\"0.025\"} 1\n"));
+    assert!(rendered.contains("factotum_http_request_duration_seconds_count{method=\"GET\",route=\"/jobs/:id/wait\",status=\"200\"} 1\n"));
+}
+
+#[test]
+fn route_label_replaces_params() {
+    let mut params = Params::new();
+    params.insert("id".to_string(), "abc123".to_string());
+    assert_eq!(get_route_label(&["jobs", "abc123", "wait"], Some(&params)), "/jobs/:id/wait");
+    assert_eq!(get_route_label(&["status"], Some(&Params::new())), "/status");
+    assert_eq!(get_route_label(&[""], Some(&Params::new())), "/");
+}
+
+#[test]
+fn route_label_unmatched() {
+    assert_eq!(get_route_label(&["wp-admin", "login.php"], None), "unmatched");
+}
@@ -14,22 +14,39 @@
 
 #[macro_use]
 pub mod command;
+pub mod auth;
+pub mod catalog;
+pub mod consul;
 pub mod server;
 pub mod dispatcher;
+pub mod events;
+pub mod factfiles;
+pub mod janitor;
+pub mod leader;
+pub mod metrics;
+pub mod openapi;
 pub mod persistence;
 pub mod responder;
+pub mod tls;
+pub mod webhook;
+pub mod writer;
 
 #[cfg(test)]
 mod tests;
 
 use std::collections::VecDeque;
+use std::env;
 use std::net::SocketAddr;
+use std::process;
 use std::str::FromStr;
 use std::sync::{Mutex, RwLock};
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Duration as ChronoDuration, UTC};
+use ctrlc;
 use iron::prelude::*;
 use iron::typemap::Key;
 use logger::Logger;
@@ -37,11 +54,31 @@ use persistent::{Read, State};
 use threadpool::ThreadPool;
 
 use Args;
+use factotum_server::auth::{Authenticator, Role, TokenStore};
 use factotum_server::command::{CommandStore, Execution};
-use factotum_server::dispatcher::{Dispatch, Dispatcher, Query};
-use factotum_server::persistence::{Persistence, ConsulPersistence, JobState, JobOutcome};
-use factotum_server::responder::{DispatcherStatus, JobStatus, WorkerStatus};
+use factotum_server::consul::{ConsulClient, ConsulSecurity, HealthCheckKind, ServiceRegistration};
+use factotum_server::dispatcher::{ClusterQueue, Dispatch, Dispatcher, Query};
+use factotum_server::events::{Event, EVENTS};
+use factotum_server::factfiles::FactfileStore;
+use factotum_server::janitor::RetentionPolicy;
+use factotum_server::leader::{Elector, Leadership};
+use factotum_server::metrics::{METRICS, RequestTimer};
+use factotum_server::persistence::{Persistence, PersistenceError, ConsulPersistence, JobState, JobOutcome, JobResult, StoredJobEntry};
+use factotum_server::responder::{DispatcherStatus, JobStatus, PersistenceStatus, WorkerStatus};
 use factotum_server::server::{ServerManager, JobRequest};
+use factotum_server::tls::{TlsConfig, TlsServer};
+use factotum_server::webhook::WebhookConfig;
+use factotum_server::writer::{EntrySwap, EntryUpdate, PersistenceWriter};
+
+// how often an idle server checks the shared queue for work submitted to its peers
+const CLUSTER_POLL_INTERVAL_SECS: u64 = 5;
+// a server rewrites the entries of the jobs it is running this often, so peers can
+// tell them from jobs left WORKING by a server that died
+const CLUSTER_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+const CLUSTER_ORPHAN_TIMEOUT_SECS: i64 = 120;
+// a lost DONE update leaves the job to be run again once it looks orphaned, so it
+// is retried a few times while Consul is unavailable
+const CLUSTER_DONE_ATTEMPTS: u32 = 5;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Server;
@@ -67,60 +104,237 @@ impl Key for Updates {
     type Value = Mutex<Sender<Dispatch>>;
 }
 
+#[derive(Debug, Copy, Clone)]
+pub struct Retention;
+impl Key for Retention {
+    type Value = RetentionPolicy;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Leader;
+impl Key for Leader {
+    type Value = Leadership;
+}
+
 pub fn start(args: Args) -> Result<(), String> {
-    let server = ServerManager::new(args.flag_ip, args.flag_port, args.flag_webhook, args.flag_no_colour, args.flag_max_stdouterr_size);
-    let persistence = ConsulPersistence::new(args.flag_consul_name, args.flag_consul_ip, args.flag_consul_port, args.flag_consul_namespace);
-    let dispatcher = Dispatcher::new(args.flag_max_jobs, args.flag_max_workers);
+    let authenticator = match args.flag_auth_tokens {
+        Some(ref path) => {
+            let token_store = TokenStore::from_file(path)?;
+            info!("API authentication enabled with {} tokens from [{}]", token_store.len(), path);
+            Some(Authenticator::new(token_store))
+        },
+        None => {
+            warn!("API authentication disabled - anyone who can reach the server can submit jobs");
+            None
+        },
+    };
+    let tls_server = match (args.flag_tls_cert, args.flag_tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_server = TlsServer::new(TlsConfig::new(&cert_path, &key_path, args.flag_tls_client_ca))?;
+            info!("TLS enabled with certificate [{}]{}", cert_path, if tls_server.config().is_mutual() { ", client certificates required" } else { "" });
+            Some(tls_server)
+        },
+        _ => None,
+    };
+    let webhook_config = WebhookConfig::new(args.flag_callback_secret_file, args.flag_callback_dead_letter_log)?;
+    let mut server = ServerManager::new(args.flag_ip, args.flag_port, args.flag_webhook, args.flag_no_colour, args.flag_max_stdouterr_size);
+    server.callbacks_enabled = webhook_config.callback_secret.is_some();
+    server.factfile_store = FactfileStore::new(args.flag_factfile_store);
+    server.factfile_roots = factfiles::get_factfile_roots(args.flag_factfile_root)?;
+    if server.factfile_roots.is_empty() {
+        warn!("No --factfile-root given - jobs can be submitted with any factfile path on this host");
+    }
+    let consul_token = get_consul_token(args.flag_consul_token, env::var(::CONSUL_TOKEN_ENV).ok());
+    let consul_security = ConsulSecurity::new(consul_token, args.flag_consul_https, args.flag_consul_ca_cert, args.flag_consul_client_cert, args.flag_consul_client_key);
+    let persistence = ConsulPersistence::new(args.flag_consul_name, args.flag_consul_ip, args.flag_consul_port, args.flag_consul_namespace, consul_security);
+    let dispatcher = Dispatcher::new(args.flag_max_jobs, args.flag_max_workers, args.flag_cluster_mode);
+    let retention_policy = RetentionPolicy::new(args.flag_retention_max_age, args.flag_retention_max_count, args.flag_retention_interval);
+    retention_policy.validate()?;
     let command_store = commands![::FACTOTUM.to_string() => args.flag_factotum_bin];
+    let service_name = args.flag_consul_service_name.unwrap_or(::CONSUL_SERVICE_NAME_DEFAULT.to_string());
+    let registration = ServiceRegistration::new(&service_name, &consul::get_node_name(), get_service_tags(&args.flag_consul_service_tags), &server.ip, server.port, get_health_check_kind(tls_server.as_ref()));
+    let consul_client = persistence.client();
+    let leadership = Leadership::new();
     
     let address = SocketAddr::from_str(&format!("{}:{}", server.ip, server.port)).expect("Failed to parse socket address");
 
-    let (requests_channel, _, _) = trigger_worker_manager(dispatcher, persistence.clone(), &command_store).expect("Failed to start up worker manager thread");
+    let (requests_channel, _, _) = trigger_worker_manager(dispatcher, persistence.clone(), &command_store, server.factfile_store.clone()).expect("Failed to start up worker manager thread");
+
+    // a restarted server must not be mistaken for the one that held the lock before it
+    let candidate = format!("{}-{}-{}", registration.id, process::id(), UTC::now().timestamp());
+    let elector = Elector::new(persistence.client(), &persistence.prepend_namespace(leader::LEADER_KEY), &candidate, leadership.clone());
+    leader::spawn_leader_election(elector);
+
+    let (callbacks_channel, _) = webhook::spawn_webhook_sender(persistence.clone(), webhook_config);
+    EVENTS.add_sink(callbacks_channel);
+
+    if retention_policy.is_enabled() {
+        janitor::spawn_janitor(persistence.clone(), retention_policy.clone(), leadership.clone(), server.factfile_store.clone());
+    }
 
     let router = router!(
-        index:      get     "/"         =>  responder::api,
-        help:       get     "/help"     =>  responder::api,
-        status:     get     "/status"   =>  responder::status,
-        settings:   post    "/settings" =>  responder::settings,
-        submit:     post    "/submit"   =>  responder::submit,
-        check:      get     "/check"    =>  responder::check
+        index:      get     "/"         =>  auth::require(Role::Viewer, responder::api),
+        help:       get     "/help"     =>  auth::require(Role::Viewer, responder::api),
+        status:     get     "/status"   =>  auth::require(Role::Viewer, responder::status),
+        settings:   post    "/settings" =>  auth::require(Role::Admin, responder::settings),
+        submit:     post    "/submit"   =>  auth::require(Role::Submitter, responder::submit),
+        validate:   post    "/validate" =>  auth::require(Role::Submitter, responder::validate),
+        check:      get     "/check"    =>  auth::require(Role::Viewer, responder::check),
+        wait:       get     "/jobs/:id/wait" => auth::require(Role::Viewer, responder::wait),
+        health:     get     "/healthz"  =>  responder::health,
+        ready:      get     "/readyz"   =>  responder::ready,
+        openapi:    get     "/openapi.json" => auth::require(Role::Viewer, responder::openapi),
+        retention:  get     "/retention" => auth::require(Role::Viewer, responder::retention),
+        metrics:    get     "/metrics"  =>  auth::require(Role::Viewer, responder::metrics),
+        events:     get     "/events"   =>  auth::require(Role::Viewer, responder::events),
+        subscribe:  post    "/subscriptions" => auth::require(Role::Admin, responder::create_subscription),
+        subscriptions: get  "/subscriptions" => auth::require(Role::Admin, responder::list_subscriptions),
+        unsubscribe: delete "/subscriptions/:id" => auth::require(Role::Admin, responder::delete_subscription),
+        register:   post    "/catalog"  =>  auth::require(Role::Admin, responder::register_catalog_job),
+        catalog:    get     "/catalog"  =>  auth::require(Role::Viewer, responder::list_catalog_jobs),
+        deregister: delete  "/catalog/:name" => auth::require(Role::Admin, responder::delete_catalog_job),
+        run:        post    "/catalog/:name/run" => auth::require(Role::Submitter, responder::run_catalog_job),
+        v2_submit:  post    "/api/v2/jobs" => auth::require(Role::Submitter, responder::v2::submit_job),
+        v2_job:     get     "/api/v2/jobs/:id" => auth::require(Role::Viewer, responder::v2::get_job),
+        v2_server:  get     "/api/v2/server" => auth::require(Role::Viewer, responder::v2::get_server)
     );
     let (logger_before, logger_after) = Logger::new(None);
 
     let mut chain = Chain::new(router);
     chain.link_before(logger_before);
+    chain.link_before(RequestTimer);
+    if let Some(authenticator) = authenticator {
+        chain.link_before(authenticator);
+    }
     chain.link(State::<Server>::both(server));
     chain.link(State::<Storage>::both(persistence));
     chain.link(Read::<Paths>::both(RwLock::new(command_store)));
     chain.link(Read::<Updates>::both(Mutex::new(requests_channel)));
+    chain.link(Read::<Retention>::both(retention_policy));
+    chain.link(Read::<Leader>::both(leadership.clone()));
+    chain.link_after(RequestTimer);
     chain.link_after(logger_after);
     
-    match Iron::new(chain).http(address) {
+    let (listener, scheme) = match tls_server {
+        Some(tls_server) => {
+            tls::spawn_tls_reloader(tls_server.clone());
+            (Iron::new(chain).https(address, tls_server), "https")
+        },
+        None => (Iron::new(chain).http(address), "http"),
+    };
+    match listener {
         Ok(listening) => {
             let socket_addr = listening.socket;
             let ip = socket_addr.ip();
             let port = socket_addr.port();
-            let start_message = format!("Factotum Server version [{}] listening on [{}:{}]", ::VERSION, ip, port);
+            let start_message = format!("Factotum Server version [{}] listening on [{}://{}:{}]", ::VERSION, scheme, ip, port);
             info!("{}", start_message);
             println!("{}", start_message);
+            let service_id = register_service(&consul_client, registration);
+            set_shutdown_handler(consul_client, service_id, leadership);
             Ok(())
         }
         Err(e) => Err(format!("Failed to start server - {}", e))
     }
 }
 
+// Service discovery
+
+fn get_health_check_kind(tls_server: Option<&TlsServer>) -> HealthCheckKind {
+    match tls_server {
+        Some(tls_server) if tls_server.config().is_mutual() => HealthCheckKind::Tcp,
+        Some(_) => HealthCheckKind::Https,
+        None => HealthCheckKind::Http,
+    }
+}
+
+fn register_service(client: &ConsulClient, registration: ServiceRegistration) -> Option<String> {
+    match client.agent_service_register(&registration) {
+        Ok(_) => {
+            info!("Registered Consul service [{}]", registration.id);
+            Some(registration.id)
+        },
+        Err(msg) => {
+            warn!("{}", msg);
+            None
+        }
+    }
+}
+
+// Deregisters the service and gives up leadership straight away on shutdown,
+// rather than leaving the cluster to wait for the health check and session TTL
+fn set_shutdown_handler(client: ConsulClient, service_id: Option<String>, leadership: Leadership) {
+    let handler = ctrlc::set_handler(move || {
+        if let Some(ref service_id) = service_id {
+            match client.agent_service_deregister(service_id) {
+                Ok(_) => info!("Deregistered Consul service [{}]", service_id),
+                Err(msg) => error!("{}", msg),
+            };
+        }
+        if let Some(session_id) = leadership.session_id() {
+            match client.session_destroy(&session_id) {
+                Ok(_) => info!("Released leader session [{}]", session_id),
+                Err(msg) => error!("{}", msg),
+            };
+        }
+        process::exit(0);
+    });
+    if let Err(e) = handler {
+        warn!("Failed to set shutdown handler - {}", e);
+    }
+}
+
+fn get_consul_token(flag_token: Option<String>, env_token: Option<String>) -> Option<String> {
+    flag_token.or(env_token).and_then(|token| if token.is_empty() { None } else { Some(token) })
+}
+
+fn get_service_tags(wrapped_tags: &Option<String>) -> Vec<String> {
+    match wrapped_tags.as_ref() {
+        Some(tags) => tags.split(",")
+                          .map(|tag| tag.trim().to_string())
+                          .filter(|tag| !tag.is_empty())
+                          .collect(),
+        None => vec![],
+    }
+}
+
 // Concurrent dispatch
 
-pub fn trigger_worker_manager<T: 'static + Clone + Persistence + Send>(dispatcher: Dispatcher, persistence: T, command_store: &CommandStore) -> Result<(Sender<Dispatch>, JoinHandle<()>, ThreadPool), String> {
+// What the worker manager hands each job besides the request itself; the cluster
+// queue is only set in cluster mode
+#[derive(Clone)]
+struct WorkerContext<T: Persistence> {
+    persistence: T,
+    persistence_writer: PersistenceWriter,
+    command_store: CommandStore,
+    factfile_store: FactfileStore,
+    cluster_queue: Option<ClusterQueue>,
+}
+
+pub fn trigger_worker_manager<T: 'static + Clone + Persistence + Send>(dispatcher: Dispatcher, persistence: T, command_store: &CommandStore, factfile_store: FactfileStore) -> Result<(Sender<Dispatch>, JoinHandle<()>, ThreadPool), String> {
     let (tx, rx) = mpsc::channel();
     let primary_pool = ThreadPool::new_with_name("primary_pool".to_string(), dispatcher.max_workers);
 
-    let join_handle = spawn_worker_manager(tx.clone(), rx, dispatcher.requests_queue, dispatcher.max_jobs, primary_pool.clone(), persistence, command_store.clone());
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+
+    let context = WorkerContext {
+        persistence: persistence,
+        persistence_writer: persistence_writer,
+        command_store: command_store.clone(),
+        factfile_store: factfile_store,
+        cluster_queue: if dispatcher.cluster_mode { Some(ClusterQueue::new()) } else { None },
+    };
+
+    if let Some(ref cluster_queue) = context.cluster_queue {
+        spawn_cluster_poller(tx.clone(), context.persistence.clone(), context.persistence_writer.clone(), cluster_queue.clone(), Duration::from_secs(CLUSTER_POLL_INTERVAL_SECS));
+    }
+
+    let join_handle = spawn_worker_manager(tx.clone(), rx, dispatcher.requests_queue, dispatcher.max_jobs, primary_pool.clone(), context);
 
     Ok((tx, join_handle, primary_pool))
 }
 
-fn spawn_worker_manager<T: 'static + Clone + Persistence + Send>(job_requests_tx: Sender<Dispatch>, job_requests_rx: Receiver<Dispatch>, requests_queue: VecDeque<JobRequest>, max_jobs: usize, primary_pool: ThreadPool, persistence: T, command_store: CommandStore) -> JoinHandle<()> {
+fn spawn_worker_manager<T: 'static + Clone + Persistence + Send>(job_requests_tx: Sender<Dispatch>, job_requests_rx: Receiver<Dispatch>, requests_queue: VecDeque<JobRequest>, max_jobs: usize, primary_pool: ThreadPool, context: WorkerContext<T>) -> JoinHandle<()> {
     let mut requests_queue = requests_queue;
     thread::spawn(move || {
         loop {
@@ -128,26 +342,43 @@ fn spawn_worker_manager<T: 'static + Clone + Persistence + Send>(job_requests_tx
 
             match message {
                 Dispatch::StatusUpdate(query) => {
-                    send_status_update(query, &mut requests_queue, max_jobs, &primary_pool)
+                    if let Some(ref cluster_queue) = context.cluster_queue {
+                        send_cluster_status_update(query, cluster_queue, max_jobs, &primary_pool, &context.persistence_writer)
+                    } else {
+                        send_status_update(query, &mut requests_queue, max_jobs, &primary_pool, &context.persistence_writer)
+                    }
                 },
                 Dispatch::CheckQueue(query) => {
-                    is_queue_full(query, &mut requests_queue, max_jobs)
+                    if let Some(ref cluster_queue) = context.cluster_queue {
+                        is_cluster_queue_full(query, cluster_queue, max_jobs)
+                    } else {
+                        is_queue_full(query, &mut requests_queue, max_jobs)
+                    }
                 },
                 Dispatch::NewRequest(request) => {
-                    match new_job_request(job_requests_tx.clone(), &mut requests_queue, &primary_pool, persistence.clone(), request) {
-                        Ok(..) => {},
-                        Err(msg) => info!("{}", msg),
+                    if let Some(ref cluster_queue) = context.cluster_queue {
+                        new_cluster_job_request(job_requests_tx.clone(), cluster_queue, request)
+                    } else {
+                        match new_job_request(job_requests_tx.clone(), &mut requests_queue, &primary_pool, request) {
+                            Ok(..) => {},
+                            Err(msg) => info!("{}", msg),
+                        }
                     }
                 },
                 Dispatch::ProcessRequest => {
-                    process_job_request(job_requests_tx.clone(), &mut requests_queue, &primary_pool, persistence.clone(), command_store.clone())
+                    if let Some(ref cluster_queue) = context.cluster_queue {
+                        process_cluster_job_request(job_requests_tx.clone(), &primary_pool, context.clone(), cluster_queue.clone())
+                    } else {
+                        process_job_request(job_requests_tx.clone(), &mut requests_queue, &primary_pool, context.clone())
+                    }
                 },
-                Dispatch::RequestComplete(request) => {
-                    let response = complete_job_request(job_requests_tx.clone(), persistence.clone(), request);
+                // only sent outside cluster mode, cluster jobs are finished on their workers
+                Dispatch::RequestComplete(request, result) => {
+                    let response = complete_job_request(job_requests_tx.clone(), &context.persistence_writer, request, result);
                     info!("{}", response)
                 },
-                Dispatch::RequestFailure(request) => {
-                    let response = failed_job_request(job_requests_tx.clone(), persistence.clone(), request);
+                Dispatch::RequestFailure(request, result) => {
+                    let response = failed_job_request(job_requests_tx.clone(), &context.persistence_writer, request, result);
                     error!("{}", response)
                 },
                 Dispatch::StopProcessing => {
@@ -159,13 +390,27 @@ fn spawn_worker_manager<T: 'static + Clone + Persistence + Send>(job_requests_tx
     })
 }
 
-fn send_status_update(query: Query<DispatcherStatus>, requests_queue: &mut VecDeque<JobRequest>, max_jobs: usize, primary_pool: &ThreadPool) {
+fn send_status_update(query: Query<DispatcherStatus>, requests_queue: &mut VecDeque<JobRequest>, max_jobs: usize, primary_pool: &ThreadPool, persistence_writer: &PersistenceWriter) {
+    let tx = query.status_tx;
+    let result = get_dispatcher_status(requests_queue.len(), max_jobs, primary_pool, persistence_writer);
+    send_status_reply(tx, result);
+}
+
+fn send_cluster_status_update(query: Query<DispatcherStatus>, cluster_queue: &ClusterQueue, max_jobs: usize, primary_pool: &ThreadPool, persistence_writer: &PersistenceWriter) {
     let tx = query.status_tx;
-    let result = get_dispatcher_status(requests_queue, max_jobs, primary_pool);
-    tx.send(result).expect("Server status channel receiver has been deallocated");
+    let in_queue = cluster_queue.queued_count();
+    let result = get_dispatcher_status(in_queue, max_jobs, primary_pool, persistence_writer);
+    send_status_reply(tx, result);
 }
 
-fn get_dispatcher_status(requests_queue: &mut VecDeque<JobRequest>, max_jobs: usize, primary_pool: &ThreadPool) -> DispatcherStatus {
+// probes stop waiting after a few seconds, a late reply mustn't take the worker manager down
+fn send_status_reply(tx: Sender<DispatcherStatus>, status: DispatcherStatus) {
+    if tx.send(status).is_err() {
+        warn!("Status query was abandoned before the dispatcher answered");
+    }
+}
+
+fn get_dispatcher_status(in_queue: usize, max_jobs: usize, primary_pool: &ThreadPool, persistence_writer: &PersistenceWriter) -> DispatcherStatus {
     let total_workers = primary_pool.max_count();
     let active_workers = primary_pool.active_count();
     DispatcherStatus {
@@ -176,8 +421,11 @@ fn get_dispatcher_status(requests_queue: &mut VecDeque<JobRequest>, max_jobs: us
         },
         jobs: JobStatus {
             max_queue_size: max_jobs,
-            in_queue: requests_queue.len(),
-        }
+            in_queue: in_queue,
+        },
+        persistence: PersistenceStatus {
+            pending_writes: persistence_writer.pending_writes(),
+        },
     }
 }
 
@@ -187,14 +435,18 @@ fn is_queue_full(query: Query<bool>, requests_queue: &mut VecDeque<JobRequest>,
     tx.send(is_full).expect("Queue query channel receiver has been deallocated");
 }
 
-fn new_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, requests_queue: &mut VecDeque<JobRequest>, primary_pool: &ThreadPool, persistence: T, request: JobRequest) -> Result<(), String> {
+// Answered from the last poll of the shared queue, so it can lag behind peers'
+// submissions by up to a poll interval
+fn is_cluster_queue_full(query: Query<bool>, cluster_queue: &ClusterQueue, max_jobs: usize) {
+    let tx = query.status_tx;
+    let is_full = cluster_queue.queued_count() >= max_jobs;
+    tx.send(is_full).expect("Queue query channel receiver has been deallocated");
+}
+
+fn new_job_request(requests_channel: Sender<Dispatch>, requests_queue: &mut VecDeque<JobRequest>, primary_pool: &ThreadPool, request: JobRequest) -> Result<(), String> {
     debug!("ADDING NEW JOB jobId:[{}]", request.job_id);
-    requests_queue.push_back(request.clone());
-    // Create entry in persistence storage
-    match persist_entry(&persistence, &request.job_id, &request, &JobState::QUEUED, &JobOutcome::WAITING) {
-        Ok(msg) => debug!("{}", msg),
-        Err(msg) => error!("{}", msg),
-    };
+    // QUEUED entry has already been claimed in persistence storage on submission
+    requests_queue.push_back(request);
     // Check queue size - return error if limit exceeded (not important right now)
     if primary_pool.active_count() < primary_pool.max_count() {
         requests_channel.send(Dispatch::ProcessRequest).expect("Job requests channel receiver has been deallocated");
@@ -204,46 +456,251 @@ fn new_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, requests_
     }
 }
 
-fn process_job_request<T: 'static + Persistence + Send>(requests_channel: Sender<Dispatch>, requests_queue: &mut VecDeque<JobRequest>, primary_pool: &ThreadPool, persistence: T, command_store: CommandStore) {
+fn process_job_request<T: 'static + Persistence + Send>(requests_channel: Sender<Dispatch>, requests_queue: &mut VecDeque<JobRequest>, primary_pool: &ThreadPool, context: WorkerContext<T>) {
     debug!("QUEUE SIZE = {}", requests_queue.len());
     match requests_queue.pop_front() {
         Some(request) => {
             primary_pool.execute(move || {
                 debug!("PROCESSING JOB REQ jobId:[{}]", request.job_id);
                 // Update status in persistence storage
-                match persist_entry(&persistence, &request.job_id, &request, &JobState::WORKING, &JobOutcome::RUNNING) {
+                match persist_entry(&context.persistence_writer, &request.job_id, &request, &JobState::WORKING, &JobOutcome::RUNNING, None) {
                     Ok(msg) => debug!("{}", msg),
                     Err(msg) => error!("{}", msg),
                 };
-                let cmd_path = match command_store.get_command(::FACTOTUM) {
-                    Ok(path) => path,
-                    Err(e) => {
-                        error!("{}", e);
-                        requests_channel.send(Dispatch::RequestFailure(request)).expect("Job requests channel receiver has been deallocated");
-                        return
-                    }
-                };
-                let mut cmd_args = vec!["run".to_string(), request.factfile_path.clone()];
-                cmd_args.extend_from_slice(request.factfile_args.as_slice());
-                match command_store.execute(cmd_path, cmd_args) {
-                    Ok(output) => {
-                        trace!("{}", output);
-                        requests_channel.send(Dispatch::RequestComplete(request)).expect("Job requests channel receiver has been deallocated");
-                    },
-                    Err(e) => {
-                        error!("{}", e);
-                        requests_channel.send(Dispatch::RequestFailure(request)).expect("Job requests channel receiver has been deallocated");
-                    }
+                let dispatch = match run_job(&context, &request) {
+                    Ok(result) => Dispatch::RequestComplete(request, result),
+                    Err(result) => Dispatch::RequestFailure(request, result),
                 };
+                requests_channel.send(dispatch).expect("Job requests channel receiver has been deallocated");
             });
         }
         None => debug!("QUEUE EMPTY")
     }
 }
 
-fn complete_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, persistence: T, request: JobRequest) -> String {
+// Cluster mode
+
+// Reads the shared queue from Consul on its own thread, so the worker manager never waits on it,
+// and sends the heartbeats of the jobs running here from the same thread
+fn spawn_cluster_poller<T: 'static + Persistence + Send>(requests_channel: Sender<Dispatch>, persistence: T, persistence_writer: PersistenceWriter, cluster_queue: ClusterQueue, interval: Duration) -> JoinHandle<()> {
+    let heartbeat_interval = Duration::from_secs(CLUSTER_HEARTBEAT_INTERVAL_SECS);
+    thread::spawn(move || {
+        let mut last_heartbeat = Instant::now();
+        loop {
+            thread::sleep(interval);
+            if last_heartbeat.elapsed() >= heartbeat_interval {
+                send_cluster_heartbeat(&cluster_queue, &persistence_writer);
+                last_heartbeat = Instant::now();
+            }
+            if let Err(e) = refresh_cluster_queue(&persistence, &persistence_writer, &cluster_queue, UTC::now()) {
+                error!("Could not read shared job queue - {}", e);
+            }
+            if requests_channel.send(Dispatch::ProcessRequest).is_err() {
+                debug!("Worker manager stopped, stopping shared queue poller");
+                break;
+            }
+        }
+    })
+}
+
+/// Updates the cached queue size, and puts back in the queue any WORKING entry
+/// nobody has written to for `CLUSTER_ORPHAN_TIMEOUT_SECS` - servers running a
+/// job rewrite its entry every heartbeat, so its server must have gone away.
+/// Returns how many entries were put back.
+pub fn refresh_cluster_queue<T: Persistence>(persistence: &T, persistence_writer: &PersistenceWriter, cluster_queue: &ClusterQueue, now: DateTime<UTC>) -> Result<usize, PersistenceError> {
+    let entries = persistence::get_entries(persistence)?;
+    let queued = entries.iter().filter(|stored_entry| stored_entry.entry.state == JobState::QUEUED).count();
+    let orphans: Vec<StoredJobEntry> = entries.into_iter()
+        .filter(|stored_entry| is_orphaned(stored_entry, cluster_queue, now))
+        .collect();
+
+    let mut requeued = 0;
+    for stored_entry in orphans {
+        let job_request = &stored_entry.entry.job_request;
+        match persistence_writer.swap(EntrySwap::new(&stored_entry, &JobState::QUEUED, &JobOutcome::WAITING, None)) {
+            Ok(_) => {
+                warn!("Job jobId:[{}] was left WORKING by [{}], putting it back in the shared queue", job_request.job_id, stored_entry.entry.last_run_from);
+                EVENTS.publish(Event::job(job_request, &JobState::QUEUED, &JobOutcome::WAITING));
+                requeued += 1;
+            },
+            Err(PersistenceError::Conflict(_)) => debug!("Job jobId:[{}] was updated since it was read, leaving it", job_request.job_id),
+            Err(e) => error!("Could not put job jobId:[{}] back in the shared queue - {}", job_request.job_id, e),
+        }
+    }
+    cluster_queue.set_queued_count(queued + requeued);
+    Ok(requeued)
+}
+
+// entries without a timestamp come from servers that predate heartbeats, and are left alone
+fn is_orphaned(stored_entry: &StoredJobEntry, cluster_queue: &ClusterQueue, now: DateTime<UTC>) -> bool {
+    match stored_entry.entry.last_updated {
+        Some(last_updated) if stored_entry.entry.state == JobState::WORKING => {
+            now.signed_duration_since(last_updated) > ChronoDuration::seconds(CLUSTER_ORPHAN_TIMEOUT_SECS)
+                && !cluster_queue.is_running(&stored_entry.entry.job_request.job_id)
+        },
+        _ => false,
+    }
+}
+
+// Rewrites the WORKING entries of jobs still running here, each against the index
+// this server last wrote it at; no events, nothing about the jobs changed. A job
+// whose entry a peer has changed since, e.g. putting it back in the queue as
+// orphaned, is given up on so this server never overwrites the peer's entry.
+fn send_cluster_heartbeat(cluster_queue: &ClusterQueue, persistence_writer: &PersistenceWriter) {
+    cluster_queue.heartbeat_jobs(|stored_entry| {
+        let job_id = &stored_entry.entry.job_request.job_id;
+        match persistence_writer.swap(EntrySwap::new(stored_entry, &JobState::WORKING, &JobOutcome::RUNNING, None)) {
+            Ok(written_entry) => Some(written_entry),
+            Err(PersistenceError::Conflict(_)) => {
+                warn!("Job jobId:[{}] was taken over by another server, no longer sending its heartbeat", job_id);
+                None
+            },
+            Err(e) => {
+                error!("Persistence Error: Failed to send heartbeat for [{}] - {}", job_id, e);
+                Some(stored_entry.clone())
+            },
+        }
+    });
+}
+
+fn new_cluster_job_request(requests_channel: Sender<Dispatch>, cluster_queue: &ClusterQueue, request: JobRequest) {
+    // QUEUED entry claimed on submission is already in the shared queue, any server can pick it up
+    debug!("ADDING NEW JOB TO SHARED QUEUE jobId:[{}]", request.job_id);
+    cluster_queue.add_queued();
+    requests_channel.send(Dispatch::ProcessRequest).expect("Job requests channel receiver has been deallocated");
+}
+
+fn process_cluster_job_request<T: 'static + Persistence + Send>(requests_channel: Sender<Dispatch>, primary_pool: &ThreadPool, context: WorkerContext<T>, cluster_queue: ClusterQueue) {
+    // count jobs handed to the pool but not yet started, so we never take more than we can run
+    if primary_pool.active_count() + primary_pool.queued_count() >= primary_pool.max_count() {
+        debug!("No threads available - leaving jobs in the shared queue");
+        return
+    }
+    // the shared queue is read and claimed from on the worker, not the worker manager
+    primary_pool.execute(move || {
+        match take_next_queued_entry(&context.persistence, &context.persistence_writer) {
+            Some(stored_entry) => {
+                cluster_queue.start_job(&stored_entry);
+                // there may be more work waiting, look again while this job runs
+                requests_channel.send(Dispatch::ProcessRequest).expect("Job requests channel receiver has been deallocated");
+                let request = stored_entry.entry.job_request;
+                debug!("PROCESSING JOB REQ jobId:[{}]", request.job_id);
+                let finished = run_job(&context, &request);
+                finish_cluster_job(requests_channel, &context.persistence_writer, &cluster_queue, &request, finished);
+            },
+            None => debug!("SHARED QUEUE EMPTY")
+        }
+    });
+}
+
+// Walks the shared queue oldest first, moving on whenever another server takes an entry first.
+// Returns the WORKING entry as written, for the job's heartbeats and DONE update to swap against.
+fn take_next_queued_entry<T: Persistence>(persistence: &T, persistence_writer: &PersistenceWriter) -> Option<StoredJobEntry> {
+    let queued = match persistence::get_queued_entries(persistence) {
+        Ok(queued) => queued,
+        Err(e) => {
+            error!("Could not read shared job queue - {}", e);
+            return None
+        }
+    };
+    for stored_entry in queued {
+        match persistence_writer.swap(EntrySwap::new(&stored_entry, &JobState::WORKING, &JobOutcome::RUNNING, None)) {
+            Ok(written_entry) => {
+                EVENTS.publish(Event::job(&stored_entry.entry.job_request, &JobState::WORKING, &JobOutcome::RUNNING));
+                return Some(written_entry)
+            },
+            Err(PersistenceError::Conflict(_)) => debug!("Job jobId:[{}] was taken by another server", stored_entry.entry.job_request.job_id),
+            Err(e) => {
+                error!("Could not take job jobId:[{}] from shared queue - {}", stored_entry.entry.job_request.job_id, e);
+                return None
+            }
+        }
+    }
+    None
+}
+
+// Writes the DONE entry against the index this server last wrote the job's entry at,
+// leaving the entry alone if a peer has taken the job over since
+fn finish_cluster_job(requests_channel: Sender<Dispatch>, persistence_writer: &PersistenceWriter, cluster_queue: &ClusterQueue, request: &JobRequest, finished: Result<JobResult, JobResult>) {
+    let (outcome, result) = match finished {
+        Ok(result) => (JobOutcome::SUCCEEDED, result),
+        Err(result) => (JobOutcome::FAILED, result),
+    };
+    let swapped = cluster_queue.finish_job(&request.job_id, |stored_entry| {
+        swap_done_entry(persistence_writer, EntrySwap::new(&stored_entry, &JobState::DONE, &outcome, Some(result)))
+    });
+    match swapped {
+        Some(Ok(_)) => {
+            debug!("Persist [{}]::[{}]", request.job_id, JobState::DONE);
+            EVENTS.publish(Event::job(request, &JobState::DONE, &outcome));
+        },
+        Some(Err(PersistenceError::Conflict(_))) | None => warn!("Job jobId:[{}] was taken over by another server, leaving its entry", request.job_id),
+        Some(Err(e)) => error!("Persistence Error: Failed to update [{}] to [{}] - {}", request.job_id, JobState::DONE, e),
+    };
+    requests_channel.send(Dispatch::ProcessRequest).expect("Job requests channel receiver has been deallocated");
+    match outcome {
+        JobOutcome::SUCCEEDED => info!("COMPLETED JOB REQ  jobId:[{}]", request.job_id),
+        _ => error!("FAILED JOB REQ jobId:[{}]", request.job_id),
+    }
+}
+
+fn swap_done_entry(persistence_writer: &PersistenceWriter, swap: EntrySwap) -> Result<StoredJobEntry, PersistenceError> {
+    let mut attempts = 1;
+    loop {
+        match persistence_writer.swap(swap.clone()) {
+            Err(PersistenceError::Unavailable(e)) if attempts < CLUSTER_DONE_ATTEMPTS => {
+                warn!("Persistence Error: Failed to update [{}] to [{}], attempt {} - {}", swap.stored_entry.key, swap.state, attempts, e);
+                thread::sleep(writer::get_retry_delay(attempts));
+                attempts += 1;
+            },
+            swapped => return swapped,
+        }
+    }
+}
+
+// Runs the job to completion, with its result as `Ok` if it succeeded and `Err` if not
+fn run_job<T: Persistence>(context: &WorkerContext<T>, request: &JobRequest) -> Result<JobResult, JobResult> {
+    let command_store = &context.command_store;
+    let cmd_path = match command_store.get_command(::FACTOTUM) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("{}", e);
+            return Err(JobResult::new(None, "", &e))
+        }
+    };
+    let factfile_path = match context.factfile_store.get_run_path(&context.persistence, request) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("{}", e);
+            return Err(JobResult::new(None, "", &e))
+        }
+    };
+    let mut cmd_args = vec!["run".to_string(), factfile_path];
+    cmd_args.extend_from_slice(request.factfile_args.as_slice());
+    let started = Instant::now();
+    let timeout = request.timeout_seconds.map(Duration::from_secs);
+    match command_store.run(cmd_path, cmd_args, timeout) {
+        Ok(ref output) if output.success() => {
+            trace!("{}", output.stdout);
+            METRICS.job_finished(&request.job_name, &JobOutcome::SUCCEEDED, started.elapsed());
+            Ok(JobResult::new(output.exit_code, &output.stdout, &output.stderr))
+        },
+        Ok(output) => {
+            error!("Job jobId:[{}] exited with code {:?} - {}", request.job_id, output.exit_code, output.stderr);
+            METRICS.job_finished(&request.job_name, &JobOutcome::FAILED, started.elapsed());
+            Err(JobResult::new(output.exit_code, &output.stdout, &output.stderr))
+        },
+        Err(e) => {
+            error!("{}", e);
+            METRICS.job_finished(&request.job_name, &JobOutcome::FAILED, started.elapsed());
+            Err(JobResult::new(None, "", &e))
+        }
+    }
+}
+
+fn complete_job_request(requests_channel: Sender<Dispatch>, persistence_writer: &PersistenceWriter, request: JobRequest, result: JobResult) -> String {
     // Update completion in persistence storage
-    match persist_entry(&persistence, &request.job_id, &request, &JobState::DONE, &JobOutcome::SUCCEEDED) {
+    match persist_entry(persistence_writer, &request.job_id, &request, &JobState::DONE, &JobOutcome::SUCCEEDED, Some(result)) {
         Ok(msg) => debug!("{}", msg),
         Err(msg) => error!("{}", msg),
     };
@@ -251,9 +708,9 @@ fn complete_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, pers
     format!("COMPLETED JOB REQ  jobId:[{}]", request.job_id)
 }
 
-fn failed_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, persistence: T, request: JobRequest) -> String {
+fn failed_job_request(requests_channel: Sender<Dispatch>, persistence_writer: &PersistenceWriter, request: JobRequest, result: JobResult) -> String {
     // Update failure in persistence storage
-    match persist_entry(&persistence, &request.job_id, &request, &JobState::DONE, &JobOutcome::FAILED) {
+    match persist_entry(persistence_writer, &request.job_id, &request, &JobState::DONE, &JobOutcome::FAILED, Some(result)) {
         Ok(msg) => debug!("{}", msg),
         Err(msg) => error!("{}", msg),
     };
@@ -261,11 +718,13 @@ fn failed_job_request<T: Persistence>(requests_channel: Sender<Dispatch>, persis
     format!("FAILED JOB REQ jobId:[{}]", request.job_id)
 }
 
-fn persist_entry<T: Persistence>(persistence: &T, client_job_id: &str, job_request: &JobRequest, job_state: &JobState, job_outcome: &JobOutcome) -> Result<String, String> {
-    let output = persistence::set_entry(persistence, client_job_id, job_request, job_state, job_outcome);
-    if output {
-        Ok(format!("Persist [{}]::[{}]", client_job_id, job_state))
-    } else {
-        Err(format!("Persistence Error: Failed to update [{}] to [{}]", client_job_id, job_state))
+// Hands the update to the persistence writer, the write itself happens off this thread
+fn persist_entry(persistence_writer: &PersistenceWriter, client_job_id: &str, job_request: &JobRequest, job_state: &JobState, job_outcome: &JobOutcome, result: Option<JobResult>) -> Result<String, String> {
+    match persistence_writer.write(EntryUpdate::new(client_job_id, job_request, job_state, job_outcome, result)) {
+        Ok(_) => {
+            EVENTS.publish(Event::job(job_request, job_state, job_outcome));
+            Ok(format!("Queued persist [{}]::[{}]", client_job_id, job_state))
+        },
+        Err(e) => Err(format!("Persistence Error: Failed to update [{}] to [{}] - {}", client_job_id, job_state, e)),
     }
 }
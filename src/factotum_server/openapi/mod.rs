@@ -0,0 +1,866 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use serde_json::{Map, Value};
+
+use factotum_server::catalog::{JobDefinition, RunRequest};
+use factotum_server::events::{JobEvent, ServerEvent};
+use factotum_server::janitor::{ExpiredEntry, RetentionPolicy};
+use factotum_server::persistence::{JobEntry, JobOutcome, JobResult, JobState};
+use factotum_server::responder::{DispatcherStatus, DryRunResult, FactotumServerStatus, JobStatus, PersistenceStatus, ProbeCheck, ProbeReport, ResponseMessage, RetentionReport, ServerStatus, SubmissionResult, ValidationFailure, WorkerStatus};
+use factotum_server::responder::v2::{AcceptedJob, ApiError, ErrorResponse};
+use factotum_server::server::{JobRequest, SettingsRequest};
+use factotum_server::webhook::{SubscriptionRequest, SubscriptionSummary, WebhookPayload};
+
+#[cfg(test)]
+mod tests;
+
+pub const OPENAPI_VERSION: &'static str = "3.0.3";
+
+/// JSON schema of a type the API reads or writes, registered under
+/// `components/schemas/<schema_name>`. Tests hold every schema to the fields the
+/// type actually serializes, so the document can't drift from the code.
+pub trait ApiSchema {
+    fn schema_name() -> &'static str;
+    fn schema() -> Value;
+
+    fn reference() -> Value {
+        json!({ "$ref": format!("#/components/schemas/{}", Self::schema_name()) })
+    }
+}
+
+// Schema helpers
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer(format: &str) -> Value {
+    json!({ "type": "integer", "format": format })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn date_time() -> Value {
+    json!({ "type": "string", "format": "date-time" })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn string_map() -> Value {
+    json!({ "type": "object", "additionalProperties": string() })
+}
+
+fn nullable(schema: Value) -> Value {
+    match schema {
+        Value::Object(mut map) => {
+            if map.contains_key("$ref") {
+                // siblings of $ref are ignored in OpenAPI 3.0
+                json!({ "allOf": [ Value::Object(map) ], "nullable": true })
+            } else {
+                map.insert("nullable".to_string(), Value::Bool(true));
+                Value::Object(map)
+            }
+        },
+        other => other,
+    }
+}
+
+fn enumeration(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn object(properties: Vec<(&str, Value)>, required: &[&str]) -> Value {
+    let mut property_map = Map::new();
+    for (name, schema) in properties {
+        property_map.insert(name.to_string(), schema);
+    }
+    json!({
+        "type": "object",
+        "properties": Value::Object(property_map),
+        "required": required,
+    })
+}
+
+// Requests
+
+impl ApiSchema for JobRequest {
+    fn schema_name() -> &'static str { "JobRequest" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("jobName", string()),
+            ("factfilePath", string()),
+            ("factfile", nullable(json!({ "type": "object", "description": "Factfile content, run from the server's factfile store instead of factfilePath" }))),
+            ("factfileHash", nullable(json!({ "type": "string", "description": "SHA256 of accepted factfile content, set by the server in place of the content" }))),
+            ("factfileArgs", array(string())),
+            ("submittedBy", nullable(string())),
+            ("callbackUrl", nullable(json!({ "type": "string", "description": "Accepted on submission, never included in job entries" }))),
+            ("tags", nullable(string_map())),
+            ("env", nullable(json!({ "type": "object" }))),
+            ("start", nullable(string())),
+            ("constraints", nullable(string_map())),
+            ("dryRun", nullable(boolean())),
+            ("timeoutSeconds", nullable(json!({ "type": "integer", "format": "int64", "minimum": 1, "description": "Accepted on submission, and set from the definition for catalog jobs; a running job is killed, and fails, once it takes longer" }))),
+        ], &["jobName"])
+    }
+}
+
+impl ApiSchema for SettingsRequest {
+    fn schema_name() -> &'static str { "SettingsRequest" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("state", enumeration(&[::SERVER_STATE_RUN, ::SERVER_STATE_DRAIN])),
+        ], &["state"])
+    }
+}
+
+impl ApiSchema for SubscriptionRequest {
+    fn schema_name() -> &'static str { "SubscriptionRequest" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("url", string()),
+            ("secret", string()),
+            ("events", array(webhook_event())),
+            ("jobName", nullable(string())),
+        ], &["url", "secret"])
+    }
+}
+
+impl ApiSchema for JobDefinition {
+    fn schema_name() -> &'static str { "JobDefinition" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("name", string()),
+            ("factfilePath", string()),
+            ("factfile", nullable(json!({ "type": "object", "description": "Factfile content, run from the server's factfile store instead of factfilePath" }))),
+            ("factfileArgs", array(string())),
+            ("env", nullable(json!({ "type": "object" }))),
+            ("tags", nullable(string_map())),
+            ("timeoutSeconds", nullable(integer("int64"))),
+            ("pool", nullable(json!({ "type": "string", "description": "Passed to factotum as the 'pool' tag" }))),
+            ("overridableArgs", json!({ "type": "array", "items": string(), "description": "Flags a run may add to factfileArgs, also needed to override start" })),
+            ("overridableEnv", json!({ "type": "array", "items": string(), "description": "Env keys a run may set" })),
+            ("registeredBy", nullable(string())),
+            ("registeredAt", nullable(date_time())),
+        ], &["name"])
+    }
+}
+
+impl ApiSchema for RunRequest {
+    fn schema_name() -> &'static str { "RunRequest" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("factfileArgs", array(string())),
+            ("env", nullable(json!({ "type": "object" }))),
+            ("tags", nullable(string_map())),
+            ("start", nullable(string())),
+            ("dryRun", nullable(boolean())),
+            ("callbackUrl", nullable(string())),
+        ], &[])
+    }
+}
+
+// Job entries
+
+impl ApiSchema for JobState {
+    fn schema_name() -> &'static str { "JobState" }
+
+    fn schema() -> Value {
+        enumeration(&["QUEUED", "WORKING", "DONE"])
+    }
+}
+
+impl ApiSchema for JobOutcome {
+    fn schema_name() -> &'static str { "JobOutcome" }
+
+    fn schema() -> Value {
+        enumeration(&["SUCCEEDED", "FAILED", "RUNNING", "WAITING"])
+    }
+}
+
+impl ApiSchema for JobResult {
+    fn schema_name() -> &'static str { "JobResult" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("exitCode", nullable(integer("int32"))),
+            ("stdout", json!({ "type": "string", "description": "The last 4KB of the run's standard output" })),
+            ("stderr", json!({ "type": "string", "description": "The last 4KB of the run's standard error" })),
+        ], &["exitCode", "stdout", "stderr"])
+    }
+}
+
+impl ApiSchema for JobEntry {
+    fn schema_name() -> &'static str { "JobEntry" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("schemaVersion", integer("int32")),
+            ("state", JobState::reference()),
+            ("jobRequest", JobRequest::reference()),
+            ("lastRunFrom", string()),
+            ("lastOutcome", JobOutcome::reference()),
+            ("lastUpdated", nullable(date_time())),
+            ("result", nullable(JobResult::reference())),
+        ], &["schemaVersion", "state", "jobRequest", "lastRunFrom", "lastOutcome"])
+    }
+}
+
+// Events
+
+impl ApiSchema for JobEvent {
+    fn schema_name() -> &'static str { "JobEvent" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("jobName", string()),
+            ("tags", string_map()),
+            ("state", JobState::reference()),
+            ("outcome", JobOutcome::reference()),
+            ("timestamp", date_time()),
+        ], &["jobId", "jobName", "tags", "state", "outcome", "timestamp"])
+    }
+}
+
+impl ApiSchema for ServerEvent {
+    fn schema_name() -> &'static str { "ServerEvent" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("state", string()),
+            ("timestamp", date_time()),
+        ], &["state", "timestamp"])
+    }
+}
+
+// Webhooks
+
+fn webhook_event() -> Value {
+    enumeration(&["job.submitted", "job.started", "job.succeeded", "job.failed", "job.cancelled"])
+}
+
+impl<'a> ApiSchema for WebhookPayload<'a> {
+    fn schema_name() -> &'static str { "WebhookPayload" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("id", string()),
+            ("event", webhook_event()),
+            ("timestamp", date_time()),
+            ("job", JobEvent::reference()),
+        ], &["id", "event", "timestamp", "job"])
+    }
+}
+
+impl ApiSchema for SubscriptionSummary {
+    fn schema_name() -> &'static str { "SubscriptionSummary" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("id", string()),
+            ("url", string()),
+            ("events", array(webhook_event())),
+            ("jobName", nullable(string())),
+            ("createdBy", nullable(string())),
+            ("createdAt", date_time()),
+        ], &["id", "url", "events", "jobName", "createdBy", "createdAt"])
+    }
+}
+
+// Responses
+
+impl ApiSchema for ResponseMessage {
+    fn schema_name() -> &'static str { "ResponseMessage" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("message", string()),
+        ], &["message"])
+    }
+}
+
+impl ApiSchema for ValidationFailure {
+    fn schema_name() -> &'static str { "ValidationFailure" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("message", string()),
+            ("stdout", json!({ "type": "string", "description": "What the Factotum dry run printed, empty if it didn't get that far" })),
+            ("stderr", string()),
+        ], &["message", "stdout", "stderr"])
+    }
+}
+
+impl ApiSchema for DryRunResult {
+    fn schema_name() -> &'static str { "DryRunResult" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("jobName", string()),
+            ("factfilePath", string()),
+            ("factfileArgs", array(string())),
+            ("plan", json!({ "type": "array", "items": string(), "description": "The dry run's stdout, one entry per non-blank line" })),
+            ("stdout", string()),
+            ("stderr", string()),
+        ], &["jobId", "jobName", "factfilePath", "factfileArgs", "plan", "stdout", "stderr"])
+    }
+}
+
+impl ApiSchema for SubmissionResult {
+    fn schema_name() -> &'static str { "SubmissionResult" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("state", JobState::reference()),
+            ("outcome", JobOutcome::reference()),
+            ("exitCode", nullable(integer("int32"))),
+            ("stdout", nullable(string())),
+            ("stderr", nullable(string())),
+        ], &["jobId", "state", "outcome", "exitCode", "stdout", "stderr"])
+    }
+}
+
+impl ApiSchema for ServerStatus {
+    fn schema_name() -> &'static str { "ServerStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("startTime", string()),
+            ("upTime", string()),
+            ("state", enumeration(&[::SERVER_STATE_RUN, ::SERVER_STATE_DRAIN])),
+            ("isLeader", boolean()),
+        ], &["startTime", "upTime", "state", "isLeader"])
+    }
+}
+
+impl ApiSchema for WorkerStatus {
+    fn schema_name() -> &'static str { "WorkerStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("total", integer("int64")),
+            ("idle", integer("int64")),
+            ("active", integer("int64")),
+        ], &["total", "idle", "active"])
+    }
+}
+
+impl ApiSchema for JobStatus {
+    fn schema_name() -> &'static str { "JobStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("maxQueueSize", integer("int64")),
+            ("inQueue", integer("int64")),
+        ], &["maxQueueSize", "inQueue"])
+    }
+}
+
+impl ApiSchema for PersistenceStatus {
+    fn schema_name() -> &'static str { "PersistenceStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("pendingWrites", integer("int64")),
+        ], &["pendingWrites"])
+    }
+}
+
+impl ApiSchema for DispatcherStatus {
+    fn schema_name() -> &'static str { "DispatcherStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("workers", WorkerStatus::reference()),
+            ("jobs", JobStatus::reference()),
+            ("persistence", PersistenceStatus::reference()),
+        ], &["workers", "jobs", "persistence"])
+    }
+}
+
+impl ApiSchema for FactotumServerStatus {
+    fn schema_name() -> &'static str { "FactotumServerStatus" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("version", string()),
+            ("server", ServerStatus::reference()),
+            ("dispatcher", DispatcherStatus::reference()),
+        ], &["version", "server", "dispatcher"])
+    }
+}
+
+impl ApiSchema for RetentionPolicy {
+    fn schema_name() -> &'static str { "RetentionPolicy" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("maxAgeHours", nullable(integer("int64"))),
+            ("maxCount", nullable(integer("int64"))),
+            ("intervalSecs", integer("int64")),
+        ], &["maxAgeHours", "maxCount", "intervalSecs"])
+    }
+}
+
+impl ApiSchema for ExpiredEntry {
+    fn schema_name() -> &'static str { "ExpiredEntry" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("jobName", string()),
+            ("lastUpdated", nullable(date_time())),
+            ("reason", string()),
+        ], &["jobId", "jobName", "lastUpdated", "reason"])
+    }
+}
+
+impl ApiSchema for RetentionReport {
+    fn schema_name() -> &'static str { "RetentionReport" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("policy", RetentionPolicy::reference()),
+            ("expired", array(ExpiredEntry::reference())),
+        ], &["policy", "expired"])
+    }
+}
+
+impl ApiSchema for ProbeCheck {
+    fn schema_name() -> &'static str { "ProbeCheck" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("name", string()),
+            ("passed", boolean()),
+            ("message", string()),
+        ], &["name", "passed", "message"])
+    }
+}
+
+impl ApiSchema for ProbeReport {
+    fn schema_name() -> &'static str { "ProbeReport" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("passed", boolean()),
+            ("checks", array(ProbeCheck::reference())),
+        ], &["passed", "checks"])
+    }
+}
+
+impl ApiSchema for AcceptedJob {
+    fn schema_name() -> &'static str { "AcceptedJob" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("jobId", string()),
+            ("state", JobState::reference()),
+            ("href", string()),
+        ], &["jobId", "state", "href"])
+    }
+}
+
+impl ApiSchema for ApiError {
+    fn schema_name() -> &'static str { "ApiError" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("code", string()),
+            ("message", string()),
+            ("stdout", json!({ "type": "string", "description": "What the dry run printed, only for INVALID_JOB_REQUEST" })),
+            ("stderr", json!({ "type": "string", "description": "What the dry run printed, only for INVALID_JOB_REQUEST" })),
+        ], &["code", "message"])
+    }
+}
+
+impl ApiSchema for ErrorResponse {
+    fn schema_name() -> &'static str { "ErrorResponse" }
+
+    fn schema() -> Value {
+        object(vec![
+            ("error", ApiError::reference()),
+        ], &["error"])
+    }
+}
+
+fn get_component_schemas() -> Value {
+    let mut schemas = Map::new();
+    {
+        let mut add = |name: &str, schema: Value| { schemas.insert(name.to_string(), schema); };
+        add(JobRequest::schema_name(), JobRequest::schema());
+        add(SettingsRequest::schema_name(), SettingsRequest::schema());
+        add(JobState::schema_name(), JobState::schema());
+        add(JobOutcome::schema_name(), JobOutcome::schema());
+        add(JobResult::schema_name(), JobResult::schema());
+        add(JobEntry::schema_name(), JobEntry::schema());
+        add(ResponseMessage::schema_name(), ResponseMessage::schema());
+        add(SubmissionResult::schema_name(), SubmissionResult::schema());
+        add(ValidationFailure::schema_name(), ValidationFailure::schema());
+        add(DryRunResult::schema_name(), DryRunResult::schema());
+        add(ServerStatus::schema_name(), ServerStatus::schema());
+        add(WorkerStatus::schema_name(), WorkerStatus::schema());
+        add(JobStatus::schema_name(), JobStatus::schema());
+        add(PersistenceStatus::schema_name(), PersistenceStatus::schema());
+        add(DispatcherStatus::schema_name(), DispatcherStatus::schema());
+        add(FactotumServerStatus::schema_name(), FactotumServerStatus::schema());
+        add(RetentionPolicy::schema_name(), RetentionPolicy::schema());
+        add(ExpiredEntry::schema_name(), ExpiredEntry::schema());
+        add(RetentionReport::schema_name(), RetentionReport::schema());
+        add(ProbeCheck::schema_name(), ProbeCheck::schema());
+        add(ProbeReport::schema_name(), ProbeReport::schema());
+        add(JobEvent::schema_name(), JobEvent::schema());
+        add(ServerEvent::schema_name(), ServerEvent::schema());
+        add(SubscriptionRequest::schema_name(), SubscriptionRequest::schema());
+        add(SubscriptionSummary::schema_name(), SubscriptionSummary::schema());
+        add(WebhookPayload::schema_name(), WebhookPayload::schema());
+        add(JobDefinition::schema_name(), JobDefinition::schema());
+        add(RunRequest::schema_name(), RunRequest::schema());
+        add(AcceptedJob::schema_name(), AcceptedJob::schema());
+        add(ApiError::schema_name(), ApiError::schema());
+        add(ErrorResponse::schema_name(), ErrorResponse::schema());
+    }
+    Value::Object(schemas)
+}
+
+// Path helpers
+
+fn pretty_param() -> Value {
+    json!({ "name": "pretty", "in": "query", "required": false, "schema": { "type": "string", "enum": ["1"] } })
+}
+
+fn id_path_param() -> Value {
+    json!({ "name": "id", "in": "path", "required": true, "schema": string() })
+}
+
+fn name_path_param() -> Value {
+    json!({ "name": "name", "in": "path", "required": true, "schema": string() })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": json_content(schema) })
+}
+
+fn request_body(schema: Value) -> Value {
+    json!({ "required": true, "content": json_content(schema) })
+}
+
+fn get_paths() -> Value {
+    let message = ResponseMessage::reference();
+    // invalid job requests carry the dry run's output, other bad requests only a message
+    let rejected = json!({ "oneOf": [ message.clone(), ValidationFailure::reference() ] });
+    let error = ErrorResponse::reference();
+    json!({
+        "/help": {
+            "get": {
+                "summary": "Lists the endpoints of the v1 API.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Endpoint descriptions", json!({ "type": "object" })) }
+            }
+        },
+        "/status": {
+            "get": {
+                "summary": "Returns general information about the server.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Server status", FactotumServerStatus::reference()) }
+            }
+        },
+        "/settings": {
+            "post": {
+                "summary": "Updates settings within the server.",
+                "parameters": [ pretty_param() ],
+                "requestBody": request_body(SettingsRequest::reference()),
+                "responses": {
+                    "200": response("Settings updated", message.clone()),
+                    "400": response("Invalid settings", message.clone())
+                }
+            }
+        },
+        "/submit": {
+            "post": {
+                "summary": "Submits a job to the queue.",
+                "parameters": [
+                    pretty_param(),
+                    { "name": "wait", "in": "query", "required": false, "schema": { "type": "boolean" } },
+                    { "name": "timeout", "in": "query", "required": false, "schema": { "type": "integer", "maximum": ::WAIT_TIMEOUT_MAX, "default": ::WAIT_TIMEOUT_DEFAULT } }
+                ],
+                "requestBody": request_body(JobRequest::reference()),
+                "responses": {
+                    "200": response("Job submitted, or finished when wait=true", json!({ "oneOf": [ message.clone(), SubmissionResult::reference() ] })),
+                    "202": response("Job still running when the wait timed out", SubmissionResult::reference()),
+                    "400": response("Invalid job request", rejected.clone()),
+                    "409": response("Job is already being processed", message.clone())
+                }
+            }
+        },
+        "/validate": {
+            "post": {
+                "summary": "Runs the same checks as /submit, including the Factotum dry run, without queueing the job.",
+                "parameters": [ pretty_param() ],
+                "requestBody": request_body(JobRequest::reference()),
+                "responses": {
+                    "200": response("Job request would be accepted", DryRunResult::reference()),
+                    "400": response("Invalid body or job request", rejected.clone())
+                }
+            }
+        },
+        "/check": {
+            "get": {
+                "summary": "Fetches the state of a job by the ID.",
+                "parameters": [
+                    pretty_param(),
+                    { "name": "id", "in": "query", "required": true, "schema": string() }
+                ],
+                "responses": {
+                    "200": response("Job entry", JobEntry::reference()),
+                    "404": response("No job entry found", message.clone())
+                }
+            }
+        },
+        "/jobs/{id}/wait": {
+            "get": {
+                "summary": "Waits for a job to finish and returns its entry.",
+                "parameters": [
+                    id_path_param(),
+                    pretty_param(),
+                    { "name": "timeout", "in": "query", "required": false, "schema": { "type": "integer", "maximum": ::WAIT_TIMEOUT_MAX, "default": ::WAIT_TIMEOUT_DEFAULT } }
+                ],
+                "responses": {
+                    "200": response("Job is done", JobEntry::reference()),
+                    "202": response("Job still running when the wait timed out", JobEntry::reference()),
+                    "404": response("No job entry found", message.clone()),
+                    "503": response("Too many waits in progress", message.clone())
+                }
+            }
+        },
+        "/retention": {
+            "get": {
+                "summary": "Lists finished job entries the janitor would remove.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Retention report", RetentionReport::reference()) }
+            }
+        },
+        "/metrics": {
+            "get": {
+                "summary": "Returns server metrics in the Prometheus text format.",
+                "responses": {
+                    "200": {
+                        "description": "Prometheus metrics",
+                        "content": { "text/plain": { "schema": string() } }
+                    }
+                }
+            }
+        },
+        "/events": {
+            "get": {
+                "summary": "Streams job state changes as `job` events and server state changes as `server` events, using server-sent events.",
+                "parameters": [
+                    { "name": "jobName", "in": "query", "required": false, "schema": string() },
+                    { "name": "tag", "in": "query", "required": false, "description": "Repeatable, as `key` or `key,value`", "schema": string() }
+                ],
+                "responses": {
+                    "200": {
+                        "description": "Event stream, each `data` line holding a JobEvent or ServerEvent",
+                        "content": { "text/event-stream": { "schema": { "oneOf": [ JobEvent::reference(), ServerEvent::reference() ] } } }
+                    },
+                    "503": response("Too many open event streams", message.clone())
+                }
+            }
+        },
+        "/subscriptions": {
+            "post": {
+                "summary": "Registers a webhook for job events, across every server sharing the Consul namespace.",
+                "parameters": [ pretty_param() ],
+                "requestBody": request_body(SubscriptionRequest::reference()),
+                "responses": {
+                    "201": response("Subscription added", SubscriptionSummary::reference()),
+                    "400": response("Invalid subscription request", message.clone())
+                },
+                "callbacks": {
+                    "jobEvent": {
+                        "{$request.body#/url}": {
+                            "post": {
+                                "summary": "Sent for each subscribed job event, retried with backoff until a 2xx response.",
+                                "parameters": [
+                                    { "name": "X-Factotum-Event", "in": "header", "required": true, "schema": webhook_event() },
+                                    { "name": "X-Factotum-Delivery", "in": "header", "required": true, "schema": string() },
+                                    { "name": "X-Factotum-Signature", "in": "header", "required": true, "description": "`sha256=` and the hex HMAC-SHA256 of the body, keyed with the subscription secret", "schema": string() }
+                                ],
+                                "requestBody": request_body(WebhookPayload::reference()),
+                                "responses": { "2XX": { "description": "Callback received" } }
+                            }
+                        }
+                    }
+                }
+            },
+            "get": {
+                "summary": "Lists webhook subscriptions, without their secrets.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Subscriptions", array(SubscriptionSummary::reference())) }
+            }
+        },
+        "/subscriptions/{id}": {
+            "delete": {
+                "summary": "Removes a webhook subscription.",
+                "parameters": [ id_path_param(), pretty_param() ],
+                "responses": {
+                    "200": response("Subscription removed", message.clone()),
+                    "404": response("No subscription found", message.clone())
+                }
+            }
+        },
+        "/catalog": {
+            "post": {
+                "summary": "Registers a named job, replacing any with the same name, across every server sharing the Consul namespace.",
+                "parameters": [ pretty_param() ],
+                "requestBody": request_body(JobDefinition::reference()),
+                "responses": {
+                    "201": response("Job registered", JobDefinition::reference()),
+                    "400": response("Invalid job definition", message.clone())
+                }
+            },
+            "get": {
+                "summary": "Lists the registered jobs by name.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Registered jobs", array(JobDefinition::reference())) }
+            }
+        },
+        "/catalog/{name}": {
+            "delete": {
+                "summary": "Removes a registered job.",
+                "parameters": [ name_path_param(), pretty_param() ],
+                "responses": {
+                    "200": response("Job removed", message.clone()),
+                    "404": response("No job registered with the name", message.clone())
+                }
+            }
+        },
+        "/catalog/{name}/run": {
+            "post": {
+                "summary": "Submits a registered job, with optional overrides: args are added to its own, env and tags replace its values key by key. Only the args and env the job lists as overridable can be given.",
+                "parameters": [
+                    name_path_param(),
+                    pretty_param(),
+                    { "name": "wait", "in": "query", "required": false, "schema": { "type": "boolean" } },
+                    { "name": "timeout", "in": "query", "required": false, "schema": { "type": "integer", "maximum": ::WAIT_TIMEOUT_MAX, "default": ::WAIT_TIMEOUT_DEFAULT } }
+                ],
+                "requestBody": { "required": false, "content": json_content(RunRequest::reference()) },
+                "responses": {
+                    "200": response("Job submitted, or finished when wait=true", json!({ "oneOf": [ message.clone(), SubmissionResult::reference() ] })),
+                    "202": response("Job still running when the wait timed out", SubmissionResult::reference()),
+                    "400": response("Invalid overrides or job request", rejected.clone()),
+                    "404": response("No job registered with the name", message.clone()),
+                    "409": response("Job is already being processed", message.clone())
+                }
+            }
+        },
+        "/healthz": {
+            "get": {
+                "summary": "Liveness probe, also used by the Consul service registration.",
+                "security": [],
+                "parameters": [ pretty_param() ],
+                "responses": {
+                    "200": response("Dispatcher is answering", ProbeReport::reference()),
+                    "503": response("Dispatcher has stopped or is stuck", ProbeReport::reference())
+                }
+            }
+        },
+        "/readyz": {
+            "get": {
+                "summary": "Readiness probe: Consul is reachable, the Factotum binary is executable and the server is in the run state.",
+                "security": [],
+                "parameters": [ pretty_param() ],
+                "responses": {
+                    "200": response("Ready to accept jobs", ProbeReport::reference()),
+                    "503": response("At least one check failed", ProbeReport::reference())
+                }
+            }
+        },
+        "/openapi.json": {
+            "get": {
+                "summary": "Returns this document.",
+                "responses": { "200": response("OpenAPI document", json!({ "type": "object" })) }
+            }
+        },
+        "/api/v2/jobs": {
+            "post": {
+                "summary": "Submits a job to the queue.",
+                "parameters": [ pretty_param() ],
+                "requestBody": request_body(JobRequest::reference()),
+                "responses": {
+                    "202": response("Job accepted", AcceptedJob::reference()),
+                    "400": response("Invalid body or job request", error.clone()),
+                    "409": response("Job is already being processed", error.clone()),
+                    "429": response("Queue is full", error.clone()),
+                    "503": response("Server is draining or storage is unavailable", error.clone())
+                }
+            }
+        },
+        "/api/v2/jobs/{id}": {
+            "get": {
+                "summary": "Fetches a job entry by the ID.",
+                "parameters": [ id_path_param(), pretty_param() ],
+                "responses": {
+                    "200": response("Job entry", JobEntry::reference()),
+                    "404": response("No job entry found", error.clone()),
+                    "503": response("Storage is unavailable", error.clone())
+                }
+            }
+        },
+        "/api/v2/server": {
+            "get": {
+                "summary": "Returns general information about the server.",
+                "parameters": [ pretty_param() ],
+                "responses": { "200": response("Server status", FactotumServerStatus::reference()) }
+            }
+        }
+    })
+}
+
+pub fn get_openapi_document() -> Value {
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": "Factotum Server",
+            "version": ::VERSION,
+            "license": { "name": "Apache 2.0", "url": "http://www.apache.org/licenses/LICENSE-2.0" }
+        },
+        "paths": get_paths(),
+        "components": {
+            "schemas": get_component_schemas(),
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer" }
+            }
+        },
+        // only enforced when the server is started with --auth-tokens
+        "security": [ { "bearerAuth": [] } ]
+    })
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::collections::BTreeSet;
+use serde::Serialize;
+use serde_json;
+use factotum_server::events::Event;
+use factotum_server::persistence::StoredJobEntry;
+use factotum_server::server::{DryRun, ValidationError};
+use factotum_server::webhook::WebhookSubscription;
+
+fn sample_job_entry() -> JobEntry {
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec!["--no-colour".to_string()]);
+    let mut job_entry = JobEntry::new(&JobState::DONE, &request, "server_id", &JobOutcome::SUCCEEDED);
+    job_entry.result = Some(JobResult::new(Some(0), "out", "err"));
+    job_entry
+}
+
+// every optional field set, as fields that are none aren't serialized
+fn sample_job_request() -> JobRequest {
+    let mut request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    request.factfile = Some(json!({ "name": "dummy" }));
+    request.factfile_hash = Some("dummy_hash".to_string());
+    request.submitted_by = Some("ops".to_string());
+    request.callback_url = Some("https://hooks.acme.com/factotum".to_string());
+    request.tags = Some(json!({ "team": "data" }));
+    request.env = Some(json!({ "bucket": "s3://acme" }));
+    request.start = Some(json!("step-2"));
+    request.constraints = Some(json!({ "host": "worker-1" }));
+    request.dry_run = Some(json!(true));
+    request.timeout_seconds = Some(3600);
+    request
+}
+
+fn sample_server_status() -> FactotumServerStatus {
+    FactotumServerStatus {
+        version: ::VERSION.to_string(),
+        server: ServerStatus {
+            start_time: String::new(),
+            up_time: String::new(),
+            state: ::SERVER_STATE_RUN.to_string(),
+            is_leader: true,
+        },
+        dispatcher: DispatcherStatus {
+            workers: WorkerStatus { total: 1, idle: 1, active: 0 },
+            jobs: JobStatus { max_queue_size: 1, in_queue: 0 },
+            persistence: PersistenceStatus { pending_writes: 0 },
+        },
+    }
+}
+
+fn get_fields(value: &Value) -> BTreeSet<String> {
+    value.as_object().expect("Expected a JSON object").keys().cloned().collect()
+}
+
+// every field the type serializes must be described, and nothing more
+fn assert_matches_schema<T: ApiSchema + Serialize>(sample: &T) {
+    let schema = T::schema();
+    let value = serde_json::to_value(sample).expect("JSON encode error");
+    let fields = get_fields(&value);
+
+    assert_eq!(get_fields(&schema["properties"]), fields, "schema for {} has drifted", T::schema_name());
+    for required in schema["required"].as_array().expect("Expected required fields") {
+        assert!(fields.contains(required.as_str().unwrap()), "{} is not a field of {}", required, T::schema_name());
+    }
+    for (name, field) in value.as_object().unwrap() {
+        if field.is_array() {
+            assert_eq!(Some("array"), schema["properties"][name.as_str()]["type"].as_str(), "{}.{} should be an array", T::schema_name(), name);
+        }
+    }
+}
+
+fn assert_enum_matches_schema<T: ApiSchema + Serialize>(variants: Vec<T>) {
+    let schema = T::schema();
+    let allowed: Vec<Value> = schema["enum"].as_array().expect("Expected enum values").clone();
+
+    assert_eq!(allowed.len(), variants.len());
+    for variant in variants {
+        assert!(allowed.contains(&serde_json::to_value(&variant).unwrap()), "{} is missing a variant", T::schema_name());
+    }
+}
+
+fn collect_refs(value: &Value, refs: &mut Vec<String>) {
+    match *value {
+        Value::Object(ref map) => {
+            for (key, child) in map {
+                match (key.as_str(), child.as_str()) {
+                    ("$ref", Some(reference)) => refs.push(reference.to_string()),
+                    _ => collect_refs(child, refs),
+                }
+            }
+        },
+        Value::Array(ref items) => {
+            for item in items {
+                collect_refs(item, refs);
+            }
+        },
+        _ => {},
+    }
+}
+
+#[test]
+fn request_schemas_match_structs() {
+    assert_matches_schema(&sample_job_request());
+    assert_matches_schema(&SettingsRequest::new(::SERVER_STATE_DRAIN));
+}
+
+#[test]
+fn job_entry_schemas_match_structs() {
+    let job_entry = sample_job_entry();
+
+    assert_matches_schema(job_entry.result.as_ref().unwrap());
+    assert_matches_schema(&job_entry);
+    assert_enum_matches_schema(vec![JobState::QUEUED, JobState::WORKING, JobState::DONE]);
+    assert_enum_matches_schema(vec![JobOutcome::SUCCEEDED, JobOutcome::FAILED, JobOutcome::RUNNING, JobOutcome::WAITING]);
+}
+
+#[test]
+fn response_schemas_match_structs() {
+    let status = sample_server_status();
+    let policy = RetentionPolicy::new(Some(24), Some(10), None);
+    let expired = ExpiredEntry {
+        job_id: "dummy_id_1".to_string(),
+        job_name: "dummy".to_string(),
+        last_updated: None,
+        reason: "Too old".to_string(),
+        stored_entry: StoredJobEntry { key: "dummy_id_1".to_string(), index: 1, entry: sample_job_entry() },
+    };
+
+    assert_matches_schema(&ResponseMessage { message: "OK".to_string() });
+    assert_matches_schema(&status.server);
+    assert_matches_schema(&status.dispatcher.workers);
+    assert_matches_schema(&status.dispatcher.jobs);
+    assert_matches_schema(&status.dispatcher.persistence);
+    assert_matches_schema(&status.dispatcher);
+    assert_matches_schema(&status);
+    assert_matches_schema(&SubmissionResult::new(sample_job_entry()));
+    assert_matches_schema(&ValidationFailure::new(&ValidationError::new("Dry run failed".to_string(), String::new(), "No such task".to_string())));
+    assert_matches_schema(&DryRunResult::new(DryRun { job_request: JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]), stdout: "Task one\n".to_string(), stderr: String::new() }));
+    assert_matches_schema(&policy);
+    assert_matches_schema(&expired);
+    assert_matches_schema(&RetentionReport { policy: policy.clone(), expired: vec![expired.clone()] });
+    assert_matches_schema(&ProbeReport::new(vec![ProbeCheck::new("consul", Ok("Consul is reachable".to_string()))]));
+}
+
+#[test]
+fn v2_schemas_match_structs() {
+    let new_error = || ApiError { stdout: Some("Task one\n".to_string()), stderr: Some(String::new()), ..ApiError::new("INVALID_JOB_REQUEST", "Validation Error: No task") };
+
+    assert_matches_schema(&AcceptedJob { job_id: "dummy_id_1".to_string(), state: JobState::QUEUED, href: "/api/v2/jobs/dummy_id_1".to_string() });
+    assert_matches_schema(&new_error());
+    assert_matches_schema(&ErrorResponse { error: new_error() });
+}
+
+#[test]
+fn event_schemas_match_structs() {
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp/somewhere", vec!["--tag".to_string(), "env,prod".to_string()]);
+
+    for event in vec![Event::job(&request, &JobState::DONE, &JobOutcome::SUCCEEDED), Event::server("drain")] {
+        match event {
+            Event::Job(ref job_event) => assert_matches_schema(job_event),
+            Event::Server(ref server_event) => assert_matches_schema(server_event),
+        }
+    }
+}
+
+#[test]
+fn webhook_schemas_match_structs() {
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp/somewhere", vec![]);
+    let subscription_request = SubscriptionRequest {
+        url: "https://hooks.acme.com/factotum".to_string(),
+        secret: "0123456789abcdef".to_string(),
+        events: vec!["job.failed".to_string()],
+        job_name: Some("dummy".to_string()),
+    };
+    let job_event = match Event::job(&request, &JobState::DONE, &JobOutcome::FAILED) {
+        Event::Job(job_event) => job_event,
+        Event::Server(_) => unreachable!(),
+    };
+
+    assert_matches_schema(&subscription_request);
+    assert_matches_schema(&SubscriptionSummary::new(WebhookSubscription::new(subscription_request, Some("ops".to_string()))));
+    assert_matches_schema(&WebhookPayload { id: "dummy_id_1.job.failed.0".to_string(), event: "job.failed".to_string(), timestamp: job_event.timestamp, job: &job_event });
+}
+
+#[test]
+fn catalog_schemas_match_structs() {
+    let definition = JobDefinition {
+        name: "acme-main".to_string(),
+        factfile_path: "/tmp/somewhere".to_string(),
+        factfile: None,
+        factfile_args: vec!["--no-colour".to_string()],
+        env: Some(json!({ "bucket": "s3://acme" })),
+        tags: Some(json!({ "team": "data" })),
+        timeout_seconds: Some(3600),
+        pool: Some("batch".to_string()),
+        overridable_args: vec!["--start".to_string()],
+        overridable_env: vec!["bucket".to_string()],
+        registered_by: Some("ops".to_string()),
+        registered_at: Some("2017-05-01T12:00:00Z".parse().unwrap()),
+    };
+    let overrides = RunRequest {
+        start: Some(json!("step-2")),
+        dry_run: Some(json!(true)),
+        callback_url: Some("https://hooks.acme.com/factotum".to_string()),
+        ..RunRequest::default()
+    };
+
+    assert_matches_schema(&definition);
+    assert_matches_schema(&overrides);
+}
+
+#[test]
+fn openapi_document_refs_resolve() {
+    let document = get_openapi_document();
+    let schemas = get_fields(&document["components"]["schemas"]);
+    let mut refs = Vec::new();
+    collect_refs(&document, &mut refs);
+
+    assert!(!refs.is_empty());
+    for reference in refs {
+        let name = reference.trim_start_matches("#/components/schemas/");
+        assert!(schemas.contains(name), "unresolved reference {}", reference);
+    }
+}
+
+#[test]
+fn submit_documents_timeout_seconds() {
+    let document = get_openapi_document();
+    let request_schema = &document["paths"]["/submit"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"];
+    assert_eq!(Some("#/components/schemas/JobRequest"), request_schema.as_str());
+
+    let timeout_seconds = &document["components"]["schemas"]["JobRequest"]["properties"]["timeoutSeconds"];
+    assert_eq!(Some(1), timeout_seconds["minimum"].as_u64());
+    assert!(timeout_seconds["description"].as_str().unwrap().starts_with("Accepted on submission"));
+    let submitted: JobRequest = serde_json::from_str(r#"{ "jobName": "dummy", "factfilePath": "/tmp", "timeoutSeconds": 60 }"#).unwrap();
+    assert_eq!(Some(60), submitted.timeout_seconds);
+}
+
+#[test]
+fn openapi_document_has_version_and_paths() {
+    let document = get_openapi_document();
+
+    assert_eq!(Some(OPENAPI_VERSION), document["openapi"].as_str());
+    assert_eq!(Some(::VERSION), document["info"]["version"].as_str());
+    assert!(document["paths"]["/api/v2/jobs"]["post"].is_object());
+    assert!(document["paths"]["/openapi.json"]["get"].is_object());
+}
@@ -12,45 +12,109 @@
 // governing permissions and limitations there under.
 //
 
+use std::cmp;
+use std::error;
 use std::fmt;
-use std::panic;
-use std::thread::Result as ThreadResult;
-use consul::Client;
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, UTC};
 use serde_json;
 use base64::decode;
 
+use factotum_server::consul::{ConsulClient, ConsulSecurity};
+use factotum_server::metrics::METRICS;
 use factotum_server::server::JobRequest;
 
 #[cfg(test)]
-mod tests;
+pub mod tests;
+
+/// Version of the `JobEntry` layout written by this server. Bump it whenever a field
+/// is added or changes meaning, and teach `migrate_entry` how to upgrade older entries.
+pub const JOB_ENTRY_SCHEMA_VERSION: u32 = 4;
+
+// entries written before `schemaVersion` existed
+const JOB_ENTRY_LEGACY_VERSION: u32 = 1;
+
+// every entry is read on each scan of the namespace, so only enough of the end of
+// the output to show why a job failed is kept
+pub const JOB_OUTPUT_MAX_BYTES: usize = 4 * 1024;
 
 pub trait Persistence {
     fn id(&self) -> &str;
-    fn set_key(&self, key: &str, value: &str) -> ThreadResult<()>;
-    fn get_key(&self, key: &str) -> ThreadResult<Option<String>>;
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError>;
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError>;
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError>;
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError>;
+    fn delete_key_if_index(&self, key: &str, index: u64) -> Result<(), PersistenceError>;
     fn prepend_namespace(&self, key: &str) -> String;
+
+    /// Waits up to `timeout` for the key to change from `index`. Backends without
+    /// blocking queries fall back to polling.
+    fn wait_for_key(&self, key: &str, _index: u64, timeout: Duration) -> Result<Option<(String, u64)>, PersistenceError> {
+        thread::sleep(cmp::min(timeout, Duration::from_millis(WAIT_POLL_INTERVAL_MILLIS)));
+        self.get_key_with_index(key)
+    }
+
+    /// Lists the keys under the prefix. Backends that can't leave the values out
+    /// fetch them and drop them.
+    fn get_keys(&self, prefix: &str) -> Result<Vec<String>, PersistenceError> {
+        self.get_keys_with_index(prefix).map(|keys| keys.into_iter().map(|(key, _, _)| key).collect())
+    }
+
+    /// Checks the backend can be reached by reading a key; the key not existing
+    /// still counts as reachable.
+    fn check_connection(&self) -> Result<(), PersistenceError> {
+        self.get_key_with_index(&self.prepend_namespace(CONNECTION_CHECK_KEY)).map(|_| ())
+    }
+}
+
+const WAIT_POLL_INTERVAL_MILLIS: u64 = 1000;
+
+const CONNECTION_CHECK_KEY: &'static str = "readiness";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PersistenceError {
+    Unavailable(String),
+    NotFound(String),
+    Corrupt(String),
+    Conflict(String),
 }
 
+impl fmt::Display for PersistenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PersistenceError::Unavailable(ref msg) => write!(f, "Persistence Error: storage unavailable - {}", msg),
+            PersistenceError::NotFound(ref msg) => write!(f, "Persistence Error: not found - {}", msg),
+            PersistenceError::Corrupt(ref msg) => write!(f, "Persistence Error: corrupt entry - {}", msg),
+            PersistenceError::Conflict(ref msg) => write!(f, "Persistence Error: conflict - {}", msg),
+        }
+    }
+}
+
+impl error::Error for PersistenceError {}
+
 #[derive(Clone, Debug)]
 pub struct ConsulPersistence {
     server_id: String,
     host: String,
     port: u32,
     namespace: String,
+    security: ConsulSecurity,
 }
 
 impl ConsulPersistence {
-    pub fn new(wrapped_id: Option<String>, wrapped_host: Option<String>, wrapped_port: Option<u32>, wrapped_namespace: Option<String>) -> ConsulPersistence {
+    pub fn new(wrapped_id: Option<String>, wrapped_host: Option<String>, wrapped_port: Option<u32>, wrapped_namespace: Option<String>, security: ConsulSecurity) -> ConsulPersistence {
         ConsulPersistence {
             server_id: if let Some(server_id) = wrapped_id { server_id } else { ::CONSUL_NAME_DEFAULT.to_string() },
             host: if let Some(host) = wrapped_host { host } else { ::CONSUL_IP_DEFAULT.to_string() },
             port: if let Some(port) = wrapped_port { port } else { ::CONSUL_PORT_DEFAULT },
             namespace: if let Some(namespace) = wrapped_namespace { namespace } else { ::CONSUL_NAMESPACE_DEFAULT.to_string() },
+            security: security,
         }
     }
 
-    fn client(&self) -> Client {
-        Client::new(&format!("{}:{}", self.host.clone(), self.port.clone()))
+    pub fn client(&self) -> ConsulClient {
+        ConsulClient::new(&self.host, self.port, &self.security)
     }
 }
 
@@ -59,62 +123,268 @@ impl Persistence for ConsulPersistence {
         &self.server_id
     }
 
-    fn set_key(&self, key: &str, value: &str) -> ThreadResult<()> {
-        panic::catch_unwind(|| {
-            self.client().keystore.set_key(key.to_owned(), value.to_owned())
-        })
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
+        self.client().kv_put(key, value).map_err(PersistenceError::Unavailable).map_err(count_error)
     }
 
-    fn get_key(&self, key: &str) -> ThreadResult<Option<String>> {
-        panic::catch_unwind(|| {
-            self.client().keystore.get_key(key.to_owned())
-        })
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        self.client().kv_get(key)
+            .map(|pair| pair.map(|p| (p.value.unwrap_or_default(), p.modify_index)))
+            .map_err(PersistenceError::Unavailable)
+            .map_err(count_error)
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError> {
+        match self.client().kv_put_cas(key, value, index) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(count_error(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))),
+            Err(msg) => Err(count_error(PersistenceError::Unavailable(msg))),
+        }
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        self.client().kv_list(prefix)
+            .map(|pairs| pairs.into_iter().map(|p| (p.key, p.value.unwrap_or_default(), p.modify_index)).collect())
+            .map_err(PersistenceError::Unavailable)
+            .map_err(count_error)
+    }
+
+    fn get_keys(&self, prefix: &str) -> Result<Vec<String>, PersistenceError> {
+        self.client().kv_keys(prefix).map_err(PersistenceError::Unavailable).map_err(count_error)
+    }
+
+    fn delete_key_if_index(&self, key: &str, index: u64) -> Result<(), PersistenceError> {
+        match self.client().kv_delete_cas(key, index) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(count_error(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))),
+            Err(msg) => Err(count_error(PersistenceError::Unavailable(msg))),
+        }
     }
 
     fn prepend_namespace(&self, job_ref: &str) -> String {
         apply_namespace_if_absent(&self.namespace, job_ref)
     }
+
+    fn wait_for_key(&self, key: &str, index: u64, timeout: Duration) -> Result<Option<(String, u64)>, PersistenceError> {
+        self.client().kv_get_blocking(key, index, timeout)
+            .map(|pair| pair.map(|p| (p.value.unwrap_or_default(), p.modify_index)))
+            .map_err(PersistenceError::Unavailable)
+            .map_err(count_error)
+    }
+}
+
+// storage errors are counted as they leave Consul, however the caller goes on to handle them
+fn count_error(e: PersistenceError) -> PersistenceError {
+    METRICS.persistence_error(&e);
+    e
+}
+
+pub fn set_entry<T: Persistence>(persistence: &T, job_ref: &str, job_request: &JobRequest, state: &JobState, outcome: &JobOutcome) -> Result<(), PersistenceError>
+{
+    set_entry_with_result(persistence, job_ref, job_request, state, outcome, None)
 }
 
-pub fn set_entry<T: Persistence>(persistence: &T, job_ref: &str, job_request: &JobRequest, state: &JobState, outcome: &JobOutcome) -> bool
+pub fn set_entry_with_result<T: Persistence>(persistence: &T, job_ref: &str, job_request: &JobRequest, state: &JobState, outcome: &JobOutcome, result: Option<&JobResult>) -> Result<(), PersistenceError>
 {
-    let job_entry = JobEntry::new(state, job_request, persistence.id(), outcome);
+    let mut job_entry = JobEntry::new(state, job_request, persistence.id(), outcome);
+    job_entry.result = result.cloned();
     let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
 
     let job_key = persistence.prepend_namespace(job_ref);
-    let result = persistence.set_key(&job_key, &job_entry_json);
+    persistence.set_key(&job_key, &job_entry_json).map_err(|e| {
+        error!("Could not set K/V: {}::{} - {}", job_key, job_entry_json, e);
+        e
+    })
+}
+
+pub fn get_entry<T: Persistence>(persistence: &T, job_ref: &str) -> Result<JobEntry, PersistenceError> {
+    let job_key = persistence.prepend_namespace(job_ref);
 
-    match result {
-        Ok(_) => true,
-        Err(_) => {
-            error!("Persistence Error: could not set K/V: {}::{}", job_key, job_entry_json);
-            false
+    match persistence.get_key_with_index(&job_key) {
+        Ok(Some((base64_str, index))) => {
+            let job_entry = decode_entry(&job_key, &base64_str)?;
+            Ok(upgrade_entry(persistence, &job_key, job_entry, index))
+        },
+        Ok(None) => Err(PersistenceError::NotFound(format!("no job entry found for id='{}'", job_ref))),
+        Err(e) => {
+            error!("Could not get key: {} - {}", job_key, e);
+            Err(e)
         },
     }
 }
 
-pub fn get_entry<T: Persistence>(persistence: &T, job_ref: &str) -> Option<JobEntry> {
+/// Blocks until the job entry reaches DONE or `timeout` runs out, returning the
+/// latest entry either way. Each wait picks up from the last ModifyIndex seen, so
+/// updates written by any server in the cluster wake the waiter.
+pub fn wait_for_entry<T: Persistence>(persistence: &T, job_ref: &str, timeout: Duration) -> Result<JobEntry, PersistenceError> {
+    let deadline = Instant::now() + timeout;
     let job_key = persistence.prepend_namespace(job_ref);
-    let result = persistence.get_key(&job_key);
 
-    let keystore_val = match result {
-        Ok(state) => state,
-        Err(_) => {
-            error!("Persistence Error: could not get key: {}", job_ref);
-            None
+    let (mut base64_str, mut index) = match persistence.get_key_with_index(&job_key)? {
+        Some(value) => value,
+        None => return Err(PersistenceError::NotFound(format!("no job entry found for id='{}'", job_ref))),
+    };
+    loop {
+        let job_entry = decode_entry(&job_key, &base64_str)?;
+        let now = Instant::now();
+        if job_entry.state == JobState::DONE || now >= deadline {
+            return Ok(migrate_entry(job_entry))
+        }
+        match persistence.wait_for_key(&job_key, index, deadline - now)? {
+            Some((next_base64_str, next_index)) => {
+                base64_str = next_base64_str;
+                // a lower index means the key was recreated, start watching from scratch
+                index = if next_index < index { 0 } else { next_index };
+            },
+            None => return Err(PersistenceError::NotFound(format!("job entry for id='{}' was removed while waiting", job_ref))),
+        }
+    }
+}
+
+/// Fetches every job entry stored directly under the namespace, along with its
+/// key and ModifyIndex. Corrupt entries are logged and skipped.
+pub fn get_entries<T: Persistence>(persistence: &T) -> Result<Vec<StoredJobEntry>, PersistenceError> {
+    let prefix = persistence.prepend_namespace("");
+    let keys = persistence.get_keys_with_index(&prefix)?;
+
+    let entries = keys.into_iter()
+        .filter(|&(ref key, _, _)| !key[prefix.len()..].contains("/"))
+        .filter_map(|(key, base64_str, index)| {
+            decode_entry(&key, &base64_str).ok().map(|job_entry| StoredJobEntry {
+                key: key,
+                index: index,
+                entry: migrate_entry(job_entry),
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+pub fn delete_entry<T: Persistence>(persistence: &T, stored_entry: &StoredJobEntry) -> Result<(), PersistenceError> {
+    persistence.delete_key_if_index(&stored_entry.key, stored_entry.index)
+}
+
+/// QUEUED entries form the shared queue in cluster mode, oldest first.
+pub fn get_queued_entries<T: Persistence>(persistence: &T) -> Result<Vec<StoredJobEntry>, PersistenceError> {
+    let mut queued: Vec<StoredJobEntry> = get_entries(persistence)?
+        .into_iter()
+        .filter(|stored_entry| stored_entry.entry.state == JobState::QUEUED)
+        .collect();
+    queued.sort_by(|a, b| a.entry.last_updated.cmp(&b.entry.last_updated));
+    Ok(queued)
+}
+
+/// Rewrites a listed entry in a new state, using check-and-set against the index
+/// it was listed at. Taking a QUEUED entry this way means only one server in the
+/// cluster picks up the job; putting a WORKING entry back in the queue means a
+/// server that is still running it never loses it.
+///
+/// Returns the entry as written along with its new index, so the next swap can be
+/// made against it. Fails with `Conflict` if the entry changed before it was read back.
+pub fn swap_entry<T: Persistence>(persistence: &T, stored_entry: &StoredJobEntry, state: &JobState, outcome: &JobOutcome, result: Option<&JobResult>) -> Result<StoredJobEntry, PersistenceError> {
+    let mut job_entry = JobEntry::new(state, &stored_entry.entry.job_request, persistence.id(), outcome);
+    job_entry.result = result.cloned();
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+
+    persistence.set_key_if_index(&stored_entry.key, &job_entry_json, stored_entry.index)?;
+
+    // Consul doesn't return the new ModifyIndex from a check-and-set write
+    match persistence.get_key_with_index(&stored_entry.key)? {
+        Some((base64_str, index)) => {
+            if decode_entry(&stored_entry.key, &base64_str)? != job_entry {
+                return Err(PersistenceError::Conflict(format!("key '{}' was modified straight after it was written", stored_entry.key)))
+            }
+            Ok(StoredJobEntry {
+                key: stored_entry.key.clone(),
+                index: index,
+                entry: job_entry,
+            })
         },
+        None => Err(PersistenceError::Conflict(format!("key '{}' was removed straight after it was written", stored_entry.key))),
+    }
+}
+
+/// Writes a QUEUED entry using check-and-set against the key's current ModifyIndex,
+/// so only one server sharing the namespace can claim a job id. Fails with
+/// `Conflict` if the job is already queued/running or another server claimed it first.
+pub fn claim_entry<T: Persistence>(persistence: &T, job_ref: &str, job_request: &JobRequest) -> Result<(), PersistenceError> {
+    let job_key = persistence.prepend_namespace(job_ref);
+
+    let index = match persistence.get_key_with_index(&job_key)? {
+        Some((base64_str, index)) => {
+            let job_entry = decode_entry(&job_key, &base64_str)?;
+            if job_entry.state != JobState::DONE {
+                debug!("Job entry id='{}' already in state='{}'", job_ref, job_entry.state);
+                return Err(PersistenceError::Conflict(format!("job id='{}' is already {}", job_ref, job_entry.state)))
+            }
+            index
+        },
+        None => 0,
     };
 
-    // decode base64 string
-    // deserialize to JobEntry
-    if let Some(base64_str) = keystore_val {
-        let decode_result = &decode(&base64_str).expect("Base64 string decode error");
-        let raw_value = ::std::str::from_utf8(decode_result).expect("Error converting from bytes to string");
-        let job_entry: JobEntry = serde_json::from_str(raw_value).expect("JSON decode error");
-        Some(job_entry)
-    } else {
-        None
+    let job_entry = JobEntry::new(&JobState::QUEUED, job_request, persistence.id(), &JobOutcome::WAITING);
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+
+    persistence.set_key_if_index(&job_key, &job_entry_json, index).map_err(|e| {
+        debug!("Could not claim job entry id='{}' at index {} - {}", job_ref, index, e);
+        e
+    })
+}
+
+/// Rewrites an entry from an older server at the current schema version, using
+/// check-and-set so a concurrent update from another server is never clobbered.
+/// Entries from newer servers are left alone, as rewriting them would drop fields
+/// this server doesn't know about. A failed upgrade doesn't fail the read.
+fn upgrade_entry<T: Persistence>(persistence: &T, job_key: &str, job_entry: JobEntry, index: u64) -> JobEntry {
+    if job_entry.schema_version > JOB_ENTRY_SCHEMA_VERSION {
+        debug!("Job entry '{}' has newer schemaVersion={}, reading known fields only", job_key, job_entry.schema_version);
+        return job_entry
+    }
+    if job_entry.schema_version == JOB_ENTRY_SCHEMA_VERSION {
+        return job_entry
+    }
+
+    let from_version = job_entry.schema_version;
+    let upgraded = migrate_entry(job_entry);
+    let job_entry_json = serde_json::to_string(&upgraded).expect("JSON compact encode error");
+    match persistence.set_key_if_index(job_key, &job_entry_json, index) {
+        Ok(_) => info!("Upgraded job entry '{}' from schemaVersion={} to {}", job_key, from_version, JOB_ENTRY_SCHEMA_VERSION),
+        Err(PersistenceError::Conflict(_)) => debug!("Job entry '{}' changed before it could be upgraded, skipping", job_key),
+        Err(e) => warn!("Could not upgrade job entry '{}' - {}", job_key, e),
     }
+    upgraded
+}
+
+/// Brings an entry decoded at an older schema version up to date in memory.
+pub fn migrate_entry(mut job_entry: JobEntry) -> JobEntry {
+    if job_entry.schema_version < 2 {
+        // v1 entries were never timestamped, `lastUpdated` stays unknown until the next write
+        job_entry.schema_version = 2;
+    }
+    if job_entry.schema_version < 3 {
+        // v2 entries have no captured `result`, which defaults to none
+        job_entry.schema_version = 3;
+    }
+    if job_entry.schema_version < 4 {
+        // v3 job requests have no `callbackUrl`, structured options or `factfileHash`,
+        // which all default to none; they always ran from `factfilePath`
+        job_entry.schema_version = 4;
+    }
+    job_entry
+}
+
+// decode base64 string
+// deserialize to JobEntry, unknown fields from newer servers are ignored
+fn decode_entry(job_key: &str, base64_str: &str) -> Result<JobEntry, PersistenceError> {
+    let corrupt = |reason: String| {
+        let e = PersistenceError::Corrupt(format!("'{}' {}", job_key, reason));
+        error!("{}", e);
+        METRICS.persistence_error(&e);
+        e
+    };
+    let decode_result = decode(base64_str).map_err(|e| corrupt(format!("is not valid base64: {}", e)))?;
+    let raw_value = ::std::str::from_utf8(&decode_result).map_err(|e| corrupt(format!("is not valid UTF-8: {}", e)))?;
+    serde_json::from_str(raw_value).map_err(|e| corrupt(format!("is not a valid job entry: {}", e)))
 }
 
 pub fn apply_namespace_if_absent(namespace: &str, id: &str) -> String {
@@ -155,19 +425,77 @@ impl fmt::Display for JobOutcome {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JobEntry {
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     pub state: JobState,
     pub job_request: JobRequest,
     pub last_run_from: String,
     pub last_outcome: JobOutcome,
+    #[serde(default)]
+    pub last_updated: Option<DateTime<UTC>>,
+    #[serde(default)]
+    pub result: Option<JobResult>,
 }
 
 impl JobEntry {
     pub fn new(state: &JobState, request: &JobRequest, server_id: &str, outcome: &JobOutcome) -> JobEntry {
         JobEntry {
+            schema_version: JOB_ENTRY_SCHEMA_VERSION,
             state: state.to_owned(),
             job_request: request.to_owned(),
             last_run_from: server_id.to_owned(),
             last_outcome: outcome.to_owned(),
+            last_updated: Some(UTC::now()),
+            result: None,
+        }
+    }
+
+    /// The entry as shown to clients, without the job's `callbackUrl` as it may
+    /// carry credentials for the receiver.
+    pub fn redacted(mut self) -> JobEntry {
+        self.job_request.callback_url = None;
+        self
+    }
+}
+
+/// Exit code and captured output of a finished run. Output beyond
+/// `JOB_OUTPUT_MAX_BYTES` is cut from the front, keeping the end where errors show up.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResult {
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl JobResult {
+    pub fn new(exit_code: Option<i32>, stdout: &str, stderr: &str) -> JobResult {
+        JobResult {
+            exit_code: exit_code,
+            stdout: truncate_output(stdout, JOB_OUTPUT_MAX_BYTES),
+            stderr: truncate_output(stderr, JOB_OUTPUT_MAX_BYTES),
         }
     }
 }
+
+pub fn truncate_output(output: &str, max_bytes: usize) -> String {
+    if output.len() <= max_bytes {
+        return output.to_owned()
+    }
+    let mut start = output.len() - max_bytes;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    output[start..].to_owned()
+}
+
+fn legacy_schema_version() -> u32 {
+    JOB_ENTRY_LEGACY_VERSION
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredJobEntry {
+    pub key: String,
+    pub index: u64,
+    pub entry: JobEntry,
+}
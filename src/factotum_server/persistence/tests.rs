@@ -15,6 +15,57 @@
 use super::*;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use base64::encode;
+
+/// Stores values base64 encoded, as Consul hands them back; shared with the
+/// tests of modules that keep their own records under the namespace.
+#[derive(Debug)]
+pub struct ConsulKeyValueMock {
+    pub ref_map: RefCell<HashMap<String, String>>,
+}
+
+impl ConsulKeyValueMock {
+    pub fn new() -> Self {
+        ConsulKeyValueMock {
+            ref_map: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Persistence for ConsulKeyValueMock {
+    fn id(&self) -> &str {
+        "test_kv"
+    }
+
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
+        self.ref_map.borrow_mut().insert(key.to_owned(), encode(value.as_bytes()));
+        Ok(())
+    }
+
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Ok(self.ref_map.borrow().get(key).map(|value| (value.to_owned(), 1)))
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, _: u64) -> Result<(), PersistenceError> {
+        self.set_key(key, value)
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        Ok(self.ref_map.borrow().iter()
+               .filter(|&(key, _)| key.starts_with(prefix))
+               .map(|(key, value)| (key.to_owned(), value.to_owned(), 1))
+               .collect())
+    }
+
+    fn delete_key_if_index(&self, key: &str, _: u64) -> Result<(), PersistenceError> {
+        self.ref_map.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn prepend_namespace(&self, key: &str) -> String {
+        apply_namespace_if_absent("com.test/namespace", key)
+    }
+}
 
 #[derive(Debug)]
 struct GoodPersistenceMock {
@@ -36,16 +87,40 @@ impl Persistence for GoodPersistenceMock {
         &self.id
     }
 
-    fn set_key(&self, key: &str, value: &str) -> ThreadResult<()> {
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
         let mut map = self.ref_map.borrow_mut();
         map.insert(key.to_owned(), value.to_owned());
         Ok(())
     }
 
-    fn get_key(&self, key: &str) -> ThreadResult<Option<String>> {
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
         let map = self.ref_map.borrow();
         let value = map.get(key);
-        Ok(value.map(|s| s.to_owned()))
+        Ok(value.map(|s| (s.to_owned(), 1)))
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.borrow_mut();
+        let current_index = if map.contains_key(key) { 1 } else { 0 };
+        if current_index != index {
+            return Err(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))
+        }
+        map.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        let map = self.ref_map.borrow();
+        Ok(map.iter()
+              .filter(|&(key, _)| key.starts_with(prefix))
+              .map(|(key, value)| (key.to_owned(), value.to_owned(), 1))
+              .collect())
+    }
+
+    fn delete_key_if_index(&self, key: &str, _: u64) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.borrow_mut();
+        map.remove(key);
+        Ok(())
     }
 
     fn prepend_namespace(&self, key: &str) -> String {
@@ -61,12 +136,24 @@ impl Persistence for BadPersistenceMock {
         "something_bad"
     }
 
-    fn set_key(&self, _: &str, _: &str) -> ThreadResult<()> {
-        Err(Box::new("setting key bad"))
+    fn set_key(&self, _: &str, _: &str) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("setting key bad".to_string()))
+    }
+
+    fn get_key_with_index(&self, _: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Err(PersistenceError::Unavailable("getting key bad".to_string()))
+    }
+
+    fn set_key_if_index(&self, _: &str, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("setting key bad".to_string()))
+    }
+
+    fn get_keys_with_index(&self, _: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        Err(PersistenceError::Unavailable("getting keys bad".to_string()))
     }
 
-    fn get_key(&self, _: &str) -> ThreadResult<Option<String>> {
-        Err(Box::new("getting key bad"))
+    fn delete_key_if_index(&self, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("deleting key bad".to_string()))
     }
 
     fn prepend_namespace(&self, key: &str) -> String {
@@ -81,7 +168,13 @@ fn set_entry_fail_error() {
 
     let result = set_entry(&persistence, "fake_entry", &request, &JobState::QUEUED, &JobOutcome::WAITING);
 
-    assert_eq!(false, result);
+    assert_eq!(Err(PersistenceError::Unavailable("setting key bad".to_string())), result);
+}
+
+#[test]
+fn check_connection_reads_a_key() {
+    assert_eq!(Ok(()), GoodPersistenceMock::new("test_connection").check_connection());
+    assert_eq!(Err(PersistenceError::Unavailable("getting key bad".to_string())), BadPersistenceMock.check_connection());
 }
 
 #[test]
@@ -95,7 +188,7 @@ fn set_entry_new_success() {
     let entry = borrowed.get("com.test/namespace/fake_entry").unwrap();
     let job_entry: JobEntry = serde_json::from_str(entry).expect("JSON decode error");
 
-    assert_eq!(true, result);
+    assert_eq!(Ok(()), result);
     assert_eq!(JobState::QUEUED, job_entry.state);
     assert_eq!("test_set".to_string(), job_entry.last_run_from);
     assert_eq!(JobOutcome::WAITING, job_entry.last_outcome);
@@ -108,7 +201,46 @@ fn get_entry_fail_none() {
 
     let result = get_entry(&persistence, "fake_entry");
 
-    assert_eq!(None, result);
+    assert_eq!(Err(PersistenceError::Unavailable("getting key bad".to_string())), result);
+}
+
+#[test]
+fn get_entry_fail_not_found() {
+    let persistence = GoodPersistenceMock::new("test_get");
+
+    let result = get_entry(&persistence, "missing_entry");
+
+    assert_eq!(Err(PersistenceError::NotFound("no job entry found for id='missing_entry'".to_string())), result);
+}
+
+#[test]
+fn get_entry_fail_corrupt_base64() {
+    let persistence = GoodPersistenceMock::new("test_get");
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_entry".to_string(), "!!not base64!!".to_string());
+    }
+
+    match get_entry(&persistence, "dummy_entry") {
+        Err(PersistenceError::Corrupt(msg)) => assert!(msg.starts_with("'com.test/namespace/dummy_entry' is not valid base64")),
+        other => panic!("Expected corrupt entry error, got {:?}", other),
+    }
+}
+
+#[test]
+fn get_entry_fail_corrupt_json() {
+    use base64::encode;
+
+    let persistence = GoodPersistenceMock::new("test_get");
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_entry".to_string(), encode(b"{\"state\":\"UNKNOWN\"}"));
+    }
+
+    match get_entry(&persistence, "dummy_entry") {
+        Err(PersistenceError::Corrupt(msg)) => assert!(msg.starts_with("'com.test/namespace/dummy_entry' is not a valid job entry")),
+        other => panic!("Expected corrupt entry error, got {:?}", other),
+    }
 }
 
 #[test]
@@ -155,3 +287,324 @@ fn get_entry_with_namespace_success_key() {
     assert_eq!(JobOutcome::WAITING, result.last_outcome);
     assert_eq!(request, result.job_request);
 }
+
+#[derive(Debug)]
+struct RacingPersistenceMock;
+
+impl Persistence for RacingPersistenceMock {
+    fn id(&self) -> &str {
+        "something_racing"
+    }
+
+    fn set_key(&self, _: &str, _: &str) -> Result<(), PersistenceError> {
+        Ok(())
+    }
+
+    fn get_key_with_index(&self, _: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Ok(None)
+    }
+
+    fn set_key_if_index(&self, _: &str, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Conflict("lost the race".to_string()))
+    }
+
+    fn get_keys_with_index(&self, _: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        Ok(vec![])
+    }
+
+    fn delete_key_if_index(&self, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Conflict("lost the race".to_string()))
+    }
+
+    fn prepend_namespace(&self, key: &str) -> String {
+        key.to_string()
+    }
+}
+
+fn insert_encoded_entry(persistence: &GoodPersistenceMock, key: &str, state: &JobState, request: &JobRequest) {
+    use base64::encode;
+
+    let job_entry = JobEntry::new(state, request, "other_server", &JobOutcome::WAITING);
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+    let mut map = persistence.ref_map.borrow_mut();
+    map.insert(key.to_string(), encode(job_entry_json.as_bytes()));
+}
+
+#[test]
+fn claim_entry_new_success() {
+    let persistence = GoodPersistenceMock::new("test_claim");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+
+    let result = claim_entry(&persistence, "dummy_id_1", &request);
+
+    let borrowed = &persistence.ref_map.borrow();
+    let entry = borrowed.get("com.test/namespace/dummy_id_1").unwrap();
+    let job_entry: JobEntry = serde_json::from_str(entry).expect("JSON decode error");
+
+    assert_eq!(Ok(()), result);
+    assert_eq!(JobState::QUEUED, job_entry.state);
+    assert_eq!("test_claim".to_string(), job_entry.last_run_from);
+    assert_eq!(request, job_entry.job_request);
+}
+
+#[test]
+fn claim_entry_done_success() {
+    let persistence = GoodPersistenceMock::new("test_claim");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::DONE, &request);
+
+    let result = claim_entry(&persistence, "dummy_id_1", &request);
+
+    assert_eq!(Ok(()), result);
+}
+
+#[test]
+fn claim_entry_already_queued() {
+    let persistence = GoodPersistenceMock::new("test_claim");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::WORKING, &request);
+
+    let result = claim_entry(&persistence, "dummy_id_1", &request);
+
+    assert_eq!(Err(PersistenceError::Conflict("job id='dummy_id_1' is already WORKING".to_string())), result);
+}
+
+#[test]
+fn claim_entry_lost_race() {
+    let persistence = RacingPersistenceMock;
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+
+    let result = claim_entry(&persistence, "dummy_id_1", &request);
+
+    assert_eq!(Err(PersistenceError::Conflict("lost the race".to_string())), result);
+}
+
+#[test]
+fn claim_entry_fail_error() {
+    let persistence = BadPersistenceMock;
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+
+    let result = claim_entry(&persistence, "dummy_id_1", &request);
+
+    assert_eq!(Err(PersistenceError::Unavailable("getting key bad".to_string())), result);
+}
+
+#[test]
+fn get_entries_skips_nested_and_corrupt_keys() {
+    let persistence = GoodPersistenceMock::new("test_get_entries");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::DONE, &request);
+    insert_encoded_entry(&persistence, "com.test/namespace/nested/dummy_id_2", &JobState::DONE, &request);
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/corrupt".to_string(), "!!not base64!!".to_string());
+        map.insert("com.other/namespace/dummy_id_3".to_string(), "ignored".to_string());
+    }
+
+    let entries = get_entries(&persistence).unwrap();
+
+    assert_eq!(1, entries.len());
+    assert_eq!("com.test/namespace/dummy_id_1", entries[0].key);
+    assert_eq!(JobState::DONE, entries[0].entry.state);
+}
+
+#[test]
+fn get_entries_fail_error() {
+    let persistence = BadPersistenceMock;
+
+    let result = get_entries(&persistence);
+
+    assert_eq!(Err(PersistenceError::Unavailable("getting keys bad".to_string())), result);
+}
+
+#[test]
+fn delete_entry_success() {
+    let persistence = GoodPersistenceMock::new("test_delete");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::DONE, &request);
+    let stored_entry = get_entries(&persistence).unwrap().remove(0);
+
+    let result = delete_entry(&persistence, &stored_entry);
+
+    assert_eq!(Ok(()), result);
+    assert!(persistence.ref_map.borrow().is_empty());
+}
+
+#[test]
+fn get_entry_upgrades_legacy_schema() {
+    use base64::encode;
+
+    let persistence = GoodPersistenceMock::new("test_get");
+    let legacy_json = r#"{"state":"DONE","jobRequest":{"jobId":"dummy_id_1","jobName":"dummy","factfilePath":"/fake/path","factfileArgs":[]},"lastRunFrom":"old_server","lastOutcome":"SUCCEEDED"}"#;
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_id_1".to_string(), encode(legacy_json.as_bytes()));
+    }
+
+    let result = get_entry(&persistence, "dummy_id_1").unwrap();
+
+    assert_eq!(JOB_ENTRY_SCHEMA_VERSION, result.schema_version);
+    assert_eq!(JobState::DONE, result.state);
+    assert_eq!("old_server".to_string(), result.last_run_from);
+    assert_eq!(None, result.last_updated);
+
+    let borrowed = &persistence.ref_map.borrow();
+    let stored: JobEntry = serde_json::from_str(borrowed.get("com.test/namespace/dummy_id_1").unwrap()).expect("JSON decode error");
+    assert_eq!(result, stored);
+}
+
+#[test]
+fn get_entry_tolerates_newer_schema() {
+    use base64::encode;
+
+    let persistence = GoodPersistenceMock::new("test_get");
+    let newer_json = r#"{"schemaVersion":99,"state":"QUEUED","jobRequest":{"jobId":"dummy_id_1","jobName":"dummy","factfilePath":"/fake/path","factfileArgs":[]},"lastRunFrom":"new_server","lastOutcome":"WAITING","lastUpdated":null,"someFutureField":{"nested":true}}"#;
+    let encoded_entry = encode(newer_json.as_bytes());
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_id_1".to_string(), encoded_entry.clone());
+    }
+
+    let result = get_entry(&persistence, "dummy_id_1").unwrap();
+
+    assert_eq!(99, result.schema_version);
+    assert_eq!(JobState::QUEUED, result.state);
+    assert_eq!(Some(&encoded_entry), persistence.ref_map.borrow().get("com.test/namespace/dummy_id_1"));
+}
+
+#[test]
+fn get_entry_current_schema_not_rewritten() {
+    let persistence = GoodPersistenceMock::new("test_get");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::DONE, &request);
+    let before = persistence.ref_map.borrow().get("com.test/namespace/dummy_id_1").cloned();
+
+    let result = get_entry(&persistence, "dummy_id_1").unwrap();
+
+    assert_eq!(JOB_ENTRY_SCHEMA_VERSION, result.schema_version);
+    assert_eq!(before, persistence.ref_map.borrow().get("com.test/namespace/dummy_id_1").cloned());
+}
+
+#[test]
+fn set_entry_with_result_success() {
+    let persistence = GoodPersistenceMock::new("test_set");
+    let request = JobRequest::new("", "dummy", "/fake/path", vec![]);
+    let result = JobResult::new(Some(1), "out", "err");
+
+    set_entry_with_result(&persistence, "fake_entry", &request, &JobState::DONE, &JobOutcome::FAILED, Some(&result)).unwrap();
+
+    let borrowed = &persistence.ref_map.borrow();
+    let job_entry: JobEntry = serde_json::from_str(borrowed.get("com.test/namespace/fake_entry").unwrap()).expect("JSON decode error");
+    assert_eq!(Some(result), job_entry.result);
+}
+
+#[test]
+fn job_result_keeps_output_tail() {
+    let stdout = format!("{}{}", "a".repeat(JOB_OUTPUT_MAX_BYTES), "the end");
+
+    let result = JobResult::new(Some(1), &stdout, "err");
+
+    assert_eq!(JOB_OUTPUT_MAX_BYTES, result.stdout.len());
+    assert!(result.stdout.ends_with("the end"));
+    assert_eq!("err", result.stderr);
+}
+
+#[test]
+fn truncate_output_keeps_tail() {
+    assert_eq!("short", truncate_output("short", 10));
+    assert_eq!("6789", truncate_output("0123456789", 4));
+    assert_eq!("é", truncate_output("aé", 2));
+    assert_eq!("", truncate_output("aé", 1));
+}
+
+#[test]
+fn migrate_entry_legacy_version() {
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    let mut job_entry = JobEntry::new(&JobState::DONE, &request, "test_migrate", &JobOutcome::SUCCEEDED);
+    job_entry.schema_version = 1;
+
+    assert_eq!(JOB_ENTRY_SCHEMA_VERSION, migrate_entry(job_entry).schema_version);
+}
+
+// Job entry that is WORKING at index 5 and finishes once watched
+#[derive(Debug)]
+struct WatchedPersistenceMock {
+    watched_index: RefCell<Option<u64>>,
+}
+
+impl WatchedPersistenceMock {
+    fn encoded_entry(state: &JobState) -> String {
+        use base64::encode;
+
+        let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+        let job_entry = JobEntry::new(state, &request, "test_wait", &JobOutcome::RUNNING);
+        encode(serde_json::to_string(&job_entry).unwrap().as_bytes())
+    }
+}
+
+impl Persistence for WatchedPersistenceMock {
+    fn id(&self) -> &str {
+        "test_wait"
+    }
+
+    fn set_key(&self, _: &str, _: &str) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("watched entry is read only".to_string()))
+    }
+
+    fn get_key_with_index(&self, _: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Ok(Some((WatchedPersistenceMock::encoded_entry(&JobState::WORKING), 5)))
+    }
+
+    fn set_key_if_index(&self, _: &str, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("watched entry is read only".to_string()))
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        let key = "dummy_id_1";
+        let encoded = WatchedPersistenceMock::encoded_entry(&JobState::WORKING);
+        Ok(if key.starts_with(prefix) { vec![(key.to_string(), encoded, 5)] } else { vec![] })
+    }
+
+    fn delete_key_if_index(&self, _: &str, _: u64) -> Result<(), PersistenceError> {
+        Err(PersistenceError::Unavailable("watched entry is read only".to_string()))
+    }
+
+    fn prepend_namespace(&self, key: &str) -> String {
+        key.to_string()
+    }
+
+    fn wait_for_key(&self, _: &str, index: u64, _: Duration) -> Result<Option<(String, u64)>, PersistenceError> {
+        *self.watched_index.borrow_mut() = Some(index);
+        Ok(Some((WatchedPersistenceMock::encoded_entry(&JobState::DONE), 6)))
+    }
+}
+
+#[test]
+fn wait_for_entry_wakes_on_change() {
+    let persistence = WatchedPersistenceMock { watched_index: RefCell::new(None) };
+
+    let result = wait_for_entry(&persistence, "dummy_id_1", Duration::from_secs(300)).unwrap();
+
+    assert_eq!(JobState::DONE, result.state);
+    assert_eq!(Some(5), *persistence.watched_index.borrow());
+}
+
+#[test]
+fn wait_for_entry_timeout_returns_current_state() {
+    let persistence = GoodPersistenceMock::new("test_wait");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/fake/path", vec![]);
+    insert_encoded_entry(&persistence, "com.test/namespace/dummy_id_1", &JobState::QUEUED, &request);
+
+    let result = wait_for_entry(&persistence, "dummy_id_1", Duration::from_millis(10)).unwrap();
+
+    assert_eq!(JobState::QUEUED, result.state);
+}
+
+#[test]
+fn wait_for_entry_fail_not_found() {
+    let persistence = GoodPersistenceMock::new("test_wait");
+
+    let result = wait_for_entry(&persistence, "missing_entry", Duration::from_secs(300));
+
+    assert_eq!(Err(PersistenceError::NotFound("no job entry found for id='missing_entry'".to_string())), result);
+}
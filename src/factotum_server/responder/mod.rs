@@ -14,51 +14,151 @@
 
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
+use std::time::Duration;
+use iron::headers::{CacheControl, CacheDirective};
 use iron::mime::*;
+use iron::modifiers::Header;
 use iron::prelude::*;
+use iron::response::WriteBody;
 use iron::status;
 use iron::status::Status;
 use url::Url;
 use bodyparser;
+use chrono::UTC;
 use persistent::{Read, State};
+use router::Router;
 use serde::Serialize;
 use serde_json;
 
-use factotum_server::{Paths, Server, Storage, Updates};
+use factotum_server::{Leader, Paths, Retention, Server, Storage, Updates};
+use factotum_server::auth::Caller;
+use factotum_server::catalog;
+use factotum_server::catalog::{JobDefinition, RunRequest};
+use factotum_server::command;
 use factotum_server::command::Execution;
 use factotum_server::dispatcher::{Dispatch, Query};
+use factotum_server::events;
+use factotum_server::events::{Event, EventFilter, EventStream, EVENTS};
+use factotum_server::factfiles;
+use factotum_server::factfiles::TempFactfile;
+use factotum_server::janitor;
+use factotum_server::janitor::{ExpiredEntry, RetentionPolicy};
+use factotum_server::metrics;
+use factotum_server::metrics::METRICS;
+use factotum_server::openapi;
 use factotum_server::persistence;
-use factotum_server::persistence::{Persistence, JobState};
-use factotum_server::server::{ServerManager, SettingsRequest, JobRequest, ValidationError};
+use factotum_server::persistence::{Persistence, PersistenceError, JobEntry, JobOutcome, JobState};
+use factotum_server::server;
+use factotum_server::server::{DryRun, ServerManager, SettingsRequest, JobRequest, ValidationError};
+use factotum_server::webhook;
+use factotum_server::webhook::{SubscriptionRequest, SubscriptionSummary, WebhookSubscription};
+
+pub mod v2;
 
 #[cfg(test)]
 mod tests;
 
+// the Consul check gives up after 5s, so the liveness probe answers well before that
+const DISPATCHER_LIVENESS_TIMEOUT_SECS: u64 = 3;
+
+// each wait holds one of the server's request threads for up to `WAIT_TIMEOUT_MAX`
+pub const MAX_CONCURRENT_WAITS: usize = 16;
+
+lazy_static! {
+    static ref WAITS: WaitSlots = WaitSlots::new(MAX_CONCURRENT_WAITS);
+}
+
+/// Counts the `/jobs/:id/wait` requests in progress, so long polls can't take
+/// every request thread and leave nothing to answer probes or submissions.
+#[derive(Clone, Debug)]
+pub struct WaitSlots {
+    max_waits: usize,
+    active: Arc<AtomicUsize>,
+}
+
+impl WaitSlots {
+    pub fn new(max_waits: usize) -> WaitSlots {
+        WaitSlots {
+            max_waits: max_waits,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<WaitSlot> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_waits {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return None
+        }
+        Some(WaitSlot { active: self.active.clone() })
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+/// Frees its slot when dropped, however the wait ends.
+#[derive(Debug)]
+pub struct WaitSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for WaitSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum SubmissionError {
+    NotRunning(String),
+    Invalid(ValidationError),
+    QueueFull,
+    Duplicate,
+    Persistence(PersistenceError),
+}
+
+impl fmt::Display for SubmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SubmissionError::NotRunning(ref state) => write!(f, "Server in [{}] state - cannot submit job", state),
+            SubmissionError::Invalid(ref e) => write!(f, "{}", e),
+            SubmissionError::QueueFull => write!(f, "Queue is full, cannot add job"),
+            SubmissionError::Duplicate => write!(f, "Job is already being processed"),
+            SubmissionError::Persistence(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
 // JSON Response Structs
 
 #[derive(Debug, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct ResponseMessage {
-    message: String
+pub struct ResponseMessage {
+    pub message: String
 }
 
 #[derive(Debug, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct FactotumServerStatus {
-    version: String,
-    server: ServerStatus,
-    dispatcher: DispatcherStatus,
+pub struct FactotumServerStatus {
+    pub version: String,
+    pub server: ServerStatus,
+    pub dispatcher: DispatcherStatus,
 }
 
 #[derive(Debug, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct ServerStatus {
-    start_time: String,
-    up_time: String,
-    state: String
+pub struct ServerStatus {
+    pub start_time: String,
+    pub up_time: String,
+    pub state: String,
+    pub is_leader: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize)]
@@ -66,6 +166,7 @@ struct ServerStatus {
 pub struct DispatcherStatus {
     pub workers: WorkerStatus,
     pub jobs: JobStatus,
+    pub persistence: PersistenceStatus,
 }
 
 #[derive(Debug,PartialEq, Serialize)]
@@ -83,6 +184,132 @@ pub struct JobStatus {
     pub in_queue: usize,
 }
 
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistenceStatus {
+    pub pending_writes: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmissionResult {
+    pub job_id: String,
+    pub state: JobState,
+    pub outcome: JobOutcome,
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+impl SubmissionResult {
+    pub fn new(job_entry: JobEntry) -> SubmissionResult {
+        let (exit_code, stdout, stderr) = match job_entry.result {
+            Some(result) => (result.exit_code, Some(result.stdout), Some(result.stderr)),
+            None => (None, None, None),
+        };
+        SubmissionResult {
+            job_id: job_entry.job_request.job_id,
+            state: job_entry.state,
+            outcome: job_entry.last_outcome,
+            exit_code: exit_code,
+            stdout: stdout,
+            stderr: stderr,
+        }
+    }
+}
+
+/// A rejected job request, with anything its dry run printed.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationFailure {
+    pub message: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ValidationFailure {
+    pub fn new(error: &ValidationError) -> ValidationFailure {
+        ValidationFailure {
+            message: error.to_string(),
+            stdout: error.stdout.clone(),
+            stderr: error.stderr.clone(),
+        }
+    }
+}
+
+/// A job request that would be accepted, as it would be run. The plan is the
+/// dry run's stdout, line by line.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DryRunResult {
+    pub job_id: String,
+    pub job_name: String,
+    pub factfile_path: String,
+    pub factfile_args: Vec<String>,
+    pub plan: Vec<String>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl DryRunResult {
+    pub fn new(dry_run: DryRun) -> DryRunResult {
+        DryRunResult {
+            job_id: dry_run.job_request.job_id,
+            job_name: dry_run.job_request.job_name,
+            factfile_path: dry_run.job_request.factfile_path,
+            factfile_args: dry_run.job_request.factfile_args,
+            plan: dry_run.stdout.lines().filter(|line| !line.trim().is_empty()).map(|line| line.to_string()).collect(),
+            stdout: dry_run.stdout,
+            stderr: dry_run.stderr,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeReport {
+    pub passed: bool,
+    pub checks: Vec<ProbeCheck>,
+}
+
+impl ProbeReport {
+    pub fn new(checks: Vec<ProbeCheck>) -> ProbeReport {
+        ProbeReport {
+            passed: checks.iter().all(|check| check.passed),
+            checks: checks,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeCheck {
+    pub name: String,
+    pub passed: bool,
+    pub message: String,
+}
+
+impl ProbeCheck {
+    pub fn new(name: &str, result: Result<String, String>) -> ProbeCheck {
+        let (passed, message) = match result {
+            Ok(message) => (true, message),
+            Err(message) => (false, message),
+        };
+        ProbeCheck {
+            name: name.to_string(),
+            passed: passed,
+            message: message,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionReport {
+    pub policy: RetentionPolicy,
+    pub expired: Vec<ExpiredEntry>,
+}
+
 // Response handlers
 
 pub fn api(request: &mut Request) -> IronResult<Response> {
@@ -111,7 +338,12 @@ pub fn status(request: &mut Request) -> IronResult<Response> {
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
 
-    let response = get_server_status(server_manager, jobs_channel.clone());
+    let leadership = match request.get::<Read<Leader>>() {
+        Ok(leadership) => leadership,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let response = get_server_status(server_manager, jobs_channel.clone(), leadership.is_leader());
     return_json(status::Ok, encode(&url, response))
 }
 
@@ -133,15 +365,83 @@ pub fn settings(request: &mut Request) -> IronResult<Response> {
 
 pub fn submit(request: &mut Request) -> IronResult<Response> {
     let url: Url = request.url.clone().into();
-    let request_body = request.get::<bodyparser::Struct<JobRequest>>();
+    let wait_timeout = match get_submit_wait_timeout(&url) {
+        Ok(wait_timeout) => wait_timeout,
+        Err(msg) => return return_json(status::BadRequest, create_warn_response(&url, &msg))
+    };
+    let request_body = attribute_to_caller(request.get::<bodyparser::Struct<JobRequest>>(), get_caller_identity(request));
+    let (accepted, persistence) = {
+        let server_rwlock = match request.get::<State<Server>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let server = match server_rwlock.write() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let storage_rwlock = match request.get::<State<Storage>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let persistence = match storage_rwlock.write() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let command_store_rwlock = match request.get::<Read<Paths>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let command_store = match command_store_rwlock.read() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let sender_mutex = match request.get::<Read<Updates>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let jobs_channel = match sender_mutex.try_lock() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+
+        (process_submission(&url, request_body, server.deref(), persistence.deref(), command_store.deref(), jobs_channel.deref()), persistence.deref().clone())
+    };
+
+    // locks are released by now, so a synchronous submission doesn't hold up other requests while it waits
+    let (status, response) = match (accepted, wait_timeout) {
+        (Ok(job_id), Some(timeout)) => wait_for_submitted_job(&url, &persistence, &job_id, timeout),
+        (Ok(job_id), None) => get_submission_response(&url, &job_id),
+        (Err(rejected), _) => rejected,
+    };
+    return_json(status, response)
+}
+
+pub fn validate(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let request_body = attribute_to_caller(request.get::<bodyparser::Struct<JobRequest>>(), get_caller_identity(request));
     let server_rwlock = match request.get::<State<Server>>() {
         Ok(lock) => lock,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let server = match server_rwlock.write() {
+    let server = match server_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let command_store_rwlock = match request.get::<Read<Paths>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let command_store = match command_store_rwlock.read() {
         Ok(result) => result,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
+
+    let (status, response) = process_validation(&url, request_body, server.deref(), command_store.deref(), JobRequest::dry_run);
+    return_json(status, response)
+}
+
+pub fn check(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
     let storage_rwlock = match request.get::<State<Storage>>() {
         Ok(lock) => lock,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
@@ -150,42 +450,300 @@ pub fn submit(request: &mut Request) -> IronResult<Response> {
         Ok(result) => result,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let command_store_rwlock = match request.get::<Read<Paths>>() {
+
+    let (status, response) = check_job_request(&url, persistence.deref());
+    return_json(status, response)
+}
+
+pub fn wait(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let job_id = request.extensions.get::<Router>().and_then(|params| params.find("id")).unwrap_or("").to_string();
+    let _slot = match WAITS.try_acquire() {
+        Some(slot) => slot,
+        None => {
+            let msg = format!("Error: Limit of {} concurrent waits reached, try again later or poll /check", MAX_CONCURRENT_WAITS);
+            return return_json(status::ServiceUnavailable, create_warn_response(&url, &msg))
+        }
+    };
+    debug!("Waiting on job entry id='{}', {} of {} waits", job_id, WAITS.active_count(), MAX_CONCURRENT_WAITS);
+    // clone so the storage lock isn't held for the whole wait
+    let persistence = {
+        let storage_rwlock = match request.get::<State<Storage>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let reader = match storage_rwlock.read() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        reader.deref().clone()
+    };
+
+    let (status, response) = wait_for_job_request(&url, &persistence, &job_id);
+    return_json(status, response)
+}
+
+pub fn retention(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let policy = match request.get::<Read<Retention>>() {
+        Ok(policy) => policy,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let storage_rwlock = match request.get::<State<Storage>>() {
         Ok(lock) => lock,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let command_store = match command_store_rwlock.read() {
+    let persistence = match storage_rwlock.read() {
         Ok(result) => result,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let sender_mutex = match request.get::<Read<Updates>>() {
+
+    let (status, response) = get_retention_report(&url, persistence.deref(), policy.deref());
+    return_json(status, response)
+}
+
+pub fn openapi(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    return_json(status::Ok, encode(&url, openapi::get_openapi_document()))
+}
+
+pub fn metrics(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let mutex = match request.get::<Read<Updates>>() {
         Ok(lock) => lock,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let jobs_channel = match sender_mutex.try_lock() {
+    let jobs_channel = match mutex.try_lock() {
         Ok(result) => result,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
 
-    let (status, response) = process_submission(&url, request_body, server.deref(), persistence.deref(), command_store.deref(), jobs_channel.deref());
+    // the dispatcher answers from memory, in cluster mode from the last poll of the shared queue
+    METRICS.dispatcher_status(&get_dispatcher_status(jobs_channel.clone()));
+    let content_type = metrics::PROMETHEUS_CONTENT_TYPE.parse::<Mime>().expect(&format!("Unable to parse Mime type for '{}'", metrics::PROMETHEUS_CONTENT_TYPE));
+    Ok(Response::with((content_type, status::Ok, METRICS.render())))
+}
+
+pub fn events(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let subscription = match EVENTS.subscribe(get_event_filter(&url)) {
+        Ok(subscription) => subscription,
+        Err(msg) => return return_json(status::ServiceUnavailable, create_warn_response(&url, &msg))
+    };
+    debug!("Event stream opened, {} of {} subscribers", EVENTS.subscriber_count(), events::MAX_SUBSCRIBERS);
+
+    let content_type = events::EVENT_STREAM_CONTENT_TYPE.parse::<Mime>().expect(&format!("Unable to parse Mime type for '{}'", events::EVENT_STREAM_CONTENT_TYPE));
+    let body: Box<WriteBody> = Box::new(EventStream::new(subscription));
+    Ok(Response::with((content_type, status::Ok, Header(CacheControl(vec![CacheDirective::NoCache])), body)))
+}
+
+pub fn create_subscription(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let request_body = request.get::<bodyparser::Struct<SubscriptionRequest>>();
+    let caller = get_caller_identity(request);
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let (status, response) = process_subscription(&url, request_body, caller, persistence.deref());
     return_json(status, response)
 }
 
-pub fn check(request: &mut Request) -> IronResult<Response> {
+pub fn list_subscriptions(request: &mut Request) -> IronResult<Response> {
     let url: Url = request.url.clone().into();
     let storage_rwlock = match request.get::<State<Storage>>() {
         Ok(lock) => lock,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
-    let persistence = match storage_rwlock.write() {
+    let persistence = match storage_rwlock.read() {
         Ok(result) => result,
         Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
     };
 
-    let (status, response) = check_job_request(&url, persistence.deref());
+    let (status, response) = get_subscription_list(&url, persistence.deref());
+    return_json(status, response)
+}
+
+pub fn delete_subscription(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let subscription_id = request.extensions.get::<Router>().and_then(|params| params.find("id")).unwrap_or("").to_string();
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let (status, response) = remove_subscription(&url, persistence.deref(), &subscription_id);
+    return_json(status, response)
+}
+
+pub fn register_catalog_job(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let request_body = request.get::<bodyparser::Struct<JobDefinition>>();
+    let caller = get_caller_identity(request);
+    let server_rwlock = match request.get::<State<Server>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let server = match server_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let (status, response) = process_catalog_registration(&url, request_body, caller, server.deref(), persistence.deref());
+    return_json(status, response)
+}
+
+pub fn list_catalog_jobs(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let (status, response) = get_catalog_list(&url, persistence.deref());
+    return_json(status, response)
+}
+
+pub fn delete_catalog_job(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let name = request.extensions.get::<Router>().and_then(|params| params.find("name")).unwrap_or("").to_string();
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let (status, response) = remove_catalog_job(&url, persistence.deref(), &name);
+    return_json(status, response)
+}
+
+pub fn run_catalog_job(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let wait_timeout = match get_submit_wait_timeout(&url) {
+        Ok(wait_timeout) => wait_timeout,
+        Err(msg) => return return_json(status::BadRequest, create_warn_response(&url, &msg))
+    };
+    let name = request.extensions.get::<Router>().and_then(|params| params.find("name")).unwrap_or("").to_string();
+    let request_body = request.get::<bodyparser::Struct<RunRequest>>();
+    let caller = get_caller_identity(request);
+    let (accepted, persistence) = {
+        let server_rwlock = match request.get::<State<Server>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let server = match server_rwlock.write() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let storage_rwlock = match request.get::<State<Storage>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let persistence = match storage_rwlock.write() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let command_store_rwlock = match request.get::<Read<Paths>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let command_store = match command_store_rwlock.read() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let sender_mutex = match request.get::<Read<Updates>>() {
+            Ok(lock) => lock,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+        let jobs_channel = match sender_mutex.try_lock() {
+            Ok(result) => result,
+            Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+        };
+
+        (process_catalog_run(&url, &name, request_body, caller, server.deref(), persistence.deref(), command_store.deref(), jobs_channel.deref(), JobRequest::validate, is_requests_queue_full), persistence.deref().clone())
+    };
+
+    let (status, response) = match (accepted, wait_timeout) {
+        (Ok(job_id), Some(timeout)) => wait_for_submitted_job(&url, &persistence, &job_id, timeout),
+        (Ok(job_id), None) => get_submission_response(&url, &job_id),
+        (Err(rejected), _) => rejected,
+    };
     return_json(status, response)
 }
 
+pub fn health(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let mutex = match request.get::<Read<Updates>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    // waits its turn rather than failing the probe when another request holds the channel
+    let jobs_channel = match mutex.lock() {
+        Ok(result) => result.clone(),
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let report = ProbeReport::new(vec![
+        ProbeCheck::new("dispatcher", check_dispatcher(jobs_channel, Duration::from_secs(DISPATCHER_LIVENESS_TIMEOUT_SECS))),
+    ]);
+    return_probe(&url, report)
+}
+
+pub fn ready(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let server_rwlock = match request.get::<State<Server>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let server = match server_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let command_rwlock = match request.get::<Read<Paths>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+    let command_store = match command_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_json(status::ServiceUnavailable, encode(&url, e.to_string()))
+    };
+
+    let report = get_readiness_report(server.deref(), persistence.deref(), command_store.deref());
+    return_probe(&url, report)
+}
+
 // Helpers
 
 fn get_help_message() -> serde_json::Value {
@@ -211,29 +769,179 @@ fn get_help_message() -> serde_json::Value {
                 "body": {
                     "jobName": "com.acme-main",
                     "factfilePath": "/com.acme-main/factfile",
-                    "factfileArgs": "[ --start step-2 ]"
+                    "factfile": "(optional) factfile JSON to run instead of factfilePath",
+                    "factfileArgs": [ "--no-colour" ],
+                    "tags": { "env": "prod" },
+                    "env": { "bucket": "s3://acme" },
+                    "start": "step-2",
+                    "constraints": { "host": "worker-1" },
+                    "dryRun": false,
+                    "callbackUrl": "https://hooks.acme.com/factotum (optional, needs --callback-secret-file)"
                 },
+                "params": "pretty=1, wait=true, timeout=[seconds]"
+            },
+            "/validate": {
+                "function": "Runs the /submit checks and a Factotum dry run without queueing the job, returning the job id, the dry run's plan, stdout and stderr.",
+                "body": "as /submit",
                 "params": "pretty=1"
             },
             "/check": {
                 "function": "Fetches the state of a job by the ID.",
                 "params": "pretty=1, id=[id string]"
+            },
+            "/jobs/[id]/wait": {
+                "function": "Waits for a job to finish and returns its entry, or its current entry with 202 if the timeout runs out first.",
+                "params": "pretty=1, timeout=[seconds, default 60, max 600]"
+            },
+            "/retention": {
+                "function": "Dry run of the janitor: lists finished job entries that would be removed.",
+                "params": "pretty=1"
+            },
+            "/healthz": {
+                "function": "Liveness probe, also used by the Consul service registration. Returns 503 when the dispatcher stops answering.",
+                "params": "pretty=1"
+            },
+            "/readyz": {
+                "function": "Readiness probe. Returns 503 unless Consul is reachable, the Factotum binary is executable and the server is in the run state.",
+                "params": "pretty=1"
+            },
+            "/events": {
+                "function": "Streams job state changes and server state changes from this server as server-sent events.",
+                "params": "jobName=[name], tag=[key] or tag=[key,value] (repeatable)"
+            },
+            "/subscriptions": {
+                "function": "POST registers a webhook for job.submitted|job.started|job.succeeded|job.failed|job.cancelled (all if no events), signed with HMAC-SHA256 of the body in X-Factotum-Signature. GET lists them without secrets.",
+                "body": {
+                    "url": "https://hooks.acme.com/factotum",
+                    "secret": "at least 16 characters",
+                    "events": [ "job.succeeded", "job.failed" ],
+                    "jobName": "com.acme-main (optional)"
+                },
+                "params": "pretty=1"
+            },
+            "/subscriptions/[id]": {
+                "function": "DELETE removes a webhook subscription.",
+                "params": "pretty=1"
+            },
+            "/catalog": {
+                "function": "POST registers a named job, replacing any with the same name. GET lists them.",
+                "body": {
+                    "name": "acme-main",
+                    "factfilePath": "/com.acme-main/factfile (or 'factfile' content)",
+                    "factfileArgs": [ "--no-colour" ],
+                    "env": { "bucket": "s3://acme" },
+                    "tags": { "team": "data" },
+                    "timeoutSeconds": 3600,
+                    "pool": "batch (passed as the 'pool' tag)"
+                },
+                "params": "pretty=1"
+            },
+            "/catalog/[name]": {
+                "function": "DELETE removes a catalog job.",
+                "params": "pretty=1"
+            },
+            "/catalog/[name]/run": {
+                "function": "Submits a catalog job. The optional body adds to its args and overrides its env and tags.",
+                "body": {
+                    "factfileArgs": [ "--start", "step-2" ],
+                    "env": { "bucket": "s3://acme-test" },
+                    "tags": { "run": "backfill" },
+                    "dryRun": false,
+                    "callbackUrl": "https://hooks.acme.com/factotum (optional)"
+                },
+                "params": "pretty=1, wait=true, timeout=[seconds]"
+            },
+            "/metrics": {
+                "function": "Returns queue, worker, job, persistence and request metrics in the Prometheus text format."
+            },
+            "/openapi.json": {
+                "function": "Returns the OpenAPI 3 description of every endpoint, including request and response schemas."
+            },
+            "/api/v2/jobs": {
+                "function": "Submits a job to the queue, returning 202 and a link to the job. Errors have a machine-readable code.",
+                "body": {
+                    "jobName": "com.acme-main",
+                    "factfilePath": "/com.acme-main/factfile",
+                    "factfileArgs": [ "--no-colour" ],
+                    "tags": { "env": "prod" },
+                    "start": "step-2"
+                },
+                "params": "pretty=1"
+            },
+            "/api/v2/jobs/[id]": {
+                "function": "Fetches a job entry by the ID, or 404 if there is none.",
+                "params": "pretty=1"
+            },
+            "/api/v2/server": {
+                "function": "Returns general information about the server, same as /status.",
+                "params": "pretty=1"
             }
         }
     )
 }
 
-fn get_server_status(server: &ServerManager, jobs_channel: Sender<Dispatch>) -> FactotumServerStatus {
+// `tag` can be repeated, each given as `key` or `key,value` like the factfile `--tag` arg
+fn get_event_filter(url: &Url) -> EventFilter {
+    let query_pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    let job_name = query_pairs.iter().find(|&&(ref key, _)| key == "jobName").map(|&(_, ref value)| value.clone());
+    let tags: Vec<String> = query_pairs.into_iter().filter(|&(ref key, _)| key == "tag").map(|(_, value)| value).collect();
+    EventFilter::new(job_name, server::get_tag_map(&tags))
+}
+
+// The dispatcher answers status queries from memory in both modes - the shared queue
+// size is cached by the cluster poller - so a Consul outage is left to readiness.
+fn check_dispatcher(jobs_channel: Sender<Dispatch>, timeout: Duration) -> Result<String, String> {
+    let (tx, rx) = mpsc::channel();
+    if jobs_channel.send(Dispatch::StatusUpdate(Query::new("liveness_query", tx))).is_err() {
+        return Err("Dispatcher has stopped".to_string())
+    }
+    match rx.recv_timeout(timeout) {
+        Ok(_) => Ok("Dispatcher is answering".to_string()),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(format!("Dispatcher did not answer within {}s", timeout.as_secs())),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("Dispatcher has stopped".to_string()),
+    }
+}
+
+fn get_readiness_report<T: Persistence, U: Execution>(server: &ServerManager, persistence: &T, command_store: &U) -> ProbeReport {
+    let consul = persistence.check_connection()
+        .map(|_| "Consul is reachable".to_string())
+        .map_err(|e| e.to_string());
+    // the path is only logged, the probe is answered without a token
+    let factotum = command_store.get_command(::FACTOTUM)
+        .and_then(|path| command::check_executable(&path))
+        .map(|_| "Factotum binary is executable".to_string())
+        .map_err(|e| {
+            warn!("Readiness: {}", e);
+            "Factotum binary is missing or not executable".to_string()
+        });
+    let state = if server.is_running() {
+        Ok(format!("Server in [{}] state", server.state))
+    } else {
+        Err(format!("Server in [{}] state - not accepting jobs", server.state))
+    };
+    ProbeReport::new(vec![
+        ProbeCheck::new("consul", consul),
+        ProbeCheck::new("factotum", factotum),
+        ProbeCheck::new("state", state),
+    ])
+}
+
+fn get_dispatcher_status(jobs_channel: Sender<Dispatch>) -> DispatcherStatus {
     let (tx, rx) = mpsc::channel();
     jobs_channel.send(Dispatch::StatusUpdate(Query::new("status_query", tx))).expect("Job requests channel receiver has been deallocated");
-    let dispatcher_status = rx.recv().expect("Server status senders have been disconnected");
+    rx.recv().expect("Server status senders have been disconnected")
+}
+
+fn get_server_status(server: &ServerManager, jobs_channel: Sender<Dispatch>, is_leader: bool) -> FactotumServerStatus {
+    let dispatcher_status = get_dispatcher_status(jobs_channel);
 
     FactotumServerStatus {
         version: ::VERSION.to_string(),
         server: ServerStatus {
             start_time: server.get_start_time(),
             up_time: server.get_uptime(),
-            state: server.state.to_string()
+            state: server.state.to_string(),
+            is_leader: is_leader,
         },
         dispatcher: dispatcher_status,
     }
@@ -260,75 +968,291 @@ fn process_settings(url: &Url, request_body: Result<Option<SettingsRequest>, bod
     };
 
     // update server state
+    if server.state != validated_settings.state {
+        EVENTS.publish(Event::server(&validated_settings.state));
+    }
     server.state = validated_settings.state.to_string();
     (status::Ok, create_ok_response(url, &format!("Update acknowledged: [state: {}]", server.state)))
 }
 
-fn process_submission<T, U>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>) -> (Status, String) where
+fn process_submission<T, U>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>) -> Result<String, (Status, String)> where
     T: Persistence,
     U: Execution {
     process_valid_submission(url, request_body, server, persistence, command_store, jobs_channel, JobRequest::validate, is_requests_queue_full)
 }
 
-fn process_valid_submission<T, U, F, G>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>, validate: F, is_requests_queue_full: G) -> (Status, String) where
+fn process_valid_submission<T, U, F, G>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>, validate: F, is_requests_queue_full: G) -> Result<String, (Status, String)> where
     T: Persistence,
     U: Execution,
     F: Fn(JobRequest, &U) -> Result<JobRequest, ValidationError>,
     G: Fn(Sender<Dispatch>) -> bool {
     // get body
-    let job_request = match request_body {
-        Ok(Some(decoded_job_request)) => decoded_job_request,
-        Ok(None) => {
-            return (status::BadRequest, create_warn_response(url, "Error: No body found in POST request"))
-        },
-        Err(e) => {
-            return (status::BadRequest, create_warn_response(url, &format!("Error decoding JSON string: {}", e.cause().expect("Cause not found"))))
-        }
+    let job_request = match decode_body(request_body) {
+        Ok(job_request) => job_request,
+        Err(msg) => return Err((status::BadRequest, create_warn_response(url, &msg)))
     };
 
+    accept_job_request(job_request, server, persistence, command_store, jobs_channel, validate, is_requests_queue_full)
+        .map_err(|e| get_rejection(url, e))
+}
+
+fn get_rejection(url: &Url, error: SubmissionError) -> (Status, String) {
+    match error {
+        SubmissionError::Duplicate => (status::Conflict, create_warn_response(url, &SubmissionError::Duplicate.to_string())),
+        SubmissionError::Persistence(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+        SubmissionError::Invalid(e) => get_validation_failure(url, &e),
+        // v1 clients expect everything else to be a bad request
+        e => (status::BadRequest, create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn get_caller_identity(request: &Request) -> Option<String> {
+    request.extensions.get::<Caller>().map(|caller| caller.identity.clone())
+}
+
+// overwrites anything the client put in `submittedBy` so the audit trail can be trusted
+fn attribute_to_caller(request_body: Result<Option<JobRequest>, bodyparser::BodyError>, caller: Option<String>) -> Result<Option<JobRequest>, bodyparser::BodyError> {
+    request_body.map(|body| body.map(|job_request| JobRequest { submitted_by: caller, ..job_request }))
+}
+
+fn decode_body<T>(request_body: Result<Option<T>, bodyparser::BodyError>) -> Result<T, String> {
+    match request_body {
+        Ok(Some(decoded)) => Ok(decoded),
+        Ok(None) => Err("Error: No body found in POST request".to_string()),
+        Err(e) => Err(format!("Error decoding JSON string: {}", e.cause().expect("Cause not found"))),
+    }
+}
+
+/// Checks and claims a job request, then hands it to the dispatcher. Returns the
+/// job id, or why the request was turned away.
+fn accept_job_request<T, U, F, G>(job_request: JobRequest, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>, validate: F, is_requests_queue_full: G) -> Result<String, SubmissionError> where
+    T: Persistence,
+    U: Execution,
+    F: Fn(JobRequest, &U) -> Result<JobRequest, ValidationError>,
+    G: Fn(Sender<Dispatch>) -> bool {
     // check state
     if !server.is_running() {
-        return (status::BadRequest, create_warn_response(url, &format!("Server in [{}] state - cannot submit job", server.state)))
+        return Err(SubmissionError::NotRunning(server.state.clone()))
     }
 
-    // validate job request
-    let mut validated_job_request = match validate(job_request, command_store) {
-        Ok(validated_job_request) => validated_job_request,
-        Err(e) => {
-            return (status::BadRequest, create_warn_response(url, &format!("{}", e)))
-        }
-    };
+    let (job_request, _temp_factfile) = prepare_job_request(job_request, server).map_err(|e| {
+        METRICS.validation_failed();
+        SubmissionError::Invalid(ValidationError::no_output(e))
+    })?;
 
-    if is_job_queued_or_running(persistence, &mut validated_job_request) {
-        return (status::BadRequest, create_warn_response(url, "Job is already being processed"))
-    }
+    // validate job request
+    let validated_job_request = validate(job_request, command_store).map_err(|e| {
+        METRICS.validation_failed();
+        SubmissionError::Invalid(e)
+    })?;
 
     // check queue size
     if is_requests_queue_full(jobs_channel.clone()) {
-        return (status::BadRequest, create_warn_response(url, "Queue is full, cannot add job"))
+        return Err(SubmissionError::QueueFull)
+    }
+
+    // entries keep the hash of inline content, the content is saved just before the claim
+    // so any server taking the job from the shared queue can fetch it
+    let (mut validated_job_request, inline_factfile) = server.factfile_store.take_inline_factfile(validated_job_request);
+    if let Some(ref factfile) = inline_factfile {
+        factfiles::save_content(persistence, factfile).map_err(SubmissionError::Persistence)?;
     }
 
     // append args
     JobRequest::append_job_args(&server.deref(), &mut validated_job_request);
+
+    // claim job id across servers sharing the namespace
+    match persistence::claim_entry(persistence, &validated_job_request.job_id, &validated_job_request) {
+        Ok(_) => EVENTS.publish(Event::job(&validated_job_request, &JobState::QUEUED, &JobOutcome::WAITING)),
+        Err(PersistenceError::Conflict(_)) => return Err(SubmissionError::Duplicate),
+        Err(e) => return Err(SubmissionError::Persistence(e)),
+    }
+
     let job_id = validated_job_request.job_id.clone();
+    if let Some(ref submitted_by) = validated_job_request.submitted_by {
+        info!("Job request jobId:[{}] submitted by [{}]", job_id, submitted_by);
+    }
+    METRICS.job_submitted(&validated_job_request.job_name);
     jobs_channel.send(Dispatch::NewRequest(validated_job_request)).expect("Job requests channel receiver has been deallocated");
-    (status::Ok, create_ok_response(url, &format!("SUBMITTING JOB REQ jobId:[{}]", job_id)))
+    Ok(job_id)
 }
 
-fn is_job_queued_or_running<T: Persistence>(persistence: &T, job_request: &mut JobRequest) -> bool {
-    let mut is_running = false;
-    match persistence::get_entry(persistence, &job_request.job_id) {
-        Some(job_entry) => {
-            debug!("Job entry id='{}' state='{}'", &job_entry.job_request.job_id, &job_entry.state);
-            if job_entry.state != JobState::DONE {
-                is_running = true;
-            }
+fn process_subscription<T: Persistence>(url: &Url, request_body: Result<Option<SubscriptionRequest>, bodyparser::BodyError>, caller: Option<String>, persistence: &T) -> (Status, String) {
+    let subscription_request = match decode_body(request_body) {
+        Ok(subscription_request) => subscription_request,
+        Err(msg) => return (status::BadRequest, create_warn_response(url, &msg))
+    };
+    let validated_request = match SubscriptionRequest::validate(subscription_request) {
+        Ok(validated_request) => validated_request,
+        Err(e) => return (status::BadRequest, create_warn_response(url, &e.to_string()))
+    };
+
+    let subscription = WebhookSubscription::new(validated_request, caller);
+    match webhook::save_subscription(persistence, &subscription) {
+        Ok(_) => {
+            info!("Webhook subscription id:[{}] added for [{}]", subscription.id, subscription.url);
+            (status::Created, encode(url, SubscriptionSummary::new(subscription)))
         },
-        None => {
-            debug!("No state found for job entry id='{}'", job_request.job_id);
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn get_subscription_list<T: Persistence>(url: &Url, persistence: &T) -> (Status, String) {
+    match webhook::get_subscriptions(persistence) {
+        Ok(subscriptions) => {
+            let summaries: Vec<SubscriptionSummary> = subscriptions.into_iter().map(SubscriptionSummary::new).collect();
+            (status::Ok, encode(url, summaries))
         },
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn remove_subscription<T: Persistence>(url: &Url, persistence: &T, subscription_id: &str) -> (Status, String) {
+    if subscription_id.is_empty() {
+        return (status::BadRequest, create_warn_response(url, "Error: No subscription id found in URL path"))
+    }
+    match webhook::delete_subscription(persistence, subscription_id) {
+        Ok(_) => (status::Ok, create_ok_response(url, &format!("Deleted webhook subscription id:[{}]", subscription_id))),
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn process_catalog_registration<T: Persistence>(url: &Url, request_body: Result<Option<JobDefinition>, bodyparser::BodyError>, caller: Option<String>, server: &ServerManager, persistence: &T) -> (Status, String) {
+    let definition = match decode_body(request_body) {
+        Ok(definition) => JobDefinition { registered_by: caller, registered_at: Some(UTC::now()), ..definition },
+        Err(msg) => return (status::BadRequest, create_warn_response(url, &msg))
     };
-    is_running
+    let mut validated_definition = match JobDefinition::validate(definition) {
+        Ok(validated_definition) => validated_definition,
+        Err(e) => return (status::BadRequest, create_warn_response(url, &e.to_string()))
+    };
+    // checked now as well as on every run, so a bad path is caught by whoever registers it
+    if !server.factfile_roots.is_empty() && validated_definition.factfile.is_none() {
+        match factfiles::check_factfile_path(&validated_definition.factfile_path, &server.factfile_roots) {
+            Ok(factfile_path) => validated_definition.factfile_path = factfile_path.to_string_lossy().into_owned(),
+            Err(msg) => return (status::BadRequest, create_warn_response(url, &ValidationError::no_output(msg).to_string()))
+        }
+    }
+
+    match catalog::save_definition(persistence, &validated_definition) {
+        Ok(_) => {
+            info!("Catalog job [{}] registered", validated_definition.name);
+            (status::Created, encode(url, validated_definition))
+        },
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn get_catalog_list<T: Persistence>(url: &Url, persistence: &T) -> (Status, String) {
+    match catalog::get_definitions(persistence) {
+        Ok(definitions) => (status::Ok, encode(url, definitions)),
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn remove_catalog_job<T: Persistence>(url: &Url, persistence: &T, name: &str) -> (Status, String) {
+    if name.is_empty() {
+        return (status::BadRequest, create_warn_response(url, "Error: No catalog job name found in URL path"))
+    }
+    match catalog::delete_definition(persistence, name) {
+        Ok(_) => (status::Ok, create_ok_response(url, &format!("Deleted catalog job [{}]", name))),
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn process_catalog_run<T, U, F, G>(url: &Url, name: &str, request_body: Result<Option<RunRequest>, bodyparser::BodyError>, caller: Option<String>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>, validate: F, is_requests_queue_full: G) -> Result<String, (Status, String)> where
+    T: Persistence,
+    U: Execution,
+    F: Fn(JobRequest, &U) -> Result<JobRequest, ValidationError>,
+    G: Fn(Sender<Dispatch>) -> bool {
+    // overrides are optional, so an empty body runs the job as registered
+    let overrides = match request_body {
+        Ok(None) => RunRequest::default(),
+        other => match decode_body(other) {
+            Ok(overrides) => overrides,
+            Err(msg) => return Err((status::BadRequest, create_warn_response(url, &msg)))
+        }
+    };
+    let definition = match catalog::get_definition(persistence, name) {
+        Ok(definition) => definition,
+        Err(e) => return Err((get_persistence_error_status(&e), create_warn_response(url, &e.to_string())))
+    };
+    if let Err(msg) = definition.check_overrides(&overrides) {
+        return Err((status::BadRequest, create_warn_response(url, &msg)))
+    }
+
+    let job_request = definition.to_job_request(overrides, caller);
+    accept_job_request(job_request, server, persistence, command_store, jobs_channel, validate, is_requests_queue_full)
+        .map_err(|e| get_rejection(url, e))
+}
+
+// the checks made before the dry run, shared by submissions and `/validate`; inline
+// factfile content is dry run from a temporary copy, removed once it's dropped
+fn prepare_job_request(job_request: JobRequest, server: &ServerManager) -> Result<(JobRequest, Option<TempFactfile>), String> {
+    if job_request.callback_url.is_some() && !server.callbacks_enabled {
+        return Err("Invalid 'callbackUrl', this server has no --callback-secret-file to sign callbacks with".to_string())
+    }
+    // only ever set by the server, in place of inline content it accepted
+    let job_request = JobRequest { factfile_hash: None, ..job_request };
+    if job_request.factfile.is_some() {
+        return factfiles::write_temp_factfile(job_request)
+    }
+    // keep submitted paths inside the factfile roots
+    get_factfile_to_run(job_request, server).map(|job_request| (job_request, None))
+}
+
+fn process_validation<U, F>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, command_store: &U, dry_run: F) -> (Status, String) where
+    U: Execution,
+    F: Fn(JobRequest, &U) -> Result<DryRun, ValidationError> {
+    let job_request = match decode_body(request_body) {
+        Ok(job_request) => job_request,
+        Err(msg) => return (status::BadRequest, create_warn_response(url, &msg))
+    };
+    let validated = prepare_job_request(job_request, server)
+        .map_err(ValidationError::no_output)
+        .and_then(|(job_request, _temp_factfile)| dry_run(job_request, command_store));
+    match validated {
+        Ok(dry_run) => (status::Ok, encode(url, DryRunResult::new(dry_run))),
+        Err(e) => get_validation_failure(url, &e),
+    }
+}
+
+fn get_validation_failure(url: &Url, error: &ValidationError) -> (Status, String) {
+    warn!("{}", error);
+    (status::BadRequest, encode(url, ValidationFailure::new(error)))
+}
+
+fn get_factfile_to_run(job_request: JobRequest, server: &ServerManager) -> Result<JobRequest, String> {
+    // without roots any path goes, and an empty one is left for validation to report
+    if server.factfile_roots.is_empty() || job_request.factfile_path.is_empty() {
+        return Ok(job_request)
+    }
+    let factfile_path = factfiles::check_factfile_path(&job_request.factfile_path, &server.factfile_roots)?;
+    Ok(JobRequest { factfile_path: factfile_path.to_string_lossy().into_owned(), ..job_request })
+}
+
+fn get_submission_response(url: &Url, job_id: &str) -> (Status, String) {
+    (status::Ok, create_ok_response(url, &format!("SUBMITTING JOB REQ jobId:[{}]", job_id)))
+}
+
+// `wait=true` holds the response until the job is done, for at most `timeout` seconds
+fn get_submit_wait_timeout(url: &Url) -> Result<Option<Duration>, String> {
+    let query_map = get_query_map(url);
+    match query_map.get("wait").map(|wait| wait.as_str()) {
+        Some("true") | Some("1") => get_timeout_param(&query_map).map(Some),
+        Some("false") | Some("0") | None => Ok(None),
+        Some(wait) => Err(format!("Error: Invalid wait '{}', expected true or false", wait)),
+    }
+}
+
+fn wait_for_submitted_job<T: Persistence>(url: &Url, persistence: &T, job_id: &str, timeout: Duration) -> (Status, String) {
+    match persistence::wait_for_entry(persistence, job_id, timeout) {
+        Ok(job_entry) => {
+            let status = if job_entry.state == JobState::DONE { status::Ok } else { status::Accepted };
+            (status, encode(url, &SubmissionResult::new(job_entry)))
+        },
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
 }
 
 fn is_requests_queue_full(jobs_channel: Sender<Dispatch>) -> bool {
@@ -344,19 +1268,75 @@ fn check_job_request<T: Persistence>(url: &Url, persistence: &T) -> (Status, Str
         None => return (status::BadRequest, create_warn_response(url, "Error: No 'id' found in URL query parameters"))
     };
     let response = match persistence::get_entry(persistence, &job_request_id) {
-        Some(job_entry) => {
+        Ok(job_entry) => {
             debug!("Job entry id='{}' state='{}'", job_entry.job_request.job_id, job_entry.state);
-            job_entry
+            job_entry.redacted()
         },
-        None => {
+        Err(PersistenceError::NotFound(_)) => {
             debug!("No job entry found for id='{}'", &job_request_id);
-            return (status::BadRequest, create_warn_response(url, &format!("Error: No job entry found for id='{}'", &job_request_id)))
+            return (status::NotFound, create_warn_response(url, &format!("Error: No job entry found for id='{}'", &job_request_id)))
+        },
+        Err(e) => {
+            return (get_persistence_error_status(&e), create_warn_response(url, &e.to_string()))
         },
     };
     debug!("{:?}", &response);
     (status::Ok, encode(&url, &response))
 }
 
+fn wait_for_job_request<T: Persistence>(url: &Url, persistence: &T, job_id: &str) -> (Status, String) {
+    if job_id.is_empty() {
+        return (status::BadRequest, create_warn_response(url, "Error: No job id found in URL path"))
+    }
+    let timeout = match get_timeout_param(&get_query_map(url)) {
+        Ok(timeout) => timeout,
+        Err(msg) => return (status::BadRequest, create_warn_response(url, &msg))
+    };
+    match persistence::wait_for_entry(persistence, job_id, timeout) {
+        Ok(job_entry) => {
+            debug!("Finished waiting for job entry id='{}' state='{}'", job_id, job_entry.state);
+            let status = if job_entry.state == JobState::DONE { status::Ok } else { status::Accepted };
+            (status, encode(url, &job_entry.redacted()))
+        },
+        Err(PersistenceError::NotFound(_)) => {
+            (status::NotFound, create_warn_response(url, &format!("Error: No job entry found for id='{}'", job_id)))
+        },
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn get_timeout_param(query_map: &HashMap<String, String>) -> Result<Duration, String> {
+    match query_map.get("timeout") {
+        Some(timeout) => match timeout.parse::<u64>() {
+            Ok(secs) => Ok(Duration::from_secs(if secs > ::WAIT_TIMEOUT_MAX { ::WAIT_TIMEOUT_MAX } else { secs })),
+            Err(_) => Err(format!("Error: Invalid timeout '{}', expected a number of seconds", timeout)),
+        },
+        None => Ok(Duration::from_secs(::WAIT_TIMEOUT_DEFAULT)),
+    }
+}
+
+fn get_retention_report<T: Persistence>(url: &Url, persistence: &T, policy: &RetentionPolicy) -> (Status, String) {
+    match janitor::report_garbage(persistence, policy) {
+        Ok(expired) => {
+            let report = RetentionReport {
+                policy: policy.clone(),
+                expired: expired,
+            };
+            (status::Ok, encode(url, &report))
+        },
+        Err(e) => (get_persistence_error_status(&e), create_warn_response(url, &e.to_string())),
+    }
+}
+
+fn get_persistence_error_status(error: &PersistenceError) -> Status {
+    match *error {
+        PersistenceError::Unavailable(_) => status::ServiceUnavailable,
+        PersistenceError::NotFound(_) => status::NotFound,
+        PersistenceError::Corrupt(_) => status::InternalServerError,
+        PersistenceError::Conflict(_) => status::Conflict,
+    }
+}
+
 fn get_query_map(url: &Url) -> HashMap<String, String> {
     let parser = url.query_pairs().into_owned();
     parser.collect()
@@ -395,6 +1375,16 @@ fn create_warn_response(url: &Url, message: &str) -> String {
     create_response(url, message)
 }
 
+fn return_probe(url: &Url, report: ProbeReport) -> IronResult<Response> {
+    if report.passed {
+        return return_json(status::Ok, encode(url, report))
+    }
+    for check in report.checks.iter().filter(|check| !check.passed) {
+        warn!("Probe check [{}] failed - {}", check.name, check.message);
+    }
+    return_json(status::ServiceUnavailable, encode(url, report))
+}
+
 fn return_json(code: Status, response: String) -> IronResult<Response> {
     let content_type = ::JSON_CONTENT_TYPE.parse::<Mime>().expect(&format!("Unable to parse Mime type for '{}'", ::JSON_CONTENT_TYPE));
     Ok(Response::with((content_type, code, response)))
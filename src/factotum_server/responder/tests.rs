@@ -13,22 +13,25 @@
 //
 
 use super::*;
+use factotum_server::consul::ConsulSecurity;
 use factotum_server::persistence;
-use factotum_server::persistence::{ConsulPersistence, JobEntry, JobOutcome};
-use factotum_server::command::Execution;
+use factotum_server::persistence::{ConsulPersistence, JobEntry, JobResult, JobState, JobOutcome};
+use factotum_server::persistence::PersistenceError;
+use factotum_server::command::{CommandOutput, Execution};
+use factotum_server::factfiles::FactfileStore;
+use std::thread;
 use std::time::Duration;
-use std::thread::Result as ThreadResult;
 use std::cell::RefCell;
 use std::collections::HashMap;
 
 #[derive(Debug)]
-struct GoodPersistenceMock {
+pub struct GoodPersistenceMock {
     id: String,
-    ref_map: RefCell<HashMap<String, String>>,
+    pub ref_map: RefCell<HashMap<String, String>>,
 }
 
 impl GoodPersistenceMock {
-    fn new(id: &str) -> Self {
+    pub fn new(id: &str) -> Self {
         GoodPersistenceMock {
             id: id.to_owned(),
             ref_map: RefCell::new(HashMap::new()),
@@ -41,16 +44,40 @@ impl Persistence for GoodPersistenceMock {
         &self.id
     }
 
-    fn set_key(&self, key: &str, value: &str) -> ThreadResult<()> {
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
         let mut map = self.ref_map.borrow_mut();
         map.insert(key.to_owned(), value.to_owned());
         Ok(())
     }
 
-    fn get_key(&self, key: &str) -> ThreadResult<Option<String>> {
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
         let map = self.ref_map.borrow();
         let value = map.get(key);
-        Ok(value.map(|s| s.to_owned()))
+        Ok(value.map(|s| (s.to_owned(), 1)))
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.borrow_mut();
+        let current_index = if map.contains_key(key) { 1 } else { 0 };
+        if current_index != index {
+            return Err(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))
+        }
+        map.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        let map = self.ref_map.borrow();
+        Ok(map.iter()
+              .filter(|&(key, _)| key.starts_with(prefix))
+              .map(|(key, value)| (key.to_owned(), value.to_owned(), 1))
+              .collect())
+    }
+
+    fn delete_key_if_index(&self, key: &str, _: u64) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.borrow_mut();
+        map.remove(key);
+        Ok(())
     }
 
     fn prepend_namespace(&self, key: &str) -> String {
@@ -117,11 +144,11 @@ fn process_submission_fail_no_body() {
     let url = Url::parse("http://not.a.real.address/").unwrap();
     let request_body = Ok(None);
     let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let persistence = ConsulPersistence::new(None, None, None, None, ConsulSecurity::default());
     let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_command".to_string()];
     let (tx, _) = mpsc::channel();
 
-    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx);
+    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx).unwrap_err();
 
     assert_eq!(status::BadRequest, status);
     assert_eq!(r#"{"message":"Error: No body found in POST request"}"#, response);
@@ -135,11 +162,11 @@ fn process_submission_fail_invalid_json() {
         cause: bodyparser::BodyErrorCause::IoError(::std::io::Error::new(::std::io::ErrorKind::Other, "bad stuff")),
     });
     let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let persistence = ConsulPersistence::new(None, None, None, None, ConsulSecurity::default());
     let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_command".to_string()];
     let (tx, _) = mpsc::channel();
 
-    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx);
+    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx).unwrap_err();
 
     assert_eq!(status::BadRequest, status);
     assert_eq!(r#"{"message":"Error decoding JSON string: bad stuff"}"#, response);
@@ -150,12 +177,12 @@ fn process_submission_fail_server_in_drain_state() {
     let url = Url::parse("http://not.a.real.address/").unwrap();
     let request_body = Ok(Some(JobRequest::new("1", "dummy", "/tmp/somewhere", vec!["--first-arg".to_string()])));
     let mut server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let persistence = ConsulPersistence::new(None, None, None, None, ConsulSecurity::default());
     let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_command".to_string()];
     let (tx, _) = mpsc::channel();
 
     server_manager.state = ::SERVER_STATE_DRAIN.to_string();
-    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx);
+    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx).unwrap_err();
 
     assert_eq!(status::BadRequest, status);
     assert_eq!(r#"{"message":"Server in [drain] state - cannot submit job"}"#, response);
@@ -166,38 +193,38 @@ fn process_submission_fail_invalid_job_request() {
     let url = Url::parse("http://not.a.real.address/").unwrap();
     let request_body = Ok(Some(JobRequest::new("1", "", "/tmp/somewhere", vec!["--first-arg".to_string()])));
     let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let persistence = ConsulPersistence::new(None, None, None, None, ConsulSecurity::default());
     let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_command".to_string()];
     let (tx, _) = mpsc::channel();
 
-    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx);
+    let (status, response) = process_submission(&url, request_body, &server_manager, &persistence, &command_store, &tx).unwrap_err();
 
     assert_eq!(status::BadRequest, status);
-    assert_eq!(r#"{"message":"Validation Error: No valid value found: field 'jobName' cannot be empty"}"#, response);
+    assert_eq!(r#"{"message":"Validation Error: No valid value found: field 'jobName' cannot be empty","stdout":"","stderr":""}"#, response);
 }
 
 #[derive(Debug)]
-struct NoopCommandMock;
+pub struct NoopCommandMock;
 
 impl Execution for NoopCommandMock {
     fn get_command(&self, _: &str) -> Result<String, String> {
         Ok("/noop/command".to_string())
     }
 
-    fn execute(&self, _: String, _: Vec<String>) -> Result<String, String> {
-        Ok("NOOP command".to_string())
+    fn run(&self, _: String, _: Vec<String>, _: Option<Duration>) -> Result<CommandOutput, String> {
+        Ok(CommandOutput { exit_code: Some(0), stdout: "NOOP command".to_string(), stderr: String::new() })
     }
 }
 
-fn validate_ok_mock<U: Execution>(request: JobRequest, _: &U) -> Result<JobRequest, ValidationError> {
+pub fn validate_ok_mock<U: Execution>(request: JobRequest, _: &U) -> Result<JobRequest, ValidationError> {
     Ok(request)
 }
 
-fn queue_is_full(_: Sender<Dispatch>) -> bool {
+pub fn queue_is_full(_: Sender<Dispatch>) -> bool {
     true
 }
 
-fn queue_is_not_full(_: Sender<Dispatch>) -> bool {
+pub fn queue_is_not_full(_: Sender<Dispatch>) -> bool {
     false
 }
 
@@ -220,9 +247,9 @@ fn process_valid_submission_fail_job_already_run() {
     let request_body = Ok(Some(request));
     let (tx, _) = mpsc::channel();
 
-    let (status, response) = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_not_full);
+    let (status, response) = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
 
-    assert_eq!(status::BadRequest, status);
+    assert_eq!(status::Conflict, status);
     assert_eq!(r#"{"message":"Job is already being processed"}"#, response);
 }
 
@@ -236,7 +263,7 @@ fn process_valid_submission_fail_queue_is_full() {
     let request_body = Ok(Some(request));
     let (tx, _) = mpsc::channel();
 
-    let (status, response) = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_full);
+    let (status, response) = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_full).unwrap_err();
 
     assert_eq!(status::BadRequest, status);
     assert_eq!(r#"{"message":"Queue is full, cannot add job"}"#, response);
@@ -252,11 +279,536 @@ fn process_valid_submission_success() {
     let request_body = Ok(Some(request.clone()));
     let (tx, rx) = mpsc::channel();
 
-    let (status, response) = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_not_full);
+    let job_id = process_valid_submission(&url, request_body, &server_manager, &persistence, &noop_command, &tx, validate_ok_mock, queue_is_not_full).unwrap();
+    let (status, response) = get_submission_response(&url, &job_id);
 
     let result = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     assert_eq!(Dispatch::NewRequest(request), result);
 
+    let borrowed = &persistence.ref_map.borrow();
+    let entry = borrowed.get("com.test/namespace/dummy_id_1").unwrap();
+    let job_entry: JobEntry = serde_json::from_str(entry).expect("JSON decode error");
+    assert_eq!(JobState::QUEUED, job_entry.state);
+
     assert_eq!(status::Ok, status);
     assert_eq!(r#"{"message":"SUBMITTING JOB REQ jobId:[dummy_id_1]"}"#, response);
-}
\ No newline at end of file
+}
+
+#[test]
+fn process_valid_submission_keeps_timeout() {
+    let url = Url::parse("http://not.a.real.address/").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_submission_timeout");
+    let request: JobRequest = serde_json::from_str(r#"{ "jobId": "dummy_id_1", "jobName": "dummy", "factfilePath": "/tmp", "timeoutSeconds": 60 }"#).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    process_valid_submission(&url, Ok(Some(request)), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap();
+
+    match rx.recv_timeout(Duration::from_millis(1000)).unwrap() {
+        Dispatch::NewRequest(dispatched) => assert_eq!(Some(60), dispatched.timeout_seconds),
+        other => panic!("Unexpected dispatch {:?}", other),
+    }
+    // kept in the entry, so whichever server takes the job enforces it
+    let job_entry: JobEntry = serde_json::from_str(&persistence.ref_map.borrow()["com.test/namespace/dummy_id_1"]).unwrap();
+    assert_eq!(Some(60), job_entry.job_request.timeout_seconds);
+}
+
+#[test]
+fn process_valid_submission_keeps_inline_factfile_hash() {
+    let url = Url::parse("http://not.a.real.address/").unwrap();
+    let mut server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let store_root = ::std::env::temp_dir().join(format!("factotum-factfiles-submission-{}", ::std::process::id()));
+    server_manager.factfile_store = FactfileStore::new(Some(store_root.to_string_lossy().into_owned()));
+    let persistence = GoodPersistenceMock::new("test_submission_inline");
+    let factfile: serde_json::Value = serde_json::from_str(r#"{ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "echo", "tasks": [] } }"#).unwrap();
+    let mut request = JobRequest::new("dummy_id_1", "echo", "", vec![]);
+    request.factfile = Some(factfile.clone());
+    let (tx, rx) = mpsc::channel();
+
+    process_valid_submission(&url, Ok(Some(request)), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap();
+
+    let hash = ::factotum_server::server::get_factfile_hash(&factfile);
+    match rx.recv_timeout(Duration::from_millis(1000)).unwrap() {
+        Dispatch::NewRequest(dispatched) => {
+            assert_eq!(server_manager.factfile_store.get_path(&factfile).to_string_lossy(), dispatched.factfile_path);
+            assert_eq!(Some(hash.clone()), dispatched.factfile_hash);
+            assert_eq!(None, dispatched.factfile);
+        },
+        other => panic!("Unexpected dispatch {:?}", other),
+    }
+    // the store is only written when the job runs, the entry keeps just the hash
+    assert!(!store_root.exists());
+    let borrowed = persistence.ref_map.borrow();
+    let job_entry: JobEntry = serde_json::from_str(&borrowed["com.test/namespace/dummy_id_1"]).expect("JSON decode error");
+    assert_eq!(Some(hash.clone()), job_entry.job_request.factfile_hash);
+    assert_eq!(None, job_entry.job_request.factfile);
+    assert!(borrowed.contains_key(&format!("com.test/namespace-factfiles/{}", hash)));
+}
+
+#[test]
+fn process_valid_submission_queue_full_saves_no_factfile() {
+    let url = Url::parse("http://not.a.real.address/").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_submission_inline_full");
+    let mut request = JobRequest::new("dummy_id_1", "echo", "", vec![]);
+    request.factfile = Some(serde_json::from_str(r#"{ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "echo", "tasks": [] } }"#).unwrap());
+    let (tx, _) = mpsc::channel();
+
+    let (status, _) = process_valid_submission(&url, Ok(Some(request)), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert!(persistence.ref_map.borrow().is_empty());
+}
+
+#[test]
+fn process_valid_submission_fail_factfile_outside_roots() {
+    let url = Url::parse("http://not.a.real.address/").unwrap();
+    let mut server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let root = ::std::env::temp_dir().join(format!("factotum-roots-submission-{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&root).unwrap();
+    server_manager.factfile_roots = vec![::std::fs::canonicalize(&root).unwrap()];
+    let persistence = GoodPersistenceMock::new("test_submission_roots");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec!["--no-colour".to_string()]);
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_valid_submission(&url, Ok(Some(request)), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Validation Error: Invalid value for 'factfilePath':'/tmp', must be inside an allowed factfile root","stdout":"","stderr":""}"#, response);
+}
+
+#[test]
+fn process_catalog_run_submits_definition() {
+    use base64::encode as base64_encode;
+
+    let url = Url::parse("http://not.a.real.address/catalog/acme-main/run").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_catalog_run");
+    let definition = JobDefinition {
+        name: "acme-main".to_string(),
+        factfile_path: "/tmp".to_string(),
+        factfile: None,
+        factfile_args: vec!["--no-colour".to_string()],
+        env: None,
+        tags: None,
+        timeout_seconds: None,
+        pool: Some("batch".to_string()),
+        overridable_args: vec!["--start".to_string()],
+        overridable_env: vec![],
+        registered_by: None,
+        registered_at: None,
+    };
+    {
+        // catalog entries are decoded from base64, as Consul hands them back
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/catalog/acme-main".to_string(), base64_encode(serde_json::to_string(&definition).unwrap().as_bytes()));
+    }
+    let overrides = RunRequest { factfile_args: vec!["--start".to_string(), "step-2".to_string()], ..RunRequest::default() };
+    let (tx, rx) = mpsc::channel();
+
+    process_catalog_run(&url, "acme-main", Ok(Some(overrides)), Some("alice".to_string()), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap();
+
+    match rx.recv_timeout(Duration::from_millis(1000)).unwrap() {
+        Dispatch::NewRequest(dispatched) => {
+            assert_eq!("acme-main", dispatched.job_name);
+            assert_eq!("/tmp", dispatched.factfile_path);
+            assert_eq!(vec!["--no-colour", "--start", "step-2"], dispatched.factfile_args);
+            assert_eq!(Some(json!({ "pool": "batch" })), dispatched.tags);
+            assert_eq!(Some("alice".to_string()), dispatched.submitted_by);
+        },
+        other => panic!("Unexpected dispatch {:?}", other),
+    }
+}
+
+#[test]
+fn process_catalog_run_fail_not_overridable() {
+    use base64::encode as base64_encode;
+
+    let url = Url::parse("http://not.a.real.address/catalog/acme-main/run").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_catalog_run_not_overridable");
+    let definition = JobDefinition::validate(serde_json::from_str(r#"{ "name": "acme-main", "factfilePath": "/tmp" }"#).unwrap()).unwrap();
+    persistence.ref_map.borrow_mut().insert("com.test/namespace/catalog/acme-main".to_string(), base64_encode(serde_json::to_string(&definition).unwrap().as_bytes()));
+    let overrides = RunRequest { env: Some(json!({ "bucket": "s3://elsewhere" })), ..RunRequest::default() };
+    let (tx, rx) = mpsc::channel();
+
+    let (status, response) = process_catalog_run(&url, "acme-main", Ok(Some(overrides)), None, &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Invalid override for 'env':'bucket', catalog job 'acme-main' doesn't list it in 'overridableEnv'"}"#, response);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn process_catalog_run_fail_not_registered() {
+    let url = Url::parse("http://not.a.real.address/catalog/acme-main/run").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_catalog_run_missing");
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_catalog_run(&url, "acme-main", Ok(None), None, &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::NotFound, status);
+    assert_eq!(r#"{"message":"Persistence Error: not found - No job named 'acme-main' found in the catalog"}"#, response);
+}
+
+pub fn dry_run_ok_mock<U: Execution>(request: JobRequest, _: &U) -> Result<DryRun, ValidationError> {
+    let job_request = JobRequest { job_id: "dummy_id_1".to_string(), ..request };
+    Ok(DryRun { job_request: job_request, stdout: "Task one\n\nTask two\n".to_string(), stderr: String::new() })
+}
+
+pub fn dry_run_fail_mock<U: Execution>(_: JobRequest, _: &U) -> Result<DryRun, ValidationError> {
+    Err(ValidationError::new("Failed to execute command: [factotum run]".to_string(), "Task one\n".to_string(), "No task 'step-9'".to_string()))
+}
+
+#[test]
+fn process_validation_success() {
+    let url = Url::parse("http://not.a.real.address/validate").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let request_body = Ok(Some(JobRequest::new("", "dummy", "/tmp", vec!["--no-colour".to_string()])));
+
+    let (status, response) = process_validation(&url, request_body, &server_manager, &NoopCommandMock, dry_run_ok_mock);
+
+    assert_eq!(status::Ok, status);
+    assert_eq!(r#"{"jobId":"dummy_id_1","jobName":"dummy","factfilePath":"/tmp","factfileArgs":["--no-colour"],"plan":["Task one","Task two"],"stdout":"Task one\n\nTask two\n","stderr":""}"#, response);
+}
+
+#[test]
+fn process_validation_leaves_factfile_store_alone() {
+    let url = Url::parse("http://not.a.real.address/validate").unwrap();
+    let mut server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let store_root = ::std::env::temp_dir().join(format!("factotum-factfiles-validation-{}", ::std::process::id()));
+    server_manager.factfile_store = FactfileStore::new(Some(store_root.to_string_lossy().into_owned()));
+    let mut job_request = JobRequest::new("", "echo", "", vec![]);
+    job_request.factfile = Some(serde_json::from_str(r#"{ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "echo", "tasks": [] } }"#).unwrap());
+    let dry_run_path = RefCell::new(String::new());
+    let dry_run_from_file = |request: JobRequest, command_store: &NoopCommandMock| {
+        assert!(::std::path::Path::new(&request.factfile_path).exists());
+        *dry_run_path.borrow_mut() = request.factfile_path.clone();
+        dry_run_ok_mock(request, command_store)
+    };
+
+    let (status, _) = process_validation(&url, Ok(Some(job_request)), &server_manager, &NoopCommandMock, dry_run_from_file);
+
+    assert_eq!(status::Ok, status);
+    assert!(!::std::path::Path::new(&*dry_run_path.borrow()).exists());
+    assert!(!store_root.exists());
+}
+
+#[test]
+fn process_validation_fail_carries_output() {
+    let url = Url::parse("http://not.a.real.address/validate").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let request_body = Ok(Some(JobRequest::new("", "dummy", "/tmp", vec![])));
+
+    let (status, response) = process_validation(&url, request_body, &server_manager, &NoopCommandMock, dry_run_fail_mock);
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Validation Error: Failed to execute command: [factotum run]","stdout":"Task one\n","stderr":"No task 'step-9'"}"#, response);
+}
+
+#[test]
+fn process_validation_fail_callback_without_secret() {
+    let url = Url::parse("http://not.a.real.address/validate").unwrap();
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, String::new(), false, Some(10_000));
+    let mut job_request = JobRequest::new("", "dummy", "/tmp", vec![]);
+    job_request.callback_url = Some("https://hooks.acme.test/".to_string());
+
+    let (status, response) = process_validation(&url, Ok(Some(job_request)), &server_manager, &NoopCommandMock, dry_run_ok_mock);
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Validation Error: Invalid 'callbackUrl', this server has no --callback-secret-file to sign callbacks with","stdout":"","stderr":""}"#, response);
+}
+
+#[test]
+fn check_job_request_fail_no_id() {
+    let url = Url::parse("http://not.a.real.address/check").unwrap();
+    let persistence = GoodPersistenceMock::new("test_check");
+
+    let (status, response) = check_job_request(&url, &persistence);
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Error: No 'id' found in URL query parameters"}"#, response);
+}
+
+#[test]
+fn check_job_request_hides_callback_url() {
+    let url = Url::parse("http://not.a.real.address/check?id=dummy_id_1").unwrap();
+    let persistence = GoodPersistenceMock::new("test_check");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::QUEUED);
+
+    let (status, response) = check_job_request(&url, &persistence);
+
+    let job_entry: JobEntry = serde_json::from_str(&response).expect("JSON decode error");
+    assert_eq!(status::Ok, status);
+    assert_eq!(None, job_entry.job_request.callback_url);
+}
+
+#[test]
+fn check_job_request_fail_not_found() {
+    let url = Url::parse("http://not.a.real.address/check?id=dummy_id_1").unwrap();
+    let persistence = GoodPersistenceMock::new("test_check");
+
+    let (status, response) = check_job_request(&url, &persistence);
+
+    assert_eq!(status::NotFound, status);
+    assert_eq!(r#"{"message":"Error: No job entry found for id='dummy_id_1'"}"#, response);
+}
+
+#[test]
+fn check_job_request_fail_corrupt_entry() {
+    let url = Url::parse("http://not.a.real.address/check?id=dummy_id_1").unwrap();
+    let persistence = GoodPersistenceMock::new("test_check");
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_id_1".to_string(), "!!not base64!!".to_string());
+    }
+
+    let (status, _) = check_job_request(&url, &persistence);
+
+    assert_eq!(status::InternalServerError, status);
+}
+
+#[test]
+fn check_job_request_fail_unavailable() {
+    let url = Url::parse("http://not.a.real.address/check?id=dummy_id_1").unwrap();
+    let persistence = ConsulPersistence::new(None, Some("127.0.0.1".to_string()), Some(1), None, ConsulSecurity::default());
+
+    let (status, _) = check_job_request(&url, &persistence);
+
+    assert_eq!(status::ServiceUnavailable, status);
+}
+
+#[test]
+fn get_persistence_error_status_mapping() {
+    assert_eq!(status::ServiceUnavailable, get_persistence_error_status(&PersistenceError::Unavailable(String::new())));
+    assert_eq!(status::NotFound, get_persistence_error_status(&PersistenceError::NotFound(String::new())));
+    assert_eq!(status::InternalServerError, get_persistence_error_status(&PersistenceError::Corrupt(String::new())));
+    assert_eq!(status::Conflict, get_persistence_error_status(&PersistenceError::Conflict(String::new())));
+}
+
+#[test]
+fn get_retention_report_success() {
+    use base64::encode as base64_encode;
+    use factotum_server::janitor::RetentionPolicy;
+
+    let url = Url::parse("http://not.a.real.address/retention").unwrap();
+    let persistence = GoodPersistenceMock::new("test_retention");
+    let policy = RetentionPolicy::new(None, Some(0), None);
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let mut job_entry = JobEntry::new(&JobState::DONE, &request, &persistence.id(), &JobOutcome::SUCCEEDED);
+    job_entry.last_updated = None;
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+    {
+        let mut map = persistence.ref_map.borrow_mut();
+        map.insert("com.test/namespace/dummy_id_1".to_string(), base64_encode(job_entry_json.as_bytes()));
+    }
+
+    let (status, response) = get_retention_report(&url, &persistence, &policy);
+
+    assert_eq!(status::Ok, status);
+    assert_eq!(r#"{"policy":{"maxAgeHours":null,"maxCount":0,"intervalSecs":3600},"expired":[{"jobId":"dummy_id_1","jobName":"dummy","lastUpdated":null,"reason":"Exceeds max count of 0 entries for jobName"}]}"#, response);
+    assert_eq!(1, persistence.ref_map.borrow().len());
+}
+
+pub fn insert_job_entry(persistence: &GoodPersistenceMock, job_id: &str, state: &JobState) {
+    use base64::encode as base64_encode;
+
+    let mut request = JobRequest::new(job_id, "dummy", "/tmp", vec![]);
+    request.callback_url = Some("https://hooks.acme.test/?token=secret".to_string());
+    let job_entry = JobEntry::new(state, &request, &persistence.id(), &JobOutcome::WAITING);
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+    let mut map = persistence.ref_map.borrow_mut();
+    map.insert(persistence.prepend_namespace(job_id), base64_encode(job_entry_json.as_bytes()));
+}
+
+#[test]
+fn wait_for_job_request_done() {
+    let url = Url::parse("http://not.a.real.address/jobs/dummy_id_1/wait?timeout=300").unwrap();
+    let persistence = GoodPersistenceMock::new("test_wait");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::DONE);
+
+    let (status, response) = wait_for_job_request(&url, &persistence, "dummy_id_1");
+
+    let job_entry: JobEntry = serde_json::from_str(&response).expect("JSON decode error");
+    assert_eq!(status::Ok, status);
+    assert_eq!(JobState::DONE, job_entry.state);
+    assert_eq!(None, job_entry.job_request.callback_url);
+}
+
+#[test]
+fn wait_for_job_request_timeout_returns_current_state() {
+    let url = Url::parse("http://not.a.real.address/jobs/dummy_id_1/wait?timeout=0").unwrap();
+    let persistence = GoodPersistenceMock::new("test_wait");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::WORKING);
+
+    let (status, response) = wait_for_job_request(&url, &persistence, "dummy_id_1");
+
+    let job_entry: JobEntry = serde_json::from_str(&response).expect("JSON decode error");
+    assert_eq!(status::Accepted, status);
+    assert_eq!(JobState::WORKING, job_entry.state);
+}
+
+#[test]
+fn wait_for_job_request_fail_invalid_timeout() {
+    let url = Url::parse("http://not.a.real.address/jobs/dummy_id_1/wait?timeout=soon").unwrap();
+    let persistence = GoodPersistenceMock::new("test_wait");
+
+    let (status, response) = wait_for_job_request(&url, &persistence, "dummy_id_1");
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"message":"Error: Invalid timeout 'soon', expected a number of seconds"}"#, response);
+}
+
+#[test]
+fn wait_slots_limit_concurrent_waits() {
+    let wait_slots = WaitSlots::new(2);
+
+    let first = wait_slots.try_acquire();
+    let second = wait_slots.try_acquire();
+
+    assert!(first.is_some() && second.is_some());
+    assert!(wait_slots.try_acquire().is_none());
+    assert_eq!(2, wait_slots.active_count());
+
+    drop(first);
+
+    assert!(wait_slots.try_acquire().is_some());
+    assert_eq!(1, wait_slots.active_count());
+}
+
+#[test]
+fn wait_for_job_request_fail_not_found() {
+    let url = Url::parse("http://not.a.real.address/jobs/missing/wait").unwrap();
+    let persistence = GoodPersistenceMock::new("test_wait");
+
+    let (status, response) = wait_for_job_request(&url, &persistence, "missing");
+
+    assert_eq!(status::NotFound, status);
+    assert_eq!(r#"{"message":"Error: No job entry found for id='missing'"}"#, response);
+}
+
+#[test]
+fn get_submit_wait_timeout_parses_params() {
+    let no_wait = Url::parse("http://not.a.real.address/submit").unwrap();
+    let wait = Url::parse("http://not.a.real.address/submit?wait=true&timeout=5").unwrap();
+    let wait_capped = Url::parse("http://not.a.real.address/submit?wait=1&timeout=100000").unwrap();
+
+    assert_eq!(Ok(None), get_submit_wait_timeout(&no_wait));
+    assert_eq!(Ok(Some(Duration::from_secs(5))), get_submit_wait_timeout(&wait));
+    assert_eq!(Ok(Some(Duration::from_secs(::WAIT_TIMEOUT_MAX))), get_submit_wait_timeout(&wait_capped));
+}
+
+#[test]
+fn get_submit_wait_timeout_fail_invalid_params() {
+    let bad_wait = Url::parse("http://not.a.real.address/submit?wait=maybe").unwrap();
+    let bad_timeout = Url::parse("http://not.a.real.address/submit?wait=true&timeout=soon").unwrap();
+
+    assert_eq!(Err("Error: Invalid wait 'maybe', expected true or false".to_string()), get_submit_wait_timeout(&bad_wait));
+    assert_eq!(Err("Error: Invalid timeout 'soon', expected a number of seconds".to_string()), get_submit_wait_timeout(&bad_timeout));
+}
+
+#[test]
+fn wait_for_submitted_job_done_returns_outcome() {
+    let url = Url::parse("http://not.a.real.address/submit?wait=true").unwrap();
+    let persistence = GoodPersistenceMock::new("test_sync_submit");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let mut job_entry = JobEntry::new(&JobState::DONE, &request, &persistence.id(), &JobOutcome::SUCCEEDED);
+    job_entry.result = Some(JobResult::new(Some(0), "all done\n", ""));
+    let job_entry_json = serde_json::to_string(&job_entry).expect("JSON compact encode error");
+    persistence.ref_map.borrow_mut().insert(persistence.prepend_namespace("dummy_id_1"), base64::encode(job_entry_json.as_bytes()));
+
+    let (status, response) = wait_for_submitted_job(&url, &persistence, "dummy_id_1", Duration::from_secs(300));
+
+    assert_eq!(status::Ok, status);
+    assert_eq!(r#"{"jobId":"dummy_id_1","state":"DONE","outcome":"SUCCEEDED","exitCode":0,"stdout":"all done\n","stderr":""}"#, response);
+}
+
+#[test]
+fn wait_for_submitted_job_timeout_returns_job_id() {
+    let url = Url::parse("http://not.a.real.address/submit?wait=true").unwrap();
+    let persistence = GoodPersistenceMock::new("test_sync_submit");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::QUEUED);
+
+    let (status, response) = wait_for_submitted_job(&url, &persistence, "dummy_id_1", Duration::from_secs(0));
+
+    assert_eq!(status::Accepted, status);
+    assert_eq!(r#"{"jobId":"dummy_id_1","state":"QUEUED","outcome":"WAITING","exitCode":null,"stdout":null,"stderr":null}"#, response);
+}
+
+#[test]
+fn attribute_to_caller_overwrites_submitted_by() {
+    let mut request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    request.submitted_by = Some("spoofed".to_string());
+
+    let attributed = attribute_to_caller(Ok(Some(request.clone())), Some("alice".to_string())).unwrap().unwrap();
+    let anonymous = attribute_to_caller(Ok(Some(request)), None).unwrap().unwrap();
+
+    assert_eq!(Some("alice".to_string()), attributed.submitted_by);
+    assert_eq!(None, anonymous.submitted_by);
+}
+
+#[test]
+fn check_dispatcher_answering() {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        if let Ok(Dispatch::StatusUpdate(query)) = rx.recv() {
+            let status = DispatcherStatus {
+                workers: WorkerStatus { total: 1, idle: 1, active: 0 },
+                jobs: JobStatus { max_queue_size: 1, in_queue: 0 },
+                persistence: PersistenceStatus { pending_writes: 0 },
+            };
+            query.status_tx.send(status).unwrap();
+        }
+    });
+
+    assert_eq!(Ok("Dispatcher is answering".to_string()), check_dispatcher(tx, Duration::from_secs(5)));
+}
+
+#[test]
+fn check_dispatcher_stuck_or_stopped() {
+    let (stuck_tx, _stuck_rx) = mpsc::channel();
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    drop(stopped_rx);
+
+    assert_eq!(Err("Dispatcher did not answer within 0s".to_string()), check_dispatcher(stuck_tx, Duration::from_millis(10)));
+    assert_eq!(Err("Dispatcher has stopped".to_string()), check_dispatcher(stopped_tx, Duration::from_secs(5)));
+}
+
+#[test]
+fn readiness_report_all_passed() {
+    let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
+    let persistence = GoodPersistenceMock::new("test_ready");
+    let command_store = commands![::FACTOTUM.to_string() => "/bin/sh".to_string()];
+
+    let report = get_readiness_report(&server_manager, &persistence, &command_store);
+
+    assert_eq!(true, report.passed);
+    assert_eq!(vec!["consul", "factotum", "state"], report.checks.iter().map(|check| check.name.as_str()).collect::<Vec<_>>());
+}
+
+#[test]
+fn readiness_report_failed_checks() {
+    let mut server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000));
+    server_manager.state = ::SERVER_STATE_DRAIN.to_string();
+    let persistence = GoodPersistenceMock::new("test_ready");
+    let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_command".to_string()];
+
+    let report = get_readiness_report(&server_manager, &persistence, &command_store);
+
+    assert_eq!(false, report.passed);
+    assert_eq!(ProbeCheck::new("consul", Ok("Consul is reachable".to_string())), report.checks[0]);
+    assert_eq!(ProbeCheck::new("factotum", Err("Factotum binary is missing or not executable".to_string())), report.checks[1]);
+    assert_eq!(ProbeCheck::new("state", Err("Server in [drain] state - not accepting jobs".to_string())), report.checks[2]);
+}
+
+#[test]
+fn get_event_filter_from_query() {
+    let url = Url::parse("http://not.a.real.address/events?jobName=com.acme-main&tag=env,prod&tag=nightly").unwrap();
+    let filter = get_event_filter(&url);
+
+    assert_eq!(Some("com.acme-main".to_string()), filter.job_name);
+    assert_eq!(Some(&"prod".to_string()), filter.tags.get("env"));
+    assert_eq!(Some(&"".to_string()), filter.tags.get("nightly"));
+    assert_eq!(EventFilter::default(), get_event_filter(&Url::parse("http://not.a.real.address/events").unwrap()));
+}
@@ -0,0 +1,272 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::ops::Deref;
+use std::sync::mpsc::Sender;
+use iron::headers::Location;
+use iron::mime::*;
+use iron::modifiers::Header;
+use iron::prelude::*;
+use iron::status;
+use iron::status::Status;
+use url::Url;
+use bodyparser;
+use persistent::{Read, State};
+use router::Router;
+
+use factotum_server::{Leader, Paths, Server, Storage, Updates};
+use factotum_server::command::Execution;
+use factotum_server::dispatcher::Dispatch;
+use factotum_server::persistence;
+use factotum_server::persistence::{Persistence, PersistenceError, JobState};
+use factotum_server::server::{ServerManager, JobRequest, ValidationError};
+use super::{SubmissionError, accept_job_request, attribute_to_caller, decode_body, get_caller_identity, encode, get_persistence_error_status, get_server_status, is_requests_queue_full};
+
+#[cfg(test)]
+mod tests;
+
+pub const API_V2_PREFIX: &'static str = "/api/v2";
+
+// Error codes
+
+const INVALID_BODY: &'static str = "INVALID_BODY";
+const INVALID_JOB_REQUEST: &'static str = "INVALID_JOB_REQUEST";
+const SERVER_NOT_RUNNING: &'static str = "SERVER_NOT_RUNNING";
+const QUEUE_FULL: &'static str = "QUEUE_FULL";
+const JOB_ALREADY_EXISTS: &'static str = "JOB_ALREADY_EXISTS";
+const JOB_NOT_FOUND: &'static str = "JOB_NOT_FOUND";
+const STORAGE_UNAVAILABLE: &'static str = "STORAGE_UNAVAILABLE";
+const STORAGE_CORRUPT: &'static str = "STORAGE_CORRUPT";
+const STORAGE_CONFLICT: &'static str = "STORAGE_CONFLICT";
+const SERVICE_UNAVAILABLE: &'static str = "SERVICE_UNAVAILABLE";
+
+// JSON Response Structs
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub error: ApiError,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+    // what the dry run printed, only for invalid job requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: &str, message: &str) -> ApiError {
+        ApiError {
+            code: code.to_string(),
+            message: message.to_string(),
+            stdout: None,
+            stderr: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AcceptedJob {
+    pub job_id: String,
+    pub state: JobState,
+    pub href: String,
+}
+
+pub fn submit_job(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let request_body = attribute_to_caller(request.get::<bodyparser::Struct<JobRequest>>(), get_caller_identity(request));
+    let server_rwlock = match request.get::<State<Server>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let server = match server_rwlock.write() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let persistence = match storage_rwlock.write() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let command_store_rwlock = match request.get::<Read<Paths>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let command_store = match command_store_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let sender_mutex = match request.get::<Read<Updates>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let jobs_channel = match sender_mutex.try_lock() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+
+    match process_job_submission(&url, request_body, server.deref(), persistence.deref(), command_store.deref(), jobs_channel.deref(), JobRequest::validate, is_requests_queue_full) {
+        Ok(job_id) => return_accepted(&url, &job_id),
+        Err((status, response)) => return_json(status, response),
+    }
+}
+
+pub fn get_job(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let job_id = request.extensions.get::<Router>().and_then(|params| params.find("id")).unwrap_or("").to_string();
+    let storage_rwlock = match request.get::<State<Storage>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let persistence = match storage_rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+
+    let (status, response) = get_job_entry(&url, persistence.deref(), &job_id);
+    return_json(status, response)
+}
+
+pub fn get_server(request: &mut Request) -> IronResult<Response> {
+    let url: Url = request.url.clone().into();
+    let rwlock = match request.get::<State<Server>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let reader = match rwlock.read() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let mutex = match request.get::<Read<Updates>>() {
+        Ok(lock) => lock,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let jobs_channel = match mutex.try_lock() {
+        Ok(result) => result,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+    let leadership = match request.get::<Read<Leader>>() {
+        Ok(leadership) => leadership,
+        Err(e) => return return_unavailable(&url, e.to_string())
+    };
+
+    let response = get_server_status(reader.deref(), jobs_channel.clone(), leadership.is_leader());
+    return_json(status::Ok, encode(&url, response))
+}
+
+// Helpers
+
+fn process_job_submission<T, U, F, G>(url: &Url, request_body: Result<Option<JobRequest>, bodyparser::BodyError>, server: &ServerManager, persistence: &T, command_store: &U, jobs_channel: &Sender<Dispatch>, validate: F, is_requests_queue_full: G) -> Result<String, (Status, String)> where
+    T: Persistence,
+    U: Execution,
+    F: Fn(JobRequest, &U) -> Result<JobRequest, ValidationError>,
+    G: Fn(Sender<Dispatch>) -> bool {
+    let job_request = match decode_body(request_body) {
+        Ok(job_request) => job_request,
+        Err(msg) => return Err(create_error_response(url, status::BadRequest, INVALID_BODY, &msg))
+    };
+
+    accept_job_request(job_request, server, persistence, command_store, jobs_channel, validate, is_requests_queue_full).map_err(|e| {
+        let (status, code) = match e {
+            SubmissionError::NotRunning(_) => (status::ServiceUnavailable, SERVER_NOT_RUNNING),
+            SubmissionError::Invalid(ref e) => return create_validation_error_response(url, e),
+            SubmissionError::QueueFull => (status::TooManyRequests, QUEUE_FULL),
+            SubmissionError::Duplicate => (status::Conflict, JOB_ALREADY_EXISTS),
+            SubmissionError::Persistence(ref e) => (get_persistence_error_status(e), get_persistence_error_code(e)),
+        };
+        create_error_response(url, status, code, &e.to_string())
+    })
+}
+
+fn get_job_entry<T: Persistence>(url: &Url, persistence: &T, job_id: &str) -> (Status, String) {
+    match persistence::get_entry(persistence, job_id) {
+        Ok(job_entry) => (status::Ok, encode(url, &job_entry.redacted())),
+        Err(PersistenceError::NotFound(_)) => {
+            create_error_response(url, status::NotFound, JOB_NOT_FOUND, &format!("No job entry found for id='{}'", job_id))
+        },
+        Err(e) => create_error_response(url, get_persistence_error_status(&e), get_persistence_error_code(&e), &e.to_string()),
+    }
+}
+
+fn get_persistence_error_code(error: &PersistenceError) -> &'static str {
+    match *error {
+        PersistenceError::Unavailable(_) => STORAGE_UNAVAILABLE,
+        PersistenceError::NotFound(_) => JOB_NOT_FOUND,
+        PersistenceError::Corrupt(_) => STORAGE_CORRUPT,
+        PersistenceError::Conflict(_) => STORAGE_CONFLICT,
+    }
+}
+
+fn get_job_href(job_id: &str) -> String {
+    format!("{}/jobs/{}", API_V2_PREFIX, job_id)
+}
+
+fn create_accepted_response(url: &Url, job_id: &str) -> String {
+    info!("Accepted job request jobId:[{}]", job_id);
+    let accepted = AcceptedJob {
+        job_id: job_id.to_string(),
+        state: JobState::QUEUED,
+        href: get_job_href(job_id),
+    };
+    encode(url, &accepted)
+}
+
+fn create_error_response(url: &Url, status: Status, code: &str, message: &str) -> (Status, String) {
+    warn!("[{}] {}", code, message);
+    let response = ErrorResponse {
+        error: ApiError::new(code, message)
+    };
+    (status, encode(url, &response))
+}
+
+// keeps the dry run's output, as v1 does, since it's often all that explains a rejection
+fn create_validation_error_response(url: &Url, error: &ValidationError) -> (Status, String) {
+    warn!("[{}] {}", INVALID_JOB_REQUEST, error);
+    let response = ErrorResponse {
+        error: ApiError {
+            stdout: Some(error.stdout.clone()),
+            stderr: Some(error.stderr.clone()),
+            ..ApiError::new(INVALID_JOB_REQUEST, &error.to_string())
+        }
+    };
+    (status::BadRequest, encode(url, &response))
+}
+
+fn get_content_type() -> Mime {
+    ::JSON_CONTENT_TYPE.parse::<Mime>().expect(&format!("Unable to parse Mime type for '{}'", ::JSON_CONTENT_TYPE))
+}
+
+fn return_json(code: Status, response: String) -> IronResult<Response> {
+    Ok(Response::with((get_content_type(), code, response)))
+}
+
+fn return_accepted(url: &Url, job_id: &str) -> IronResult<Response> {
+    let response = create_accepted_response(url, job_id);
+    Ok(Response::with((get_content_type(), status::Accepted, response, Header(Location(get_job_href(job_id))))))
+}
+
+fn return_unavailable(url: &Url, message: String) -> IronResult<Response> {
+    let (status, response) = create_error_response(url, status::ServiceUnavailable, SERVICE_UNAVAILABLE, &message);
+    return_json(status, response)
+}
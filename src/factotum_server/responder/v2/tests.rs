@@ -0,0 +1,154 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::sync::mpsc;
+use factotum_server::responder::tests::{GoodPersistenceMock, NoopCommandMock, insert_job_entry, validate_ok_mock, queue_is_full, queue_is_not_full};
+
+fn new_server_manager() -> ServerManager {
+    ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), false, Some(10_000))
+}
+
+fn validate_fail_mock<U: Execution>(_: JobRequest, _: &U) -> Result<JobRequest, ValidationError> {
+    Err(ValidationError::no_output("No valid value found: field 'jobName' cannot be empty".to_string()))
+}
+
+#[test]
+fn process_job_submission_accepted() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let (tx, rx) = mpsc::channel();
+
+    let job_id = process_job_submission(&url, Ok(Some(request.clone())), &new_server_manager(), &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap();
+
+    assert_eq!("dummy_id_1", job_id);
+    match rx.try_recv().unwrap() {
+        Dispatch::NewRequest(dispatched) => assert_eq!(request.job_id, dispatched.job_id),
+        other => panic!("expected a new request, got {:?}", other),
+    }
+    assert_eq!(r#"{"jobId":"dummy_id_1","state":"QUEUED","href":"/api/v2/jobs/dummy_id_1"}"#, create_accepted_response(&url, &job_id));
+}
+
+#[test]
+fn process_job_submission_fail_no_body() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(None), &new_server_manager(), &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"error":{"code":"INVALID_BODY","message":"Error: No body found in POST request"}}"#, response);
+}
+
+#[test]
+fn process_job_submission_fail_invalid_job_request() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let request = JobRequest::new("dummy_id_1", "", "/tmp", vec![]);
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(Some(request)), &new_server_manager(), &persistence, &NoopCommandMock, &tx, validate_fail_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"error":{"code":"INVALID_JOB_REQUEST","message":"Validation Error: No valid value found: field 'jobName' cannot be empty","stdout":"","stderr":""}}"#, response);
+}
+
+#[test]
+fn process_job_submission_fail_dry_run_keeps_output() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let failing_dry_run = |_: JobRequest, _: &NoopCommandMock| Err(ValidationError::new("Failed to execute command: [factotum run]".to_string(), "Task one\n".to_string(), "No task 'step-9'".to_string()));
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(Some(request)), &new_server_manager(), &persistence, &NoopCommandMock, &tx, failing_dry_run, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::BadRequest, status);
+    assert_eq!(r#"{"error":{"code":"INVALID_JOB_REQUEST","message":"Validation Error: Failed to execute command: [factotum run]","stdout":"Task one\n","stderr":"No task 'step-9'"}}"#, response);
+}
+
+#[test]
+fn process_job_submission_fail_server_draining() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let mut server_manager = new_server_manager();
+    server_manager.state = ::SERVER_STATE_DRAIN.to_string();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(Some(request)), &server_manager, &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::ServiceUnavailable, status);
+    assert_eq!(r#"{"error":{"code":"SERVER_NOT_RUNNING","message":"Server in [drain] state - cannot submit job"}}"#, response);
+}
+
+#[test]
+fn process_job_submission_fail_queue_full() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(Some(request)), &new_server_manager(), &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_full).unwrap_err();
+
+    assert_eq!(status::TooManyRequests, status);
+    assert_eq!(r#"{"error":{"code":"QUEUE_FULL","message":"Queue is full, cannot add job"}}"#, response);
+}
+
+#[test]
+fn process_job_submission_fail_duplicate() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::QUEUED);
+    let request = JobRequest::new("dummy_id_1", "dummy", "/tmp", vec![]);
+    let (tx, _) = mpsc::channel();
+
+    let (status, response) = process_job_submission(&url, Ok(Some(request)), &new_server_manager(), &persistence, &NoopCommandMock, &tx, validate_ok_mock, queue_is_not_full).unwrap_err();
+
+    assert_eq!(status::Conflict, status);
+    assert_eq!(r#"{"error":{"code":"JOB_ALREADY_EXISTS","message":"Job is already being processed"}}"#, response);
+}
+
+#[test]
+fn get_job_entry_found() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs/dummy_id_1").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+    insert_job_entry(&persistence, "dummy_id_1", &JobState::WORKING);
+
+    let (status, response) = get_job_entry(&url, &persistence, "dummy_id_1");
+
+    assert_eq!(status::Ok, status);
+    assert!(!response.contains("callbackUrl"));
+}
+
+#[test]
+fn get_job_entry_fail_not_found() {
+    let url = Url::parse("http://not.a.real.address/api/v2/jobs/missing").unwrap();
+    let persistence = GoodPersistenceMock::new("test_v2");
+
+    let (status, response) = get_job_entry(&url, &persistence, "missing");
+
+    assert_eq!(status::NotFound, status);
+    assert_eq!(r#"{"error":{"code":"JOB_NOT_FOUND","message":"No job entry found for id='missing'"}}"#, response);
+}
+
+#[test]
+fn get_persistence_error_code_maps_errors() {
+    assert_eq!("STORAGE_UNAVAILABLE", get_persistence_error_code(&PersistenceError::Unavailable(String::new())));
+    assert_eq!("JOB_NOT_FOUND", get_persistence_error_code(&PersistenceError::NotFound(String::new())));
+    assert_eq!("STORAGE_CORRUPT", get_persistence_error_code(&PersistenceError::Corrupt(String::new())));
+    assert_eq!("STORAGE_CONFLICT", get_persistence_error_code(&PersistenceError::Conflict(String::new())));
+}
@@ -17,14 +17,19 @@ use std::error;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use chrono::{DateTime, UTC};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use getopts::Options;
 use serde_json;
+use serde_json::Value;
+use url::Url;
 
+use factotum_server::command;
 use factotum_server::command::Execution;
+use factotum_server::factfiles::FactfileStore;
+use factotum_server::webhook;
 
 #[cfg(test)]
 mod tests;
@@ -38,6 +43,11 @@ pub struct ServerManager {
     pub webhook_uri: String,
     pub no_colour: bool,
     pub max_stdouterr_size: Option<usize>,
+    // job requests may only carry a `callbackUrl` when there's a secret to sign callbacks with
+    pub callbacks_enabled: bool,
+    pub factfile_store: FactfileStore,
+    // submitted factfile paths must resolve inside one of these, any path is allowed when empty
+    pub factfile_roots: Vec<PathBuf>,
 }
 
 impl ServerManager {
@@ -50,6 +60,9 @@ impl ServerManager {
             webhook_uri: webhook_uri.to_string(),
             no_colour: no_colour,
             max_stdouterr_size: max_stdouterr_size,
+            callbacks_enabled: false,
+            factfile_store: FactfileStore::new(None),
+            factfile_roots: vec![],
         }
     }
 
@@ -77,8 +90,38 @@ pub struct JobRequest {
     #[serde(default)]
     pub job_id: String,
     pub job_name: String,
+    #[serde(default)]
     pub factfile_path: String,
-    pub factfile_args: Vec<String>
+    // inline content, taken out of the request once it's accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub factfile: Option<Value>,
+    // set in place of inline content, which is then run from the factfile store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub factfile_hash: Option<String>,
+    #[serde(default)]
+    pub factfile_args: Vec<String>,
+    // set from the authenticated caller, never from the request body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    // structured options, left untyped so a wrong type is a validation error rather
+    // than a body that can't be decoded; validation moves them into `factfile_args`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub constraints: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<Value>,
+    // accepted on submission and set from catalog definitions; a running job is
+    // killed, and fails, once it takes longer than this. Kept in the job entry so
+    // whichever server takes the job enforces it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
 }
 
 impl JobRequest {
@@ -88,11 +131,27 @@ impl JobRequest {
             job_id: job_id.to_owned(),
             job_name: job_name.to_owned(),
             factfile_path: factfile_path.to_owned(),
+            factfile: None,
+            factfile_hash: None,
             factfile_args: factfile_args,
+            submitted_by: None,
+            callback_url: None,
+            tags: None,
+            env: None,
+            start: None,
+            constraints: None,
+            dry_run: None,
+            timeout_seconds: None,
         }
     }
 
     pub fn validate<U: Execution>(request: JobRequest, command_store: &U) -> Result<JobRequest, ValidationError> {
+        JobRequest::dry_run(request, command_store).map(|dry_run| dry_run.job_request)
+    }
+
+    /// Validates the request with a `factotum run --dry-run`, keeping what it printed.
+    pub fn dry_run<U: Execution>(request: JobRequest, command_store: &U) -> Result<DryRun, ValidationError> {
+        let mut request = request;
         // check job name not empty
         // check factfile path not empty
         // check factfile args not empty
@@ -105,6 +164,26 @@ impl JobRequest {
             error!("{}", message);
             return Err(ValidationError::no_output(message))
         }
+        // check callback url is one we can post to, its host is resolved when a callback is sent
+        if let Some(ref callback_url) = request.callback_url {
+            if let Err(message) = webhook::check_callback_url("callbackUrl", callback_url) {
+                error!("{}", message);
+                return Err(ValidationError::no_output(message))
+            }
+        }
+        if request.timeout_seconds == Some(0) {
+            let message = format!("Invalid value for 'timeoutSeconds', must be greater than 0");
+            error!("{}", message);
+            return Err(ValidationError::no_output(message))
+        }
+        // translate structured options into factotum args
+        match request.take_option_args() {
+            Ok(option_args) => request.factfile_args.extend(option_args),
+            Err(message) => {
+                error!("{}", message);
+                return Err(ValidationError::no_output(message))
+            }
+        }
         // check valid factfile path exists
         if !Path::new(&request.factfile_path).exists() {
             let message = format!("Value does not exist on host for 'factfilePath':'{}'", request.factfile_path);
@@ -114,33 +193,38 @@ impl JobRequest {
         // attempt dry run
         let cmd_path = try!(command_store.get_command(::FACTOTUM));
         let mut cmd_args = vec!["run".to_string(), request.factfile_path.clone(), "--dry-run".to_string()];
-        cmd_args.extend_from_slice(request.factfile_args.as_slice());
-        match command_store.execute(cmd_path, cmd_args) {
-            Ok(_) => {
-                debug!("Dry run success");
-            },
-            Err(e) => {
-                error!("{}", e);
-                return Err(ValidationError::no_output(e))
-            }
+        // a request that is a dry run itself already has the flag
+        cmd_args.extend(request.factfile_args.iter().filter(|arg| *arg != "--dry-run").cloned());
+        let output = command_store.run(cmd_path.clone(), cmd_args.clone(), None).map_err(|e| {
+            error!("{}", e);
+            ValidationError::no_output(e)
+        })?;
+        if !output.success() {
+            let message = format!("{} - {}", command::get_failed_command_msg(&cmd_path, &cmd_args), output.stderr);
+            error!("{}", message);
+            return Err(ValidationError::new(message, output.stdout, output.stderr))
         }
+        debug!("Dry run success");
         // generate unique job id
-        let mut request = request;
         let tags = match extract_tags(&request.factfile_args) {
             Ok(extracted) => extracted,
             Err(e) => {
                 error!("{}", e);
-                return Err(ValidationError::no_output(e))
+                return Err(ValidationError::new(e, output.stdout, output.stderr))
             }
         };
         request.job_id = match generate_id(&request.factfile_path, tags) {
             Ok(id) => id,
             Err(e) => {
                 error!("{}", e);
-                return Err(ValidationError::no_output(e))
+                return Err(ValidationError::new(e, output.stdout, output.stderr))
             }
         };
-        Ok(request)
+        Ok(DryRun {
+            job_request: request,
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
     }
 
     pub fn append_job_args(server: &ServerManager, job: &mut JobRequest) {
@@ -158,6 +242,63 @@ impl JobRequest {
             job.factfile_args.push("--no-colour".to_string());
         }
     }
+
+    /// Removes the structured options, returning the factotum args they stand for.
+    /// Single-valued options can't also be given in `factfileArgs`.
+    pub fn take_option_args(&mut self) -> Result<Vec<String>, String> {
+        let mut args = vec![];
+        if let Some(tags) = self.tags.take() {
+            for (key, value) in get_string_pairs("tags", &tags)? {
+                // the job id is made from the tags split on commas, so one in a value would be lost
+                if value.contains(',') {
+                    return Err(format!("Invalid value for 'tags', '{}' must have no commas in its value", key))
+                }
+                args.push("--tag".to_string());
+                args.push(if value.is_empty() { key } else { format!("{},{}", key, value) });
+            }
+        }
+        if let Some(env) = self.env.take() {
+            check_not_in_args("env", "--env", &self.factfile_args)?;
+            if !env.is_object() {
+                return Err("Invalid value for 'env', must be a JSON object".to_string())
+            }
+            args.push("--env".to_string());
+            args.push(serde_json::to_string(&env).expect("JSON compact encode error"));
+        }
+        if let Some(start) = self.start.take() {
+            check_not_in_args("start", "--start", &self.factfile_args)?;
+            match start.as_str() {
+                Some(task) if !task.trim().is_empty() => {
+                    args.push("--start".to_string());
+                    args.push(task.to_string());
+                },
+                _ => return Err("Invalid value for 'start', must be the name of a task".to_string()),
+            }
+        }
+        if let Some(constraints) = self.constraints.take() {
+            for (key, value) in get_string_pairs("constraints", &constraints)? {
+                if value.is_empty() {
+                    return Err(format!("Invalid value for 'constraints', '{}' must have a non-empty value", key))
+                }
+                args.push("--constraint".to_string());
+                args.push(format!("{},{}", key, value));
+            }
+        }
+        if let Some(dry_run) = self.dry_run.take() {
+            check_not_in_args("dryRun", "--dry-run", &self.factfile_args)?;
+            match dry_run.as_bool() {
+                Some(true) => args.push("--dry-run".to_string()),
+                Some(false) => {},
+                None => return Err("Invalid value for 'dryRun', must be true or false".to_string()),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Tags passed with `--tag` in the factfile args; none if they can't be parsed.
+    pub fn get_tags(&self) -> HashMap<String, String> {
+        extract_tags(&self.factfile_args).ok().and_then(|tags| tags).unwrap_or_default()
+    }
 }
 
 impl PartialEq for JobRequest {
@@ -165,11 +306,20 @@ impl PartialEq for JobRequest {
         self.job_id        == other.job_id &&
         self.job_name      == other.job_name &&
         self.factfile_path == other.factfile_path &&
-        self.factfile_args == other.factfile_args
+        self.factfile      == other.factfile &&
+        self.factfile_hash == other.factfile_hash &&
+        self.factfile_args == other.factfile_args &&
+        self.callback_url  == other.callback_url &&
+        self.tags          == other.tags &&
+        self.env           == other.env &&
+        self.start         == other.start &&
+        self.constraints   == other.constraints &&
+        self.dry_run       == other.dry_run &&
+        self.timeout_seconds == other.timeout_seconds
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SettingsRequest {
     pub state: String
@@ -197,6 +347,14 @@ impl SettingsRequest {
     }
 }
 
+/// A job request that passed validation, with the plan its dry run printed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRun {
+    pub job_request: JobRequest,
+    pub stdout: String,
+    pub stderr: String,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ValidationError {
     pub error: String,
@@ -236,15 +394,57 @@ impl From<String> for ValidationError {
     }
 }
 
+pub fn check_http_url(field: &str, value: &str) -> Result<(), String> {
+    match Url::parse(value) {
+        Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        Ok(_) => Err(format!("Invalid value for '{}':'{}', must be an http or https URL", field, value)),
+        Err(e) => Err(format!("Invalid value for '{}':'{}' - {}", field, value, e)),
+    }
+}
+
+// `{"key": "value"}` pairs in key order, for options that become `key,value` args
+fn get_string_pairs(field: &str, value: &Value) -> Result<Vec<(String, String)>, String> {
+    let invalid = || format!("Invalid value for '{}', must be an object of strings with non-empty keys and no commas in them", field);
+    let object = value.as_object().ok_or_else(&invalid)?;
+    let mut pairs = vec![];
+    for (key, value) in object.iter() {
+        let value = value.as_str().ok_or_else(&invalid)?;
+        if key.trim().is_empty() || key.contains(',') {
+            return Err(invalid())
+        }
+        pairs.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(pairs)
+}
+
+fn check_not_in_args(field: &str, flag: &str, factfile_args: &[String]) -> Result<(), String> {
+    let prefixed_flag = format!("{}=", flag);
+    if factfile_args.iter().any(|arg| arg == flag || arg.starts_with(&prefixed_flag)) {
+        return Err(format!("Invalid value for '{}', '{}' is already given in 'factfileArgs'", field, flag))
+    }
+    Ok(())
+}
+
+// factfiles are hashed in compact form, so formatting doesn't change the hash
+fn get_factfile_digest(factfile: &Value) -> Sha256 {
+    let ff = serde_json::to_string(factfile).expect("JSON compact encode error");
+    let mut digest = Sha256::new();
+    digest.input_str(&ff);
+    digest
+}
+
+/// SHA256 of a factfile's content, the part of a job id that comes before its tags.
+pub fn get_factfile_hash(factfile: &Value) -> String {
+    get_factfile_digest(factfile).result_str()
+}
+
 fn generate_id(factfile: &str, tags: Option<HashMap<String, String>>) -> Result<String, String> {
     let mut fh = try!(File::open(factfile)
         .map_err(|e| format!("Could not open '{}' for reading: {}", factfile, e)));
     let mut file = String::new();
     try!(fh.read_to_string(&mut file).map_err(|e| format!("Could not read '{}': {}", factfile, e)));
     let schema: serde_json::Value = try!(serde_json::from_str(&file).map_err(|e| e.to_string()));
-    let ff: String = try!(serde_json::to_string(&schema).map_err(|e| e.to_string()));
-    let mut job_digest = Sha256::new();
-    job_digest.input_str(&ff);
+    let mut job_digest = get_factfile_digest(&schema);
 
     if let Some(ref tags_map) = tags {
         let mut sorted_keys:Vec<_> = tags_map.keys().collect();
@@ -288,7 +488,7 @@ fn extract_tags(factfile_args: &Vec<String>) -> Result<Option<HashMap<String, St
     }
 }
 
-fn get_tag_map(args: &Vec<String>) -> HashMap<String, String> {
+pub fn get_tag_map(args: &Vec<String>) -> HashMap<String, String> {
     let mut arg_map: HashMap<String, String> = HashMap::new();
 
     for arg in args.iter() {
@@ -14,6 +14,7 @@
 
 use super::*;
 use std::error::Error;
+use std::io::Write;
 
 #[test]
 fn create_new_server_manager() {
@@ -82,6 +83,40 @@ fn job_request_invalid_factfile_path() {
     assert_eq!(validation_error, ValidationError::no_output("Value does not exist on host for 'factfilePath':'/tmp/somewhere'".to_string()));
 }
 
+#[test]
+fn job_request_invalid_callback_url() {
+    let mut job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
+    job_request.callback_url = Some("ftp://hooks.acme.test/".to_string());
+    let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_path".to_string()];
+    let validation_error = JobRequest::validate(job_request.clone(), &command_store).unwrap_err();
+    assert_eq!(validation_error, ValidationError::no_output("Invalid value for 'callbackUrl':'ftp://hooks.acme.test/', must be an http or https URL".to_string()));
+}
+
+#[test]
+fn job_request_internal_callback_url() {
+    let mut job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
+    job_request.callback_url = Some("http://10.0.0.5:8500/v1/kv/".to_string());
+    let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_path".to_string()];
+    let validation_error = JobRequest::validate(job_request.clone(), &command_store).unwrap_err();
+    assert_eq!(validation_error, ValidationError::no_output("Invalid value for 'callbackUrl':'http://10.0.0.5:8500/v1/kv/', host resolves to non-public address 10.0.0.5".to_string()));
+}
+
+#[test]
+fn job_request_zero_timeout() {
+    let mut job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
+    job_request.timeout_seconds = Some(0);
+    let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_path".to_string()];
+    let validation_error = JobRequest::validate(job_request.clone(), &command_store).unwrap_err();
+    assert_eq!(validation_error, ValidationError::no_output("Invalid value for 'timeoutSeconds', must be greater than 0".to_string()));
+}
+
+#[test]
+fn check_http_url_schemes() {
+    assert_eq!(Ok(()), check_http_url("url", "https://hooks.acme.test/factotum?token=abc"));
+    assert_eq!(Ok(()), check_http_url("url", "http://127.0.0.1:8080/"));
+    assert!(check_http_url("url", "hooks.acme.test").unwrap_err().starts_with("Invalid value for 'url':'hooks.acme.test' - "));
+}
+
 #[test]
 fn job_request_can_append_job_args() {
     let server_manager = ServerManager::new(Some("0.0.0.0".to_string()), 8080, "http://dummy.test/".to_string(), true, Some(10_000));
@@ -90,6 +125,62 @@ fn job_request_can_append_job_args() {
     assert_eq!(job_request.factfile_args, vec!["--first-arg", "--webhook", "http://dummy.test/", "--max-stdouterr-size", "10000", "--no-colour"]);
 }
 
+#[test]
+fn job_request_get_tags() {
+    let tagged = JobRequest::new("1", "dummy", "/tmp/somewhere", vec!["--tag".to_string(), "env,prod".to_string(), "--tag".to_string(), "nightly".to_string()]);
+    let untagged = JobRequest::new("1", "dummy", "/tmp/somewhere", vec!["--no-colour".to_string()]);
+
+    let tags = tagged.get_tags();
+    assert_eq!(2, tags.len());
+    assert_eq!(Some(&"prod".to_string()), tags.get("env"));
+    assert_eq!(Some(&"".to_string()), tags.get("nightly"));
+    assert!(untagged.get_tags().is_empty());
+}
+
+#[test]
+fn job_request_option_args() {
+    let mut job_request: JobRequest = serde_json::from_str(r#"{
+        "jobName": "dummy",
+        "factfilePath": "/tmp/somewhere",
+        "tags": { "env": "prod", "nightly": "" },
+        "env": { "bucket": "s3://acme" },
+        "start": "step-2",
+        "constraints": { "host": "worker-1" },
+        "dryRun": true
+    }"#).unwrap();
+
+    let option_args = job_request.take_option_args().unwrap();
+
+    assert_eq!(option_args, vec!["--tag", "env,prod", "--tag", "nightly", "--env", "{\"bucket\":\"s3://acme\"}", "--start", "step-2", "--constraint", "host,worker-1", "--dry-run"]);
+    assert_eq!(job_request, JobRequest::new("", "dummy", "/tmp/somewhere", vec![]));
+}
+
+#[test]
+fn job_request_option_args_wrong_types() {
+    let take_option_args = |options: &str| {
+        let mut job_request: JobRequest = serde_json::from_str(&format!(r#"{{ "jobName": "dummy", "factfilePath": "/tmp/somewhere", {} }}"#, options)).unwrap();
+        job_request.take_option_args().unwrap_err()
+    };
+
+    assert_eq!("Invalid value for 'tags', must be an object of strings with non-empty keys and no commas in them", take_option_args(r#""tags": { "env": 1 }"#));
+    assert_eq!("Invalid value for 'constraints', must be an object of strings with non-empty keys and no commas in them", take_option_args(r#""constraints": ["host,worker-1"]"#));
+    assert_eq!("Invalid value for 'constraints', 'host' must have a non-empty value", take_option_args(r#""constraints": { "host": "  " }"#));
+    assert_eq!("Invalid value for 'tags', 'k' must have no commas in its value", take_option_args(r#""tags": { "k": "a,b" }"#));
+    assert_eq!("Invalid value for 'env', must be a JSON object", take_option_args(r#""env": "{}""#));
+    assert_eq!("Invalid value for 'start', must be the name of a task", take_option_args(r#""start": """#));
+    assert_eq!("Invalid value for 'dryRun', must be true or false", take_option_args(r#""dryRun": "yes""#));
+    assert_eq!("Invalid value for 'start', '--start' is already given in 'factfileArgs'", take_option_args(r#""start": "step-2", "factfileArgs": ["--start=step-1"]"#));
+}
+
+#[test]
+fn job_request_invalid_options_fail_validation() {
+    let mut job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
+    job_request.dry_run = Some(Value::String("yes".to_string()));
+    let command_store = commands![::FACTOTUM.to_string() => "/tmp/fake_path".to_string()];
+    let validation_error = JobRequest::validate(job_request, &command_store).unwrap_err();
+    assert_eq!(validation_error, ValidationError::no_output("Invalid value for 'dryRun', must be true or false".to_string()));
+}
+
 #[test]
 fn settings_request_is_valid() {
     let settings_request = SettingsRequest::new(::SERVER_STATE_RUN);
@@ -103,3 +194,32 @@ fn settings_request_is_invalid() {
     let validation_error = SettingsRequest::validate(settings_request).err().unwrap();
     assert_eq!(validation_error.description(), "Invalid 'state', must be one of (run|drain)");
 }
+
+#[test]
+fn job_request_dry_run_keeps_output() {
+    let factfile_path = ::std::env::temp_dir().join(format!("factotum-dry-run-{}.factfile", ::std::process::id()));
+    ::std::fs::File::create(&factfile_path).unwrap().write_all(br#"{ "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0", "data": { "name": "echo", "tasks": [] } }"#).unwrap();
+    let factfile_path = factfile_path.to_string_lossy().into_owned();
+    let job_request = JobRequest::new("", "dummy", &factfile_path, vec!["--no-colour".to_string()]);
+    let command_store = commands![::FACTOTUM.to_string() => "/bin/echo".to_string()];
+    let dry_run = JobRequest::dry_run(job_request, &command_store).unwrap();
+    assert_eq!(format!("run {} --dry-run --no-colour\n", factfile_path), dry_run.stdout);
+    assert_eq!("", dry_run.stderr);
+    assert!(!dry_run.job_request.job_id.is_empty());
+
+    let mut job_request = JobRequest::new("", "dummy", &factfile_path, vec![]);
+    job_request.dry_run = Some(json!(true));
+    let dry_run = JobRequest::dry_run(job_request, &command_store).unwrap();
+    assert_eq!(format!("run {} --dry-run\n", factfile_path), dry_run.stdout);
+    assert_eq!(vec!["--dry-run".to_string()], dry_run.job_request.factfile_args);
+    ::std::fs::remove_file(&factfile_path).unwrap();
+}
+
+#[test]
+fn job_request_failed_dry_run_keeps_output() {
+    let job_request = JobRequest::new("", "dummy", "/tmp", vec![]);
+    let command_store = commands![::FACTOTUM.to_string() => "/bin/sh".to_string()];
+    let validation_error = JobRequest::validate(job_request, &command_store).unwrap_err();
+    assert!(validation_error.error.starts_with("Failed to execute command: [/bin/sh run /tmp --dry-run] - "));
+    assert!(!validation_error.stderr.is_empty());
+}
@@ -13,16 +13,112 @@
 //
 
 use super::*;
-use std::time::Duration;
+use factotum_server::consul::ConsulSecurity;
+use factotum_server::persistence::{apply_namespace_if_absent, JobEntry};
+use factotum_server::writer::{EntryUpdate, WriteRequest};
+use factotum_server::writer;
+use std::sync::Arc;
+use std::collections::HashMap;
+use base64::encode;
+use chrono::{Duration as ChronoDuration, UTC};
+use serde_json;
+
+// Emulates the Consul K/V store: values are returned base64 encoded and every
+// write bumps the key's ModifyIndex. Writes to `raced_keys` always lose the race.
+#[derive(Clone, Debug)]
+struct SharedQueueMock {
+    ref_map: Arc<Mutex<HashMap<String, (String, u64)>>>,
+    raced_keys: Vec<String>,
+}
+
+impl SharedQueueMock {
+    fn new(raced_keys: Vec<String>) -> Self {
+        SharedQueueMock {
+            ref_map: Arc::new(Mutex::new(HashMap::new())),
+            raced_keys: raced_keys,
+        }
+    }
+
+    fn insert_entry(&self, job_id: &str, state: JobState, minutes_ago: i64) {
+        let request = JobRequest::new(job_id, "dummy", "/tmp/somewhere", vec![]);
+        let mut job_entry = JobEntry::new(&state, &request, "other_server", &JobOutcome::WAITING);
+        job_entry.last_updated = Some(UTC::now() - ChronoDuration::minutes(minutes_ago));
+        let job_entry_json = serde_json::to_string(&job_entry).unwrap();
+        self.set_key(&self.prepend_namespace(job_id), &job_entry_json).unwrap();
+    }
+
+    fn get_state(&self, job_id: &str) -> JobState {
+        persistence::get_entry(self, job_id).unwrap().state
+    }
+
+    fn get_stored_entry(&self, job_id: &str) -> StoredJobEntry {
+        persistence::get_entries(self).unwrap().into_iter()
+            .find(|stored_entry| stored_entry.entry.job_request.job_id == job_id)
+            .unwrap()
+    }
+}
+
+impl Persistence for SharedQueueMock {
+    fn id(&self) -> &str {
+        "test_cluster"
+    }
+
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.lock().unwrap();
+        let index = map.get(key).map(|&(_, index)| index + 1).unwrap_or(1);
+        map.insert(key.to_owned(), (encode(value.as_bytes()), index));
+        Ok(())
+    }
+
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Ok(self.ref_map.lock().unwrap().get(key).cloned())
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError> {
+        let current_index = self.ref_map.lock().unwrap().get(key).map(|&(_, index)| index).unwrap_or(0);
+        if current_index != index || self.raced_keys.contains(&key.to_owned()) {
+            return Err(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))
+        }
+        self.set_key(key, value)
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        Ok(self.ref_map.lock().unwrap().iter()
+               .filter(|&(key, _)| key.starts_with(prefix))
+               .map(|(key, &(ref value, index))| (key.to_owned(), value.to_owned(), index))
+               .collect())
+    }
+
+    fn delete_key_if_index(&self, key: &str, _: u64) -> Result<(), PersistenceError> {
+        self.ref_map.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn prepend_namespace(&self, key: &str) -> String {
+        apply_namespace_if_absent("com.test/namespace", key)
+    }
+}
+
+fn worker_context<T: Persistence>(persistence: T, persistence_writer: PersistenceWriter, cluster_queue: Option<ClusterQueue>) -> WorkerContext<T> {
+    WorkerContext {
+        persistence: persistence,
+        persistence_writer: persistence_writer,
+        command_store: commands!["dummy".to_string() => "/tmp/fake_command".to_string()],
+        factfile_store: FactfileStore::new(None),
+        cluster_queue: cluster_queue,
+    }
+}
 
 #[test]
 fn worker_manager_spawn_check_queue_and_exit() {
     let (tx, rx) = mpsc::channel();
     let pool = ThreadPool::new(2);
-    let persistence = ConsulPersistence::new(None, None, None, None);
-    let command_store = commands!["dummy".to_string() => "/tmp/fake_command".to_string()];
+    let persistence = ConsulPersistence::new(None, None, None, None, ConsulSecurity::default());
+
+    let (writer_tx, _writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
 
-    let handle = spawn_worker_manager(tx.clone(), rx, VecDeque::new(), 2, pool.clone(), persistence, command_store);
+    let handle = spawn_worker_manager(tx.clone(), rx, VecDeque::new(), 2, pool.clone(), worker_context(persistence, persistence_writer, None));
 
     let (qtx, qrx) = mpsc::channel();
     let query = Query::new("queue_query", qtx);
@@ -37,6 +133,29 @@ fn worker_manager_spawn_check_queue_and_exit() {
     assert_eq!((), output);
 }
 
+#[test]
+fn worker_manager_cluster_status_answered_from_cache() {
+    let (tx, rx) = mpsc::channel();
+    let pool = ThreadPool::new(2);
+    // nothing listens on port 1, any read of the shared queue would fail
+    let persistence = ConsulPersistence::new(None, Some("127.0.0.1".to_string()), Some(1), None, ConsulSecurity::default());
+    let (writer_tx, _writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
+    let cluster_queue = ClusterQueue::new();
+    cluster_queue.set_queued_count(3);
+
+    let handle = spawn_worker_manager(tx.clone(), rx, VecDeque::new(), 10, pool, worker_context(persistence, persistence_writer, Some(cluster_queue)));
+
+    let (qtx, qrx) = mpsc::channel();
+    tx.send(Dispatch::StatusUpdate(Query::new("liveness_query", qtx))).unwrap();
+
+    let status = qrx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert_eq!(3, status.jobs.in_queue);
+
+    tx.send(Dispatch::StopProcessing).unwrap();
+    handle.join().unwrap();
+}
+
 #[test]
 fn send_status_update_success() {
     let (tx, rx) = mpsc::channel();
@@ -45,8 +164,11 @@ fn send_status_update_success() {
     let job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
     let mut requests_queue = VecDeque::new();
     requests_queue.push_back(job_request);
+    let (writer_tx, _writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
+    persistence_writer.write(EntryUpdate::new("1", &JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]), &JobState::DONE, &JobOutcome::SUCCEEDED, None)).unwrap();
 
-    send_status_update(query, &mut requests_queue, 10, &pool);
+    send_status_update(query, &mut requests_queue, 10, &pool, &persistence_writer);
 
     let actual = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     let expected = DispatcherStatus {
@@ -58,7 +180,10 @@ fn send_status_update_success() {
         jobs: JobStatus {
             max_queue_size: 10,
             in_queue: 1,
-        }
+        },
+        persistence: PersistenceStatus {
+            pending_writes: 1,
+        },
     };
     assert_eq!(expected, actual);
 }
@@ -94,11 +219,10 @@ fn is_queue_full_false() {
 fn new_job_request_success_with_threads_available() {
     let (tx, rx) = mpsc::channel();
     let pool = ThreadPool::new(2);
-    let persistence = ConsulPersistence::new(None, None, None, None);
     let job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
     let mut requests_queue = VecDeque::new();
 
-    let result = new_job_request(tx.clone(), &mut requests_queue, &pool, persistence, job_request.clone());
+    let result = new_job_request(tx.clone(), &mut requests_queue, &pool, job_request.clone());
     
     let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     assert_eq!(Dispatch::ProcessRequest, output);
@@ -110,16 +234,15 @@ fn new_job_request_success_with_threads_available() {
 fn new_job_request_success_with_no_threads_available() {
     let (tx, rx) = mpsc::channel();
     let pool = ThreadPool::new(1);
-    let persistence = ConsulPersistence::new(None, None, None, None);
     let job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
     let mut requests_queue = VecDeque::new();
 
-    let first = new_job_request(tx.clone(), &mut requests_queue, &pool, persistence.clone(), job_request.clone());
+    let first = new_job_request(tx.clone(), &mut requests_queue, &pool, job_request.clone());
     pool.execute(move || {
         thread::sleep(Duration::from_millis(1000));
     });
     thread::sleep(Duration::from_millis(100));
-    let second = new_job_request(tx.clone(), &mut requests_queue, &pool, persistence, job_request.clone());
+    let second = new_job_request(tx.clone(), &mut requests_queue, &pool, job_request.clone());
     
     let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     assert_eq!(Dispatch::ProcessRequest, output);
@@ -132,40 +255,273 @@ fn new_job_request_success_with_no_threads_available() {
 fn process_job_request_failure() {
     let (tx, rx) = mpsc::channel();
     let pool = ThreadPool::new(2);
-    let persistence = ConsulPersistence::new(None, None, None, None);
-    let command_store = commands!["dummy".to_string() => "/tmp/fake_command".to_string()];
+    let (writer_tx, writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
     let job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
     let mut requests_queue = VecDeque::new();
     requests_queue.push_back(job_request.clone());
 
-    process_job_request(tx.clone(), &mut requests_queue, &pool, persistence, command_store);
+    process_job_request(tx.clone(), &mut requests_queue, &pool, worker_context(SharedQueueMock::new(vec![]), persistence_writer, None));
+
+    let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    let expected_result = JobResult::new(None, "", "Command <factotum> not found in map.");
+    assert_eq!(Dispatch::RequestFailure(job_request.clone(), expected_result), output);
+    let update = writer_rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert_eq!(WriteRequest::Update(EntryUpdate::new("1", &job_request, &JobState::WORKING, &JobOutcome::RUNNING, None)), update);
+}
+
+#[test]
+fn is_cluster_queue_full_true() {
+    let (tx, rx) = mpsc::channel();
+    let query = Query::new("queue_query", tx);
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 2);
+    persistence.insert_entry("2", JobState::QUEUED, 1);
+    let cluster_queue = ClusterQueue::new();
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    refresh_cluster_queue(&persistence, &persistence_writer, &cluster_queue, UTC::now()).unwrap();
+
+    is_cluster_queue_full(query, &cluster_queue, 2);
+
+    let result = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert!(result == true);
+}
+
+#[test]
+fn is_cluster_queue_full_false() {
+    let (tx, rx) = mpsc::channel();
+    let query = Query::new("queue_query", tx);
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 2);
+    persistence.insert_entry("2", JobState::WORKING, 1);
+    persistence.insert_entry("3", JobState::DONE, 1);
+    let cluster_queue = ClusterQueue::new();
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    refresh_cluster_queue(&persistence, &persistence_writer, &cluster_queue, UTC::now()).unwrap();
+
+    is_cluster_queue_full(query, &cluster_queue, 2);
+
+    let result = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert!(result == false);
+}
+
+#[test]
+fn refresh_cluster_queue_requeues_orphaned_entries() {
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("orphaned", JobState::WORKING, 10);
+    persistence.insert_entry("running_here", JobState::WORKING, 10);
+    persistence.insert_entry("heartbeating", JobState::WORKING, 1);
+    persistence.insert_entry("queued", JobState::QUEUED, 1);
+    let cluster_queue = ClusterQueue::new();
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    cluster_queue.start_job(&persistence.get_stored_entry("running_here"));
+
+    let result = refresh_cluster_queue(&persistence, &persistence_writer, &cluster_queue, UTC::now());
+
+    assert_eq!(Ok(1), result);
+    assert_eq!(2, cluster_queue.queued_count());
+    assert_eq!(JobState::QUEUED, persistence.get_state("orphaned"));
+    assert_eq!(JobState::WORKING, persistence.get_state("running_here"));
+    assert_eq!(JobState::WORKING, persistence.get_state("heartbeating"));
+}
+
+#[test]
+fn refresh_cluster_queue_leaves_orphans_updated_by_peers() {
+    let persistence = SharedQueueMock::new(vec!["com.test/namespace/orphaned".to_string()]);
+    persistence.insert_entry("orphaned", JobState::WORKING, 10);
+    let cluster_queue = ClusterQueue::new();
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+
+    let result = refresh_cluster_queue(&persistence, &persistence_writer, &cluster_queue, UTC::now());
+
+    assert_eq!(Ok(0), result);
+    assert_eq!(0, cluster_queue.queued_count());
+    assert_eq!(JobState::WORKING, persistence.get_state("orphaned"));
+}
+
+#[test]
+fn send_cluster_heartbeat_rewrites_running_jobs() {
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 10);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    let cluster_queue = ClusterQueue::new();
+    cluster_queue.start_job(&take_next_queued_entry(&persistence, &persistence_writer).unwrap());
+
+    send_cluster_heartbeat(&cluster_queue, &persistence_writer);
+    send_cluster_heartbeat(&cluster_queue, &persistence_writer);
+
+    assert!(cluster_queue.is_running("1"));
+    assert_eq!(JobState::WORKING, persistence.get_state("1"));
+    assert_eq!(4, persistence.get_stored_entry("1").index);
+}
+
+#[test]
+fn send_cluster_heartbeat_stops_once_a_peer_takes_the_job() {
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 10);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    let cluster_queue = ClusterQueue::new();
+    cluster_queue.start_job(&take_next_queued_entry(&persistence, &persistence_writer).unwrap());
+    // a peer judged the job orphaned, put it back in the queue and took it itself
+    persistence.insert_entry("1", JobState::WORKING, 0);
+
+    send_cluster_heartbeat(&cluster_queue, &persistence_writer);
+
+    assert!(!cluster_queue.is_running("1"));
+    assert_eq!("other_server", persistence.get_stored_entry("1").entry.last_run_from);
+}
+
+#[test]
+fn new_cluster_job_request_success() {
+    let (tx, rx) = mpsc::channel();
+    let job_request = JobRequest::new("1", "dummy", "/tmp/somewhere", vec![]);
+    let cluster_queue = ClusterQueue::new();
+
+    new_cluster_job_request(tx, &cluster_queue, job_request);
 
     let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
-    assert_eq!(Dispatch::RequestFailure(job_request), output);
+    assert_eq!(Dispatch::ProcessRequest, output);
+    assert_eq!(1, cluster_queue.queued_count());
+}
+
+#[test]
+fn take_next_queued_entry_oldest_first() {
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("newer", JobState::QUEUED, 1);
+    persistence.insert_entry("older", JobState::QUEUED, 10);
+    persistence.insert_entry("done", JobState::DONE, 20);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+
+    let result = take_next_queued_entry(&persistence, &persistence_writer);
+
+    assert_eq!(Some(("older".to_string(), 2)), result.map(|stored_entry| (stored_entry.entry.job_request.job_id, stored_entry.index)));
+    assert_eq!(JobState::WORKING, persistence.get_state("older"));
+    assert_eq!(JobState::QUEUED, persistence.get_state("newer"));
+}
+
+#[test]
+fn take_next_queued_entry_skips_entries_taken_by_peers() {
+    let persistence = SharedQueueMock::new(vec!["com.test/namespace/older".to_string()]);
+    persistence.insert_entry("newer", JobState::QUEUED, 1);
+    persistence.insert_entry("older", JobState::QUEUED, 10);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+
+    let result = take_next_queued_entry(&persistence, &persistence_writer);
+
+    assert_eq!(Some("newer".to_string()), result.map(|stored_entry| stored_entry.entry.job_request.job_id));
+    assert_eq!(JobState::WORKING, persistence.get_state("newer"));
+}
+
+#[test]
+fn take_next_queued_entry_empty_queue() {
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("working", JobState::WORKING, 1);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+
+    assert_eq!(None, take_next_queued_entry(&persistence, &persistence_writer));
+}
+
+#[test]
+fn finish_cluster_job_writes_done_entry() {
+    let (tx, rx) = mpsc::channel();
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 10);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    let cluster_queue = ClusterQueue::new();
+    let stored_entry = take_next_queued_entry(&persistence, &persistence_writer).unwrap();
+    cluster_queue.start_job(&stored_entry);
+    let result = JobResult::new(Some(0), "done", "");
+
+    finish_cluster_job(tx, &persistence_writer, &cluster_queue, &stored_entry.entry.job_request, Ok(result.clone()));
+
+    assert_eq!(Dispatch::ProcessRequest, rx.recv_timeout(Duration::from_millis(1000)).unwrap());
+    assert!(!cluster_queue.is_running("1"));
+    let job_entry = persistence.get_stored_entry("1").entry;
+    assert_eq!((JobState::DONE, JobOutcome::SUCCEEDED, Some(result)), (job_entry.state, job_entry.last_outcome, job_entry.result));
+}
+
+#[test]
+fn finish_cluster_job_leaves_entry_taken_by_peer() {
+    let (tx, rx) = mpsc::channel();
+    let persistence = SharedQueueMock::new(vec![]);
+    persistence.insert_entry("1", JobState::QUEUED, 10);
+    let (persistence_writer, _) = writer::spawn_writer(persistence.clone());
+    let cluster_queue = ClusterQueue::new();
+    let stored_entry = take_next_queued_entry(&persistence, &persistence_writer).unwrap();
+    cluster_queue.start_job(&stored_entry);
+    // a peer judged the job orphaned, put it back in the queue and took it itself
+    persistence.insert_entry("1", JobState::WORKING, 0);
+
+    finish_cluster_job(tx, &persistence_writer, &cluster_queue, &stored_entry.entry.job_request, Err(JobResult::new(Some(1), "", "failed")));
+
+    assert_eq!(Dispatch::ProcessRequest, rx.recv_timeout(Duration::from_millis(1000)).unwrap());
+    assert!(!cluster_queue.is_running("1"));
+    let job_entry = persistence.get_stored_entry("1").entry;
+    assert_eq!((JobState::WORKING, "other_server".to_string()), (job_entry.state, job_entry.last_run_from));
 }
 
 #[test]
 fn complete_job_request_success() {
     let (tx, rx) = mpsc::channel();
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let (writer_tx, writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
     let job_request = JobRequest::new("dummy_id_1", "dummy", "/tmp/somewhere", vec![]);
 
-    let outcome = complete_job_request(tx, persistence, job_request);
+    let result = JobResult::new(Some(0), "done", "");
+
+    let outcome = complete_job_request(tx, &persistence_writer, job_request.clone(), result.clone());
 
     let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     assert_eq!(Dispatch::ProcessRequest, output);
     assert_eq!("COMPLETED JOB REQ  jobId:[dummy_id_1]".to_string(), outcome);
+    let update = writer_rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert_eq!(WriteRequest::Update(EntryUpdate::new("dummy_id_1", &job_request, &JobState::DONE, &JobOutcome::SUCCEEDED, Some(result))), update);
 }
 
 #[test]
 fn failed_job_request_success() {
     let (tx, rx) = mpsc::channel();
-    let persistence = ConsulPersistence::new(None, None, None, None);
+    let (writer_tx, writer_rx) = mpsc::channel();
+    let persistence_writer = PersistenceWriter::new(writer_tx);
     let job_request = JobRequest::new("dummy_id_1", "dummy", "/tmp/somewhere", vec![]);
 
-    let outcome = failed_job_request(tx, persistence, job_request);
+    let result = JobResult::new(Some(1), "", "failed");
+
+    let outcome = failed_job_request(tx, &persistence_writer, job_request.clone(), result.clone());
 
     let output = rx.recv_timeout(Duration::from_millis(1000)).unwrap();
     assert_eq!(Dispatch::ProcessRequest, output);
     assert_eq!("FAILED JOB REQ jobId:[dummy_id_1]".to_string(), outcome);
+    let update = writer_rx.recv_timeout(Duration::from_millis(1000)).unwrap();
+    assert_eq!(WriteRequest::Update(EntryUpdate::new("dummy_id_1", &job_request, &JobState::DONE, &JobOutcome::FAILED, Some(result))), update);
+}
+
+#[test]
+fn get_service_tags_success() {
+    let tags = get_service_tags(&Some("prod, eu-west-1,,batch".to_string()));
+    assert_eq!(vec!["prod".to_string(), "eu-west-1".to_string(), "batch".to_string()], tags);
+}
+
+#[test]
+fn get_service_tags_none() {
+    let tags = get_service_tags(&None);
+    assert!(tags.is_empty());
+}
+
+#[test]
+fn get_consul_token_prefers_flag() {
+    let token = get_consul_token(Some("flag_token".to_string()), Some("env_token".to_string()));
+    assert_eq!(Some("flag_token".to_string()), token);
+}
+
+#[test]
+fn get_consul_token_falls_back_to_env() {
+    let token = get_consul_token(None, Some("env_token".to_string()));
+    assert_eq!(Some("env_token".to_string()), token);
+}
+
+#[test]
+fn get_consul_token_empty_is_none() {
+    let token = get_consul_token(None, Some(String::new()));
+    assert_eq!(None, token);
 }
@@ -0,0 +1,202 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509Name;
+
+#[cfg(test)]
+mod tests;
+
+const TLS_RELOAD_INTERVAL_SECS: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: &str, key_path: &str, client_ca_path: Option<String>) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            client_ca_path: client_ca_path,
+        }
+    }
+
+    pub fn is_mutual(&self) -> bool {
+        self.client_ca_path.is_some()
+    }
+
+    fn paths(&self) -> Vec<&str> {
+        let mut paths = vec![self.cert_path.as_str(), self.key_path.as_str()];
+        if let Some(ref client_ca_path) = self.client_ca_path {
+            paths.push(client_ca_path);
+        }
+        paths
+    }
+}
+
+pub fn build_acceptor(config: &TlsConfig) -> Result<SslAcceptor, String> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|e| format!("Failed to set up TLS: {}", e))?;
+    builder.set_certificate_chain_file(&config.cert_path).map_err(|e| format!("Failed to load TLS certificate '{}': {}", config.cert_path, e))?;
+    builder.set_private_key_file(&config.key_path, SslFiletype::PEM).map_err(|e| format!("Failed to load TLS key '{}': {}", config.key_path, e))?;
+    builder.check_private_key().map_err(|e| format!("TLS key '{}' does not match certificate '{}': {}", config.key_path, config.cert_path, e))?;
+    if let Some(ref client_ca_path) = config.client_ca_path {
+        builder.set_ca_file(client_ca_path).map_err(|e| format!("Failed to load TLS client CA '{}': {}", client_ca_path, e))?;
+        let client_ca_names = X509Name::load_client_ca_file(client_ca_path).map_err(|e| format!("Failed to load TLS client CA '{}': {}", client_ca_path, e))?;
+        builder.set_client_ca_list(client_ca_names);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder.build())
+}
+
+// modification time and size of every file, so a rewrite in place is noticed
+fn get_fingerprint(config: &TlsConfig) -> Vec<Option<(SystemTime, u64)>> {
+    config.paths()
+          .iter()
+          .map(|path| fs::metadata(path).ok().and_then(|metadata| metadata.modified().ok().map(|modified| (modified, metadata.len()))))
+          .collect()
+}
+
+/// HTTPS support for the Iron listener whose certificates can be swapped while
+/// running. Handshakes in progress keep the acceptor they started with.
+#[derive(Clone)]
+pub struct TlsServer {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+    fingerprint: Arc<Mutex<Vec<Option<(SystemTime, u64)>>>>,
+}
+
+impl TlsServer {
+    pub fn new(config: TlsConfig) -> Result<TlsServer, String> {
+        let fingerprint = get_fingerprint(&config);
+        let acceptor = build_acceptor(&config)?;
+        Ok(TlsServer {
+            config: config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+            fingerprint: Arc::new(Mutex::new(fingerprint)),
+        })
+    }
+
+    pub fn config(&self) -> &TlsConfig {
+        &self.config
+    }
+
+    /// Rebuilds the acceptor when any of the files changed. A broken update
+    /// (e.g. the key renewed before the certificate) keeps the current acceptor
+    /// and is retried on the next call.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let fingerprint = get_fingerprint(&self.config);
+        let mut current = self.fingerprint.lock().map_err(|e| e.to_string())?;
+        if *current == fingerprint {
+            return Ok(false)
+        }
+        let acceptor = build_acceptor(&self.config)?;
+        *self.acceptor.write().map_err(|e| e.to_string())? = acceptor;
+        *current = fingerprint;
+        Ok(true)
+    }
+
+    fn get_acceptor(&self) -> Result<SslAcceptor, String> {
+        self.acceptor.read().map(|acceptor| acceptor.clone()).map_err(|e| e.to_string())
+    }
+}
+
+fn ssl_error(message: String) -> hyper::Error {
+    hyper::Error::Ssl(Box::new(io::Error::new(io::ErrorKind::Other, message)))
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let acceptor = self.get_acceptor().map_err(ssl_error)?;
+        match acceptor.accept(stream) {
+            Ok(ssl_stream) => Ok(TlsStream(Arc::new(Mutex::new(ssl_stream)))),
+            Err(e) => {
+                debug!("TLS handshake failed - {}", e);
+                Err(ssl_error(e.to_string()))
+            },
+        }
+    }
+}
+
+/// Hyper needs to clone connections, which an `SslStream` can't do by itself.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<SslStream<HttpStream>>>);
+
+impl TlsStream {
+    fn lock(&self) -> io::Result<MutexGuard<SslStream<HttpStream>>> {
+        self.0.lock().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock()?.get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock()?.get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.lock()?.get_mut().close(how)
+    }
+}
+
+pub fn spawn_tls_reloader(server: TlsServer) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(TLS_RELOAD_INTERVAL_SECS));
+            match server.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate from [{}]", server.config().cert_path),
+                Ok(false) => {},
+                Err(msg) => error!("Keeping current TLS certificate - {}", msg),
+            }
+        }
+    })
+}
@@ -0,0 +1,173 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::env;
+use std::fs::File;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::SslConnector;
+use openssl::x509::{X509, X509NameBuilder};
+
+fn new_test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("factotum-tls-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn new_self_signed(common_name: &str) -> (X509, PKey<Private>) {
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    (builder.build(), pkey)
+}
+
+fn write_pem(path: &PathBuf, pem: &[u8]) {
+    File::create(path).unwrap().write_all(pem).unwrap();
+}
+
+fn write_cert_and_key(dir: &PathBuf, common_name: &str) -> TlsConfig {
+    let (cert, pkey) = new_self_signed(common_name);
+    let cert_path = dir.join("server.crt");
+    let key_path = dir.join("server.key");
+    write_pem(&cert_path, &cert.to_pem().unwrap());
+    write_pem(&key_path, &pkey.private_key_to_pem_pkcs8().unwrap());
+    TlsConfig::new(cert_path.to_str().unwrap(), key_path.to_str().unwrap(), None)
+}
+
+#[test]
+fn build_acceptor_with_valid_cert_and_key() {
+    let dir = new_test_dir("valid");
+    let config = write_cert_and_key(&dir, "localhost");
+
+    assert!(build_acceptor(&config).is_ok());
+    assert_eq!(false, config.is_mutual());
+}
+
+#[test]
+fn build_acceptor_fails_with_mismatched_key() {
+    let dir = new_test_dir("mismatched");
+    let config = write_cert_and_key(&dir, "localhost");
+    let (_, other_key) = new_self_signed("localhost");
+    write_pem(&PathBuf::from(&config.key_path), &other_key.private_key_to_pem_pkcs8().unwrap());
+
+    assert!(build_acceptor(&config).is_err());
+}
+
+#[test]
+fn build_acceptor_fails_with_missing_files() {
+    let config = TlsConfig::new("/does/not/exist.crt", "/does/not/exist.key", None);
+
+    assert!(build_acceptor(&config).is_err());
+}
+
+#[test]
+fn reload_only_when_files_change() {
+    let dir = new_test_dir("reload");
+    let config = write_cert_and_key(&dir, "localhost");
+    let server = TlsServer::new(config.clone()).unwrap();
+
+    assert_eq!(Ok(false), server.reload_if_changed());
+
+    write_cert_and_key(&dir, "renewed.localhost");
+
+    assert_eq!(Ok(true), server.reload_if_changed());
+    assert_eq!(Ok(false), server.reload_if_changed());
+}
+
+#[test]
+fn reload_keeps_acceptor_when_update_is_broken() {
+    let dir = new_test_dir("broken");
+    let config = write_cert_and_key(&dir, "localhost");
+    let server = TlsServer::new(config.clone()).unwrap();
+
+    write_pem(&PathBuf::from(&config.cert_path), b"not a certificate");
+
+    assert!(server.reload_if_changed().is_err());
+    assert!(server.get_acceptor().is_ok());
+
+    write_cert_and_key(&dir, "localhost");
+
+    assert_eq!(Ok(true), server.reload_if_changed());
+}
+
+#[test]
+fn wrap_server_completes_handshake() {
+    let dir = new_test_dir("handshake");
+    let server = TlsServer::new(write_cert_and_key(&dir, "localhost")).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut stream = connector.build().connect("localhost", TcpStream::connect(address).unwrap()).unwrap();
+        stream.write_all(b"ping").unwrap();
+        stream.flush().unwrap();
+    });
+
+    let (tcp_stream, _) = listener.accept().unwrap();
+    let mut tls_stream = server.wrap_server(HttpStream(tcp_stream)).unwrap();
+    let mut buf = [0; 4];
+    tls_stream.read_exact(&mut buf).unwrap();
+    client.join().unwrap();
+
+    assert_eq!(b"ping", &buf);
+    assert!(tls_stream.peer_addr().is_ok());
+}
+
+#[test]
+fn wrap_server_requires_client_cert_for_mutual_tls() {
+    let dir = new_test_dir("mutual");
+    let mut config = write_cert_and_key(&dir, "localhost");
+    let (client_ca, _) = new_self_signed("client-ca");
+    let client_ca_path = dir.join("client-ca.crt");
+    write_pem(&client_ca_path, &client_ca.to_pem().unwrap());
+    config.client_ca_path = Some(client_ca_path.to_str().unwrap().to_string());
+    let server = TlsServer::new(config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        // with TLS 1.3 the rejection may only show up on the first read
+        if let Ok(mut stream) = connector.build().connect("localhost", TcpStream::connect(address).unwrap()) {
+            let _ = stream.write_all(b"ping");
+            let _ = stream.read(&mut [0; 4]);
+        }
+    });
+
+    let (tcp_stream, _) = listener.accept().unwrap();
+    let result = server.wrap_server(HttpStream(tcp_stream));
+    client.join().unwrap();
+
+    assert!(result.is_err());
+}
@@ -0,0 +1,667 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::cmp;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use base64::decode;
+use chrono::{DateTime, UTC};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use curl::easy::{Easy, List};
+use rustc_serialize::hex::ToHex;
+use serde_json;
+use threadpool::ThreadPool;
+use url::{Host, Url};
+
+use factotum_server::events::{Event, JobEvent};
+use factotum_server::persistence::{Persistence, PersistenceError, JobOutcome, JobState};
+use factotum_server::server;
+use factotum_server::server::ValidationError;
+use factotum_server::writer::get_retry_delay;
+
+#[cfg(test)]
+mod tests;
+
+// kept under the namespace but out of the job entries, which are never nested
+const SUBSCRIPTIONS_PREFIX: &'static str = "subscriptions/";
+
+pub const SIGNATURE_HEADER: &'static str = "X-Factotum-Signature";
+pub const EVENT_HEADER: &'static str = "X-Factotum-Event";
+pub const DELIVERY_HEADER: &'static str = "X-Factotum-Delivery";
+const SIGNATURE_PREFIX: &'static str = "sha256=";
+
+const MIN_SECRET_LENGTH: usize = 16;
+
+// with the persistence writer's backoff this keeps trying for about three minutes
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 10;
+
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+// subscriptions can be changed from any server sharing the namespace, so the
+// sender re-reads them this often rather than on every event
+const SUBSCRIPTIONS_REFRESH_SECS: u64 = 30;
+
+// callbacks are sent in parallel so one slow receiver doesn't delay the rest
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+// how soon the sender looks for the results of callbacks being sent
+const SENDING_POLL_MILLIS: u64 = 500;
+
+/// Job-level transitions a callback can be sent for. Nothing produces
+/// `Cancelled` until jobs can be cancelled, but subscribing to it is allowed
+/// so subscriptions don't need changing when they can.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebhookEvent {
+    Submitted,
+    Started,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl WebhookEvent {
+    pub fn from_transition(state: &JobState, outcome: &JobOutcome) -> Option<WebhookEvent> {
+        match (state, outcome) {
+            (&JobState::QUEUED, _) => Some(WebhookEvent::Submitted),
+            (&JobState::WORKING, _) => Some(WebhookEvent::Started),
+            (&JobState::DONE, &JobOutcome::SUCCEEDED) => Some(WebhookEvent::Succeeded),
+            (&JobState::DONE, &JobOutcome::FAILED) => Some(WebhookEvent::Failed),
+            (&JobState::DONE, _) => None,
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(event: &str) -> Result<WebhookEvent, String> {
+        match event {
+            "job.submitted" => Ok(WebhookEvent::Submitted),
+            "job.started" => Ok(WebhookEvent::Started),
+            "job.succeeded" => Ok(WebhookEvent::Succeeded),
+            "job.failed" => Ok(WebhookEvent::Failed),
+            "job.cancelled" => Ok(WebhookEvent::Cancelled),
+            _ => Err(format!("Unknown event '{}', must be one of (job.submitted|job.started|job.succeeded|job.failed|job.cancelled)", event)),
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let event = match *self {
+            WebhookEvent::Submitted => "job.submitted",
+            WebhookEvent::Started => "job.started",
+            WebhookEvent::Succeeded => "job.succeeded",
+            WebhookEvent::Failed => "job.failed",
+            WebhookEvent::Cancelled => "job.cancelled",
+        };
+        write!(f, "{}", event)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionRequest {
+    pub url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub job_name: Option<String>,
+}
+
+impl SubscriptionRequest {
+    pub fn validate(request: SubscriptionRequest) -> Result<SubscriptionRequest, ValidationError> {
+        check_public_url("url", &request.url)?;
+        if request.secret.len() < MIN_SECRET_LENGTH {
+            return Err(ValidationError::no_output(format!("Invalid 'secret', must be at least {} characters", MIN_SECRET_LENGTH)))
+        }
+        for event in request.events.iter() {
+            WebhookEvent::from_str(event).map_err(ValidationError::no_output)?;
+        }
+        Ok(request)
+    }
+}
+
+/// A registered callback, stored in Consul so every server sharing the namespace
+/// sends to it. An empty `events` list means every event.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookSubscription {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub job_name: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<UTC>,
+}
+
+impl WebhookSubscription {
+    pub fn new(request: SubscriptionRequest, created_by: Option<String>) -> WebhookSubscription {
+        let created_at = UTC::now();
+        WebhookSubscription {
+            id: generate_subscription_id(&request.url, &created_at),
+            url: request.url,
+            secret: request.secret,
+            events: request.events,
+            job_name: request.job_name,
+            created_by: created_by,
+            created_at: created_at,
+        }
+    }
+
+    pub fn matches(&self, event: WebhookEvent, job_name: &str) -> bool {
+        let wants_event = self.events.is_empty() || self.events.iter().any(|wanted| *wanted == event.to_string());
+        let wants_job = self.job_name.as_ref().map(|wanted| wanted == job_name).unwrap_or(true);
+        wants_event && wants_job
+    }
+}
+
+/// A subscription as shown back to clients, without its secret.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionSummary {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub job_name: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<UTC>,
+}
+
+impl SubscriptionSummary {
+    pub fn new(subscription: WebhookSubscription) -> SubscriptionSummary {
+        SubscriptionSummary {
+            id: subscription.id,
+            url: subscription.url,
+            events: subscription.events,
+            job_name: subscription.job_name,
+            created_by: subscription.created_by,
+            created_at: subscription.created_at,
+        }
+    }
+}
+
+fn generate_subscription_id(url: &str, created_at: &DateTime<UTC>) -> String {
+    let mut digest = Sha256::new();
+    digest.input_str(url);
+    digest.input_str(&created_at.to_rfc3339());
+    digest.result_str()[..16].to_string()
+}
+
+/// Checks a callback URL can only reach the public internet: every address its
+/// host resolves to must be public, so a URL can't point the server at the cloud
+/// metadata service, the Consul agent or anything else only reachable from here.
+/// Gives the `host:port:address` entry that pins a request to the address that
+/// was checked, none when the URL names an address itself.
+pub fn check_public_url(field: &str, value: &str) -> Result<Option<String>, String> {
+    server::check_http_url(field, value)?;
+    let url = Url::parse(value).map_err(|e| format!("Invalid value for '{}':'{}' - {}", field, value, e))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let (addresses, domain) = match url.host() {
+        Some(Host::Ipv4(address)) => (vec![IpAddr::V4(address)], None),
+        Some(Host::Ipv6(address)) => (vec![IpAddr::V6(address)], None),
+        Some(Host::Domain(domain)) => {
+            let addresses = (domain, port).to_socket_addrs()
+                .map_err(|e| format!("Invalid value for '{}':'{}', could not resolve host - {}", field, value, e))?
+                .map(|socket_address| socket_address.ip())
+                .collect();
+            (addresses, Some(domain))
+        },
+        None => (vec![], None),
+    };
+    if let Some(address) = addresses.iter().find(|address| !is_public_address(address)) {
+        return Err(format!("Invalid value for '{}':'{}', host resolves to non-public address {}", field, value, address))
+    }
+    match (addresses.first(), domain) {
+        (Some(address), Some(domain)) => Ok(Some(format!("{}:{}:{}", domain, port, address))),
+        (Some(_), None) => Ok(None),
+        (None, _) => Err(format!("Invalid value for '{}':'{}', host has no addresses", field, value)),
+    }
+}
+
+/// Checks a job's callback URL as far as can be done without resolving its host:
+/// it must be http or https, and an address given in place of a host must be
+/// public. Hosts are resolved and checked with `check_public_url` when the
+/// callback is sent, so a submission never waits on DNS.
+pub fn check_callback_url(field: &str, value: &str) -> Result<(), String> {
+    server::check_http_url(field, value)?;
+    let url = Url::parse(value).map_err(|e| format!("Invalid value for '{}':'{}' - {}", field, value, e))?;
+    let address = match url.host() {
+        Some(Host::Ipv4(address)) => IpAddr::V4(address),
+        Some(Host::Ipv6(address)) => IpAddr::V6(address),
+        _ => return Ok(()),
+    };
+    if !is_public_address(&address) {
+        return Err(format!("Invalid value for '{}':'{}', host resolves to non-public address {}", field, value, address))
+    }
+    Ok(())
+}
+
+pub fn is_public_address(address: &IpAddr) -> bool {
+    match *address {
+        IpAddr::V4(ref address) => is_public_ipv4(address),
+        IpAddr::V6(ref address) => match ipv4_in_ipv6(address) {
+            Some(ref mapped) => is_public_ipv4(mapped),
+            None => {
+                let first_segment = address.segments()[0];
+                !(address.is_loopback() || address.is_unspecified() || address.is_multicast() ||
+                  (first_segment & 0xfe00) == 0xfc00 ||     // unique local, fc00::/7
+                  (first_segment & 0xffc0) == 0xfe80)       // link-local, fe80::/10
+            },
+        },
+    }
+}
+
+fn is_public_ipv4(address: &Ipv4Addr) -> bool {
+    let octets = address.octets();
+    !(address.is_loopback() || address.is_private() || address.is_link_local() ||
+      address.is_unspecified() || address.is_broadcast() || address.is_multicast() ||
+      octets[0] == 0 ||                                 // "this network", 0.0.0.0/8
+      (octets[0] == 100 && (octets[1] & 0xc0) == 64))   // carrier-grade NAT, 100.64.0.0/10
+}
+
+// IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d) addresses reach the IPv4 host
+fn ipv4_in_ipv6(address: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match address.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] | [0, 0, 0, 0, 0, 0, _, _] if !address.is_loopback() && !address.is_unspecified() => address.to_ipv4(),
+        _ => None,
+    }
+}
+
+// Subscription storage
+
+fn get_subscription_key<T: Persistence>(persistence: &T, id: &str) -> String {
+    persistence.prepend_namespace(&format!("{}{}", SUBSCRIPTIONS_PREFIX, id))
+}
+
+pub fn save_subscription<T: Persistence>(persistence: &T, subscription: &WebhookSubscription) -> Result<(), PersistenceError> {
+    let subscription_json = serde_json::to_string(subscription).expect("JSON compact encode error");
+    persistence.set_key(&get_subscription_key(persistence, &subscription.id), &subscription_json)
+}
+
+/// Every stored subscription; one that can't be decoded is skipped rather than
+/// stopping callbacks to the rest.
+pub fn get_subscriptions<T: Persistence>(persistence: &T) -> Result<Vec<WebhookSubscription>, PersistenceError> {
+    let keys = persistence.get_keys_with_index(&persistence.prepend_namespace(SUBSCRIPTIONS_PREFIX))?;
+    let mut subscriptions: Vec<WebhookSubscription> = keys.into_iter()
+        .filter_map(|(key, base64_str, _)| {
+            let decoded = decode(&base64_str).map_err(|e| e.to_string())
+                .and_then(|bytes| String::from_utf8(bytes).map_err(|e| e.to_string()))
+                .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+            match decoded {
+                Ok(subscription) => Some(subscription),
+                Err(e) => {
+                    warn!("Skipping webhook subscription '{}' - {}", key, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    subscriptions.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(subscriptions)
+}
+
+/// The subscriptions last read from storage, read again once they are older than
+/// the refresh interval. If a read fails the last ones read are kept.
+pub struct SubscriptionCache {
+    subscriptions: Vec<WebhookSubscription>,
+    refreshed_at: Option<Instant>,
+    refresh_interval: Duration,
+}
+
+impl SubscriptionCache {
+    pub fn new(refresh_interval: Duration) -> SubscriptionCache {
+        SubscriptionCache {
+            subscriptions: vec![],
+            refreshed_at: None,
+            refresh_interval: refresh_interval,
+        }
+    }
+
+    pub fn get<T: Persistence>(&mut self, persistence: &T, now: Instant) -> &[WebhookSubscription] {
+        let is_stale = self.refreshed_at.map(|refreshed_at| now >= refreshed_at + self.refresh_interval).unwrap_or(true);
+        if is_stale {
+            match get_subscriptions(persistence) {
+                Ok(subscriptions) => self.subscriptions = subscriptions,
+                Err(e) => error!("Could not read webhook subscriptions, using the {} last read - {}", self.subscriptions.len(), e),
+            }
+            self.refreshed_at = Some(now);
+        }
+        &self.subscriptions
+    }
+}
+
+pub fn delete_subscription<T: Persistence>(persistence: &T, id: &str) -> Result<(), PersistenceError> {
+    let key = get_subscription_key(persistence, id);
+    match persistence.get_key_with_index(&key)? {
+        Some((_, index)) => persistence.delete_key_if_index(&key, index),
+        None => Err(PersistenceError::NotFound(format!("No webhook subscription found with id '{}'", id))),
+    }
+}
+
+// Delivery
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub callback_secret: Option<String>,
+    pub dead_letter_path: String,
+}
+
+impl WebhookConfig {
+    pub fn new(callback_secret_file: Option<String>, dead_letter_path: Option<String>) -> Result<WebhookConfig, String> {
+        let callback_secret = match callback_secret_file {
+            Some(path) => Some(read_secret_file(&path)?),
+            None => None,
+        };
+        Ok(WebhookConfig {
+            callback_secret: callback_secret,
+            dead_letter_path: dead_letter_path.unwrap_or(::CALLBACK_DEAD_LETTER_LOG_DEFAULT.to_string()),
+        })
+    }
+}
+
+fn read_secret_file(path: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| format!("Could not read callback secret file '{}' - {}", path, e))?;
+    let secret = contents.trim().to_string();
+    if secret.len() < MIN_SECRET_LENGTH {
+        return Err(format!("Callback secret in '{}' must be at least {} characters", path, MIN_SECRET_LENGTH))
+    }
+    Ok(secret)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload<'a> {
+    pub id: String,
+    pub event: String,
+    pub timestamp: DateTime<UTC>,
+    pub job: &'a JobEvent,
+}
+
+/// Hex HMAC-SHA256 of the body, so receivers can check a callback came from us.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body.as_bytes());
+    format!("{}{}", SIGNATURE_PREFIX, hmac.result().code().to_hex())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub id: String,
+    pub url: String,
+    pub event: WebhookEvent,
+    pub body: String,
+    pub signature: String,
+    pub attempts: u32,
+    pub next_attempt: Instant,
+    pub last_error: String,
+}
+
+/// One delivery per matching subscription, plus one to the job's own
+/// `callbackUrl` signed with the server's callback secret.
+pub fn get_deliveries(job_event: &JobEvent, subscriptions: &[WebhookSubscription], callback_secret: Option<&str>, now: Instant) -> Vec<Delivery> {
+    let event = match WebhookEvent::from_transition(&job_event.state, &job_event.outcome) {
+        Some(event) => event,
+        None => return vec![],
+    };
+    let payload = WebhookPayload {
+        id: format!("{}.{}.{}", job_event.job_id, event, job_event.timestamp.timestamp()),
+        event: event.to_string(),
+        timestamp: job_event.timestamp,
+        job: job_event,
+    };
+    let body = serde_json::to_string(&payload).expect("JSON compact encode error");
+
+    let mut targets: Vec<(String, String)> = subscriptions.iter()
+        .filter(|subscription| subscription.matches(event, &job_event.job_name))
+        .map(|subscription| (subscription.url.clone(), subscription.secret.clone()))
+        .collect();
+    match (job_event.callback_url.as_ref(), callback_secret) {
+        (Some(callback_url), Some(secret)) => targets.push((callback_url.clone(), secret.to_string())),
+        (Some(_), None) => warn!("Not calling back for job jobId:[{}] - no callback secret configured", job_event.job_id),
+        _ => {},
+    }
+
+    targets.into_iter()
+        .map(|(url, secret)| Delivery {
+            id: payload.id.clone(),
+            url: url,
+            event: event,
+            signature: sign(&secret, &body),
+            body: body.clone(),
+            attempts: 0,
+            next_attempt: now,
+            last_error: String::new(),
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub delivery_id: String,
+    pub url: String,
+    pub event: String,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: DateTime<UTC>,
+    pub payload: serde_json::Value,
+}
+
+impl DeadLetter {
+    pub fn new(delivery: Delivery) -> DeadLetter {
+        DeadLetter {
+            payload: serde_json::from_str(&delivery.body).unwrap_or(serde_json::Value::Null),
+            delivery_id: delivery.id,
+            url: delivery.url,
+            event: delivery.event.to_string(),
+            attempts: delivery.attempts,
+            last_error: delivery.last_error,
+            failed_at: UTC::now(),
+        }
+    }
+}
+
+/// Callbacks waiting to be sent or retried, and those being sent.
+pub struct DeliveryQueue {
+    deliveries: Vec<Delivery>,
+    sending: usize,
+    results_tx: Sender<(Delivery, Result<(), String>)>,
+    results_rx: Receiver<(Delivery, Result<(), String>)>,
+}
+
+impl DeliveryQueue {
+    pub fn new() -> DeliveryQueue {
+        let (results_tx, results_rx) = mpsc::channel();
+        DeliveryQueue {
+            deliveries: vec![],
+            sending: 0,
+            results_tx: results_tx,
+            results_rx: results_rx,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deliveries.is_empty() && self.sending == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.deliveries.len() + self.sending
+    }
+
+    pub fn push(&mut self, delivery: Delivery) {
+        self.deliveries.push(delivery);
+    }
+
+    /// Takes the results of deliveries sent since the last flush, keeping failed
+    /// ones for a retry with exponential backoff, then hands every delivery that
+    /// is due to the pool without waiting for it. Returns the deliveries that ran
+    /// out of attempts.
+    pub fn flush<F>(&mut self, pool: &ThreadPool, send: F, now: Instant) -> Vec<DeadLetter> where
+        F: 'static + Fn(&Delivery) -> Result<(), String> + Send + Sync {
+        let mut dead_letters = vec![];
+        while let Ok((mut delivery, result)) = self.results_rx.try_recv() {
+            self.sending -= 1;
+            match result {
+                Ok(_) => debug!("Delivered [{}] to [{}]", delivery.id, delivery.url),
+                Err(e) => {
+                    delivery.attempts += 1;
+                    delivery.last_error = e;
+                    if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+                        error!("Giving up on callback [{}] to [{}] after {} attempts - {}", delivery.id, delivery.url, delivery.attempts, delivery.last_error);
+                        dead_letters.push(DeadLetter::new(delivery));
+                    } else {
+                        warn!("Callback [{}] to [{}] failed, attempt {} - {}", delivery.id, delivery.url, delivery.attempts, delivery.last_error);
+                        delivery.next_attempt = now + get_retry_delay(delivery.attempts);
+                        self.deliveries.push(delivery);
+                    }
+                },
+            }
+        }
+
+        let send = Arc::new(send);
+        let (due, waiting): (Vec<Delivery>, Vec<Delivery>) = self.deliveries.drain(..).partition(|delivery| delivery.next_attempt <= now);
+        self.deliveries = waiting;
+        for delivery in due {
+            let send = send.clone();
+            let results_tx = self.results_tx.clone();
+            self.sending += 1;
+            pool.execute(move || {
+                let result = send(&delivery);
+                results_tx.send((delivery, result)).unwrap_or(());
+            });
+        }
+        dead_letters
+    }
+
+    /// How long until the next flush has something to do: a retry falls due, or
+    /// callbacks being sent may have finished.
+    pub fn time_until_next_attempt(&self, now: Instant) -> Option<Duration> {
+        let next_attempt = self.deliveries.iter()
+                                          .map(|delivery| delivery.next_attempt)
+                                          .min()
+                                          .map(|next_attempt| if next_attempt > now { next_attempt - now } else { Duration::from_millis(0) });
+        if self.sending == 0 {
+            return next_attempt
+        }
+        let poll = Duration::from_millis(SENDING_POLL_MILLIS);
+        Some(next_attempt.map(|next_attempt| cmp::min(next_attempt, poll)).unwrap_or(poll))
+    }
+}
+
+/// Appends each dead letter to the log as a line of JSON.
+pub fn write_dead_letters<W: Write>(log: &mut W, dead_letters: &[DeadLetter]) -> Result<(), String> {
+    for dead_letter in dead_letters {
+        let line = serde_json::to_string(dead_letter).expect("JSON compact encode error");
+        writeln!(log, "{}", line).map_err(|e| e.to_string())?;
+    }
+    log.flush().map_err(|e| e.to_string())
+}
+
+fn post_delivery(delivery: &Delivery) -> Result<(), String> {
+    // checked again as the host's addresses may have changed since it was accepted
+    let pinned_address = check_public_url("url", &delivery.url)?;
+    let mut easy = Easy::new();
+    configure(&mut easy, delivery, pinned_address).map_err(|e| format!("Error building request - {}", e))?;
+    easy.transfer().perform().map_err(|e| e.to_string())?;
+    match easy.response_code().map_err(|e| e.to_string())? {
+        code if code >= 200 && code < 300 => Ok(()),
+        code => Err(format!("HTTP {}", code)),
+    }
+}
+
+fn configure(easy: &mut Easy, delivery: &Delivery, pinned_address: Option<String>) -> Result<(), ::curl::Error> {
+    easy.url(&delivery.url)?;
+    if let Some(pinned_address) = pinned_address {
+        let mut resolve = List::new();
+        resolve.append(&pinned_address)?;
+        easy.resolve(resolve)?;
+    }
+    // redirects are never followed, so a public receiver can't bounce a callback inwards
+    easy.follow_location(false)?;
+    easy.post(true)?;
+    easy.post_fields_copy(delivery.body.as_bytes())?;
+    easy.connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+    easy.timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))?;
+    let mut headers = List::new();
+    headers.append(&format!("Content-Type: {}", ::JSON_CONTENT_TYPE))?;
+    headers.append(&format!("User-Agent: factotum-server/{}", ::VERSION))?;
+    headers.append(&format!("{}: {}", EVENT_HEADER, delivery.event))?;
+    headers.append(&format!("{}: {}", DELIVERY_HEADER, delivery.id))?;
+    headers.append(&format!("{}: {}", SIGNATURE_HEADER, delivery.signature))?;
+    easy.http_headers(headers)
+}
+
+/// Sends callbacks for the job events it is given, on its own thread so a slow
+/// receiver never holds up a job.
+pub fn spawn_webhook_sender<T: 'static + Persistence + Send>(persistence: T, config: WebhookConfig) -> (Sender<(u64, Event)>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let join_handle = thread::spawn(move || run_webhook_sender(rx, persistence, config));
+    (tx, join_handle)
+}
+
+fn run_webhook_sender<T: Persistence>(rx: Receiver<(u64, Event)>, persistence: T, config: WebhookConfig) {
+    let mut queue = DeliveryQueue::new();
+    let mut subscriptions = SubscriptionCache::new(Duration::from_secs(SUBSCRIPTIONS_REFRESH_SECS));
+    let pool = ThreadPool::new_with_name("webhook_pool".to_string(), MAX_CONCURRENT_DELIVERIES);
+    loop {
+        // sleep until the next event arrives or a failed callback is due for a retry
+        let received = match queue.time_until_next_attempt(Instant::now()) {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((_, Event::Job(job_event))) => {
+                let now = Instant::now();
+                for delivery in get_deliveries(&job_event, subscriptions.get(&persistence, now), config.callback_secret.as_ref().map(|secret| secret.as_str()), now) {
+                    queue.push(delivery);
+                }
+            },
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                if !queue.is_empty() {
+                    error!("Webhook sender stopping with {} callbacks unsent", queue.len());
+                }
+                break;
+            },
+        }
+        let dead_letters = queue.flush(&pool, post_delivery, Instant::now());
+        if !dead_letters.is_empty() {
+            let written = OpenOptions::new().create(true).append(true).open(&config.dead_letter_path)
+                .map_err(|e| e.to_string())
+                .and_then(|mut log| write_dead_letters(&mut log, &dead_letters));
+            if let Err(e) = written {
+                error!("Could not write {} dead letters to '{}' - {}", dead_letters.len(), config.dead_letter_path, e);
+            }
+        }
+    }
+}
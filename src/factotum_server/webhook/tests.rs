@@ -0,0 +1,267 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use base64::encode;
+use factotum_server::persistence::tests::ConsulKeyValueMock;
+use factotum_server::server::JobRequest;
+
+fn subscription_request(url: &str, events: &[&str], job_name: Option<&str>) -> SubscriptionRequest {
+    SubscriptionRequest {
+        url: url.to_string(),
+        secret: "0123456789abcdef".to_string(),
+        events: events.iter().map(|event| event.to_string()).collect(),
+        job_name: job_name.map(|job_name| job_name.to_string()),
+    }
+}
+
+fn job_event(state: JobState, outcome: JobOutcome, callback_url: Option<&str>) -> JobEvent {
+    let mut job_request = JobRequest::new("dummy_id_1", "com.acme-main", "/tmp/somewhere", vec![]);
+    job_request.callback_url = callback_url.map(|url| url.to_string());
+    match Event::job(&job_request, &state, &outcome) {
+        Event::Job(mut job_event) => {
+            job_event.timestamp = "2017-05-01T12:00:00Z".parse().unwrap();
+            job_event
+        },
+        Event::Server(_) => unreachable!(),
+    }
+}
+
+fn failing_delivery(now: Instant) -> Delivery {
+    get_deliveries(&job_event(JobState::DONE, JobOutcome::FAILED, Some("http://hooks.acme.test/")), &[], Some("0123456789abcdef"), now).remove(0)
+}
+
+#[test]
+fn sign_body_with_hmac_sha256() {
+    // RFC 4231 test case 2
+    assert_eq!("sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843", sign("Jefe", "what do ya want for nothing?"));
+}
+
+#[test]
+fn webhook_event_from_transition() {
+    assert_eq!(Some(WebhookEvent::Submitted), WebhookEvent::from_transition(&JobState::QUEUED, &JobOutcome::WAITING));
+    assert_eq!(Some(WebhookEvent::Started), WebhookEvent::from_transition(&JobState::WORKING, &JobOutcome::RUNNING));
+    assert_eq!(Some(WebhookEvent::Succeeded), WebhookEvent::from_transition(&JobState::DONE, &JobOutcome::SUCCEEDED));
+    assert_eq!(Some(WebhookEvent::Failed), WebhookEvent::from_transition(&JobState::DONE, &JobOutcome::FAILED));
+    assert_eq!(None, WebhookEvent::from_transition(&JobState::DONE, &JobOutcome::RUNNING));
+}
+
+#[test]
+fn webhook_event_names_round_trip() {
+    for event in vec![WebhookEvent::Submitted, WebhookEvent::Started, WebhookEvent::Succeeded, WebhookEvent::Failed, WebhookEvent::Cancelled] {
+        assert_eq!(Ok(event), WebhookEvent::from_str(&event.to_string()));
+    }
+    assert!(WebhookEvent::from_str("job.exploded").is_err());
+}
+
+#[test]
+fn validate_subscription_request() {
+    assert!(SubscriptionRequest::validate(subscription_request("https://203.0.113.10/factotum", &["job.failed"], None)).is_ok());
+
+    let invalid_url = SubscriptionRequest::validate(subscription_request("ftp://hooks.acme.test/", &[], None)).unwrap_err();
+    assert_eq!("Invalid value for 'url':'ftp://hooks.acme.test/', must be an http or https URL", invalid_url.error);
+
+    let invalid_event = SubscriptionRequest::validate(subscription_request("https://203.0.113.10/", &["job.exploded"], None)).unwrap_err();
+    assert!(invalid_event.error.starts_with("Unknown event 'job.exploded'"));
+
+    let mut short_secret = subscription_request("https://203.0.113.10/", &[], None);
+    short_secret.secret = "hunter2".to_string();
+    assert_eq!("Invalid 'secret', must be at least 16 characters", SubscriptionRequest::validate(short_secret).unwrap_err().error);
+
+    let internal_url = SubscriptionRequest::validate(subscription_request("http://169.254.169.254/latest/meta-data/", &[], None)).unwrap_err();
+    assert_eq!("Invalid value for 'url':'http://169.254.169.254/latest/meta-data/', host resolves to non-public address 169.254.169.254", internal_url.error);
+}
+
+#[test]
+fn public_addresses_only() {
+    let public = ["203.0.113.10", "8.8.8.8", "100.128.0.1", "2001:4860:4860::8888", "::ffff:8.8.8.8"];
+    for address in public.iter() {
+        assert!(is_public_address(&address.parse().unwrap()), "{} should be public", address);
+    }
+    let internal = ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "0.1.2.3",
+                    "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "ff02::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254"];
+    for address in internal.iter() {
+        assert!(!is_public_address(&address.parse().unwrap()), "{} should not be public", address);
+    }
+}
+
+#[test]
+fn check_public_url_rejects_internal_hosts() {
+    assert_eq!(Ok(None), check_public_url("url", "https://203.0.113.10:8443/factotum"));
+    assert!(check_public_url("url", "http://localhost:8500/v1/kv/").unwrap_err().starts_with("Invalid value for 'url':'http://localhost:8500/v1/kv/', host resolves to non-public address "));
+    assert_eq!(Err("Invalid value for 'url':'http://[::1]/', host resolves to non-public address ::1".to_string()), check_public_url("url", "http://[::1]/"));
+}
+
+#[test]
+fn check_callback_url_never_resolves() {
+    assert_eq!(Ok(()), check_callback_url("callbackUrl", "https://hooks.acme.test/factotum"));
+    assert_eq!(Ok(()), check_callback_url("callbackUrl", "https://203.0.113.10:8443/factotum"));
+    assert_eq!(Err("Invalid value for 'callbackUrl':'http://169.254.169.254/', host resolves to non-public address 169.254.169.254".to_string()), check_callback_url("callbackUrl", "http://169.254.169.254/"));
+    assert_eq!(Err("Invalid value for 'callbackUrl':'ftp://hooks.acme.test/', must be an http or https URL".to_string()), check_callback_url("callbackUrl", "ftp://hooks.acme.test/"));
+}
+
+#[test]
+fn subscription_matches_events_and_job_name() {
+    let everything = WebhookSubscription::new(subscription_request("https://hooks.acme.test/", &[], None), None);
+    let failures = WebhookSubscription::new(subscription_request("https://hooks.acme.test/", &["job.failed"], Some("com.acme-main")), None);
+
+    assert!(everything.matches(WebhookEvent::Started, "com.acme-other"));
+    assert!(failures.matches(WebhookEvent::Failed, "com.acme-main"));
+    assert!(!failures.matches(WebhookEvent::Succeeded, "com.acme-main"));
+    assert!(!failures.matches(WebhookEvent::Failed, "com.acme-other"));
+}
+
+#[test]
+fn subscriptions_round_trip_through_storage() {
+    let persistence = ConsulKeyValueMock::new();
+    let subscription = WebhookSubscription::new(subscription_request("https://hooks.acme.test/", &["job.failed"], None), Some("ops".to_string()));
+    save_subscription(&persistence, &subscription).unwrap();
+    persistence.ref_map.borrow_mut().insert("com.test/namespace/subscriptions/corrupt".to_string(), encode(b"{\"url\":"));
+
+    assert_eq!(vec![subscription.clone()], get_subscriptions(&persistence).unwrap());
+    assert_eq!(Ok(()), delete_subscription(&persistence, &subscription.id));
+    assert!(get_subscriptions(&persistence).unwrap().is_empty());
+    assert_eq!(Err(PersistenceError::NotFound(format!("No webhook subscription found with id '{}'", subscription.id))), delete_subscription(&persistence, &subscription.id));
+}
+
+#[test]
+fn subscription_cache_refreshes_after_interval() {
+    let persistence = ConsulKeyValueMock::new();
+    let first = WebhookSubscription::new(subscription_request("https://hooks.acme.test/first", &[], None), None);
+    save_subscription(&persistence, &first).unwrap();
+    let mut cache = SubscriptionCache::new(Duration::from_secs(30));
+    let start = Instant::now();
+
+    assert_eq!(vec![first.clone()], cache.get(&persistence, start).to_vec());
+    persistence.ref_map.borrow_mut().clear();
+    assert_eq!(vec![first], cache.get(&persistence, start + Duration::from_secs(29)).to_vec());
+    assert!(cache.get(&persistence, start + Duration::from_secs(30)).is_empty());
+}
+
+#[test]
+fn deliveries_are_signed_per_target() {
+    let subscriptions = vec![
+        WebhookSubscription::new(subscription_request("https://hooks.acme.test/all", &[], None), None),
+        WebhookSubscription::new(subscription_request("https://hooks.acme.test/succeeded", &["job.succeeded"], None), None),
+    ];
+    let now = Instant::now();
+    let deliveries = get_deliveries(&job_event(JobState::DONE, JobOutcome::FAILED, Some("http://callback.acme.test/")), &subscriptions, Some("server-secret-0123"), now);
+
+    assert_eq!(vec!["https://hooks.acme.test/all", "http://callback.acme.test/"], deliveries.iter().map(|delivery| delivery.url.as_str()).collect::<Vec<_>>());
+    let expected_body = "{\"id\":\"dummy_id_1.job.failed.1493640000\",\"event\":\"job.failed\",\"timestamp\":\"2017-05-01T12:00:00Z\",\"job\":{\"jobId\":\"dummy_id_1\",\"jobName\":\"com.acme-main\",\"tags\":{},\"state\":\"DONE\",\"outcome\":\"FAILED\",\"timestamp\":\"2017-05-01T12:00:00Z\"}}";
+    assert_eq!(expected_body, deliveries[0].body);
+    assert_eq!(sign("0123456789abcdef", expected_body), deliveries[0].signature);
+    assert_eq!(sign("server-secret-0123", expected_body), deliveries[1].signature);
+    assert_eq!(WebhookEvent::Failed, deliveries[1].event);
+}
+
+#[test]
+fn deliveries_skip_callback_url_without_secret() {
+    let deliveries = get_deliveries(&job_event(JobState::QUEUED, JobOutcome::WAITING, Some("http://callback.acme.test/")), &[], None, Instant::now());
+    assert!(deliveries.is_empty());
+}
+
+#[test]
+fn delivery_queue_retries_with_backoff() {
+    let start = Instant::now();
+    let mut queue = DeliveryQueue::new();
+    queue.push(failing_delivery(start));
+    let pool = ThreadPool::new(2);
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let fail_first = {
+        let attempts = attempts.clone();
+        move |_: &Delivery| if attempts.fetch_add(1, Ordering::SeqCst) == 0 { Err("HTTP 502".to_string()) } else { Ok(()) }
+    };
+
+    assert!(queue.flush(&pool, fail_first.clone(), start).is_empty());
+    pool.join();
+    assert!(queue.flush(&pool, fail_first.clone(), start).is_empty());
+    assert_eq!(Some(get_retry_delay(1)), queue.time_until_next_attempt(start));
+    assert!(queue.flush(&pool, fail_first.clone(), start).is_empty());
+    pool.join();
+    assert_eq!(1, attempts.load(Ordering::SeqCst));
+
+    assert!(queue.flush(&pool, fail_first.clone(), start + get_retry_delay(1)).is_empty());
+    pool.join();
+    assert!(queue.flush(&pool, fail_first, start + get_retry_delay(1)).is_empty());
+    assert_eq!(2, attempts.load(Ordering::SeqCst));
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn delivery_queue_sends_concurrently() {
+    let now = Instant::now();
+    let mut queue = DeliveryQueue::new();
+    queue.push(failing_delivery(now));
+    queue.push(failing_delivery(now));
+    let pool = ThreadPool::new(2);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most_in_flight = Arc::new(AtomicUsize::new(0));
+    let slow_send = {
+        let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+        move |_: &Delivery| {
+            let sending = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most_in_flight.fetch_max(sending, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    };
+
+    let flushed = Instant::now();
+    assert!(queue.flush(&pool, slow_send.clone(), now).is_empty());
+
+    // the sender never waits on a delivery, it checks back for the result
+    assert!(flushed.elapsed() < Duration::from_millis(200));
+    assert_eq!(2, queue.len());
+    assert_eq!(Some(Duration::from_millis(SENDING_POLL_MILLIS)), queue.time_until_next_attempt(now));
+    pool.join();
+    assert!(queue.flush(&pool, slow_send, now).is_empty());
+    assert!(queue.is_empty());
+    assert_eq!(2, most_in_flight.load(Ordering::SeqCst));
+}
+
+#[test]
+fn delivery_queue_dead_letters_after_max_attempts() {
+    let mut now = Instant::now();
+    let mut queue = DeliveryQueue::new();
+    queue.push(failing_delivery(now));
+    let pool = ThreadPool::new(2);
+    let always_fail = |_: &Delivery| Err("Connection refused".to_string());
+
+    for _ in 1..MAX_DELIVERY_ATTEMPTS {
+        assert!(queue.flush(&pool, always_fail, now).is_empty());
+        pool.join();
+        assert!(queue.flush(&pool, always_fail, now).is_empty());
+        now += queue.time_until_next_attempt(now).unwrap();
+    }
+    assert!(queue.flush(&pool, always_fail, now).is_empty());
+    pool.join();
+    let dead_letters = queue.flush(&pool, always_fail, now);
+
+    assert!(queue.is_empty());
+    assert_eq!(1, dead_letters.len());
+    assert_eq!(MAX_DELIVERY_ATTEMPTS, dead_letters[0].attempts);
+    assert_eq!("Connection refused", dead_letters[0].last_error);
+    assert_eq!("job.failed", dead_letters[0].event);
+
+    let mut log: Vec<u8> = vec![];
+    write_dead_letters(&mut log, &dead_letters).unwrap();
+    let line = String::from_utf8(log).unwrap();
+    assert!(line.starts_with("{\"deliveryId\":\"dummy_id_1.job.failed.1493640000\",\"url\":\"http://hooks.acme.test/\""));
+    assert_eq!(1, line.lines().count());
+    let logged: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(Some("dummy_id_1"), logged["payload"]["job"]["jobId"].as_str());
+}
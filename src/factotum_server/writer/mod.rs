@@ -0,0 +1,271 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use factotum_server::persistence;
+use factotum_server::persistence::{Persistence, PersistenceError, JobState, JobOutcome, JobResult, StoredJobEntry};
+use factotum_server::server::JobRequest;
+
+#[cfg(test)]
+mod tests;
+
+const RETRY_BASE_MILLIS: u64 = 500;
+const RETRY_MAX_MILLIS: u64 = 30000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryUpdate {
+    pub job_ref: String,
+    pub job_request: JobRequest,
+    pub state: JobState,
+    pub outcome: JobOutcome,
+    pub result: Option<JobResult>,
+}
+
+impl EntryUpdate {
+    pub fn new(job_ref: &str, job_request: &JobRequest, state: &JobState, outcome: &JobOutcome, result: Option<JobResult>) -> EntryUpdate {
+        EntryUpdate {
+            job_ref: job_ref.to_owned(),
+            job_request: job_request.to_owned(),
+            state: state.to_owned(),
+            outcome: outcome.to_owned(),
+            result: result,
+        }
+    }
+}
+
+/// Check-and-set rewrite of an entry as it was listed, see `persistence::swap_entry`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntrySwap {
+    pub stored_entry: StoredJobEntry,
+    pub state: JobState,
+    pub outcome: JobOutcome,
+    pub result: Option<JobResult>,
+}
+
+impl EntrySwap {
+    pub fn new(stored_entry: &StoredJobEntry, state: &JobState, outcome: &JobOutcome, result: Option<JobResult>) -> EntrySwap {
+        EntrySwap {
+            stored_entry: stored_entry.to_owned(),
+            state: state.to_owned(),
+            outcome: outcome.to_owned(),
+            result: result,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteRequest {
+    Update(EntryUpdate),
+    Swap(EntrySwap, Sender<Result<StoredJobEntry, PersistenceError>>),
+}
+
+impl PartialEq for WriteRequest {
+    fn eq(&self, other: &WriteRequest) -> bool {
+        match (self, other) {
+            (&WriteRequest::Update(ref a), &WriteRequest::Update(ref b)) => a == b,
+            (&WriteRequest::Swap(ref a, _), &WriteRequest::Swap(ref b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Handle used to send job entry writes to the writer thread, so the threads that
+/// dispatch and run jobs never write to Consul themselves.
+#[derive(Clone, Debug)]
+pub struct PersistenceWriter {
+    tx: Sender<WriteRequest>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl PersistenceWriter {
+    pub fn new(tx: Sender<WriteRequest>) -> PersistenceWriter {
+        PersistenceWriter {
+            tx: tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queues the update without waiting for it to be written.
+    pub fn write(&self, update: EntryUpdate) -> Result<(), String> {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.tx.send(WriteRequest::Update(update)).map_err(|e| {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            format!("Persistence writer has stopped - {}", e)
+        })
+    }
+
+    /// Waits for the writer to attempt the swap; a lost race is never retried.
+    pub fn swap(&self, swap: EntrySwap) -> Result<StoredJobEntry, PersistenceError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.tx.send(WriteRequest::Swap(swap, reply_tx))
+            .map_err(|e| PersistenceError::Unavailable(format!("Persistence writer has stopped - {}", e)))?;
+        reply_rx.recv()
+            .map_err(|e| PersistenceError::Unavailable(format!("Persistence writer has stopped - {}", e)))?
+    }
+
+    /// Updates queued or waiting to be retried that haven't reached Consul yet.
+    pub fn pending_writes(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+struct PendingWrite {
+    update: EntryUpdate,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Latest not-yet-persisted update per job entry. A newer update for the same key
+/// replaces the older one, as only the final state needs to reach Consul.
+pub struct Outbox {
+    writes: BTreeMap<String, PendingWrite>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Outbox {
+    pub fn new(pending: Arc<AtomicUsize>) -> Outbox {
+        Outbox {
+            writes: BTreeMap::new(),
+            pending: pending,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn push(&mut self, update: EntryUpdate, now: Instant) {
+        let pending_write = PendingWrite {
+            update: update,
+            attempts: 0,
+            next_attempt: now,
+        };
+        if let Some(replaced) = self.writes.insert(pending_write.update.job_ref.clone(), pending_write) {
+            debug!("Coalesced update for [{}] in state [{}]", replaced.update.job_ref, replaced.update.state);
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Writes every update that is due, keeping failed ones for a retry with
+    /// exponential backoff. Returns how many were persisted.
+    pub fn flush<T: Persistence>(&mut self, persistence: &T, now: Instant) -> usize {
+        let due: Vec<String> = self.writes.iter()
+                                          .filter(|&(_, pending_write)| pending_write.next_attempt <= now)
+                                          .map(|(job_ref, _)| job_ref.clone())
+                                          .collect();
+        let mut written = 0;
+        for job_ref in due {
+            let result = {
+                let update = &self.writes[&job_ref].update;
+                persistence::set_entry_with_result(persistence, &update.job_ref, &update.job_request, &update.state, &update.outcome, update.result.as_ref())
+            };
+            match result {
+                Ok(_) => {
+                    if let Some(pending_write) = self.writes.remove(&job_ref) {
+                        debug!("Persist [{}]::[{}]", job_ref, pending_write.update.state);
+                    }
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    written += 1;
+                },
+                Err(e) => {
+                    if let Some(pending_write) = self.writes.get_mut(&job_ref) {
+                        pending_write.attempts += 1;
+                        pending_write.next_attempt = now + get_retry_delay(pending_write.attempts);
+                        warn!("Persistence Error: Failed to update [{}] to [{}], attempt {} - {}", job_ref, pending_write.update.state, pending_write.attempts, e);
+                    }
+                },
+            }
+        }
+        written
+    }
+
+    pub fn time_until_next_attempt(&self, now: Instant) -> Option<Duration> {
+        self.writes.values()
+                   .map(|pending_write| pending_write.next_attempt)
+                   .min()
+                   .map(|next_attempt| if next_attempt > now { next_attempt - now } else { Duration::from_millis(0) })
+    }
+}
+
+pub fn get_retry_delay(attempts: u32) -> Duration {
+    let exponent = cmp::min(attempts.saturating_sub(1), 16);
+    Duration::from_millis(cmp::min(RETRY_BASE_MILLIS << exponent, RETRY_MAX_MILLIS))
+}
+
+pub fn spawn_writer<T: 'static + Persistence + Send>(persistence: T) -> (PersistenceWriter, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let writer = PersistenceWriter::new(tx);
+    let outbox = Outbox::new(writer.pending.clone());
+    let join_handle = thread::spawn(move || run_writer(rx, outbox, persistence));
+    (writer, join_handle)
+}
+
+fn run_writer<T: Persistence>(rx: Receiver<WriteRequest>, outbox: Outbox, persistence: T) {
+    let mut outbox = outbox;
+    loop {
+        // sleep until the next update arrives or a failed write is due for a retry
+        let received = match outbox.time_until_next_attempt(Instant::now()) {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let disconnected = match received {
+            Ok(request) => {
+                handle_request(request, &mut outbox, &persistence);
+                false
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        // batch up everything already waiting so repeated updates coalesce
+        while let Ok(request) = rx.try_recv() {
+            handle_request(request, &mut outbox, &persistence);
+        }
+        if !disconnected {
+            outbox.flush(&persistence, Instant::now());
+        } else {
+            // last chance for anything still waiting on a retry
+            outbox.flush(&persistence, Instant::now() + Duration::from_millis(RETRY_MAX_MILLIS));
+            if !outbox.is_empty() {
+                error!("Persistence writer stopping with {} unwritten job entry updates", outbox.len());
+            }
+            break;
+        }
+    }
+}
+
+// Updates wait in the outbox, swaps are made straight away as their callers are waiting
+fn handle_request<T: Persistence>(request: WriteRequest, outbox: &mut Outbox, persistence: &T) {
+    match request {
+        WriteRequest::Update(update) => outbox.push(update, Instant::now()),
+        WriteRequest::Swap(swap, reply_tx) => {
+            let result = persistence::swap_entry(persistence, &swap.stored_entry, &swap.state, &swap.outcome, swap.result.as_ref());
+            if reply_tx.send(result).is_err() {
+                warn!("Swap of [{}] to [{}] was abandoned before it was written", swap.stored_entry.key, swap.state);
+            }
+        },
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (c) 2017-2021 Snowplow Analytics Ltd. All rights reserved.
+//
+// This program is licensed to you under the Apache License Version 2.0, and
+// you may not use this file except in compliance with the Apache License
+// Version 2.0.  You may obtain a copy of the Apache License Version 2.0 at
+// http://www.apache.org/licenses/LICENSE-2.0.
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the Apache License Version 2.0 is distributed on an "AS
+// IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the Apache License Version 2.0 for the specific language
+// governing permissions and limitations there under.
+//
+
+use super::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use base64::encode;
+use serde_json;
+use factotum_server::persistence::{apply_namespace_if_absent, JobEntry, PersistenceError};
+
+// Fails the first `failures` writes, then stores values as plain JSON and returns
+// them base64 encoded; every key is at index 1
+#[derive(Clone, Debug)]
+struct FlakyPersistenceMock {
+    ref_map: Arc<Mutex<HashMap<String, String>>>,
+    failures: Arc<Mutex<usize>>,
+}
+
+impl FlakyPersistenceMock {
+    fn new(failures: usize) -> Self {
+        FlakyPersistenceMock {
+            ref_map: Arc::new(Mutex::new(HashMap::new())),
+            failures: Arc::new(Mutex::new(failures)),
+        }
+    }
+
+    fn get_state(&self, key: &str) -> Option<JobState> {
+        self.ref_map.lock().unwrap().get(key).map(|value| {
+            let job_entry: JobEntry = serde_json::from_str(value).expect("JSON decode error");
+            job_entry.state
+        })
+    }
+
+    fn writes(&self) -> usize {
+        self.ref_map.lock().unwrap().len()
+    }
+}
+
+impl Persistence for FlakyPersistenceMock {
+    fn id(&self) -> &str {
+        "test_writer"
+    }
+
+    fn set_key(&self, key: &str, value: &str) -> Result<(), PersistenceError> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(PersistenceError::Unavailable("setting key flaky".to_string()))
+        }
+        self.ref_map.lock().unwrap().insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn get_key_with_index(&self, key: &str) -> Result<Option<(String, u64)>, PersistenceError> {
+        Ok(self.ref_map.lock().unwrap().get(key).map(|value| (encode(value.as_bytes()), 1)))
+    }
+
+    fn set_key_if_index(&self, key: &str, value: &str, index: u64) -> Result<(), PersistenceError> {
+        let current_index = if self.ref_map.lock().unwrap().contains_key(key) { 1 } else { 0 };
+        if current_index != index {
+            return Err(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))
+        }
+        self.set_key(key, value)
+    }
+
+    fn get_keys_with_index(&self, prefix: &str) -> Result<Vec<(String, String, u64)>, PersistenceError> {
+        Ok(self.ref_map.lock().unwrap().iter()
+               .filter(|&(key, _)| key.starts_with(prefix))
+               .map(|(key, value)| (key.to_owned(), encode(value.as_bytes()), 1))
+               .collect())
+    }
+
+    fn delete_key_if_index(&self, key: &str, index: u64) -> Result<(), PersistenceError> {
+        let mut map = self.ref_map.lock().unwrap();
+        if !map.contains_key(key) || index != 1 {
+            return Err(PersistenceError::Conflict(format!("key '{}' was modified since index {}", key, index)))
+        }
+        map.remove(key);
+        Ok(())
+    }
+
+    fn prepend_namespace(&self, key: &str) -> String {
+        apply_namespace_if_absent("com.test/namespace", key)
+    }
+}
+
+fn update(job_id: &str, state: JobState, outcome: JobOutcome) -> EntryUpdate {
+    let request = JobRequest::new(job_id, "dummy", "/fake/path", vec![]);
+    EntryUpdate::new(job_id, &request, &state, &outcome, None)
+}
+
+fn stored_entry(job_id: &str, state: JobState, index: u64) -> StoredJobEntry {
+    let request = JobRequest::new(job_id, "dummy", "/fake/path", vec![]);
+    StoredJobEntry {
+        key: format!("com.test/namespace/{}", job_id),
+        index: index,
+        entry: JobEntry::new(&state, &request, "other_server", &JobOutcome::WAITING),
+    }
+}
+
+fn new_outbox() -> (Outbox, Arc<AtomicUsize>) {
+    let pending = Arc::new(AtomicUsize::new(0));
+    (Outbox::new(pending.clone()), pending)
+}
+
+#[test]
+fn get_retry_delay_backs_off_up_to_max() {
+    assert_eq!(Duration::from_millis(500), get_retry_delay(1));
+    assert_eq!(Duration::from_millis(1000), get_retry_delay(2));
+    assert_eq!(Duration::from_millis(4000), get_retry_delay(4));
+    assert_eq!(Duration::from_millis(30000), get_retry_delay(10));
+    assert_eq!(Duration::from_millis(30000), get_retry_delay(1000));
+}
+
+#[test]
+fn outbox_coalesces_updates_to_same_key() {
+    let (mut outbox, pending) = new_outbox();
+    let persistence = FlakyPersistenceMock::new(0);
+    let now = Instant::now();
+    pending.store(3, Ordering::SeqCst);
+
+    outbox.push(update("1", JobState::WORKING, JobOutcome::RUNNING), now);
+    outbox.push(update("1", JobState::DONE, JobOutcome::SUCCEEDED), now);
+    outbox.push(update("2", JobState::WORKING, JobOutcome::RUNNING), now);
+
+    assert_eq!(2, outbox.len());
+    assert_eq!(2, pending.load(Ordering::SeqCst));
+
+    assert_eq!(2, outbox.flush(&persistence, now));
+    assert!(outbox.is_empty());
+    assert_eq!(0, pending.load(Ordering::SeqCst));
+    assert_eq!(Some(JobState::DONE), persistence.get_state("com.test/namespace/1"));
+    assert_eq!(Some(JobState::WORKING), persistence.get_state("com.test/namespace/2"));
+}
+
+#[test]
+fn outbox_keeps_failed_writes_for_retry() {
+    let (mut outbox, pending) = new_outbox();
+    let persistence = FlakyPersistenceMock::new(1);
+    let now = Instant::now();
+    pending.store(1, Ordering::SeqCst);
+    outbox.push(update("1", JobState::DONE, JobOutcome::SUCCEEDED), now);
+
+    assert_eq!(0, outbox.flush(&persistence, now));
+    assert_eq!(1, outbox.len());
+    assert_eq!(1, pending.load(Ordering::SeqCst));
+    assert_eq!(Some(get_retry_delay(1)), outbox.time_until_next_attempt(now));
+
+    // not due yet
+    assert_eq!(0, outbox.flush(&persistence, now));
+    assert_eq!(None, persistence.get_state("com.test/namespace/1"));
+
+    assert_eq!(1, outbox.flush(&persistence, now + get_retry_delay(1)));
+    assert!(outbox.is_empty());
+    assert_eq!(0, pending.load(Ordering::SeqCst));
+    assert_eq!(Some(JobState::DONE), persistence.get_state("com.test/namespace/1"));
+}
+
+#[test]
+fn outbox_newer_update_resets_retry() {
+    let (mut outbox, _) = new_outbox();
+    let persistence = FlakyPersistenceMock::new(1);
+    let now = Instant::now();
+    outbox.push(update("1", JobState::WORKING, JobOutcome::RUNNING), now);
+    outbox.flush(&persistence, now);
+
+    outbox.push(update("1", JobState::DONE, JobOutcome::FAILED), now);
+
+    assert_eq!(Some(Duration::from_millis(0)), outbox.time_until_next_attempt(now));
+    assert_eq!(1, outbox.flush(&persistence, now));
+    assert_eq!(Some(JobState::DONE), persistence.get_state("com.test/namespace/1"));
+}
+
+#[test]
+fn spawn_writer_persists_and_stops() {
+    let persistence = FlakyPersistenceMock::new(0);
+    let (writer, handle) = spawn_writer(persistence.clone());
+
+    writer.write(update("1", JobState::WORKING, JobOutcome::RUNNING)).unwrap();
+    writer.write(update("2", JobState::DONE, JobOutcome::SUCCEEDED)).unwrap();
+    drop(writer);
+    handle.join().unwrap();
+
+    assert_eq!(2, persistence.writes());
+}
+
+#[test]
+fn spawn_writer_retries_on_stop() {
+    let persistence = FlakyPersistenceMock::new(1);
+    let (writer, handle) = spawn_writer(persistence.clone());
+    let pending_writer = writer.clone();
+
+    writer.write(update("1", JobState::DONE, JobOutcome::SUCCEEDED)).unwrap();
+    drop(writer);
+    drop(pending_writer);
+    handle.join().unwrap();
+
+    assert_eq!(Some(JobState::DONE), persistence.get_state("com.test/namespace/1"));
+}
+
+#[test]
+fn write_fails_when_writer_stopped() {
+    let (tx, rx) = mpsc::channel();
+    let writer = PersistenceWriter::new(tx);
+    drop(rx);
+
+    assert!(writer.write(update("1", JobState::DONE, JobOutcome::SUCCEEDED)).is_err());
+    assert_eq!(0, writer.pending_writes());
+}
+
+#[test]
+fn spawn_writer_swaps_entry_at_listed_index() {
+    let persistence = FlakyPersistenceMock::new(0);
+    persistence.set_key("com.test/namespace/1", "{}").unwrap();
+    let (writer, _) = spawn_writer(persistence.clone());
+
+    let result = writer.swap(EntrySwap::new(&stored_entry("1", JobState::QUEUED, 1), &JobState::WORKING, &JobOutcome::RUNNING, None));
+
+    assert_eq!(Ok((JobState::WORKING, 1)), result.map(|stored_entry| (stored_entry.entry.state, stored_entry.index)));
+    assert_eq!(Some(JobState::WORKING), persistence.get_state("com.test/namespace/1"));
+    assert_eq!(0, writer.pending_writes());
+}
+
+#[test]
+fn spawn_writer_swap_loses_race() {
+    let persistence = FlakyPersistenceMock::new(0);
+    persistence.set_key("com.test/namespace/1", "{}").unwrap();
+    let (writer, _) = spawn_writer(persistence.clone());
+
+    let result = writer.swap(EntrySwap::new(&stored_entry("1", JobState::QUEUED, 0), &JobState::WORKING, &JobOutcome::RUNNING, None));
+
+    assert_eq!(Err(PersistenceError::Conflict("key 'com.test/namespace/1' was modified since index 0".to_string())), result);
+}
+
+#[test]
+fn swap_fails_when_writer_stopped() {
+    let (tx, rx) = mpsc::channel();
+    let writer = PersistenceWriter::new(tx);
+    drop(rx);
+
+    let result = writer.swap(EntrySwap::new(&stored_entry("1", JobState::QUEUED, 1), &JobState::WORKING, &JobOutcome::RUNNING, None));
+
+    assert!(result.is_err());
+}
@@ -36,8 +36,12 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
-extern crate consul;
+extern crate curl;
 extern crate base64;
+extern crate ctrlc;
+extern crate hyper;
+extern crate openssl;
+extern crate libc;
 
 use docopt::Docopt;
 use log::LogLevelFilter;
@@ -56,17 +60,28 @@ const IP_DEFAULT: &'static str = "0.0.0.0";
 const PORT_DEFAULT: u32 = 3000;
 const MAX_JOBS_DEFAULT: usize = 1000;
 const MAX_WORKERS_DEFAULT: usize = 20;
+const RETENTION_INTERVAL_DEFAULT: u64 = 3600;
+const WAIT_TIMEOUT_DEFAULT: u64 = 60;
+const WAIT_TIMEOUT_MAX: u64 = 600;
 
 const CONSUL_NAME_DEFAULT: &'static str = FACTOTUM;
 const CONSUL_IP_DEFAULT: &'static str = "127.0.0.1";
 const CONSUL_PORT_DEFAULT: u32 = 8500;
 const CONSUL_NAMESPACE_DEFAULT: &'static str = "com.snowplowanalytics/factotum";
+const CONSUL_SERVICE_NAME_DEFAULT: &'static str = "factotum-server";
+const CONSUL_TOKEN_ENV: &'static str = "CONSUL_HTTP_TOKEN";
 
 const SERVER_STATE_RUN: &'static str = "run";
 const SERVER_STATE_DRAIN: &'static str = "drain";
 
 const JSON_CONTENT_TYPE: &'static str = "application/json; charset=UTF-8";
 
+const CALLBACK_DEAD_LETTER_LOG_DEFAULT: &'static str = "factotum-server-dead-letters.log";
+const FACTFILE_STORE_DEFAULT: &'static str = "factotum-server-factfiles";
+
+const HEALTH_CHECK_PATH: &'static str = "/healthz";
+const READINESS_CHECK_PATH: &'static str = "/readyz";
+
 const VALID_IP_REGEX: &'static str = r"\b(?:(?:25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])\b";
 
 const USAGE: &'static str =
@@ -74,7 +89,7 @@ const USAGE: &'static str =
 Factotum Server.
 
 Usage:
-  factotum-server --factotum-bin=<path> [--ip=<address>] [--port=<number>] [--max-jobs=<size>] [--max-workers=<size>] [--webhook=<url>] [--no-colour] [--consul-name=<name>] [--consul-ip=<address>] [--consul-port=<number>] [--consul-namespace=<namespace>] [--log-level=<level>] [--max-stdouterr-size=<bytes>]
+  factotum-server --factotum-bin=<path> [--ip=<address>] [--port=<number>] [--max-jobs=<size>] [--max-workers=<size>] [--webhook=<url>] [--no-colour] [--consul-name=<name>] [--consul-ip=<address>] [--consul-port=<number>] [--consul-namespace=<namespace>] [--consul-token=<token>] [--consul-https] [--consul-ca-cert=<path>] [--consul-client-cert=<path>] [--consul-client-key=<path>] [--consul-service-name=<name>] [--consul-service-tags=<tags>] [--retention-max-age=<hours>] [--retention-max-count=<count>] [--retention-interval=<seconds>] [--cluster-mode] [--auth-tokens=<path>] [--tls-cert=<path> --tls-key=<path>] [--tls-client-ca=<path>] [--callback-secret-file=<path>] [--callback-dead-letter-log=<path>] [--factfile-store=<path>] [--factfile-root=<path>...] [--log-level=<level>] [--max-stdouterr-size=<bytes>]
   factotum-server (-h | --help)
   factotum-server (-v | --version)
 
@@ -93,6 +108,25 @@ Options:
   --consul-ip=<address>                 Specify IP address for Consul server agent.
   --consul-port=<number>                Specify port number for Consul server agent.
   --consul-namespace=<namespace>        Specify namespace of job references stored in Consul persistence.
+  --consul-token=<token>                ACL token sent with every Consul request (defaults to $CONSUL_HTTP_TOKEN).
+  --consul-https                        Use HTTPS to talk to the Consul server agent.
+  --consul-ca-cert=<path>               CA bundle used to verify the Consul server agent certificate.
+  --consul-client-cert=<path>           Client certificate presented to the Consul server agent.
+  --consul-client-key=<path>            Private key for the Consul client certificate.
+  --consul-service-name=<name>          Specify service name this server registers itself under in Consul.
+  --consul-service-tags=<tags>          Comma-separated tags for the Consul service registration.
+  --retention-max-age=<hours>           Remove finished job entries older than this many hours.
+  --retention-max-count=<count>         Keep at most this many finished job entries per job name.
+  --retention-interval=<seconds>        How often the janitor removes finished job entries.
+  --cluster-mode                        Share one job queue in Consul between every server using the same namespace.
+  --tls-cert=<path>                     Serve HTTPS with this PEM certificate chain, reloaded when the file changes.
+  --tls-key=<path>                      PEM private key for the TLS certificate.
+  --tls-client-ca=<path>                Require client certificates signed by this CA (mutual TLS).
+  --callback-secret-file=<path>         Sign callbacks to a job's own callbackUrl with the secret in this file; without it callbackUrl is refused.
+  --callback-dead-letter-log=<path>     Append callbacks that could not be delivered to this file (defaults to factotum-server-dead-letters.log).
+  --factfile-store=<path>               Directory factfiles submitted inline are run from, named by SHA256 (defaults to factotum-server-factfiles). The janitor removes those no job entry refers to.
+  --factfile-root=<path>                Only run factfile paths that resolve inside this directory; repeatable.
+  --auth-tokens=<path>                  Require a bearer token from this file (lines of '<identity> <token|sha256:hex> [viewer|submitter|admin]', viewer if no role is given).
   --max-stdouterr-size=<bytes>          The maximum size of the individual stdout/err sent via the webhook functions for job updates.
 ";
 
@@ -111,7 +145,26 @@ pub struct Args {
     flag_consul_ip: Option<String>,
     flag_consul_port: Option<u32>,
     flag_consul_namespace: Option<String>,
+    flag_consul_token: Option<String>,
+    flag_consul_https: bool,
+    flag_consul_ca_cert: Option<String>,
+    flag_consul_client_cert: Option<String>,
+    flag_consul_client_key: Option<String>,
+    flag_consul_service_name: Option<String>,
+    flag_consul_service_tags: Option<String>,
     flag_max_stdouterr_size: Option<usize>,
+    flag_retention_max_age: Option<i64>,
+    flag_retention_max_count: Option<usize>,
+    flag_retention_interval: Option<u64>,
+    flag_cluster_mode: bool,
+    flag_auth_tokens: Option<String>,
+    flag_tls_cert: Option<String>,
+    flag_tls_key: Option<String>,
+    flag_tls_client_ca: Option<String>,
+    flag_callback_secret_file: Option<String>,
+    flag_callback_dead_letter_log: Option<String>,
+    flag_factfile_store: Option<String>,
+    flag_factfile_root: Vec<String>,
 }
 
 fn main() {
@@ -151,6 +204,14 @@ fn check_and_init_logger(args: &Args) -> Result<(), String> {
         Ok(..) => {},
         Err(e) => return Err(e),
     };
+    match check_consul_tls_args(&args.flag_consul_ca_cert, &args.flag_consul_client_cert, &args.flag_consul_client_key) {
+        Ok(..) => {},
+        Err(e) => return Err(e),
+    };
+    match check_tls_args(&args.flag_tls_cert, &args.flag_tls_key, &args.flag_tls_client_ca) {
+        Ok(..) => {},
+        Err(e) => return Err(e),
+    };
     match init_logger(&args.flag_log_level) {
         Ok(..) => {},
         Err(e) => return Err(e),
@@ -165,6 +226,33 @@ fn check_factotum_bin_arg(factotum_bin: &str) -> Result<(), String> {
     Ok(())
 }
 
+fn check_consul_tls_args(ca_cert: &Option<String>, client_cert: &Option<String>, client_key: &Option<String>) -> Result<(), String> {
+    if client_cert.is_some() != client_key.is_some() {
+        return Err("Both --consul-client-cert and --consul-client-key must be provided together".to_string())
+    }
+    for path in vec![ca_cert, client_cert, client_key].into_iter().filter_map(|p| p.as_ref()) {
+        if !std::path::Path::new(path).exists() {
+            return Err(format!("Invalid path for Consul TLS file at: '{}'", path))
+        }
+    }
+    Ok(())
+}
+
+fn check_tls_args(cert: &Option<String>, key: &Option<String>, client_ca: &Option<String>) -> Result<(), String> {
+    if cert.is_some() != key.is_some() {
+        return Err("Both --tls-cert and --tls-key must be provided together".to_string())
+    }
+    if client_ca.is_some() && cert.is_none() {
+        return Err("--tls-client-ca requires --tls-cert and --tls-key".to_string())
+    }
+    for path in vec![cert, key, client_ca].into_iter().filter_map(|p| p.as_ref()) {
+        if !std::path::Path::new(path).exists() {
+            return Err(format!("Invalid path for TLS file at: '{}'", path))
+        }
+    }
+    Ok(())
+}
+
 fn check_ip_arg(wrapped_ip: &Option<String>) -> Result<(), String> {
     if let Some(ip) = wrapped_ip.as_ref() {
         if !is_a_valid_ip(&ip) {
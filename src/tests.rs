@@ -28,6 +28,55 @@ fn check_factotum_bin_arg_success() {
     assert_eq!(expected, actual);
 }
 
+#[test]
+fn check_consul_tls_args_success_with_none() {
+    let expected = Ok(());
+    let actual = check_consul_tls_args(&None, &None, &None);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_consul_tls_args_fail_missing_key() {
+    let expected = Err("Both --consul-client-cert and --consul-client-key must be provided together".to_string());
+    let actual = check_consul_tls_args(&None, &Some(".".to_string()), &None);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_consul_tls_args_fail_invalid_path() {
+    let expected = Err("Invalid path for Consul TLS file at: '/fake/ca.pem'".to_string());
+    let actual = check_consul_tls_args(&Some("/fake/ca.pem".to_string()), &None, &None);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_tls_args_success_with_none() {
+    let expected = Ok(());
+    let actual = check_tls_args(&None, &None, &None);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_tls_args_fail_missing_key() {
+    let expected = Err("Both --tls-cert and --tls-key must be provided together".to_string());
+    let actual = check_tls_args(&Some(".".to_string()), &None, &None);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_tls_args_fail_client_ca_without_cert() {
+    let expected = Err("--tls-client-ca requires --tls-cert and --tls-key".to_string());
+    let actual = check_tls_args(&None, &None, &Some(".".to_string()));
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn check_tls_args_fail_invalid_path() {
+    let expected = Err("Invalid path for TLS file at: '/fake/server.key'".to_string());
+    let actual = check_tls_args(&Some(".".to_string()), &Some("/fake/server.key".to_string()), &None);
+    assert_eq!(expected, actual);
+}
+
 #[test]
 fn check_ip_arg_fail() {
     let expected = Err("Invalid IP address: [NOT.AN.IP] - Regex mismatch".to_string());